@@ -0,0 +1,366 @@
+// 认证和按 key 前缀的权限控制
+//
+// users 文件是 json：
+// {"users": [{"name": "alice", "password": "<hash>", "tokens": ["<hash>"],
+//             "acl": [{"prefix": "app/", "perm": "rw"}, {"prefix": "", "perm": "r"}]}]}
+// password 和 token 只保存哈希，分别用 `kvs hash-password` 和 `kvs hash-password --token` 生成
+// password 用 argon2id 加盐哈希，token 是随机生成的长字符串，只做一次 SHA-256，
+// 这样只带 token 的认证可以直接按哈希查找，不需要和每个 token 做一次慢哈希
+// 一个 key 只要匹配任意一条规则的前缀并且规则包含需要的权限就允许访问
+//
+// 错误信息以 NOAUTH、WRONGPASS、NOPERM 开头，和 redis 的错误类型一致，客户端可以据此区分
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Algorithm, Argon2,
+};
+use chacha20poly1305::aead::OsRng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::Result;
+
+const TOKEN_PREFIX: &str = "sha256";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthError {
+    // 没有认证
+    NoAuth,
+    // 用户名或者密码错误
+    WrongPass,
+    // 没有访问这个 key 的权限
+    NoPerm {
+        user: String,
+        perm: Perm,
+        key: String,
+    },
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::NoAuth => write!(f, "NOAUTH Authentication required"),
+            AuthError::WrongPass => write!(f, "WRONGPASS invalid username-password pair"),
+            AuthError::NoPerm { user, perm, key } => write!(
+                f,
+                "NOPERM user '{}' has no {} permission on key '{}'",
+                user, perm, key
+            ),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Perm {
+    Read,
+    Write,
+}
+
+impl fmt::Display for Perm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Perm::Read => write!(f, "read"),
+            Perm::Write => write!(f, "write"),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Rule {
+    pub prefix: String,
+    // "r"、"w" 或者 "rw"
+    pub perm: String,
+}
+
+impl Rule {
+    fn allows(&self, perm: Perm, key: &str) -> bool {
+        let c = match perm {
+            Perm::Read => 'r',
+            Perm::Write => 'w',
+        };
+        key.starts_with(&self.prefix) && self.perm.contains(c)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct User {
+    pub name: String,
+    #[serde(default)]
+    password: Option<String>,
+    #[serde(default)]
+    tokens: Vec<String>,
+    #[serde(default)]
+    pub acl: Vec<Rule>,
+}
+
+impl User {
+    pub fn allows(&self, perm: Perm, key: &str) -> bool {
+        self.acl.iter().any(|r| r.allows(perm, key))
+    }
+}
+
+#[derive(Deserialize)]
+struct UsersFile {
+    users: Vec<User>,
+}
+
+// 生成 PHC 格式的 argon2id 哈希："$argon2id$v=19$m=..,t=..,p=..$<salt>$<hash>"
+// 参数保存在哈希中，以后调整参数不影响已有的哈希
+pub fn hash_secret(secret: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(secret.as_bytes(), &salt)
+        .expect("argon2 with the default parameters does not fail")
+        .to_string()
+}
+
+// 生成 "sha256$<hash>" 格式的 token 哈希
+pub fn hash_token(token: &str) -> String {
+    format!(
+        "{}${}",
+        TOKEN_PREFIX,
+        hex(&Sha256::digest(token.as_bytes()))
+    )
+}
+
+fn hex(b: &[u8]) -> String {
+    b.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn is_password_hash(hash: &str) -> bool {
+    PasswordHash::new(hash).is_ok_and(|h| h.algorithm == Algorithm::Argon2id.ident())
+}
+
+// 按照哈希中的参数重新计算，比较是常数时间的
+pub fn verify_secret(hash: &str, secret: &str) -> bool {
+    PasswordHash::new(hash).is_ok_and(|h| {
+        h.algorithm == Algorithm::Argon2id.ident()
+            && Argon2::default()
+                .verify_password(secret.as_bytes(), &h)
+                .is_ok()
+    })
+}
+
+#[derive(Serialize)]
+struct AuditRecord<'a> {
+    time: u64,
+    peer: &'a str,
+    user: Option<&'a str>,
+    error: String,
+}
+
+// 所有连接共享的用户表和审计日志
+pub struct Authenticator {
+    users: HashMap<String, Arc<User>>,
+    // token 的哈希 -> 用户
+    tokens: HashMap<String, Arc<User>>,
+    // 用户不存在时也验证一次这个哈希，响应时间不会暴露用户是否存在
+    dummy: String,
+    // 被拒绝的操作，每行一个 json
+    audit: Option<Mutex<File>>,
+}
+
+impl Authenticator {
+    pub fn new(users: Vec<User>, audit: Option<&Path>) -> Result<Self> {
+        let audit = match audit {
+            Some(p) => Some(Mutex::new(
+                OpenOptions::new().create(true).append(true).open(p)?,
+            )),
+            None => None,
+        };
+        let mut by_name = HashMap::new();
+        let mut tokens = HashMap::new();
+        for u in users {
+            if u.password.as_deref().is_some_and(|h| !is_password_hash(h)) {
+                return Err(format!(
+                    "user {}: password is not an argon2id hash, generate it with `kvs hash-password`",
+                    u.name
+                )
+                .into());
+            }
+            let u = Arc::new(u);
+            for t in &u.tokens {
+                let valid = t
+                    .strip_prefix(TOKEN_PREFIX)
+                    .and_then(|h| h.strip_prefix('$'))
+                    .and_then(unhex)
+                    .is_some_and(|h| h.len() == 32);
+                if !valid {
+                    return Err(format!(
+                        "user {}: token is not a {} hash, generate it with `kvs hash-password --token`",
+                        u.name, TOKEN_PREFIX
+                    )
+                    .into());
+                }
+                if tokens.insert(t.clone(), u.clone()).is_some() {
+                    return Err(format!("user {}: token is used by another user", u.name).into());
+                }
+            }
+            by_name.insert(u.name.clone(), u);
+        }
+        Ok(Authenticator {
+            users: by_name,
+            tokens,
+            dummy: hash_secret(""),
+            audit,
+        })
+    }
+
+    pub fn load(users_file: &Path, audit: Option<&Path>) -> Result<Self> {
+        let f: UsersFile = serde_json::from_str(&fs::read_to_string(users_file)?)?;
+        Self::new(f.users, audit)
+    }
+
+    // 没有用户名时把 secret 当作 token，按哈希查找
+    pub fn authenticate(&self, user: Option<&str>, secret: &str) -> Option<Arc<User>> {
+        match user {
+            Some(name) => {
+                let u = self.users.get(name);
+                let hash = u.and_then(|u| u.password.as_deref()).unwrap_or(&self.dummy);
+                let ok = verify_secret(hash, secret);
+                u.filter(|u| ok && u.password.is_some()).cloned()
+            }
+            None => self.tokens.get(&hash_token(secret)).cloned(),
+        }
+    }
+
+    pub fn user(&self, name: &str) -> Option<Arc<User>> {
+        self.users.get(name).cloned()
+    }
+
+    fn audit(&self, peer: &str, user: Option<&str>, e: &AuthError) {
+        if let Some(f) = &self.audit {
+            let r = AuditRecord {
+                time: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or_default(),
+                peer,
+                user,
+                error: e.to_string(),
+            };
+            if let Ok(mut line) = serde_json::to_vec(&r) {
+                line.push(b'\n');
+                let _ = f.lock().unwrap().write_all(&line);
+            }
+        }
+    }
+}
+
+// 一个连接的认证状态，没有配置 Authenticator 时所有操作都允许
+pub struct Session {
+    auth: Option<Arc<Authenticator>>,
+    user: Option<Arc<User>>,
+    peer: String,
+}
+
+impl Session {
+    pub fn new(auth: Option<Arc<Authenticator>>, peer: String) -> Self {
+        Session {
+            auth,
+            user: None,
+            peer,
+        }
+    }
+
+    pub fn peer(&self) -> &str {
+        &self.peer
+    }
+
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_ref().map(|u| u.name.as_str())
+    }
+
+    pub fn login(
+        &mut self,
+        user: Option<&str>,
+        secret: &str,
+    ) -> std::result::Result<(), AuthError> {
+        let auth = match &self.auth {
+            Some(a) => a,
+            None => return Ok(()),
+        };
+        match auth.authenticate(user, secret) {
+            Some(u) => {
+                self.user = Some(u);
+                Ok(())
+            }
+            None => {
+                auth.audit(&self.peer, user, &AuthError::WrongPass);
+                Err(AuthError::WrongPass)
+            }
+        }
+    }
+
+    // mTLS 证书的 CN 和用户名相同时直接认证为这个用户
+    pub fn login_identity(&mut self, name: &str) -> bool {
+        match self.auth.as_ref().and_then(|a| a.user(name)) {
+            Some(u) => {
+                self.user = Some(u);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // 不访问 key 的命令（比如事务的开始和提交）只需要认证
+    pub fn check_auth(&self) -> std::result::Result<(), AuthError> {
+        match (&self.auth, &self.user) {
+            (Some(auth), None) => {
+                auth.audit(&self.peer, None, &AuthError::NoAuth);
+                Err(AuthError::NoAuth)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn check(&self, perm: Perm, key: &str) -> std::result::Result<(), AuthError> {
+        let auth = match &self.auth {
+            Some(a) => a,
+            None => return Ok(()),
+        };
+        self.check_auth()?;
+        let user = self.user.as_ref().unwrap();
+        if user.allows(perm, key) {
+            return Ok(());
+        }
+        let e = AuthError::NoPerm {
+            user: user.name.clone(),
+            perm,
+            key: key.to_owned(),
+        };
+        auth.audit(&self.peer, Some(&user.name), &e);
+        Err(e)
+    }
+
+    // 列出 key 时用来过滤没有读权限的 key，不记审计日志
+    pub fn can(&self, perm: Perm, key: &str) -> bool {
+        match (&self.auth, &self.user) {
+            (None, _) => true,
+            (Some(_), Some(u)) => u.allows(perm, key),
+            (Some(_), None) => false,
+        }
+    }
+}
@@ -0,0 +1,505 @@
+// kvs-bench 使用的工作负载、key 分布、延迟统计和报告
+//
+// 先写入所有 key（YCSB 的 load 阶段），然后 concurrency 个线程在 duration 内不停地发送请求
+// 每个请求的延迟记录在线程自己的直方图中，结束之后合并
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Write},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+
+use crate::{proxy::Remote, sim::Rng, KvsEngine, Result};
+
+// YCSB 默认的 zipfian 常数
+pub const ZIPF_THETA: f64 = 0.99;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Distribution {
+    Uniform,
+    // 少量 key 占大部分请求，热点 key 打散在整个 key 空间中
+    Zipfian,
+}
+
+// YCSB 的核心工作负载，没有 scan 的 E
+#[derive(Copy, Clone, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Preset {
+    // 50% 读 50% 更新
+    A,
+    // 95% 读 5% 更新
+    B,
+    // 只读
+    C,
+    // 50% 读 50% 读后写
+    F,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Workload {
+    // preset 的名字，或者 custom
+    pub name: String,
+    // 剩下的是 update，rmw 是先 get 再 set 同一个 key，算一个请求
+    pub read: f64,
+    pub rmw: f64,
+    pub distribution: Distribution,
+    pub keys: u64,
+    pub value_size: usize,
+}
+
+impl Workload {
+    pub fn preset(p: Preset) -> Self {
+        let (name, read, rmw) = match p {
+            Preset::A => ("a", 0.5, 0.0),
+            Preset::B => ("b", 0.95, 0.0),
+            Preset::C => ("c", 1.0, 0.0),
+            Preset::F => ("f", 0.5, 0.5),
+        };
+        Workload {
+            name: name.to_owned(),
+            read,
+            rmw,
+            distribution: Distribution::Zipfian,
+            ..Workload::default()
+        }
+    }
+}
+
+impl Default for Workload {
+    fn default() -> Self {
+        Workload {
+            name: "custom".to_owned(),
+            read: 0.5,
+            rmw: 0.0,
+            distribution: Distribution::Uniform,
+            keys: 10000,
+            value_size: 100,
+        }
+    }
+}
+
+impl fmt::Display for Workload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {:.0}% read", self.name, self.read * 100.0)?;
+        if self.rmw > 0.0 {
+            write!(f, ", {:.0}% read-modify-write", self.rmw * 100.0)?;
+        }
+        let update = 1.0 - self.read - self.rmw;
+        if update > 0.0 {
+            write!(f, ", {:.0}% update", update * 100.0)?;
+        }
+        let distribution = match self.distribution {
+            Distribution::Uniform => "uniform",
+            Distribution::Zipfian => "zipfian",
+        };
+        write!(
+            f,
+            ", {} over {} keys, {} byte values",
+            distribution, self.keys, self.value_size
+        )
+    }
+}
+
+// YCSB 的 ScrambledZipfianGenerator：按 Gray 等人的方法生成 zipfian 分布的序号，再用 FNV 哈希打散
+pub struct Zipfian {
+    n: u64,
+    theta: f64,
+    alpha: f64,
+    zetan: f64,
+    eta: f64,
+}
+
+impl Zipfian {
+    pub fn new(n: u64, theta: f64) -> Self {
+        let zeta = |n: u64| (1..=n).map(|i| 1.0 / (i as f64).powf(theta)).sum::<f64>();
+        let zetan = zeta(n);
+        let zeta2 = zeta(2.min(n));
+        Zipfian {
+            n,
+            theta,
+            alpha: 1.0 / (1.0 - theta),
+            zetan,
+            eta: (1.0 - (2.0 / n as f64).powf(1.0 - theta)) / (1.0 - zeta2 / zetan),
+        }
+    }
+
+    // 热度排名，0 最热
+    pub fn rank(&self, rng: &mut Rng) -> u64 {
+        let u = rng.next_f64();
+        let uz = u * self.zetan;
+        if uz < 1.0 {
+            return 0;
+        }
+        if uz < 1.0 + 0.5f64.powf(self.theta) {
+            return 1.min(self.n - 1);
+        }
+        let r = (self.n as f64 * (self.eta * u - self.eta + 1.0).powf(self.alpha)) as u64;
+        r.min(self.n - 1)
+    }
+
+    pub fn next(&self, rng: &mut Rng) -> u64 {
+        fnv64(self.rank(rng)) % self.n
+    }
+}
+
+fn fnv64(v: u64) -> u64 {
+    let mut h = 0xcbf2_9ce4_8422_2325u64;
+    for b in v.to_le_bytes() {
+        h ^= b as u64;
+        h = h.wrapping_mul(0x0100_0000_01b3);
+    }
+    h
+}
+
+// 每个 2 的幂次分成 128 个桶，相对误差小于 1%
+const SUB_BITS: u32 = 7;
+const SUB: u64 = 1 << SUB_BITS;
+
+#[derive(Clone)]
+pub struct LatencyHistogram {
+    // 纳秒
+    counts: Vec<u64>,
+    total: u64,
+    sum: u128,
+    max: u64,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        LatencyHistogram {
+            counts: vec![0; ((64 - SUB_BITS as u64 + 1) * SUB) as usize],
+            total: 0,
+            sum: 0,
+            max: 0,
+        }
+    }
+}
+
+fn bucket(v: u64) -> usize {
+    if v < SUB {
+        return v as usize;
+    }
+    let e = 63 - v.leading_zeros() - SUB_BITS;
+    (((e as u64 + 1) << SUB_BITS) + (v >> e) - SUB) as usize
+}
+
+// 桶中最大的值
+fn bucket_high(i: usize) -> u64 {
+    let i = i as u64;
+    if i < SUB {
+        return i;
+    }
+    let e = i / SUB - 1;
+    ((i % SUB + SUB + 1) << e) - 1
+}
+
+impl LatencyHistogram {
+    pub fn record(&mut self, d: Duration) {
+        let v = d.as_nanos().min(u64::MAX as u128) as u64;
+        self.counts[bucket(v)] += 1;
+        self.total += 1;
+        self.sum += v as u128;
+        self.max = self.max.max(v);
+    }
+
+    pub fn merge(&mut self, other: &LatencyHistogram) {
+        for (a, b) in self.counts.iter_mut().zip(&other.counts) {
+            *a += b;
+        }
+        self.total += other.total;
+        self.sum += other.sum;
+        self.max = self.max.max(other.max);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.total
+    }
+
+    // q 在 0 到 1 之间，没有记录时返回 0
+    pub fn percentile(&self, q: f64) -> Duration {
+        let target = ((self.total as f64 * q).ceil() as u64).max(1);
+        let mut acc = 0;
+        for (i, c) in self.counts.iter().enumerate() {
+            acc += c;
+            if acc >= target {
+                return Duration::from_nanos(bucket_high(i).min(self.max));
+            }
+        }
+        Duration::ZERO
+    }
+
+    pub fn summary(&self) -> LatencySummary {
+        let us = |d: Duration| d.as_nanos() as f64 / 1000.0;
+        LatencySummary {
+            ops: self.total,
+            mean_us: match self.total {
+                0 => 0.0,
+                n => self.sum as f64 / n as f64 / 1000.0,
+            },
+            p50_us: us(self.percentile(0.5)),
+            p99_us: us(self.percentile(0.99)),
+            p999_us: us(self.percentile(0.999)),
+            max_us: self.max as f64 / 1000.0,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct LatencySummary {
+    pub ops: u64,
+    pub mean_us: f64,
+    pub p50_us: f64,
+    pub p99_us: f64,
+    pub p999_us: f64,
+    pub max_us: f64,
+}
+
+// 压测的对象，每个线程一个
+pub trait BenchClient: Send {
+    fn get(&mut self, key: String) -> Result<Option<String>>;
+
+    fn set(&mut self, key: String, value: String) -> Result<()>;
+}
+
+// 进程内的引擎，所有线程共享
+pub struct Embedded(pub Arc<dyn KvsEngine + Sync>);
+
+impl BenchClient for Embedded {
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.0.get(key)
+    }
+
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.0.set(key, value)
+    }
+}
+
+// 运行中的 kvs-server，每个线程一个连接
+impl BenchClient for Remote {
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        Remote::get(self, key)
+    }
+
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        Remote::set(self, key, value)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct BenchOptions {
+    pub workload: Workload,
+    pub concurrency: usize,
+    pub duration: Duration,
+    // 测试之前先写入所有 key
+    pub load: bool,
+}
+
+impl Default for BenchOptions {
+    fn default() -> Self {
+        BenchOptions {
+            workload: Workload::default(),
+            concurrency: 8,
+            duration: Duration::from_secs(10),
+            load: true,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct BenchReport {
+    // 引擎名字或者 server 地址
+    pub target: String,
+    pub workload: Workload,
+    pub concurrency: usize,
+    pub load_secs: f64,
+    pub duration_secs: f64,
+    pub ops: u64,
+    pub errors: u64,
+    // 每秒完成的请求数，不包括出错的
+    pub throughput: f64,
+    // all 和每种请求的延迟
+    pub latency: BTreeMap<String, LatencySummary>,
+}
+
+impl BenchReport {
+    pub fn to_text(&self) -> String {
+        let mut s = String::new();
+        let _ = writeln!(s, "target       {}", self.target);
+        let _ = writeln!(s, "workload     {}", self.workload);
+        let _ = writeln!(
+            s,
+            "concurrency  {} for {:.1}s (load {:.1}s)",
+            self.concurrency, self.duration_secs, self.load_secs
+        );
+        let _ = writeln!(s, "ops          {} ({} errors)", self.ops, self.errors);
+        let _ = writeln!(s, "throughput   {:.1} ops/s", self.throughput);
+        let _ = writeln!(
+            s,
+            "latency      {:>10} {:>10} {:>10} {:>10} {:>10} {:>10}",
+            "ops", "mean", "p50", "p99", "p999", "max"
+        );
+        for (name, l) in &self.latency {
+            let _ = writeln!(
+                s,
+                "  {:<10} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10}",
+                name,
+                l.ops,
+                fmt_us(l.mean_us),
+                fmt_us(l.p50_us),
+                fmt_us(l.p99_us),
+                fmt_us(l.p999_us),
+                fmt_us(l.max_us)
+            );
+        }
+        s
+    }
+}
+
+fn fmt_us(us: f64) -> String {
+    if us >= 1000.0 {
+        format!("{:.2}ms", us / 1000.0)
+    } else {
+        format!("{:.1}us", us)
+    }
+}
+
+const KINDS: [&str; 3] = ["read", "update", "rmw"];
+
+fn key(i: u64) -> String {
+    format!("user{:010}", i)
+}
+
+fn value(size: usize, rng: &mut Rng) -> String {
+    let c = (b'a' + rng.below(26) as u8) as char;
+    c.to_string().repeat(size)
+}
+
+fn seed() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as u64)
+}
+
+// connect 为每个线程创建一个客户端
+pub fn run<F>(target: &str, opts: &BenchOptions, connect: F) -> Result<BenchReport>
+where
+    F: Fn() -> Result<Box<dyn BenchClient>>,
+{
+    let w = &opts.workload;
+    if w.keys == 0 || opts.concurrency == 0 {
+        return Err("keys and concurrency must be positive".into());
+    }
+    if !(0.0..=1.0).contains(&w.read) || !(0.0..=1.0 - w.read).contains(&w.rmw) {
+        return Err("read and read-modify-write ratios must add up to at most 1".into());
+    }
+    let mut clients = (0..opts.concurrency)
+        .map(|_| connect())
+        .collect::<Result<Vec<_>>>()?;
+
+    // 每个线程写入一段 key
+    let start = Instant::now();
+    if opts.load {
+        let per = w.keys.div_ceil(opts.concurrency as u64);
+        thread::scope(|s| {
+            let handles: Vec<_> = clients
+                .iter_mut()
+                .enumerate()
+                .map(|(t, c)| {
+                    s.spawn(move || {
+                        let mut rng = Rng::new(seed() ^ t as u64);
+                        let end = (per * (t as u64 + 1)).min(w.keys);
+                        for i in per * t as u64..end {
+                            c.set(key(i), value(w.value_size, &mut rng))
+                                .map_err(|e| format!("load {}: {}", key(i), e))?;
+                        }
+                        Ok::<(), String>(())
+                    })
+                })
+                .collect();
+            handles.into_iter().try_for_each(|h| h.join().unwrap())
+        })?;
+    }
+    let load_secs = start.elapsed().as_secs_f64();
+
+    let zipf = match w.distribution {
+        Distribution::Zipfian => Some(Zipfian::new(w.keys, ZIPF_THETA)),
+        Distribution::Uniform => None,
+    };
+    let start = Instant::now();
+    let deadline = start + opts.duration;
+    let results: Vec<([LatencyHistogram; 3], u64)> = thread::scope(|s| {
+        let handles: Vec<_> = clients
+            .into_iter()
+            .enumerate()
+            .map(|(t, mut c)| {
+                let zipf = zipf.as_ref();
+                s.spawn(move || {
+                    let mut rng = Rng::new(seed().wrapping_mul(31) ^ t as u64);
+                    let mut hists: [LatencyHistogram; 3] = Default::default();
+                    let mut errors = 0;
+                    while Instant::now() < deadline {
+                        let k = key(match zipf {
+                            Some(z) => z.next(&mut rng),
+                            None => rng.below(w.keys),
+                        });
+                        let p = rng.next_f64();
+                        let kind = if p < w.read {
+                            0
+                        } else if p < w.read + w.rmw {
+                            2
+                        } else {
+                            1
+                        };
+                        let begin = Instant::now();
+                        let r = match kind {
+                            0 => c.get(k).map(|_| ()),
+                            1 => c.set(k, value(w.value_size, &mut rng)),
+                            _ => c
+                                .get(k.clone())
+                                .and_then(|_| c.set(k, value(w.value_size, &mut rng))),
+                        };
+                        match r {
+                            Ok(()) => hists[kind].record(begin.elapsed()),
+                            Err(_) => errors += 1,
+                        }
+                    }
+                    (hists, errors)
+                })
+            })
+            .collect();
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    });
+    let elapsed = start.elapsed().as_secs_f64();
+
+    let mut all = LatencyHistogram::default();
+    let mut kinds: [LatencyHistogram; 3] = Default::default();
+    let mut errors = 0;
+    for (hists, e) in &results {
+        for (k, h) in kinds.iter_mut().zip(hists) {
+            k.merge(h);
+            all.merge(h);
+        }
+        errors += e;
+    }
+    let mut latency = BTreeMap::new();
+    latency.insert("all".to_owned(), all.summary());
+    for (name, h) in KINDS.iter().zip(&kinds) {
+        if h.count() > 0 {
+            latency.insert(name.to_string(), h.summary());
+        }
+    }
+    Ok(BenchReport {
+        target: target.to_owned(),
+        workload: w.clone(),
+        concurrency: opts.concurrency,
+        load_secs,
+        duration_secs: elapsed,
+        ops: all.count(),
+        errors,
+        throughput: all.count() as f64 / elapsed,
+        latency,
+    })
+}
@@ -0,0 +1,212 @@
+// kvs-bench [--engine ENGINE-NAME] [--dir DIR] [--pool POOL] [--addr ADDR] [--preset PRESET] [OPTIONS]
+
+// Measure throughput and latency. Without --addr the engine (kvs, the default, or sled) is opened in this process, in DIR or in a temporary directory that is removed afterwards. With --pool naive|shared-queue|rayon a kvs-server with that thread pool (of --pool-threads threads, default 8) is started in this process on a free local TCP port and the engine is driven through it. With --addr IP:PORT or unix:PATH a running kvs-server is driven instead.
+
+// Every key is written once first (the load phase, skipped with --no-load). Then --concurrency threads (default 8), each with its own connection, send requests back to back for --duration seconds (default 10).
+
+// --preset a|b|c|f picks a YCSB core workload, all with zipfian keys: a is 50% read and 50% update, b is 95% read and 5% update, c is read only, f is 50% read and 50% read-modify-write. --read-ratio (default 0.5), --distribution uniform|zipfian (default uniform), --keys (default 10000) and --value-size in bytes (default 100) set the workload or override the preset.
+
+// The report gives the number of requests, errors, throughput, and the mean, p50, p99, p999 and max latency of all requests and of each kind. --format json prints the same report as a JSON object.
+
+// kvs-bench -V
+
+// Print the version.
+
+use std::{
+    env, fs,
+    net::TcpListener,
+    path::{Path, PathBuf},
+    process::{self, exit},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use clap::{Parser, ValueEnum};
+use slog::{o, Discard, Logger};
+
+use kvs::{
+    bench::{self, BenchClient, BenchOptions, Distribution, Embedded, Preset, Workload},
+    net::{Addr, Stream},
+    proxy::Remote,
+    thread_pool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool},
+    KvStore, KvsEngine, KvsServer, Result, SledKvsEngine,
+};
+
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    // 运行中的 kvs-server，不指定时在进程内打开引擎
+    #[arg(long, conflicts_with_all = ["engine", "dir", "pool"])]
+    addr: Option<Addr>,
+    #[arg(long, value_enum, default_value_t = Engine::Kvs)]
+    engine: Engine,
+    // 不指定时使用临时目录，结束之后删除
+    #[arg(long)]
+    dir: Option<PathBuf>,
+    // 在进程内用这个线程池启动 kvs-server，通过 TCP 访问引擎
+    #[arg(long, value_enum)]
+    pool: Option<Pool>,
+    #[arg(long, default_value_t = 8)]
+    pool_threads: u32,
+    // YCSB 的工作负载，下面的参数可以覆盖其中的设置
+    #[arg(long, value_enum)]
+    preset: Option<Preset>,
+    #[arg(long)]
+    read_ratio: Option<f64>,
+    #[arg(long, value_enum)]
+    distribution: Option<Distribution>,
+    #[arg(long)]
+    keys: Option<u64>,
+    #[arg(long)]
+    value_size: Option<usize>,
+    #[arg(long, default_value_t = 8)]
+    concurrency: usize,
+    // 秒，可以是小数
+    #[arg(long, default_value_t = 10.0)]
+    duration: f64,
+    // 不预先写入所有 key，读到的大多是不存在的 key
+    #[arg(long)]
+    no_load: bool,
+    #[arg(long, value_enum, default_value_t = Format::Text)]
+    format: Format,
+}
+
+#[derive(Copy, Clone, ValueEnum)]
+enum Engine {
+    Kvs,
+    Sled,
+}
+
+#[derive(Copy, Clone, ValueEnum)]
+enum Pool {
+    Naive,
+    SharedQueue,
+    Rayon,
+}
+
+#[derive(Copy, Clone, ValueEnum)]
+enum Format {
+    Text,
+    Json,
+}
+
+fn main() {
+    let cli = Cli::parse();
+    let mut workload = match cli.preset {
+        Some(p) => Workload::preset(p),
+        None => Workload::default(),
+    };
+    if let Some(r) = cli.read_ratio {
+        workload.read = r;
+        workload.rmw = workload.rmw.min(1.0 - r);
+    }
+    if let Some(d) = cli.distribution {
+        workload.distribution = d;
+    }
+    if let Some(k) = cli.keys {
+        workload.keys = k;
+    }
+    if let Some(s) = cli.value_size {
+        workload.value_size = s;
+    }
+    if !cli.duration.is_finite() || cli.duration <= 0.0 {
+        eprintln!("--duration must be positive");
+        exit(1);
+    }
+    let opts = BenchOptions {
+        workload,
+        concurrency: cli.concurrency,
+        duration: Duration::from_secs_f64(cli.duration),
+        load: !cli.no_load,
+    };
+
+    let report = match &cli.addr {
+        Some(addr) => remote(&format!("kvs-server at {}", addr), addr.clone(), &opts),
+        None => embedded(&cli, &opts),
+    };
+    match report {
+        Ok(r) => match cli.format {
+            Format::Text => print!("{}", r.to_text()),
+            Format::Json => println!("{}", serde_json::to_string_pretty(&r).unwrap()),
+        },
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    }
+}
+
+fn remote(target: &str, addr: Addr, opts: &BenchOptions) -> Result<bench::BenchReport> {
+    // 先确认可以连接，Remote 在第一次请求时才连接
+    Stream::connect(&addr).map_err(|e| format!("connect {}: {}", addr, e))?;
+    bench::run(target, opts, || {
+        Ok(Box::new(Remote::new(addr.clone())) as Box<dyn BenchClient>)
+    })
+}
+
+fn embedded(cli: &Cli, opts: &BenchOptions) -> Result<bench::BenchReport> {
+    let (dir, temp) = match &cli.dir {
+        Some(d) => (d.clone(), false),
+        None => (
+            env::temp_dir().join(format!("kvs-bench-{}", process::id())),
+            true,
+        ),
+    };
+    fs::create_dir_all(&dir)?;
+    let r = run_engine(cli, &dir, opts);
+    if temp {
+        let _ = fs::remove_dir_all(&dir);
+    }
+    r
+}
+
+fn run_engine(cli: &Cli, dir: &Path, opts: &BenchOptions) -> Result<bench::BenchReport> {
+    let (name, engine): (&str, Arc<dyn KvsEngine + Sync>) = match cli.engine {
+        Engine::Kvs => ("kvs", Arc::new(KvStore::open(dir)?)),
+        Engine::Sled => ("sled", Arc::new(SledKvsEngine::open(dir)?)),
+    };
+    let threads = cli.pool_threads;
+    let (pool, addr) = match cli.pool {
+        None => {
+            let target = format!("{} (embedded)", name);
+            return bench::run(&target, opts, || {
+                Ok(Box::new(Embedded(engine.clone())) as Box<dyn BenchClient>)
+            });
+        }
+        Some(Pool::Naive) => ("naive", serve::<NaiveThreadPool>(engine, threads)?),
+        Some(Pool::SharedQueue) => (
+            "shared-queue",
+            serve::<SharedQueueThreadPool>(engine, threads)?,
+        ),
+        Some(Pool::Rayon) => ("rayon", serve::<RayonThreadPool>(engine, threads)?),
+    };
+    let target = format!(
+        "{} via kvs-server with {} pool of {} threads at {}",
+        name, pool, threads, addr
+    );
+    remote(&target, addr, opts)
+}
+
+// 在空闲的本地端口上启动 kvs-server，进程退出时结束
+fn serve<P: ThreadPool + Send + 'static>(
+    engine: Arc<dyn KvsEngine + Sync>,
+    threads: u32,
+) -> Result<Addr> {
+    let addr = Addr::Tcp(TcpListener::bind("127.0.0.1:0")?.local_addr()?);
+    let server = KvsServer::new(engine, P::new(threads)?, Logger::root(Discard, o!()));
+    let a = addr.clone();
+    thread::spawn(move || {
+        if let Err(e) = server.run(a) {
+            eprintln!("server: {}", e);
+            exit(1);
+        }
+    });
+    for _ in 0..100 {
+        if Stream::connect(&addr).is_ok() {
+            return Ok(addr);
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    Err(format!("server at {} did not start", addr).into())
+}
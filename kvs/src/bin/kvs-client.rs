@@ -22,60 +22,381 @@
 
 // Print an error and return a non-zero exit code on server error, or if IP-PORT does not parse as an address. A "key not found" is also treated as an error in the "rm" command.
 
+// --addr also accepts unix:PATH to connect to a server listening on a unix domain socket.
+
+// --user and --password, or --token, authenticate before sending the command. Authentication and permission errors are printed to stderr with a non-zero exit code.
+
+// --tls-ca connects with TLS and verifies the server certificate against that CA, --tls-cert and --tls-key present a client certificate for mutual TLS.
+
+// kvs-client role [--addr IP-PORT]
+
+// Print the replication role of the server as JSON: the offset of a primary, or the applied offset and lag of a replica.
+
+// kvs-client add-node <ID> <ADDR> [--addr IP-PORT]
+// kvs-client remove-node <ID> [--addr IP-PORT]
+
+// Add a node to or remove a node from a Raft cluster. Like get, set and rm, the command follows a NOTLEADER reply to the leader.
+
+// kvs-client keys [PREFIX] [--addr IP-PORT]
+
+// Print the keys starting with PREFIX, one per line, in order.
+
+// kvs-client add-backend <ADDR> [--addr IP-PORT]
+
+// Add a backend kvs-server to a kvs-proxy. The keys that now hash to the new backend are moved to it before the command returns.
+
+// kvs-client watch <KEY|PREFIX*> [--resume RUN_ID:SEQ] [--addr IP-PORT]
+
+// Print every following set and rm of KEY, or of the keys starting with PREFIX, as JSON lines. The first line carries the run_id and sequence number to resume from. After a disconnect the client reconnects and continues from the last sequence number; if the server restarted or the events were dropped, the new first line has "resumed": false.
+
+// kvs-client publish <CHANNEL> <MESSAGE> [--addr IP-PORT]
+
+// Publish MESSAGE on CHANNEL and print the number of subscribers that received it.
+
+// kvs-client subscribe <CHANNEL>... [--addr IP-PORT]
+// kvs-client psubscribe <PATTERN>... [--addr IP-PORT]
+
+// Print the messages published on the channels, or on the channels matching the glob patterns, as JSON lines. Messages are not kept for disconnected subscribers, so the client exits with a non-zero code when the connection is lost, including when the server disconnects it for reading too slowly.
+
+// kvs-client channels [--addr IP-PORT]
+
+// Print the number of subscribers of every channel and pattern as JSON.
+
+// kvs-client admin info [--addr IP-PORT]
+
+// Print the engine name, key count, disk usage, uptime, open connections and thread pool state of the server as JSON. `admin stats` is an alias.
+
+// kvs-client admin compact [--addr IP-PORT]
+
+// Compact the data files of the server now and print the file sizes before and after, the bytes reclaimed and the time taken as JSON. Needs write access to all keys when authentication is enabled.
+
+// kvs-client admin slowlog get [COUNT] [--addr IP-PORT]
+
+// Print the last COUNT (default all) commands of the slow log, newest first, one JSON object per line with the command, key, duration in microseconds and client address.
+
 // kvs-client -V
 
 // Print the version.
 
 use std::{
     io::{BufRead, BufReader, Write},
-    net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
     process::exit,
+    thread,
+    time::Duration,
 };
 
 use clap::{Parser, Subcommand};
-use kvs::Command;
+use kvs::{
+    net::{Addr, Stream},
+    pubsub::PubSubMessage,
+    raft::NOT_LEADER,
+    tls,
+    watch::WatchMessage,
+    Command,
+};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
     #[command(subcommand)]
     command: Commands,
-    #[arg(long,global=true,default_value_t=SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4000))]
-    addr: SocketAddr,
+    #[arg(long, global = true, default_value_t = Addr::Tcp(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4000)))]
+    addr: Addr,
+    // 指定后使用 TLS 连接，用这个 CA 验证 server 的证书
+    #[arg(long, global = true)]
+    tls_ca: Option<PathBuf>,
+    // mTLS 时客户端的证书和私钥
+    #[arg(long, global = true, requires_all = ["tls_key", "tls_ca"])]
+    tls_cert: Option<PathBuf>,
+    #[arg(long, global = true, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    // 验证证书时使用的域名，默认使用 --addr 中的 IP
+    #[arg(long, global = true)]
+    tls_server_name: Option<String>,
+    // server 开启认证时使用，--user 和 --password 或者只用 --token
+    #[arg(long, global = true, requires = "password")]
+    user: Option<String>,
+    #[arg(long, global = true, requires = "user")]
+    password: Option<String>,
+    #[arg(long, global = true, conflicts_with = "user")]
+    token: Option<String>,
 }
 
 #[derive(Subcommand)]
 enum Commands {
-    Get { key: String },
-    Rm { key: String },
-    Set { key: String, value: String },
+    Get {
+        key: String,
+    },
+    Rm {
+        key: String,
+    },
+    Set {
+        key: String,
+        value: String,
+    },
+    Role,
+    // 参数名不能和全局的 --addr 相同
+    AddNode {
+        id: u64,
+        #[arg(id = "node_addr", value_name = "ADDR")]
+        addr: String,
+    },
+    RemoveNode {
+        id: u64,
+    },
+    Keys {
+        #[arg(default_value = "")]
+        prefix: String,
+    },
+    AddBackend {
+        #[arg(id = "backend_addr", value_name = "ADDR")]
+        addr: String,
+    },
+    Watch {
+        key: String,
+        // 从上次输出的 run_id 和序号继续
+        #[arg(long, value_name = "RUN_ID:SEQ", value_parser = parse_resume)]
+        resume: Option<(String, u64)>,
+    },
+    Publish {
+        channel: String,
+        message: String,
+    },
+    Subscribe {
+        #[arg(required = true)]
+        channels: Vec<String>,
+    },
+    Psubscribe {
+        #[arg(required = true)]
+        patterns: Vec<String>,
+    },
+    Channels,
+    Admin {
+        #[command(subcommand)]
+        command: AdminCommands,
+    },
+}
+
+#[derive(Subcommand)]
+enum AdminCommands {
+    #[command(visible_alias = "stats")]
+    Info,
+    Compact,
+    Slowlog {
+        #[command(subcommand)]
+        command: SlowlogCommands,
+    },
+}
+
+#[derive(Subcommand)]
+enum SlowlogCommands {
+    Get { count: Option<usize> },
+}
+
+fn parse_resume(s: &str) -> Result<(String, u64), String> {
+    let (run_id, seq) = s
+        .split_once(':')
+        .ok_or_else(|| format!("invalid resume point '{}', expected RUN_ID:SEQ", s))?;
+    let seq = seq
+        .parse()
+        .map_err(|e| format!("invalid sequence number '{}': {}", seq, e))?;
+    Ok((run_id.to_owned(), seq))
 }
 
 fn main() {
     let cli = Cli::parse();
-    let mut stream = TcpStream::connect(cli.addr).unwrap();
-    let mut rm_flag = false;
-    match cli.command {
-        Commands::Get { key } => {
-            let s = serde_json::to_string(&Command::Get(key)).unwrap();
-            stream.write_all((s + "\n").as_bytes()).unwrap();
+    let (command, rm_flag) = match &cli.command {
+        Commands::Get { key } => (Command::Get(key.clone()), false),
+        Commands::Rm { key } => (Command::Rm(key.clone()), true),
+        Commands::Set { key, value } => (Command::Set(key.clone(), value.clone()), false),
+        Commands::Role => (Command::Role, false),
+        Commands::AddNode { id, addr } => (Command::AddNode(*id, addr.clone()), false),
+        Commands::RemoveNode { id } => (Command::RemoveNode(*id), false),
+        Commands::Keys { prefix } => (Command::Keys(prefix.clone()), false),
+        Commands::AddBackend { addr } => (Command::AddBackend(addr.clone()), false),
+        Commands::Watch { key, resume } => watch(&cli, key, resume.clone()),
+        Commands::Publish { channel, message } => {
+            (Command::Publish(channel.clone(), message.clone()), false)
         }
-        Commands::Rm { key } => {
-            let s = serde_json::to_string(&Command::Rm(key)).unwrap();
-            stream.write_all((s + "\n").as_bytes()).unwrap();
-            rm_flag = true;
+        Commands::Subscribe { channels } => subscribe(&cli, Command::Subscribe(channels.clone())),
+        Commands::Psubscribe { patterns } => subscribe(&cli, Command::PSubscribe(patterns.clone())),
+        Commands::Channels => (Command::Channels, false),
+        Commands::Admin { command } => match command {
+            AdminCommands::Info => (Command::Info, false),
+            AdminCommands::Compact => (Command::Compact, false),
+            AdminCommands::Slowlog {
+                command: SlowlogCommands::Get { count },
+            } => (Command::SlowLogGet(*count), false),
+        },
+    };
+    // raft 集群中的 follower 回复 leader 的地址，改为发给 leader
+    let mut addr = cli.addr.clone();
+    for _ in 0..3 {
+        let s = send(&cli, &addr, &command);
+        match s.strip_prefix(NOT_LEADER).map(|a| a.trim().parse::<Addr>()) {
+            Some(Ok(leader)) => addr = leader,
+            _ => {
+                if (s.contains("Key not found") && rm_flag) || is_typed_error(&s) {
+                    eprintln!("{}", s);
+                    exit(1);
+                }
+                // key 列表和慢日志每行输出一个
+                match serde_json::from_str::<Vec<serde_json::Value>>(&s) {
+                    Ok(keys) if matches!(command, Command::Keys(_)) => keys
+                        .iter()
+                        .for_each(|k| println!("{}", k.as_str().unwrap_or_default())),
+                    Ok(entries) if matches!(command, Command::SlowLogGet(_)) => {
+                        entries.iter().for_each(|e| println!("{}", e))
+                    }
+                    _ => println!("{}", s),
+                }
+                return;
+            }
         }
-        Commands::Set { key, value } => {
-            let s = serde_json::to_string(&Command::Set(key, value)).unwrap();
-            stream.write_all((s + "\n").as_bytes()).unwrap();
+    }
+    eprintln!("{}: too many redirects", NOT_LEADER);
+    exit(1);
+}
+
+// 连接 server，需要时先认证，再发送命令
+fn send(cli: &Cli, addr: &Addr, command: &Command) -> String {
+    let (mut stream, mut bf) = match connect(cli, addr) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("connect {}: {}", addr, e);
+            exit(1);
         }
+    };
+    request(&mut stream, &mut bf, command)
+}
+
+// 持续输出事件，连接断开之后带着最后看到的序号重连
+fn watch(cli: &Cli, key: &str, mut resume: Option<(String, u64)>) -> ! {
+    loop {
+        match connect(cli, &cli.addr) {
+            Ok((mut stream, mut bf)) => {
+                let c = Command::Watch(key.to_owned(), resume.clone());
+                let s = serde_json::to_string(&c).unwrap() + "\n";
+                let mut line = String::new();
+                // 写失败时下面读到的也是错误，一起重连
+                let _ = stream.write_all(s.as_bytes());
+                while matches!(bf.read_line(&mut line), Ok(n) if n > 0) {
+                    match serde_json::from_str::<WatchMessage>(&line) {
+                        Ok(m) => {
+                            match &m {
+                                WatchMessage::Watching { run_id, seq, .. } => {
+                                    resume = Some((run_id.clone(), *seq))
+                                }
+                                WatchMessage::Event(e) => {
+                                    resume.as_mut().unwrap().1 = e.seq;
+                                }
+                                WatchMessage::Ping(seq) => resume.as_mut().unwrap().1 = *seq,
+                            }
+                            if !matches!(m, WatchMessage::Ping(_)) {
+                                print!("{}", line);
+                            }
+                        }
+                        // 订阅之前的错误，比如没有权限
+                        Err(_) if resume.is_none() => {
+                            eprint!("{}", line);
+                            exit(1);
+                        }
+                        Err(_) => eprint!("{}", line),
+                    }
+                    line.clear();
+                }
+                eprintln!("connection lost, reconnecting");
+            }
+            Err(e) => eprintln!("connect {}: {}", cli.addr, e),
+        }
+        thread::sleep(Duration::from_secs(1));
     }
-    let mut bf = BufReader::new(stream);
-    let mut s = String::new();
-    bf.read_line(&mut s).unwrap();
-    if s.contains("Key not found") && rm_flag {
-        eprintln!("{}", s);
+}
+
+// 输出收到的消息，消息不会为断开的订阅者保留，连接断开时直接退出
+fn subscribe(cli: &Cli, command: Command) -> ! {
+    let (mut stream, mut bf) = match connect(cli, &cli.addr) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("connect {}: {}", cli.addr, e);
+            exit(1);
+        }
+    };
+    let s = serde_json::to_string(&command).unwrap() + "\n";
+    if let Err(e) = stream.write_all(s.as_bytes()) {
+        eprintln!("send request: {}", e);
+        exit(1);
+    }
+    let mut line = String::new();
+    while matches!(bf.read_line(&mut line), Ok(n) if n > 0) {
+        match serde_json::from_str::<PubSubMessage>(&line) {
+            Ok(PubSubMessage::Ping) => {}
+            Ok(_) => print!("{}", line),
+            // 没有认证或者读得太慢被断开
+            Err(_) => {
+                eprint!("{}", line);
+                exit(1);
+            }
+        }
+        line.clear();
+    }
+    eprintln!("connection closed by server");
+    exit(1);
+}
+
+// 建立连接，指定了用户或者 token 时先认证，认证失败直接退出
+fn connect(cli: &Cli, addr: &Addr) -> kvs::Result<(Stream, BufReader<Stream>)> {
+    let stream = match &cli.tls_ca {
+        Some(ca) => {
+            let cert_key = cli.tls_cert.as_deref().zip(cli.tls_key.as_deref());
+            tls::client_config(ca, cert_key)
+                .and_then(|c| Stream::connect_tls(addr, c, cli.tls_server_name.as_deref()))
+        }
+        None => Stream::connect(addr).map_err(Into::into),
+    };
+    let mut stream = stream?;
+    let mut bf = BufReader::new(stream.try_clone()?);
+    if let Some(secret) = cli.password.clone().or(cli.token.clone()) {
+        let reply = request(
+            &mut stream,
+            &mut bf,
+            &Command::Auth(cli.user.clone(), secret),
+        );
+        if reply != "Success" {
+            eprintln!("{}", reply);
+            exit(1);
+        }
+    }
+    Ok((stream, bf))
+}
+
+// 发送一条命令并读取回复，连接出错时直接退出
+fn request(stream: &mut Stream, bf: &mut BufReader<Stream>, c: &Command) -> String {
+    let s = serde_json::to_string(c).unwrap();
+    if let Err(e) = stream.write_all((s + "\n").as_bytes()) {
+        eprintln!("send request: {}", e);
         exit(1);
     }
-    println!("{}", s);
+    let mut s = String::new();
+    // TLS 握手被 server 拒绝时错误在读回复时才出现
+    match bf.read_line(&mut s) {
+        Ok(0) => {
+            eprintln!("connection closed by server");
+            exit(1);
+        }
+        Ok(_) => s.trim_end().to_owned(),
+        Err(e) => {
+            eprintln!("read reply: {}", e);
+            exit(1);
+        }
+    }
+}
+
+// 认证相关、写 replica 和不是 raft leader 的错误以错误类型开头，见 kvs::auth::AuthError
+fn is_typed_error(s: &str) -> bool {
+    ["NOAUTH ", "WRONGPASS ", "NOPERM ", "READONLY ", NOT_LEADER]
+        .iter()
+        .any(|p| s.starts_with(p))
 }
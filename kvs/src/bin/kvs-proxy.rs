@@ -0,0 +1,87 @@
+// kvs-proxy --backend ADDR[,ADDR...] [--addr IP-PORT] [--vnodes N] [--protocol PROTOCOL]
+
+// Start a sharding proxy in front of several kvs-server backends. Clients connect with kvs-client (or redis-cli with --protocol resp) exactly as they would to a single kvs-server.
+
+// Each key is routed to one backend by a consistent-hash ring with --vnodes virtual nodes per backend (default 160). KEYS and other multi-key commands are sent to every backend and the results merged. A transaction is split per backend and is not atomic across backends.
+
+// The backend list is saved in proxy.json in the current directory. A backend given with --backend that is not in proxy.json, or added at runtime with `kvs-client add-backend`, joins the ring and the keys that now hash to it are moved from the other backends. An interrupted migration is resumed on the next start.
+
+// --log-level and --log-format work as in kvs-server.
+
+// kvs-proxy -V
+
+// Print the version.
+
+use std::{
+    env,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    process::exit,
+    sync::Arc,
+};
+
+use clap::Parser;
+use slog::{error, info};
+use sloggers::types::Severity;
+
+use kvs::{
+    logging::{self, LogFormat},
+    net::Addr,
+    proxy::Proxy,
+    thread_pool::{NaiveThreadPool, ThreadPool},
+    KvsServer, Protocol, ServerOptions,
+};
+
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    #[arg(long, default_value_t = Addr::Tcp(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4000)))]
+    addr: Addr,
+    // 后端 kvs-server 的地址，逗号分隔
+    #[arg(long, value_delimiter = ',')]
+    backend: Vec<Addr>,
+    // 每个后端在哈希环上的虚拟节点数，改变之后需要重新分布所有 key
+    #[arg(long, default_value_t = 160)]
+    vnodes: usize,
+    #[arg(long, value_enum, default_value_t = Protocol::Kvs)]
+    protocol: Protocol,
+    #[arg(long, default_value = "debug", value_parser = logging::parse_level)]
+    log_level: Severity,
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+}
+
+fn main() {
+    let cli = Cli::parse();
+    let logger = logging::build_logger(cli.log_level, cli.log_format).unwrap();
+    let _guard = slog_scope::set_global_logger(logger.clone());
+    info!(
+        logger,
+        "address is {}, version is {}",
+        cli.addr,
+        env!("CARGO_PKG_VERSION")
+    );
+    let d = env::current_dir().unwrap();
+    let proxy = match Proxy::open(&d, &cli.backend, cli.vnodes, logger.clone()) {
+        Ok(p) => Arc::new(p),
+        Err(e) => {
+            error!(logger, "open proxy: {}", e);
+            exit(1);
+        }
+    };
+    info!(logger, "backends are {:?}", proxy.backends());
+    let opts = ServerOptions {
+        protocol: cli.protocol,
+        proxy: Some(proxy.clone()),
+        ..ServerOptions::default()
+    };
+    let server = KvsServer::with_options(
+        proxy,
+        NaiveThreadPool::new(10).unwrap(),
+        logger.clone(),
+        opts,
+    );
+    if let Err(e) = server.run(cli.addr) {
+        error!(logger, "{}", e);
+        exit(1);
+    }
+}
@@ -2,10 +2,36 @@
 
 // Start the server and begin listening for incoming connections. --addr accepts an IP address, either v4 or v6, and a port number, with the format IP:PORT. If --addr is not specified then listen on 127.0.0.1:4000.
 
+// --addr also accepts unix:PATH to listen on a unix domain socket. The socket file is created with --socket-mode (octal, default 660), a stale socket left by a previous run is removed on start.
+
+// --tls-cert and --tls-key enable TLS on a TCP address. With --tls-client-ca clients must present a certificate signed by that CA, and its CN identifies the connection.
+// --http-addr IP:PORT also serves the HTTP/JSON gateway on that address, over TLS with the same certificate when --tls-cert is given. Together with --users-file it requires --tls-cert, since HTTP clients send their password or token with every request.
+
+// --users-file enables authentication: clients must AUTH (or present a client certificate whose CN is a known user) and can only access keys their ACL allows. Denied operations are appended to --audit-log.
+
+// --replica-of HOST:PORT starts a read-only replica that copies every write from that primary. It resumes from the offset saved in replica.json after a disconnect or restart, and `kvs-client role` reports the replication lag. --repl-backlog is the number of recent writes a primary keeps for replicas to resume from.
+// A replica of a primary with authentication logs in with --primary-user and --primary-password (the password alone is used as a token); the user needs read access to every key. --primary-tls-ca connects to the primary over TLS, --primary-tls-cert and --primary-tls-key add a client certificate for mTLS and --primary-tls-server-name overrides the name checked against its certificate.
+
+// --raft-id starts the server as a node of a Raft cluster. --raft-peers lists the initial members as ID=HOST:PORT including this node; a node joining an existing cluster omits it and is added with `kvs-client add-node`. Reads and writes are only served by the leader, other nodes reply NOTLEADER with the leader address. Raft state is kept in the raft directory next to the data; the data must be empty when a node starts for the first time, and on every restart it is rebuilt from the latest Raft snapshot and log.
+// Raft RPCs between nodes go through the same authentication and TLS as clients. With --users-file every node logs in to its peers with --raft-user and --raft-password (the password alone is used as a token, so one token shared by all nodes acts as the cluster secret); the user needs write access to every key, which `add-node` and `remove-node` also require. --raft-tls-ca, --raft-tls-cert, --raft-tls-key and --raft-tls-server-name work like the --primary-tls-* options.
+
+// PUBLISH messages are delivered to the clients subscribed on this server at that moment. Each subscriber buffers at most --pubsub-buffer messages; a subscriber that falls further behind, or does not read for 5 seconds, is disconnected.
+
+// --metrics-addr IP:PORT serves metrics in the Prometheus text format on GET /metrics: requests and latency by command, open connections, thread pool queue depth and busy workers, and for the kvs engine the live keys, log records, reclaimable bytes, compression ratio and compactions.
+
+// A connection that sends no request for --idle-timeout seconds (default 300, 0 disables it) is closed, so idle clients do not hold thread pool workers; this includes HTTP gateway connections. WATCH, SUBSCRIBE, replica and Raft peer connections run on their own threads instead of the pool.
+
+// Logs go to stderr. --log-level is one of trace, debug (the default), info, warning, error and critical, --log-format json writes one JSON object per line. Every connection gets a conn id and every request on it a req id, which also tag the engine's compaction events caused by that request.
+
+// Commands that take longer than --slowlog-threshold-us (default 10000) from request to reply are kept in a slow log of the last --slowlog-len entries (default 128, 0 disables it), with their key, duration and client address. `kvs-client admin slowlog get` reads it, `kvs-client admin info` reports the engine, key count, disk usage, uptime, connections and thread pool state.
+
+// The kvs engine compacts its log after a write once it holds --compaction-ratio (default 3) times as many records as live keys and at least --compaction-keys (default 1000) records; --compaction-ratio 0 turns this off. --compaction-interval SECS also compacts in the background every SECS seconds, only inside the UTC time window --compaction-window HH:MM-HH:MM if given (it may wrap past midnight, e.g. 22:00-04:00). `kvs-client admin compact` compacts immediately and prints the bytes reclaimed.
+
 // If --engine is specified, then ENGINE-NAME must be either "kvs", in which case the built-in engine is used,
 // or "sled", in which case sled is used. If this is the first run (there is no data previously persisted) then the default value is "kvs";
 //  if there is previously persisted data then the default is the engine already in use.
 //  If data was previously persisted with a different engine than selected, print an error and exit with a non-zero exit code.
+// --compression and the encryption key (--key-file or KVS_ENCRYPTION_KEY) only apply to the kvs engine; the server refuses to start with either of them when the store uses sled, rather than writing plaintext or uncompressed data.
 
 // Print an error and return a non-zero exit code on failure to bind a socket, if ENGINE-NAME is invalid, if IP-PORT does not parse as an address.
 
@@ -14,23 +40,35 @@
 // Print the version.
 
 use std::{
+    collections::BTreeMap,
     env,
-    io::{BufRead, BufReader, Write},
-    net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
     process::exit,
     sync::Arc,
+    thread,
+    time::Duration,
 };
 
-use slog::{error, info};
-use sloggers::terminal::{Destination, TerminalLoggerBuilder};
+use slog::{error, info, o};
 use sloggers::types::Severity;
-use sloggers::Build;
 
 use clap::{Parser, ValueEnum};
 
 use kvs::{
+    auth::Authenticator,
+    compaction::{run_scheduled, CompactionWindow},
+    logging::{self, LogFormat},
+    metrics::Metrics,
+    net::Addr,
+    pubsub::{PubSub, PUBSUB_BUFFER},
+    raft::{RaftNode, RaftOptions},
+    replication::{Primary, ReadOnly, Replica, ReplicaOptions, Replication},
+    slowlog::{SlowLog, SLOWLOG_LEN, SLOWLOG_THRESHOLD},
     thread_pool::{NaiveThreadPool, ThreadPool},
-    Command, KvStore, KvsEngine, SledKvsEngine, LOGFILENAM,
+    tls, Compression, EncryptionKey, HttpGateway, KvStore, KvStoreOptions, KvsEngine, KvsServer,
+    Protocol, ServerOptions, SledKvsEngine, COMPACTION_KEYS, COMPACTION_RATIO, IDLE_TIMEOUT,
+    LOGFILENAM,
 };
 
 #[derive(Parser)]
@@ -38,8 +76,132 @@ use kvs::{
 struct Cli {
     #[arg(long, value_enum)]
     engine: Option<Engine>,
-    #[arg(long,default_value_t=SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4000))]
-    addr: SocketAddr,
+    // IP:PORT 或者 unix:/path/to.sock
+    #[arg(long, default_value_t = Addr::Tcp(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4000)))]
+    addr: Addr,
+    // unix socket 文件的权限，八进制
+    #[arg(long, default_value = "660", value_parser = parse_mode)]
+    socket_mode: u32,
+    // 只对 kvs 引擎生效，sled 引擎指定时拒绝启动；已经写入的记录不受影响
+    #[arg(long, value_enum, default_value_t = Compression::None)]
+    compression: Compression,
+    #[arg(long, default_value_t = 1024)]
+    compression_threshold: usize,
+    // 加密 key 文件，没有指定时读取 KVS_ENCRYPTION_KEY 环境变量，只对 kvs 引擎生效
+    #[arg(long)]
+    key_file: Option<PathBuf>,
+    // resp 模式下可以用 redis-cli 访问
+    #[arg(long, value_enum, default_value_t = Protocol::Kvs)]
+    protocol: Protocol,
+    // 同时启动 HTTP/JSON 网关，指定了 --tls-cert 时网关也使用 TLS
+    #[arg(long)]
+    http_addr: Option<SocketAddr>,
+    // 证书和私钥都指定时 TCP 连接使用 TLS
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    // 指定后要求客户端提供这个 CA 签发的证书（mTLS）
+    #[arg(long, requires = "tls_cert")]
+    tls_client_ca: Option<PathBuf>,
+    // 用户、密码哈希和权限，指定后客户端需要先 AUTH
+    #[arg(long)]
+    users_file: Option<PathBuf>,
+    // 被拒绝的操作写入这个文件
+    #[arg(long, default_value = "audit.log", requires = "users_file")]
+    audit_log: PathBuf,
+    // 作为只读的 replica 从这个 primary 复制数据
+    #[arg(long, value_name = "HOST:PORT")]
+    replica_of: Option<String>,
+    // primary 开启认证时 replica 用这个用户连接，只有密码时作为 token
+    #[arg(long, requires = "primary_password")]
+    primary_user: Option<String>,
+    #[arg(long, requires = "replica_of")]
+    primary_password: Option<String>,
+    // 指定时用 TLS 连接 primary，用这个 CA 验证 primary 的证书
+    #[arg(long, requires = "replica_of")]
+    primary_tls_ca: Option<PathBuf>,
+    // primary 要求客户端证书（mTLS）时使用
+    #[arg(long, requires_all = ["primary_tls_key", "primary_tls_ca"])]
+    primary_tls_cert: Option<PathBuf>,
+    #[arg(long, requires = "primary_tls_cert")]
+    primary_tls_key: Option<PathBuf>,
+    // 验证证书时使用的名字，默认是 primary 的 IP 地址
+    #[arg(long, requires = "primary_tls_ca")]
+    primary_tls_server_name: Option<String>,
+    // primary 在内存中保留的最近写入条数，断开时间更长的 replica 需要全量同步
+    #[arg(long, default_value_t = 10000)]
+    repl_backlog: usize,
+    // 作为 raft 集群中的节点启动
+    #[arg(long, conflicts_with = "replica_of")]
+    raft_id: Option<u64>,
+    // 初始成员，ID=HOST:PORT，逗号分隔，包括自己
+    #[arg(long, value_delimiter = ',', value_parser = parse_peer, requires = "raft_id")]
+    raft_peers: Vec<(u64, String)>,
+    // 应用过的日志超过这个条数时生成 snapshot
+    #[arg(long, default_value_t = 10000)]
+    raft_snapshot_threshold: u64,
+    // 其他节点开启认证时用这个用户连接，只有密码时作为 token，需要对所有 key 的写权限
+    #[arg(long, requires = "raft_password")]
+    raft_user: Option<String>,
+    #[arg(long, requires = "raft_id")]
+    raft_password: Option<String>,
+    // 指定时用 TLS 连接其他节点，用这个 CA 验证它们的证书
+    #[arg(long, requires = "raft_id")]
+    raft_tls_ca: Option<PathBuf>,
+    // 其他节点要求客户端证书（mTLS）时使用
+    #[arg(long, requires_all = ["raft_tls_key", "raft_tls_ca"])]
+    raft_tls_cert: Option<PathBuf>,
+    #[arg(long, requires = "raft_tls_cert")]
+    raft_tls_key: Option<PathBuf>,
+    // 验证证书时使用的名字，默认是节点的 IP 地址
+    #[arg(long, requires = "raft_tls_ca")]
+    raft_tls_server_name: Option<String>,
+    // 每个订阅者最多缓存的消息数，超过时断开这个订阅者
+    #[arg(long, default_value_t = PUBSUB_BUFFER)]
+    pubsub_buffer: usize,
+    // 以 Prometheus 的文本格式提供指标，GET /metrics
+    #[arg(long)]
+    metrics_addr: Option<SocketAddr>,
+    // 自动 compaction 的阈值，见 KvStoreOptions，ratio 为 0 时不在写入之后自动执行
+    #[arg(long, default_value_t = COMPACTION_RATIO)]
+    compaction_ratio: usize,
+    #[arg(long, default_value_t = COMPACTION_KEYS)]
+    compaction_keys: usize,
+    // 每隔这么多秒在后台执行一次 compaction
+    #[arg(long, value_name = "SECS")]
+    compaction_interval: Option<u64>,
+    // 定时的 compaction 只在这个 UTC 时间段内执行
+    #[arg(long, value_name = "HH:MM-HH:MM", requires = "compaction_interval")]
+    compaction_window: Option<CompactionWindow>,
+    // 超过这个时间的命令记录到慢日志，单位微秒
+    #[arg(long, default_value_t = SLOWLOG_THRESHOLD.as_micros() as u64)]
+    slowlog_threshold_us: u64,
+    // 慢日志保留的条数，0 时不记录
+    #[arg(long, default_value_t = SLOWLOG_LEN)]
+    slowlog_len: usize,
+    // 秒，0 时不关闭空闲的连接
+    #[arg(long, default_value_t = IDLE_TIMEOUT.as_secs())]
+    idle_timeout: u64,
+    #[arg(long, default_value = "debug", value_parser = logging::parse_level)]
+    log_level: Severity,
+    // text 或者 json，json 时每条日志一行
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+}
+
+fn parse_peer(s: &str) -> Result<(u64, String), String> {
+    let (id, addr) = s
+        .split_once('=')
+        .ok_or_else(|| format!("invalid peer '{}', expected ID=HOST:PORT", s))?;
+    let id = id
+        .parse()
+        .map_err(|e| format!("invalid peer id '{}': {}", id, e))?;
+    Ok((id, addr.to_owned()))
+}
+
+fn parse_mode(s: &str) -> Result<u32, String> {
+    u32::from_str_radix(s, 8).map_err(|e| format!("invalid octal mode: {}", e))
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
@@ -49,14 +211,11 @@ enum Engine {
 }
 
 fn main() {
-    let mut builder = TerminalLoggerBuilder::new();
-    builder.level(Severity::Debug);
-    builder.destination(Destination::Stderr);
-
-    let logger = builder.build().unwrap();
-    info!(logger, "Hello World!",);
-
     let cli = Cli::parse();
+    // 引擎中没有 Logger 参数的代码使用全局的 Logger，见 kvs::logging
+    let logger = logging::build_logger(cli.log_level, cli.log_format).unwrap();
+    let _guard = slog_scope::set_global_logger(logger.clone());
+    info!(logger, "Hello World!",);
     info!(
         logger,
         "address is {}, engine is {:?}, version is {}",
@@ -67,11 +226,57 @@ fn main() {
 
     let binding = env::current_dir().unwrap();
     let d = binding.as_path();
-    let store: Arc<dyn KvsEngine + Sync + Send>;
+    let store: Arc<dyn KvsEngine + Sync>;
+    // 使用 kvs 引擎时指标中包含它的统计
+    let mut kvs_store = None;
     let b = d.join("db");
     let sledkv = b.exists();
     let b2 = d.join(LOGFILENAM);
     let kvskv = b2.exists();
+    let encryption_key = match EncryptionKey::load(cli.key_file.as_deref()) {
+        Ok(k) => k,
+        Err(e) => {
+            error!(logger, "load encryption key: {}", e);
+            exit(1);
+        }
+    };
+    let opts = KvStoreOptions {
+        compression: cli.compression,
+        compression_threshold: cli.compression_threshold,
+        encryption_key,
+        compaction_ratio: cli.compaction_ratio,
+        compaction_keys: cli.compaction_keys,
+        ..KvStoreOptions::default()
+    };
+    // 目录被其他进程锁住等错误需要打印出来而不是 panic
+    let open_kvs = || -> KvStore {
+        match KvStore::open_with_options(d, opts.clone()) {
+            Ok(s) => s,
+            Err(e) => {
+                error!(logger, "open kvs engine: {}", e);
+                exit(1);
+            }
+        }
+    };
+
+    // sled 不支持这些选项，忽略它们会让数据以为加密了实际上是明文
+    let open_sled = || -> SledKvsEngine {
+        let unsupported = if opts.encryption_key.is_some() {
+            Some("encryption (--key-file or KVS_ENCRYPTION_KEY)")
+        } else if opts.compression != Compression::None {
+            Some("--compression")
+        } else {
+            None
+        };
+        if let Some(o) = unsupported {
+            error!(
+                logger,
+                "{} is only supported by the kvs engine, the store uses sled", o
+            );
+            exit(1);
+        }
+        SledKvsEngine::open(d).unwrap()
+    };
 
     match cli.engine {
         Some(e) => match e {
@@ -80,72 +285,184 @@ fn main() {
                     error!(logger, "store engine is wrong");
                     exit(1);
                 }
-                store = Arc::new(KvStore::open(d).unwrap())
+                let s = open_kvs();
+                kvs_store = Some(s.clone());
+                store = Arc::new(s)
             }
             Engine::Sled => {
                 if kvskv {
                     error!(logger, "store engine is wrong");
                     exit(1);
                 }
-                store = Arc::new(SledKvsEngine::open(d).unwrap())
+                store = Arc::new(open_sled())
             }
         },
         None => {
             if sledkv {
-                store = Arc::new(SledKvsEngine::open(d).unwrap())
+                store = Arc::new(open_sled())
             } else {
-                store = Arc::new(KvStore::open(d).unwrap())
+                let s = open_kvs();
+                kvs_store = Some(s.clone());
+                store = Arc::new(s)
             }
         }
     }
-    let listener = TcpListener::bind(cli.addr).unwrap();
-    info!(logger, "server is started");
-    let tp = NaiveThreadPool::new(10).unwrap();
-    for income in listener.incoming() {
-        match income {
-            Ok(mut stream) => {
-                // 通过原子引用计数在多线程共享数据
-                let store_clone=store.clone();
-                tp.spawn(move || {
-                    let mut s = String::new();
-                    let mut bf = BufReader::new(&stream);
-                    bf.read_line(&mut s).unwrap();
-                    println!("read data {}", s);
-                    // parse s to rm/get/set
-                    let command: Command = serde_json::from_str(&s).unwrap();
-                    match command {
-                        Command::Get(key) => {
-                            let r = store_clone.get(key).unwrap();
-                            match r {
-                                Some(rs) => {
-                                    stream.write((rs + "\n").as_bytes()).unwrap();
-                                }
-                                None => {
-                                    stream.write(b"Key not found\n").unwrap();
-                                }
-                            }
-                        }
-                        Command::Rm(key) => {
-                            let r = store_clone.remove(key);
-                            match r {
-                                Ok(_) => {
-                                    stream.write(b"Success\n").unwrap();
-                                }
-                                Err(e) => {
-                                    stream.write((e.to_string() + "\n").as_bytes()).unwrap();
-                                }
-                            }
-                        }
-                        Command::Set(key, value) => {
-                                store_clone.set(key, value).unwrap();
-                                stream.write(b"Success\n").unwrap();
-                        }
-                        _ => unreachable!(), // Either no subcommand or one not tested for...
-                    }
-                });
+    // replica 对外只读，复制线程直接写入引擎；其他情况都可以作为 primary 被复制
+    let (store, replication): (Arc<dyn KvsEngine + Sync>, _) = match (cli.raft_id, &cli.replica_of)
+    {
+        (Some(id), _) => {
+            let peers: BTreeMap<u64, String> = cli.raft_peers.iter().cloned().collect();
+            if !peers.is_empty() && !peers.contains_key(&id) {
+                error!(logger, "--raft-peers must include this node {}", id);
+                exit(1);
+            }
+            let tls = cli.raft_tls_ca.as_deref().map(|ca| {
+                let cert_key = cli
+                    .raft_tls_cert
+                    .as_deref()
+                    .zip(cli.raft_tls_key.as_deref());
+                tls::client_config(ca, cert_key).unwrap_or_else(|e| {
+                    error!(logger, "load raft tls config: {}", e);
+                    exit(1);
+                })
+            });
+            let opts = RaftOptions {
+                tls,
+                tls_server_name: cli.raft_tls_server_name.clone(),
+                user: cli.raft_user.clone(),
+                password: cli.raft_password.clone(),
+            };
+            let dir = d.join("raft");
+            let l = logger.new(o!("raft" => id));
+            let threshold = cli.raft_snapshot_threshold;
+            match RaftNode::with_options(id, peers, store, &dir, threshold, l, opts) {
+                Ok(n) => (n.engine(), Replication::Raft(n)),
+                Err(e) => {
+                    error!(logger, "start raft node: {}", e);
+                    exit(1);
+                }
+            }
+        }
+        (None, Some(primary)) => {
+            let tls = cli.primary_tls_ca.as_deref().map(|ca| {
+                let cert_key = cli
+                    .primary_tls_cert
+                    .as_deref()
+                    .zip(cli.primary_tls_key.as_deref());
+                tls::client_config(ca, cert_key).unwrap_or_else(|e| {
+                    error!(logger, "load primary tls config: {}", e);
+                    exit(1);
+                })
+            });
+            let opts = ReplicaOptions {
+                tls,
+                tls_server_name: cli.primary_tls_server_name.clone(),
+                user: cli.primary_user.clone(),
+                password: cli.primary_password.clone(),
+            };
+            match Replica::with_options(primary, store.clone(), d, logger.clone(), opts) {
+                Ok(r) => (Arc::new(ReadOnly(store)), Replication::Replica(r)),
+                Err(e) => {
+                    error!(logger, "start replication: {}", e);
+                    exit(1);
+                }
+            }
+        }
+        (None, None) => {
+            let p = Primary::new(store, cli.repl_backlog);
+            (Arc::new(p.clone()), Replication::Primary(p))
+        }
+    };
+    if let Some(secs) = cli.compaction_interval {
+        let engine = store.clone();
+        let l = logger.new(o!("compaction" => "scheduled"));
+        let window = cli.compaction_window;
+        info!(logger, "scheduled compaction"; "interval_secs" => secs,
+            "window" => window.map(|w| w.to_string()));
+        thread::spawn(move || run_scheduled(engine, Duration::from_secs(secs), window, l));
+    }
+    let auth = match &cli.users_file {
+        Some(p) => match Authenticator::load(p, Some(&cli.audit_log)) {
+            Ok(a) => Some(Arc::new(a)),
+            Err(e) => {
+                error!(logger, "load users file: {}", e);
+                exit(1);
+            }
+        },
+        None => None,
+    };
+    let tls = match (&cli.tls_cert, &cli.tls_key) {
+        (Some(cert), Some(key)) => {
+            match tls::server_config(cert, key, cli.tls_client_ca.as_deref()) {
+                Ok(c) => Some(c),
+                Err(e) => {
+                    error!(logger, "load tls config: {}", e);
+                    exit(1);
+                }
             }
-            Err(_) => todo!(),
         }
+        _ => None,
+    };
+    let idle_timeout = match cli.idle_timeout {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    };
+    if let Some(addr) = cli.http_addr {
+        if auth.is_some() && tls.is_none() {
+            error!(logger, "--http-addr with --users-file requires --tls-cert");
+            exit(1);
+        }
+        let gateway = HttpGateway::new(
+            store.clone(),
+            NaiveThreadPool::new(10).unwrap(),
+            logger.clone(),
+        )
+        .with_auth(auth.clone())
+        .with_tls(tls.clone())
+        .with_idle_timeout(idle_timeout);
+        let logger = logger.clone();
+        thread::spawn(move || {
+            if let Err(e) = gateway.run(addr) {
+                error!(logger, "http gateway: {}", e);
+                exit(1);
+            }
+        });
+    }
+    let metrics = match kvs_store {
+        Some(s) => Metrics::default().with_store(s),
+        None => Metrics::default(),
+    };
+    let metrics = Arc::new(metrics);
+    if let Some(addr) = cli.metrics_addr {
+        let metrics = metrics.clone();
+        let logger = logger.clone();
+        thread::spawn(move || {
+            if let Err(e) = metrics.run(addr, &logger) {
+                error!(logger, "metrics endpoint: {}", e);
+                exit(1);
+            }
+        });
+    }
+    let tp = NaiveThreadPool::new(10).unwrap();
+    let opts = ServerOptions {
+        protocol: cli.protocol,
+        socket_mode: cli.socket_mode,
+        tls,
+        auth,
+        replication: Some(replication),
+        proxy: None,
+        pubsub: Arc::new(PubSub::new(cli.pubsub_buffer)),
+        metrics,
+        slowlog: Arc::new(SlowLog::new(
+            Duration::from_micros(cli.slowlog_threshold_us),
+            cli.slowlog_len,
+        )),
+        idle_timeout,
+    };
+    let server = KvsServer::with_options(store, tp, logger.clone(), opts);
+    if let Err(e) = server.run(cli.addr) {
+        error!(logger, "{}", e);
+        exit(1);
     }
 }
 
@@ -0,0 +1,160 @@
+// kvs-tool dump [--dir DIR] [--key-file FILE]
+
+// Print every record of DIR/kvs.log (default the current directory) with its offset, type, size in bytes and key, one per line. Corrupt regions are printed in place with their offset, length and the error found there.
+
+// kvs-tool verify [--dir DIR] [--key-file FILE]
+
+// Check the framing of every record and replay the log. Print the record counts, live keys, keys written more than once, stale bytes and their ratio, and every corrupt region. Exit with a non-zero code if the log has a corrupt region.
+
+// kvs-tool repair [--dir DIR] [--key-file FILE]
+
+// Copy every intact record into a new log and replace kvs.log with it. The original is kept as kvs.log.corrupt.<UNIX TIME>. Nothing is changed when the log has no corrupt region. The store must not be open in any other process.
+
+// kvs-tool stat [--dir DIR] [--key-file FILE]
+
+// Print the key count, record count, file size and histograms of the key and value sizes of the live keys, in power-of-two buckets.
+
+// After a corrupt region the tool looks for the next record that decodes completely, so one damaged record does not hide the records after it. dump, verify and stat take the same shared lock as a read-only kvs and can run while kvs-server is using the store; the record being appended at that moment may then show as a truncated tail. An encrypted log needs its key, from --key-file or the KVS_ENCRYPTION_KEY environment variable.
+
+// kvs-tool -V
+
+// Print the version.
+
+use std::{collections::BTreeMap, path::PathBuf, process::exit};
+
+use clap::{Parser, Subcommand};
+use kvs::{
+    inspect::{histogram, repair, summarize, Entry, Scanner},
+    Command, EncryptionKey,
+};
+
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    #[command(subcommand)]
+    command: Commands,
+    // kvs.log 所在的目录
+    #[arg(long, global = true, default_value = ".")]
+    dir: PathBuf,
+    // 没有指定时读取 KVS_ENCRYPTION_KEY 环境变量
+    #[arg(long, global = true)]
+    key_file: Option<PathBuf>,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    Dump,
+    Verify,
+    Repair,
+    Stat,
+}
+
+fn main() {
+    let cli = Cli::parse();
+    let key = EncryptionKey::load(cli.key_file.as_deref()).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        exit(1);
+    });
+    if let Commands::Repair = cli.command {
+        match repair(&cli.dir, key) {
+            Ok(r) => match r.backup {
+                Some(backup) => println!(
+                    "salvaged {} records, dropped {} corrupt regions ({} bytes), original log kept as {}",
+                    r.records,
+                    r.corrupt_regions,
+                    r.corrupt_bytes,
+                    backup.display()
+                ),
+                None => println!("log is intact, {} records, nothing to repair", r.records),
+            },
+            Err(e) => {
+                eprintln!("{}", e);
+                exit(1);
+            }
+        }
+        return;
+    }
+    let scanner = Scanner::open(&cli.dir, key).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        exit(1);
+    });
+    match cli.command {
+        Commands::Dump => dump(scanner),
+        Commands::Verify => verify(scanner),
+        Commands::Stat => stat(scanner),
+        Commands::Repair => unreachable!(),
+    }
+}
+
+fn dump(scanner: Scanner) {
+    println!("{:>12}  {:<7}  {:>8}  KEY", "OFFSET", "TYPE", "SIZE");
+    for e in scanner {
+        match e {
+            Entry::Record {
+                offset,
+                buf,
+                command,
+            } => {
+                let (kind, key) = match &command {
+                    Command::Set(k, _) => ("set", k),
+                    Command::Rm(k) => ("rm", k),
+                    _ => unreachable!(),
+                };
+                println!("{:>12}  {:<7}  {:>8}  {}", offset, kind, buf.len(), key);
+            }
+            Entry::Corrupt { offset, len, error } => {
+                println!("{:>12}  {:<7}  {:>8}  {}", offset, "CORRUPT", len, error)
+            }
+        }
+    }
+}
+
+fn verify(scanner: Scanner) {
+    let s = summarize(scanner);
+    println!("records: {} ({} set, {} rm)", s.records, s.sets, s.removes);
+    println!("live keys: {}", s.live.len());
+    println!(
+        "keys written more than once: {} ({} records overwritten or removed)",
+        s.duplicate_keys, s.overwritten
+    );
+    if s.orphan_removes > 0 {
+        println!("rm of a missing key: {}", s.orphan_removes);
+    }
+    println!(
+        "stale bytes: {} of {} ({:.1}%)",
+        s.stale_bytes(),
+        s.record_bytes,
+        s.stale_ratio() * 100.0
+    );
+    if s.corrupt.is_empty() {
+        println!("log is intact");
+        return;
+    }
+    println!(
+        "corrupt regions: {} ({} bytes)",
+        s.corrupt.len(),
+        s.corrupt_bytes()
+    );
+    for (offset, len, error) in &s.corrupt {
+        println!("  offset {}: {} ({} bytes skipped)", offset, error, len);
+    }
+    exit(1);
+}
+
+fn stat(scanner: Scanner) {
+    let s = summarize(scanner);
+    println!("keys: {}", s.live.len());
+    println!("records: {}", s.records);
+    println!("file size: {} bytes", s.file_size);
+    print_histogram("key size", &histogram(s.live.keys().map(|k| k.len())));
+    print_histogram("value size", &histogram(s.live.values().copied()));
+}
+
+fn print_histogram(name: &str, h: &BTreeMap<usize, u64>) {
+    println!("{} histogram:", name);
+    let total: u64 = h.values().sum();
+    for (bound, n) in h {
+        let percent = *n as f64 * 100.0 / total as f64;
+        println!("  <= {:>10} B  {:>10}  {:>5.1}%", bound, n, percent);
+    }
+}
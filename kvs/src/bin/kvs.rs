@@ -1,15 +1,32 @@
 use std::env;
+use std::path::PathBuf;
 use std::process::exit;
 
 use clap::Arg;
 use clap::Command;
+use kvs::EncryptionKey;
 use kvs::KvStore;
+use kvs::KvStoreOptions;
 use kvs::KvsEngine;
 fn main() {
     let c = Command::new("kvs")
         .version(env!("CARGO_PKG_VERSION"))
         .author("lilinghai")
         .about("key value storage")
+        .arg(
+            Arg::new("key-file")
+                .long("key-file")
+                .global(true)
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("encryption key file, defaults to the KVS_ENCRYPTION_KEY env var"),
+        )
+        .arg(
+            Arg::new("read-only")
+                .long("read-only")
+                .global(true)
+                .action(clap::ArgAction::SetTrue)
+                .help("open without the write lock, can run alongside kvs-server"),
+        )
         .subcommand(Command::new("get").arg(Arg::new("Key").required(true)))
         .subcommand(
             Command::new("set")
@@ -17,11 +34,58 @@ fn main() {
                 .arg(Arg::new("Value").required(true)),
         )
         .subcommand(Command::new("rm").arg(Arg::new("Key").required(true)))
+        .subcommand(
+            Command::new("rekey")
+                .about("rewrite the log under a new key, or as plaintext without --new-key-file")
+                .arg(
+                    Arg::new("new-key-file")
+                        .long("new-key-file")
+                        .value_parser(clap::value_parser!(PathBuf)),
+                ),
+        )
+        .subcommand(
+            Command::new("compact")
+                .about("rewrite the log without overwritten and removed records"),
+        )
+        .subcommand(
+            Command::new("hash-password")
+                .about("print the hash of a password or token for the kvs-server users file")
+                .arg(Arg::new("Password").required(true))
+                .arg(
+                    Arg::new("token")
+                        .long("token")
+                        .help("hash a token instead of a password")
+                        .action(clap::ArgAction::SetTrue),
+                ),
+        )
         .get_matches();
 
+    // 不需要打开 store
+    if let Some(("hash-password", sub_m)) = c.subcommand() {
+        let p: &String = sub_m.get_one("Password").unwrap();
+        if sub_m.get_flag("token") {
+            println!("{}", kvs::auth::hash_token(p));
+        } else {
+            println!("{}", kvs::auth::hash_secret(p));
+        }
+        return;
+    }
+
     let binding = env::current_dir().unwrap();
     let d = binding.as_path();
-    let store = KvStore::open(d).unwrap();
+    let key_file: Option<&PathBuf> = c.get_one("key-file");
+    let opts = KvStoreOptions {
+        encryption_key: EncryptionKey::load(key_file.map(|p| p.as_path())).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            exit(1);
+        }),
+        read_only: c.get_flag("read-only"),
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(d, opts).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        exit(1);
+    });
 
     match c.subcommand() {
         Some(("get", sub_m)) => {
@@ -45,7 +109,42 @@ fn main() {
             match r {
                 Ok(_) => {}
                 Err(e) => {
-                    println!("{}", e.to_string());
+                    println!("{}", e);
+                    exit(1);
+                }
+            }
+        }
+        Some(("rekey", sub_m)) => {
+            let p: Option<&PathBuf> = sub_m.get_one("new-key-file");
+            let r = p
+                .map(|p| EncryptionKey::from_file(p))
+                .transpose()
+                .and_then(|k| store.rekey(k));
+            if let Err(e) = r {
+                eprintln!("{}", e);
+                exit(1);
+            }
+        }
+        // 进度写到 stderr，结果写到 stdout
+        Some(("compact", _)) => {
+            let mut last = 0;
+            let r = store.compact_with_progress(|copied, total| {
+                let percent = copied * 100 / total;
+                if percent > last {
+                    eprint!("\rcompacting {}% ({}/{} keys)", percent, copied, total);
+                    last = percent;
+                }
+                if copied == total {
+                    eprintln!();
+                }
+            });
+            match r {
+                Ok(r) => println!(
+                    "reclaimed {} bytes ({} -> {} bytes, {} keys) in {} ms",
+                    r.reclaimed_bytes, r.before_bytes, r.after_bytes, r.keys, r.elapsed_ms
+                ),
+                Err(e) => {
+                    eprintln!("{}", e);
                     exit(1);
                 }
             }
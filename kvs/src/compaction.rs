@@ -0,0 +1,92 @@
+// 定时的后台 compaction
+//
+// 每隔 interval 调用一次 KvsEngine::compact，指定了时间窗口时只在窗口内执行，
+// 配合 KvStoreOptions::compaction_ratio 为 0 关闭写入触发的 compaction，可以把整理文件的 IO 放到业务低峰
+// 时间窗口使用 UTC，结束时间小于开始时间时跨过午夜，比如 22:00-04:00
+use std::{
+    fmt,
+    str::FromStr,
+    sync::Arc,
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use slog::{debug, error, Logger};
+
+use crate::KvsEngine;
+
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+// 一天中的一段时间，单位是从 0 点开始的分钟数，包含开始不包含结束
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CompactionWindow {
+    start: u32,
+    end: u32,
+}
+
+impl CompactionWindow {
+    pub fn contains(&self, minute: u32) -> bool {
+        if self.start <= self.end {
+            self.start <= minute && minute < self.end
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+fn parse_time(s: &str) -> Option<u32> {
+    let (h, m) = s.split_once(':')?;
+    let (h, m): (u32, u32) = (h.parse().ok()?, m.parse().ok()?);
+    (h < 24 && m < 60).then_some(h * 60 + m)
+}
+
+// HH:MM-HH:MM
+impl FromStr for CompactionWindow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("invalid window '{}', expected HH:MM-HH:MM", s);
+        let (start, end) = s.split_once('-').ok_or_else(err)?;
+        Ok(CompactionWindow {
+            start: parse_time(start).ok_or_else(err)?,
+            end: parse_time(end).ok_or_else(err)?,
+        })
+    }
+}
+
+impl fmt::Display for CompactionWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (s, e) = (self.start, self.end);
+        write!(f, "{:02}:{:02}-{:02}:{:02}", s / 60, s % 60, e / 60, e % 60)
+    }
+}
+
+// 当前的 UTC 时间在一天中的分钟数
+pub fn utc_minute() -> u32 {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    (secs / 60 % MINUTES_PER_DAY as u64) as u32
+}
+
+// 不会返回，在单独的线程中运行；失败只记录日志，下一次继续
+pub fn run_scheduled(
+    engine: Arc<dyn KvsEngine + Sync>,
+    interval: Duration,
+    window: Option<CompactionWindow>,
+    logger: Logger,
+) -> ! {
+    loop {
+        thread::sleep(interval);
+        if window.is_some_and(|w| !w.contains(utc_minute())) {
+            continue;
+        }
+        // 引擎中的 compaction 日志带着 scheduled 标记
+        match slog_scope::scope(&logger, || engine.compact()) {
+            Ok(r) => debug!(logger, "scheduled compaction finished";
+                "reclaimed_bytes" => r.reclaimed_bytes,
+                "elapsed_ms" => r.elapsed_ms),
+            Err(e) => error!(logger, "scheduled compaction: {}", e),
+        }
+    }
+}
@@ -0,0 +1,281 @@
+// 所有 KvsEngine 都应该满足的行为
+//
+// 每个函数接收一个打开引擎的闭包，在新建的临时目录上打开、关闭、再打开引擎，失败时 panic
+// 内置的引擎在 tests/conformance.rs 中运行，其他 crate 实现的引擎可以这样运行同样的测试：
+//
+//     kvs::engine_conformance_tests!(my_engine, |p: &std::path::Path| MyEngine::open(p));
+//
+// 会生成一个名为 my_engine 的模块，每项检查是其中的一个 #[test]
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use super::{KvsEngine, KEY_NOT_FOUND};
+use crate::Result;
+
+static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+
+// 系统临时目录下的一个新目录，drop 时删除
+pub struct TestDir(PathBuf);
+
+impl TestDir {
+    pub fn new() -> Result<Self> {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.subsec_nanos());
+        let name = format!(
+            "kvs-conformance-{}-{}-{}",
+            std::process::id(),
+            NEXT_DIR.fetch_add(1, Ordering::Relaxed),
+            nanos
+        );
+        let p = std::env::temp_dir().join(name);
+        fs::create_dir_all(&p)?;
+        Ok(TestDir(p))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+// 写入的值在关闭并重新打开之后仍然可以读到，不存在的 key 返回 None
+pub fn persistence<E, F>(open: F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let dir = TestDir::new()?;
+    let engine = open(dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(engine.get("missing".to_owned())?, None);
+
+    drop(engine);
+    let engine = open(dir.path())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(engine.get("missing".to_owned())?, None);
+    assert_eq!(engine.keys("")?, ["key1", "key2"]);
+    Ok(())
+}
+
+// 覆盖之后读到最新的值，重新打开之后也是
+pub fn overwrite<E, F>(open: F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let dir = TestDir::new()?;
+    let engine = open(dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+    // 空字符串是合法的值
+    engine.set("key1".to_owned(), String::new())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some(String::new()));
+
+    drop(engine);
+    let engine = open(dir.path())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some(String::new()));
+    engine.set("key1".to_owned(), "value3".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(engine.keys("")?, ["key1"]);
+    Ok(())
+}
+
+// 删除之后读不到，重新打开之后也读不到；删除不存在的 key 返回 KEY_NOT_FOUND
+pub fn remove<E, F>(open: F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let dir = TestDir::new()?;
+    let engine = open(dir.path())?;
+    let err = engine.remove("missing".to_owned()).unwrap_err();
+    assert_eq!(err.to_string(), KEY_NOT_FOUND);
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    engine.remove("key1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    let err = engine.remove("key1".to_owned()).unwrap_err();
+    assert_eq!(err.to_string(), KEY_NOT_FOUND);
+
+    drop(engine);
+    let engine = open(dir.path())?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert_eq!(engine.keys("")?, ["key2"]);
+    // 删除之后可以重新写入
+    engine.set("key1".to_owned(), "value3".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// 多个线程同时写入不同的 key、同时读取，所有写入都可以读到，重新打开之后也是
+pub fn concurrency<E, F>(open: F) -> Result<()>
+where
+    E: KvsEngine + Sync,
+    F: Fn(&Path) -> Result<E>,
+{
+    const THREADS: usize = 16;
+    const KEYS: usize = 100;
+    let dir = TestDir::new()?;
+    let engine = Arc::new(open(dir.path())?);
+    let handles: Vec<_> = (0..THREADS)
+        .map(|t| {
+            let engine = engine.clone();
+            thread::spawn(move || {
+                for i in 0..KEYS {
+                    let key = format!("key{}-{}", t, i);
+                    engine.set(key, format!("value{}", i)).unwrap();
+                }
+            })
+        })
+        .collect();
+    for h in handles {
+        h.join().unwrap();
+    }
+
+    let check = |engine: &Arc<E>| {
+        let handles: Vec<_> = (0..THREADS)
+            .map(|t| {
+                let engine = engine.clone();
+                thread::spawn(move || {
+                    for i in 0..KEYS {
+                        // 每个线程读所有线程写入的 key
+                        let key = format!("key{}-{}", (t + i) % THREADS, i);
+                        let v = engine.get(key).unwrap();
+                        assert_eq!(v, Some(format!("value{}", i)));
+                    }
+                })
+            })
+            .collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+    };
+    check(&engine);
+    assert_eq!(engine.keys("")?.len(), THREADS * KEYS);
+
+    drop(engine);
+    let engine = Arc::new(open(dir.path())?);
+    check(&engine);
+    Ok(())
+}
+
+// 几 MB 的值可以原样写入和读取
+pub fn large_values<E, F>(open: F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let dir = TestDir::new()?;
+    let engine = open(dir.path())?;
+    // 包含多字节字符，不能按字节截断
+    let big: String = "0123456789abcdef日本".repeat(256 * 1024);
+    engine.set("big".to_owned(), big.clone())?;
+    engine.set("small".to_owned(), "v".to_owned())?;
+    assert_eq!(engine.get("big".to_owned())?.as_ref(), Some(&big));
+
+    drop(engine);
+    let engine = open(dir.path())?;
+    assert_eq!(engine.get("big".to_owned())?.as_ref(), Some(&big));
+    assert_eq!(engine.get("small".to_owned())?, Some("v".to_owned()));
+    Ok(())
+}
+
+// 反复覆盖同一批 key 时引擎自己回收空间，占用的磁盘不会随写入量一直增长
+// compact 之后数据不变
+pub fn compaction<E, F>(open: F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    const KEYS: usize = 100;
+    const ROUNDS: usize = 100;
+    let dir = TestDir::new()?;
+    let engine = open(dir.path())?;
+    let value = "x".repeat(1024);
+    for r in 0..ROUNDS {
+        for k in 0..KEYS {
+            engine.set(format!("key{}", k), format!("{}{}", value, r))?;
+        }
+    }
+    let written = (KEYS * ROUNDS * value.len()) as u64;
+    let disk = engine.info()?.disk_bytes;
+    assert!(
+        disk < written / 2,
+        "{} bytes on disk after writing {} bytes",
+        disk,
+        written
+    );
+
+    let report = engine.compact()?;
+    assert_eq!(report.keys, KEYS as u64);
+    let last = format!("{}{}", value, ROUNDS - 1);
+    for k in 0..KEYS {
+        assert_eq!(engine.get(format!("key{}", k))?.as_ref(), Some(&last));
+    }
+
+    drop(engine);
+    let engine = open(dir.path())?;
+    for k in 0..KEYS {
+        assert_eq!(engine.get(format!("key{}", k))?.as_ref(), Some(&last));
+    }
+    Ok(())
+}
+
+// 为一个引擎生成所有检查的 #[test]，$open 是 Fn(&Path) -> Result<E> 的闭包
+#[macro_export]
+macro_rules! engine_conformance_tests {
+    ($name:ident, $open:expr) => {
+        mod $name {
+            #[allow(unused_imports)]
+            use super::*;
+
+            #[test]
+            fn persistence() -> $crate::Result<()> {
+                $crate::conformance::persistence($open)
+            }
+
+            #[test]
+            fn overwrite() -> $crate::Result<()> {
+                $crate::conformance::overwrite($open)
+            }
+
+            #[test]
+            fn remove() -> $crate::Result<()> {
+                $crate::conformance::remove($open)
+            }
+
+            #[test]
+            fn concurrency() -> $crate::Result<()> {
+                $crate::conformance::concurrency($open)
+            }
+
+            #[test]
+            fn large_values() -> $crate::Result<()> {
+                $crate::conformance::large_values($open)
+            }
+
+            #[test]
+            fn compaction() -> $crate::Result<()> {
+                $crate::conformance::compaction($open)
+            }
+        }
+    };
+}
@@ -0,0 +1,299 @@
+// kvs.log 的离线检查和修复，kvs-tool 使用
+//
+// Scanner 顺序读取每条记录，记录头或者 payload 损坏时逐字节向后查找下一条可以完整解码的记录，
+// 中间跳过的字节作为一个损坏区域返回，一处损坏不会导致后面的记录全部丢失
+// 加密的 log 必须提供 key，否则无法区分损坏的记录和加密的记录
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    io::{BufWriter, Write},
+    os::unix::prelude::FileExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use super::record::{
+    read_header, write_header, Compression, LogHeader, RecordCodec, FLAG_ENCRYPTED, FLAG_LZ4,
+    RECORD_HEADER_LEN,
+};
+use super::storage::lock_file;
+use super::{Command, EncryptionKey, LOGFILENAM, SHARED_LOCKFILENAM};
+use crate::Result;
+
+pub enum Entry {
+    Record {
+        offset: u64,
+        // 包含记录头的完整记录
+        buf: Vec<u8>,
+        command: Command,
+    },
+    // 从 offset 开始的 len 个字节中没有可以解码的记录，error 是 offset 处的错误
+    Corrupt {
+        offset: u64,
+        len: u64,
+        error: String,
+    },
+}
+
+pub struct Scanner {
+    file: fs::File,
+    size: u64,
+    pos: u64,
+    header: LogHeader,
+    codec: RecordCodec,
+    _lock: Arc<fs::File>,
+}
+
+impl Scanner {
+    // 和只读打开 KvStore 一样加共享锁，可以和正在写入的 kvs-server 同时运行，
+    // 这时最后一条记录可能还没有写完，会显示为损坏
+    pub fn open(dir: &Path, key: Option<EncryptionKey>) -> Result<Self> {
+        let lock = lock_file(dir, SHARED_LOCKFILENAM, false)?;
+        Self::open_locked(dir, key, lock)
+    }
+
+    fn open_locked(dir: &Path, key: Option<EncryptionKey>, lock: Arc<fs::File>) -> Result<Self> {
+        let mut file = fs::File::open(dir.join(LOGFILENAM))?;
+        let size = file.metadata()?.len();
+        let (header, pos) = read_header(&mut file)?.ok_or(
+            "log has no header, it is empty or in the legacy format; open it with kvs to upgrade",
+        )?;
+        match (&header.key_id, &key) {
+            (Some(id), None) => {
+                return Err(format!("log is encrypted with key {}, no key given", id).into())
+            }
+            (Some(id), Some(k)) if *id != k.id => {
+                return Err(format!(
+                    "log is encrypted with key {}, but key {} is given",
+                    id, k.id
+                )
+                .into())
+            }
+            _ => {}
+        }
+        Ok(Scanner {
+            file,
+            size,
+            pos,
+            header,
+            codec: RecordCodec {
+                compression: Compression::None,
+                compression_threshold: usize::MAX,
+                key,
+            },
+            _lock: lock,
+        })
+    }
+
+    pub fn header(&self) -> &LogHeader {
+        &self.header
+    }
+
+    pub fn file_size(&self) -> u64 {
+        self.size
+    }
+
+    // 读取并解码 offset 处的记录，任何一步不合法都返回错误
+    fn read_at(&self, offset: u64) -> std::result::Result<(Vec<u8>, Command), String> {
+        let remaining = self.size - offset;
+        if remaining < RECORD_HEADER_LEN as u64 {
+            return Err("truncated record header".to_owned());
+        }
+        let mut h = [0; RECORD_HEADER_LEN];
+        self.file
+            .read_exact_at(&mut h, offset)
+            .map_err(|e| e.to_string())?;
+        let len = u32::from_le_bytes([h[0], h[1], h[2], h[3]]) as usize;
+        let flags = h[4];
+        if flags & !(FLAG_LZ4 | FLAG_ENCRYPTED) != 0 {
+            return Err(format!("unknown record flags {:#04x}", flags));
+        }
+        if (RECORD_HEADER_LEN + len) as u64 > remaining {
+            return Err("truncated record payload".to_owned());
+        }
+        let mut buf = vec![0; RECORD_HEADER_LEN + len];
+        self.file
+            .read_exact_at(&mut buf, offset)
+            .map_err(|e| e.to_string())?;
+        match self.codec.decode(&buf).map_err(|e| e.to_string())? {
+            c @ (Command::Set(..) | Command::Rm(_)) => Ok((buf, c)),
+            c => Err(format!("unexpected {} command in log", c.name())),
+        }
+    }
+}
+
+impl Iterator for Scanner {
+    type Item = Entry;
+
+    fn next(&mut self) -> Option<Entry> {
+        if self.pos >= self.size {
+            return None;
+        }
+        let offset = self.pos;
+        match self.read_at(offset) {
+            Ok((buf, command)) => {
+                self.pos += buf.len() as u64;
+                Some(Entry::Record {
+                    offset,
+                    buf,
+                    command,
+                })
+            }
+            Err(error) => {
+                let mut next = offset + 1;
+                while next < self.size && self.read_at(next).is_err() {
+                    next += 1;
+                }
+                self.pos = next;
+                Some(Entry::Corrupt {
+                    offset,
+                    len: next - offset,
+                    error,
+                })
+            }
+        }
+    }
+}
+
+// 回放整个 log 得到的统计
+#[derive(Default)]
+pub struct LogSummary {
+    pub file_size: u64,
+    pub records: u64,
+    pub sets: u64,
+    pub removes: u64,
+    // 所有完整记录的字节数和最后仍然有效的记录的字节数
+    pub record_bytes: u64,
+    pub live_bytes: u64,
+    // 每个有效的 key 的 value 长度
+    pub live: HashMap<String, usize>,
+    // 有不止一条 set 记录的 key 的数量，和被之后的 set/rm 覆盖的记录数
+    pub duplicate_keys: u64,
+    pub overwritten: u64,
+    // 删除一个不存在的 key，正常写入的 log 中不会出现
+    pub orphan_removes: u64,
+    // 损坏区域的 (offset, 长度, 错误)
+    pub corrupt: Vec<(u64, u64, String)>,
+}
+
+impl LogSummary {
+    pub fn stale_bytes(&self) -> u64 {
+        self.record_bytes - self.live_bytes
+    }
+
+    pub fn stale_ratio(&self) -> f64 {
+        if self.record_bytes == 0 {
+            return 0.0;
+        }
+        self.stale_bytes() as f64 / self.record_bytes as f64
+    }
+
+    pub fn corrupt_bytes(&self) -> u64 {
+        self.corrupt.iter().map(|c| c.1).sum()
+    }
+}
+
+pub fn summarize(scanner: Scanner) -> LogSummary {
+    let mut s = LogSummary {
+        file_size: scanner.file_size(),
+        ..LogSummary::default()
+    };
+    // key -> (记录长度, value 长度)
+    let mut live: HashMap<String, (u64, usize)> = HashMap::new();
+    let mut sets: HashMap<String, u64> = HashMap::new();
+    for e in scanner {
+        let (buf, command) = match e {
+            Entry::Record { buf, command, .. } => (buf, command),
+            Entry::Corrupt { offset, len, error } => {
+                s.corrupt.push((offset, len, error));
+                continue;
+            }
+        };
+        s.records += 1;
+        s.record_bytes += buf.len() as u64;
+        let old = match command {
+            Command::Set(k, v) => {
+                s.sets += 1;
+                *sets.entry(k.clone()).or_default() += 1;
+                live.insert(k, (buf.len() as u64, v.len()))
+            }
+            Command::Rm(k) => {
+                s.removes += 1;
+                let old = live.remove(&k);
+                if old.is_none() {
+                    s.orphan_removes += 1;
+                }
+                old
+            }
+            _ => unreachable!(),
+        };
+        if old.is_some() {
+            s.overwritten += 1;
+        }
+    }
+    s.duplicate_keys = sets.values().filter(|n| **n > 1).count() as u64;
+    s.live_bytes = live.values().map(|v| v.0).sum();
+    s.live = live.into_iter().map(|(k, v)| (k, v.1)).collect();
+    s
+}
+
+// 按 2 的幂分桶，返回 桶的上限 -> 数量
+pub fn histogram(sizes: impl Iterator<Item = usize>) -> BTreeMap<usize, u64> {
+    let mut h = BTreeMap::new();
+    for s in sizes {
+        *h.entry(s.max(1).next_power_of_two()).or_default() += 1;
+    }
+    h
+}
+
+pub struct RepairReport {
+    pub records: u64,
+    pub corrupt_regions: u64,
+    pub corrupt_bytes: u64,
+    // 原来的 log 改名之后的路径，log 没有损坏时为 None，文件不变
+    pub backup: Option<PathBuf>,
+}
+
+// 把所有完整的记录原样拷贝到新的 log，替换之前把原来的 log 改名保留
+// 需要独占整个目录，kvs-server 或者 kvs 正在使用时返回错误
+pub fn repair(dir: &Path, key: Option<EncryptionKey>) -> Result<RepairReport> {
+    let lock = lock_file(dir, SHARED_LOCKFILENAM, true)?;
+    let scanner = Scanner::open_locked(dir, key, lock)?;
+    let tmp = dir.join(LOGFILENAM.to_owned() + ".repair");
+    let mut w = BufWriter::new(fs::File::create(&tmp)?);
+    write_header(&mut w, scanner.header())?;
+    let mut report = RepairReport {
+        records: 0,
+        corrupt_regions: 0,
+        corrupt_bytes: 0,
+        backup: None,
+    };
+    for e in scanner {
+        match e {
+            Entry::Record { buf, .. } => {
+                report.records += 1;
+                w.write_all(&buf)?;
+            }
+            Entry::Corrupt { len, .. } => {
+                report.corrupt_regions += 1;
+                report.corrupt_bytes += len;
+            }
+        }
+    }
+    if report.corrupt_regions == 0 {
+        drop(w);
+        fs::remove_file(&tmp)?;
+        return Ok(report);
+    }
+    w.flush()?;
+    w.get_ref().sync_all()?;
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let backup = dir.join(format!("{}.corrupt.{}", LOGFILENAM, secs));
+    fs::rename(dir.join(LOGFILENAM), &backup)?;
+    fs::rename(&tmp, dir.join(LOGFILENAM))?;
+    report.backup = Some(backup);
+    Ok(report)
+}
@@ -1,26 +1,105 @@
+use super::record::{
+    payload_sizes, read_header, read_record, write_header, Compression, EncryptionKey, LogHeader,
+    RecordCodec, FLAG_ENCRYPTED, FLAG_LZ4, RECORD_HEADER_LEN, TRUNCATED_RECORD,
+};
+use super::storage::{DiskStorage, FileReader, LockGuard, Storage, StorageFile};
 use super::Command;
-use super::KvsEngine;
+use super::{
+    CompactionReport, EngineInfo, KvsEngine, KvsSnapshot, Transaction, Watch, KEY_NOT_FOUND,
+    TXN_CONFLICT,
+};
 use crate::Result;
+use slog::{info, warn};
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::{
-    collections::HashMap,
-    fs::{self},
-    io::{BufRead, BufReader, Seek, Write},
-    os::unix::prelude::FileExt,
+    collections::{BTreeMap, HashMap},
+    io::{self, BufRead, BufReader, Seek, Write},
     path,
+    time::{Duration, Instant},
 };
 
-// log file 分隔符号
+const READ_ONLY: &str = "store is opened read-only";
+const BROKEN: &str = "an earlier write failed and the log could not be restored, reopen the store";
+// 旧版本 log file 分隔符号
 const DELIMITER: u8 = b'#';
-const COMPACTION_RATIO: usize = 3;
-const COMPACTION_KEYS: usize = 1000;
+// 日志中的记录数超过 key 数量的 COMPACTION_RATIO 倍，并且不少于 COMPACTION_KEYS 时，写入之后自动 compaction
+pub const COMPACTION_RATIO: usize = 3;
+pub const COMPACTION_KEYS: usize = 1000;
 pub const LOGFILENAM: &str = "kvs.log";
+// 写进程对 LOCK 加排他锁，防止两个进程同时追加同一个 log
+pub const LOCKFILENAM: &str = "LOCK";
+// 所有打开 store 的进程（包括只读的）都对 LOCK.shared 加共享锁
+// 需要独占整个目录的离线工具对它加排他锁
+pub const SHARED_LOCKFILENAM: &str = "LOCK.shared";
+
+// 打开 KvStore 时可以指定的选项
+#[derive(Clone, Debug)]
+pub struct KvStoreOptions {
+    pub compression: Compression,
+    // 序列化之后超过这个字节数的记录才压缩
+    pub compression_threshold: usize,
+    // 设置之后新写入的记录都会加密，打开已有的 log 时必须和文件头中的 key id 一致
+    pub encryption_key: Option<EncryptionKey>,
+    // 只读模式不获取写锁，可以和正在写入的进程同时打开，所有写操作返回错误
+    // 只读打开看到的是打开时刻的数据
+    pub read_only: bool,
+    // 自动 compaction 的阈值，compaction_ratio 为 0 时只在调用 compact 时执行
+    pub compaction_ratio: usize,
+    pub compaction_keys: usize,
+    // 每次写入之后 fsync，断电也不会丢失已经返回成功的写入
+    pub sync: bool,
+    // 测试中可以换成注入故障的 MemStorage
+    pub storage: Arc<dyn Storage>,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        KvStoreOptions {
+            compression: Compression::None,
+            compression_threshold: 1024,
+            encryption_key: None,
+            read_only: false,
+            compaction_ratio: COMPACTION_RATIO,
+            compaction_keys: COMPACTION_KEYS,
+            sync: false,
+            storage: Arc::new(DiskStorage),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct KvStoreStats {
+    // 内存表中 key 的数量
+    pub live_keys: usize,
+    // 日志文件中记录的数量
+    pub log_keys: usize,
+    // 日志文件中所有记录压缩前和压缩后的 payload 字节数
+    pub raw_bytes: u64,
+    pub stored_bytes: u64,
+    // 日志文件中被覆盖、删除的记录和删除记录本身占用的字节数，compaction 可以回收
+    pub stale_bytes: u64,
+    // 打开之后执行过的 compaction 次数和总耗时
+    pub compactions: u64,
+    pub compaction_time: Duration,
+}
+
+impl KvStoreStats {
+    pub fn compression_ratio(&self) -> f64 {
+        if self.stored_bytes == 0 {
+            return 1.0;
+        }
+        self.raw_bytes as f64 / self.stored_bytes as f64
+    }
+}
 
 #[derive(Clone)]
 pub struct KvStore {
     ws: Arc<Mutex<WriteStore>>,
+    watch: Arc<Watch>,
     dir: String,
+    // 持有目录锁，最后一个 clone 被 drop 时关闭文件释放锁
+    _locks: Vec<LockGuard>,
 }
 
 // data log_keys,wlog 需要确保原子性，不能分开设置 mutex
@@ -29,8 +108,31 @@ struct WriteStore {
     data: HashMap<String, (u64, usize)>,
     // 记录日志文件中 keys 的数量，判断 compaction 的时机
     log_keys: usize,
-    wlog: fs::File,
-    rlog: fs::File,
+    // 每次写入递增的序号，快照固定在某个序号上
+    seq: u64,
+    // key 最后一次被修改（set/rm）时的序号，事务提交时用来检测冲突，没有记录的 key 的版本是 0
+    // compaction 时只去掉已经删除的 key
+    versions: HashMap<String, u64>,
+    read_only: bool,
+    sync: bool,
+    // 写入失败之后没能截掉写了一半的记录，继续追加会让 log 无法回放，之后的写入都返回错误
+    broken: bool,
+    raw_bytes: u64,
+    stored_bytes: u64,
+    // 日志文件中所有记录的字节数和内存表引用的记录的字节数，两者的差是可以回收的空间
+    log_bytes: u64,
+    live_bytes: u64,
+    compactions: u64,
+    compaction_time: Duration,
+    compaction_ratio: usize,
+    compaction_keys: usize,
+    codec: RecordCodec,
+    storage: Arc<dyn Storage>,
+    wlog: Box<dyn StorageFile>,
+    // 快照会持有 rlog 的引用，compaction 替换 rlog 后旧文件仍然可以被快照读取
+    rlog: Arc<dyn StorageFile>,
+    // 在 ws 的锁内发布，事件的顺序和 log 中的顺序一致
+    watch: Arc<Watch>,
 }
 
 // KvStore 需要在线程间传递，需要使用 Arc 原子计数引用，Arc 要求是不可变的，所以 set/get/remove 都需要 &self 而不是 &mut self
@@ -41,88 +143,299 @@ struct WriteStore {
 
 impl KvStore {
     pub fn open(p: &path::Path) -> Result<Self> {
-        let wf: fs::File = fs::OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .append(true)
-            .open(p.join(LOGFILENAM).to_str().unwrap())?;
-        let mut rf: fs::File = fs::OpenOptions::new()
-            .read(true)
-            .open(p.join(LOGFILENAM).to_str().unwrap())?;
+        Self::open_with_options(p, KvStoreOptions::default())
+    }
+
+    pub fn open_with_options(p: &path::Path, opts: KvStoreOptions) -> Result<Self> {
+        let start = Instant::now();
+        let codec = RecordCodec {
+            compression: opts.compression,
+            compression_threshold: opts.compression_threshold,
+            key: opts.encryption_key,
+        };
+        let storage = opts.storage;
+        let mut locks = vec![storage.lock(p, SHARED_LOCKFILENAM, false)?];
+        if !opts.read_only {
+            locks.push(storage.lock(p, LOCKFILENAM, true)?);
+            init_log(storage.as_ref(), p, &codec)?;
+        }
+        let log = p.join(LOGFILENAM);
+        let wf = if opts.read_only {
+            storage.open_read(&log)?
+        } else {
+            storage.open_append(&log)?
+        };
+        let rf: Arc<dyn StorageFile> = storage.open_read(&log)?.into();
         let mut data = HashMap::new();
         let mut log_keys = 0;
-        let mut r = BufReader::new(&rf);
-        loop {
-            let mut buf = vec![];
-            let pos = r.stream_position()?;
-            let s = r.read_until(DELIMITER, &mut buf).unwrap();
-            // println!("buf {}, len {}", String::from_utf8(buf.to_vec()).unwrap(),s);
-            if s == 0 {
-                break;
+        let (mut raw_bytes, mut stored_bytes) = (0, 0);
+        let (mut log_bytes, mut live_bytes) = (0, 0);
+        let size = rf.size()?;
+        let mut r = BufReader::new(FileReader::new(rf.as_ref()));
+        let mut pos = match read_header(&mut r)? {
+            Some((h, len)) => {
+                check_key(&h, &codec)?;
+                len
             }
-            buf.pop();
+            // 可写打开时 init_log 已经保证文件头存在
+            None => return Err("log is empty or in the legacy format".into()),
+        };
+        loop {
+            let buf = match read_record(&mut r, size.saturating_sub(pos)) {
+                Ok(Some(buf)) => buf,
+                Ok(None) => break,
+                // 崩溃时正在追加的记录只写入了一部分，这次写入没有返回成功，截掉之后才能继续追加
+                // 只读打开时可能是其他进程正在写入，只忽略不截断
+                // 后面还有完整的记录时是长度字段损坏，截断会丢掉已经确认的写入
+                Err(e) if e.to_string() == TRUNCATED_RECORD => {
+                    if let Some(next) = next_record(rf.as_ref(), &codec, pos + 1, size) {
+                        return Err(corrupt(pos, &format!("record runs past the end of the log, but a record follows at offset {}", next)));
+                    }
+                    warn!(slog_scope::logger(), "torn record at the end of the log discarded";
+                        "offset" => pos,
+                        "bytes" => size - pos);
+                    if !opts.read_only {
+                        wf.set_len(pos)?;
+                    }
+                    break;
+                }
+                Err(e) => return Err(e),
+            };
             log_keys += 1;
-            let c: Command = serde_json::from_slice(&buf)?;
-            if let Command::Set(k, _value) = c {
-                data.insert(k, (pos, s));
+            let (stored, raw) = payload_sizes(&buf);
+            stored_bytes += stored as u64;
+            raw_bytes += raw as u64;
+            let c = codec
+                .decode(&buf)
+                .map_err(|e| corrupt(pos, &e.to_string()))?;
+            log_bytes += buf.len() as u64;
+            let old = if let Command::Set(k, _value) = c {
+                live_bytes += buf.len() as u64;
+                data.insert(k, (pos, buf.len()))
             } else if let Command::Rm(k) = c {
-                data.remove(&k);
-            }
+                data.remove(&k)
+            } else {
+                None
+            };
+            live_bytes -= old.map_or(0, |(_, len)| len as u64);
+            pos += buf.len() as u64;
         }
-        rf.rewind().unwrap();
 
+        // 引擎没有自己的 Logger，使用调用方的日志上下文，见 logging
+        info!(slog_scope::logger(), "log replayed";
+            "dir" => %p.display(),
+            "records" => log_keys,
+            "live_keys" => data.len(),
+            "stale_bytes" => log_bytes - live_bytes,
+            "elapsed_ms" => start.elapsed().as_millis() as u64);
+        let watch = Arc::new(Watch::default());
         Ok(KvStore {
             ws: Arc::new(Mutex::new(WriteStore {
                 data,
                 log_keys,
+                seq: log_keys as u64,
+                versions: HashMap::new(),
+                read_only: opts.read_only,
+                sync: opts.sync,
+                broken: false,
+                raw_bytes,
+                stored_bytes,
+                log_bytes,
+                live_bytes,
+                compactions: 0,
+                compaction_time: Duration::ZERO,
+                compaction_ratio: opts.compaction_ratio,
+                compaction_keys: opts.compaction_keys,
+                codec,
+                storage,
                 wlog: wf,
                 rlog: rf,
+                watch: watch.clone(),
             })),
+            watch,
             dir: p.to_str().unwrap().to_owned(),
+            _locks: locks,
         })
     }
 
-    // 新开辟一个文件写入
+    pub fn stats(&self) -> KvStoreStats {
+        let ws = self.ws.lock().unwrap();
+        KvStoreStats {
+            live_keys: ws.data.len(),
+            log_keys: ws.log_keys,
+            raw_bytes: ws.raw_bytes,
+            stored_bytes: ws.stored_bytes,
+            stale_bytes: ws.log_bytes - ws.live_bytes,
+            compactions: ws.compactions,
+            compaction_time: ws.compaction_time,
+        }
+    }
+
+    // 写入之后检查是否达到自动 compaction 的阈值
+    // 这时写入已经成功，compaction 失败只记录日志，旧的 log 保持不变，下次写入之后再试
     fn compaction(&self) {
         let mut ws = self.ws.lock().unwrap();
 
-        if ws.log_keys / (ws.data.len() + 1) < COMPACTION_RATIO || ws.log_keys < COMPACTION_KEYS {
+        if ws.compaction_ratio == 0
+            || ws.log_keys / (ws.data.len() + 1) < ws.compaction_ratio
+            || ws.log_keys < ws.compaction_keys
+        {
             return;
         }
-        println!(
-            "start compaction [log keys] {} , [real keys] {}",
-            ws.log_keys,
-            ws.data.len()
-        );
-        let mut wf: fs::File = fs::OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .append(true)
-            .open(self.dir.to_owned() + "/" + LOGFILENAM + "1")
-            .unwrap();
+        if let Err(e) = self.compact_locked(&mut ws, &mut log_progress()) {
+            warn!(slog_scope::logger(), "compaction failed"; "error" => %e);
+        }
+    }
 
-        let rlog = ws.rlog.try_clone().unwrap();
-        for v in ws.data.values_mut() {
-            let mut buf: Vec<u8> = vec![0; v.1];
-            rlog.read_exact_at(&mut buf, v.0).unwrap();
-            v.0 = wf.stream_position().unwrap();
-            wf.write_all(&buf).unwrap();
+    // progress 在拷贝每个 key 之后调用，参数是已经拷贝的和总的 key 数量
+    // 没有可以回收的记录时不重写
+    pub fn compact_with_progress(
+        &self,
+        mut progress: impl FnMut(usize, usize),
+    ) -> Result<CompactionReport> {
+        let mut ws = self.ws.lock().unwrap();
+        if ws.read_only {
+            return Err(READ_ONLY.into());
+        }
+        if ws.log_keys == ws.data.len() {
+            let size = ws.wlog.size()?;
+            return Ok(CompactionReport {
+                before_bytes: size,
+                after_bytes: size,
+                keys: ws.data.len() as u64,
+                ..CompactionReport::default()
+            });
         }
+        self.compact_locked(&mut ws, &mut progress)
+    }
+
+    fn compact_locked(
+        &self,
+        ws: &mut WriteStore,
+        progress: &mut dyn FnMut(usize, usize),
+    ) -> Result<CompactionReport> {
+        let logger = slog_scope::logger();
+        let before = ws.wlog.size()?;
+        info!(logger, "compaction started";
+            "log_keys" => ws.log_keys,
+            "live_keys" => ws.data.len(),
+            "stale_bytes" => ws.log_bytes - ws.live_bytes);
+        let start = Instant::now();
+        self.rewrite(ws, None, progress)?;
+        let elapsed = start.elapsed();
+        ws.compactions += 1;
+        ws.compaction_time += elapsed;
+        let after = ws.wlog.size()?;
+        let report = CompactionReport {
+            before_bytes: before,
+            after_bytes: after,
+            reclaimed_bytes: before.saturating_sub(after),
+            keys: ws.data.len() as u64,
+            elapsed_ms: elapsed.as_millis() as u64,
+        };
+        info!(logger, "compaction finished";
+            "reclaimed_bytes" => report.reclaimed_bytes,
+            "elapsed_ms" => report.elapsed_ms);
+        Ok(report)
+    }
+
+    // 用新的 key 重写整个 log，key 为 None 时改写成明文
+    // 和 compaction 走同样的路径，完成之前旧的 log 保持不变
+    pub fn rekey(&self, key: Option<EncryptionKey>) -> Result<()> {
+        let mut ws = self.ws.lock().unwrap();
+        let mut codec = ws.codec.clone();
+        codec.key = key;
+        self.rewrite(&mut ws, Some(codec), &mut |_, _| {})?;
+        info!(slog_scope::logger(), "log rewritten"; "key_id" => ws.codec.key_id());
+        Ok(())
+    }
 
-        fs::rename(
-            self.dir.to_owned() + "/" + LOGFILENAM + "1",
-            self.dir.to_owned() + "/" + LOGFILENAM,
-        )
-        .unwrap();
+    // 新开辟一个文件写入内存表中的记录，写完之后替换 log
+    // new_codec 为空时记录原样拷贝，压缩、加密过的记录保持不变，否则解码后用 new_codec 重新编码
+    fn rewrite(
+        &self,
+        ws: &mut WriteStore,
+        new_codec: Option<RecordCodec>,
+        progress: &mut dyn FnMut(usize, usize),
+    ) -> Result<()> {
+        if ws.read_only {
+            return Err(READ_ONLY.into());
+        }
+        if ws.broken {
+            return Err(BROKEN.into());
+        }
+        let dir = path::Path::new(&self.dir);
+        let tmp = dir.join(LOGFILENAM.to_owned() + "1");
+        let storage = ws.storage.clone();
+        // 上次 compaction 中途退出可能残留了临时文件
+        remove_if_exists(storage.as_ref(), &tmp)?;
+        let r = self.write_compacted(ws, new_codec, &tmp, progress);
+        if r.is_err() {
+            // 临时文件可能占用了大量空间，磁盘满时不删除会让之后的写入也失败
+            let _ = storage.remove_file(&tmp);
+        }
+        r
+    }
+
+    // 写入临时文件，sync 之后替换 log，替换之前任何一步失败旧的 log 都保持不变
+    fn write_compacted(
+        &self,
+        ws: &mut WriteStore,
+        new_codec: Option<RecordCodec>,
+        tmp: &path::Path,
+        progress: &mut dyn FnMut(usize, usize),
+    ) -> Result<()> {
+        let mut wf = ws.storage.open_append(tmp)?;
+        let codec = new_codec.unwrap_or_else(|| ws.codec.clone());
+        let header = LogHeader {
+            key_id: codec.key_id(),
+            ..LogHeader::default()
+        };
+        write_header(&mut wf, &header)?;
+        let mut pos = wf.size()?;
+
+        // rename 之后旧的 log 文件被 unlink，但是已经打开的 fd 仍然可以读取
+        // 快照持有旧 rlog 的 Arc，所以快照引用的数据在快照释放之前不会丢失
+        let recode = codec.key_id() != ws.codec.key_id()
+            || codec.compression != ws.codec.compression
+            || codec.compression_threshold != ws.codec.compression_threshold;
+        let mut data = HashMap::with_capacity(ws.data.len());
+        let (mut raw_bytes, mut stored_bytes, mut live_bytes) = (0, 0, 0);
+        let total = ws.data.len();
+        for (i, (k, v)) in ws.data.iter().enumerate() {
+            let mut buf: Vec<u8> = vec![0; v.1];
+            ws.rlog.read_exact_at(&mut buf, v.0)?;
+            if recode {
+                buf = codec.encode(&ws.codec.decode(&buf)?)?;
+            }
+            let (stored, raw) = payload_sizes(&buf);
+            stored_bytes += stored as u64;
+            raw_bytes += raw as u64;
+            wf.write_all(&buf)?;
+            live_bytes += buf.len() as u64;
+            data.insert(k.clone(), (pos, buf.len()));
+            pos += buf.len() as u64;
+            progress(i + 1, total);
+        }
+        wf.sync_all()?;
+        // 打开的文件在 rename 之后仍然指向新的 log，rename 之后不再有可能失败的操作
+        let rf = ws.storage.open_read(tmp)?;
+        ws.storage
+            .rename(tmp, &path::Path::new(&self.dir).join(LOGFILENAM))?;
         ws.wlog = wf;
-        let rf = fs::OpenOptions::new()
-            .read(true)
-            .open(self.dir.to_owned() + "/" + LOGFILENAM)
-            .unwrap();
-        ws.rlog = rf;
+        ws.rlog = rf.into();
+        ws.data = data;
+        ws.codec = codec;
         ws.log_keys = ws.data.len();
+        ws.raw_bytes = raw_bytes;
+        ws.stored_bytes = stored_bytes;
+        ws.log_bytes = live_bytes;
+        ws.live_bytes = live_bytes;
+        // 删除过的 key 的版本不再保留，避免一直占用内存
+        // 之前读过这个 key 的事务看到版本变成 0，提交时报告冲突，不会漏掉冲突
+        let WriteStore { versions, data, .. } = &mut *ws;
+        versions.retain(|k, _| data.contains_key(k));
+        Ok(())
     }
 
     // 遍历 log
@@ -191,41 +504,15 @@ impl KvsEngine for KvStore {
     fn get(&self, key: String) -> Result<Option<String>> {
         let ws = self.ws.lock().unwrap();
         match ws.data.get(&key) {
-            Some(v) => {
-                // println!("debug get key {}", key);
-                let mut buf: Vec<u8> = vec![0; v.1];
-                ws.rlog.read_exact_at(&mut buf, v.0)?;
-                // rm delimiter
-                buf.pop();
-                let c: Command = serde_json::from_slice(&buf)?;
-                // println!("debug store key {:#?},pos {}, len{}", c, v.0, v.1);
-
-                if let Command::Set(k, value) = c {
-                    assert_eq!(key, k);
-                    Ok(Some(value))
-                } else {
-                    Ok(None)
-                }
-            }
+            Some(v) => read_value(ws.rlog.as_ref(), &ws.codec, &key, *v),
             None => Ok(None),
         }
     }
     fn set(&self, key: String, value: String) -> Result<()> {
-        {
-            let sc = Command::Set(key.clone(), value);
-            let mut s = serde_json::to_string(&sc)?;
-            s.push(char::from_u32(DELIMITER.into()).unwrap());
-            let b = s.as_bytes();
-            let len = b.len();
-
-            let mut ws = self.ws.lock().unwrap();
-            ws.wlog.write_all(b)?;
-            let pos = ws.wlog.stream_position()?;
-            // println!("debug set {},{},{}", pos, len, u64::try_from(len).unwrap());
-            ws.data
-                .insert(key, (pos - u64::try_from(len).unwrap(), len));
-            ws.log_keys += 1;
-        }
+        self.ws
+            .lock()
+            .unwrap()
+            .append(vec![Command::Set(key, value)])?;
         self.compaction();
         Ok(())
     }
@@ -233,21 +520,380 @@ impl KvsEngine for KvStore {
     fn remove(&self, key: String) -> Result<()> {
         let mut ws = self.ws.lock().unwrap();
 
-        match ws.data.remove(&key) {
-            Some(_) => {
-                let sc = Command::Rm(key.clone());
-                let mut s = serde_json::to_string(&sc)?;
-                s.push(char::from_u32(DELIMITER.into()).unwrap());
-                let b = s.as_bytes();
-
-                ws.wlog.write_all(b)?;
-                ws.log_keys += 1;
-                drop(ws);
-                self.compaction();
-                Ok(())
+        if !ws.data.contains_key(&key) {
+            return Err(KEY_NOT_FOUND.into());
+        }
+        ws.append(vec![Command::Rm(key)])?;
+        drop(ws);
+        self.compaction();
+        Ok(())
+    }
+
+    fn keys(&self, prefix: &str) -> Result<Vec<String>> {
+        let ws = self.ws.lock().unwrap();
+        let mut keys: Vec<String> = ws
+            .data
+            .keys()
+            .filter(|k| k.starts_with(prefix))
+            .cloned()
+            .collect();
+        keys.sort();
+        Ok(keys)
+    }
+
+    fn snapshot(&self) -> Result<Box<dyn KvsSnapshot>> {
+        let ws = self.ws.lock().unwrap();
+        Ok(Box::new(KvStoreSnapshot {
+            seq: ws.seq,
+            data: ws.data.iter().map(|(k, v)| (k.clone(), *v)).collect(),
+            codec: ws.codec.clone(),
+            rlog: ws.rlog.clone(),
+        }))
+    }
+
+    fn begin(&self) -> Result<Box<dyn Transaction>> {
+        let ws = self.ws.lock().unwrap();
+        Ok(Box::new(KvStoreTransaction {
+            store: self.clone(),
+            start_seq: ws.seq,
+            reads: HashMap::new(),
+            writes: BTreeMap::new(),
+        }))
+    }
+
+    fn watch(&self) -> Result<Arc<Watch>> {
+        Ok(self.watch.clone())
+    }
+
+    // 进度每 10% 写一条日志
+    fn compact(&self) -> Result<CompactionReport> {
+        self.compact_with_progress(log_progress())
+    }
+
+    // 磁盘空间是日志文件的大小，包括还没有被 compaction 回收的记录
+    fn info(&self) -> Result<EngineInfo> {
+        let ws = self.ws.lock().unwrap();
+        Ok(EngineInfo {
+            engine: "kvs".to_owned(),
+            keys: ws.data.len() as u64,
+            disk_bytes: ws.wlog.size()?,
+        })
+    }
+}
+
+impl WriteStore {
+    fn version(&self, key: &str) -> u64 {
+        self.versions.get(key).copied().unwrap_or(0)
+    }
+
+    // 把一组 set/rm 一次性写入 log 再更新内存表，事务提交和单个写入共用
+    fn append(&mut self, cmds: Vec<Command>) -> Result<()> {
+        if self.read_only {
+            return Err(READ_ONLY.into());
+        }
+        if self.broken {
+            return Err(BROKEN.into());
+        }
+        let mut b = vec![];
+        let mut lens = vec![];
+        let (mut stored_bytes, mut raw_bytes) = (0, 0);
+        for c in &cmds {
+            let r = self.codec.encode(c)?;
+            let (stored, raw) = payload_sizes(&r);
+            stored_bytes += stored as u64;
+            raw_bytes += raw as u64;
+            lens.push(r.len());
+            b.extend_from_slice(&r);
+        }
+        let start = self.wlog.size()?;
+        let mut r = self.wlog.write_all(&b);
+        if r.is_ok() && self.sync {
+            r = self.wlog.sync_all();
+        }
+        // 写入失败时可能已经写入了一部分，截掉之后 log 和内存表保持一致，这次写入视为没有发生
+        if let Err(e) = r {
+            if self.wlog.set_len(start).is_err() {
+                self.broken = true;
+            }
+            return Err(Box::new(e));
+        }
+        self.stored_bytes += stored_bytes;
+        self.raw_bytes += raw_bytes;
+        let mut pos = start;
+        let mut p = self.watch.publisher();
+        for (c, len) in cmds.into_iter().zip(lens) {
+            self.seq += 1;
+            self.log_keys += 1;
+            self.log_bytes += len as u64;
+            let old = match c {
+                Command::Set(k, v) => {
+                    self.live_bytes += len as u64;
+                    self.versions.insert(k.clone(), self.seq);
+                    let old = self.data.insert(k.clone(), (pos, len));
+                    p.publish(k, Some(v));
+                    old
+                }
+                Command::Rm(k) => {
+                    let old = self.data.remove(&k);
+                    self.versions.insert(k.clone(), self.seq);
+                    p.publish(k, None);
+                    old
+                }
+                _ => unreachable!(),
+            };
+            self.live_bytes -= old.map_or(0, |(_, len)| len as u64);
+            pos += len as u64;
+        }
+        Ok(())
+    }
+}
+
+// log 中间的记录损坏时不能自动修复，交给 kvs-tool
+fn corrupt(pos: u64, e: &str) -> Box<dyn std::error::Error> {
+    format!(
+        "log is corrupt at offset {}: {}; inspect it with `kvs-tool verify` and salvage the intact records with `kvs-tool repair`",
+        pos, e
+    )
+    .into()
+}
+
+// [from, size) 中第一条可以完整解码的记录的位置，和 kvs-tool 查找损坏区域之后的记录一样逐字节尝试
+fn next_record(f: &dyn StorageFile, codec: &RecordCodec, from: u64, size: u64) -> Option<u64> {
+    (from..size).find(|&off| {
+        let remaining = size - off;
+        let mut h = [0; RECORD_HEADER_LEN];
+        if remaining < RECORD_HEADER_LEN as u64 || f.read_exact_at(&mut h, off).is_err() {
+            return false;
+        }
+        let len = u32::from_le_bytes([h[0], h[1], h[2], h[3]]) as usize;
+        if h[4] & !(FLAG_LZ4 | FLAG_ENCRYPTED) != 0 || (RECORD_HEADER_LEN + len) as u64 > remaining
+        {
+            return false;
+        }
+        let mut buf = vec![0; RECORD_HEADER_LEN + len];
+        f.read_exact_at(&mut buf, off).is_ok()
+            && matches!(codec.decode(&buf), Ok(Command::Set(..) | Command::Rm(_)))
+    })
+}
+
+// 打开时给定的 key 需要和文件头中记录的 key id 一致
+fn check_key(h: &LogHeader, codec: &RecordCodec) -> Result<()> {
+    match (&h.key_id, codec.key_id()) {
+        (None, None) => Ok(()),
+        (Some(a), Some(b)) if *a == b => Ok(()),
+        (Some(a), None) => Err(format!("log is encrypted with key {}, no key given", a).into()),
+        (None, Some(_)) => Err("log is not encrypted, use `kvs rekey` to encrypt it".into()),
+        (Some(a), Some(b)) => {
+            Err(format!("log is encrypted with key {}, but key {} is given", a, b).into())
+        }
+    }
+}
+
+// 可写打开之前确保 log 存在并且有文件头
+// 不存在或者为空时新建；旧版本的 log 是以 '#' 分隔的 json，不能存放压缩后的二进制数据，
+// 只保留最新的 value 改写成新的格式
+fn init_log(storage: &dyn Storage, dir: &path::Path, codec: &RecordCodec) -> Result<()> {
+    let p = dir.join(LOGFILENAM);
+    let f = match storage.open_read(&p) {
+        Ok(f) => f,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return write_log(storage, dir, codec, BTreeMap::new())
+        }
+        Err(e) => return Err(Box::new(e)),
+    };
+    if f.size()? == 0 {
+        return write_log(storage, dir, codec, BTreeMap::new());
+    }
+    let mut r = BufReader::new(FileReader::new(f.as_ref()));
+    if read_header(&mut r)?.is_some() {
+        return Ok(());
+    }
+    r.rewind()?;
+    let mut data = BTreeMap::new();
+    loop {
+        let mut buf = vec![];
+        let s = r.read_until(DELIMITER, &mut buf)?;
+        if s == 0 {
+            break;
+        }
+        buf.pop();
+        match serde_json::from_slice(&buf)? {
+            Command::Set(k, v) => {
+                data.insert(k, v);
+            }
+            Command::Rm(k) => {
+                data.remove(&k);
+            }
+            _ => {}
+        }
+    }
+    write_log(storage, dir, codec, data)?;
+    info!(slog_scope::logger(), "legacy log upgraded"; "dir" => %dir.display());
+    Ok(())
+}
+
+// 在临时文件中写好文件头和记录，sync 之后替换 log，崩溃时 log 不会只有一半的文件头
+fn write_log(
+    storage: &dyn Storage,
+    dir: &path::Path,
+    codec: &RecordCodec,
+    data: BTreeMap<String, String>,
+) -> Result<()> {
+    let tmp = dir.join(LOGFILENAM.to_owned() + "1");
+    remove_if_exists(storage, &tmp)?;
+    let mut wf = storage.open_append(&tmp)?;
+    let header = LogHeader {
+        key_id: codec.key_id(),
+        ..LogHeader::default()
+    };
+    write_header(&mut wf, &header)?;
+    for (k, v) in data {
+        wf.write_all(&codec.encode(&Command::Set(k, v))?)?;
+    }
+    wf.sync_all()?;
+    storage.rename(&tmp, &dir.join(LOGFILENAM))?;
+    Ok(())
+}
+
+fn remove_if_exists(storage: &dyn Storage, p: &path::Path) -> Result<()> {
+    match storage.remove_file(p) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(Box::new(e)),
+        _ => Ok(()),
+    }
+}
+
+// 乐观并发控制：读写都先缓存在事务里，提交时加锁检查
+// 读过的 key 在读之后被修改过是 read-write 冲突，写过的 key 在事务开始之后被修改过是 write-write 冲突
+pub struct KvStoreTransaction {
+    store: KvStore,
+    start_seq: u64,
+    // key -> 读取时的版本
+    reads: HashMap<String, u64>,
+    // key -> 新的 value，None 表示删除
+    writes: BTreeMap<String, Option<String>>,
+}
+
+impl Transaction for KvStoreTransaction {
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        if let Some(v) = self.writes.get(&key) {
+            return Ok(v.clone());
+        }
+        let ws = self.store.ws.lock().unwrap();
+        self.reads.entry(key.clone()).or_insert(ws.version(&key));
+        match ws.data.get(&key) {
+            Some(v) => read_value(ws.rlog.as_ref(), &ws.codec, &key, *v),
+            None => Ok(None),
+        }
+    }
+
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.writes.insert(key, Some(value));
+        Ok(())
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        if self.get(key.clone())?.is_none() {
+            return Err(KEY_NOT_FOUND.into());
+        }
+        self.writes.insert(key, None);
+        Ok(())
+    }
+
+    fn commit(self: Box<Self>) -> Result<()> {
+        let mut ws = self.store.ws.lock().unwrap();
+        for (k, version) in &self.reads {
+            if ws.version(k) != *version {
+                return Err(TXN_CONFLICT.into());
+            }
+        }
+        for k in self.writes.keys() {
+            if ws.version(k) > self.start_seq {
+                return Err(TXN_CONFLICT.into());
+            }
+        }
+        let cmds: Vec<Command> = self
+            .writes
+            .into_iter()
+            .filter_map(|(k, v)| match v {
+                Some(v) => Some(Command::Set(k, v)),
+                // 事务里先 set 再 rm 一个原本不存在的 key，不需要写 rm 记录
+                None if ws.data.contains_key(&k) => Some(Command::Rm(k)),
+                None => None,
+            })
+            .collect();
+        if cmds.is_empty() {
+            return Ok(());
+        }
+        ws.append(cmds)?;
+        drop(ws);
+        self.store.compaction();
+        Ok(())
+    }
+}
+
+// compaction 的进度每增加 10% 写一条日志
+fn log_progress() -> impl FnMut(usize, usize) {
+    let logger = slog_scope::logger();
+    let mut last = 0;
+    move |copied, total| {
+        let percent = copied * 100 / total.max(1);
+        if percent / 10 > last / 10 {
+            info!(logger, "compaction progress"; "copied_keys" => copied, "total_keys" => total, "percent" => percent);
+            last = percent;
+        }
+    }
+}
+
+// 根据内存表中的位置读取 value
+fn read_value(
+    rlog: &dyn StorageFile,
+    codec: &RecordCodec,
+    key: &str,
+    v: (u64, usize),
+) -> Result<Option<String>> {
+    let mut buf: Vec<u8> = vec![0; v.1];
+    rlog.read_exact_at(&mut buf, v.0)?;
+    let c = codec.decode(&buf)?;
+    if let Command::Set(k, value) = c {
+        assert_eq!(key, k);
+        Ok(Some(value))
+    } else {
+        Ok(None)
+    }
+}
+
+// KvStore 的快照：拷贝一份内存表，同时持有拍快照时的 rlog
+// 之后的写入只会追加到 log 尾部，不会影响快照中记录的位置
+pub struct KvStoreSnapshot {
+    seq: u64,
+    data: BTreeMap<String, (u64, usize)>,
+    codec: RecordCodec,
+    rlog: Arc<dyn StorageFile>,
+}
+
+impl KvsSnapshot for KvStoreSnapshot {
+    fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        match self.data.get(&key) {
+            Some(v) => read_value(self.rlog.as_ref(), &self.codec, &key, *v),
+            None => Ok(None),
+        }
+    }
+
+    fn scan(&self, prefix: &str) -> Result<Vec<(String, String)>> {
+        let mut kvs = vec![];
+        for (k, v) in self.data.range(prefix.to_owned()..) {
+            if !k.starts_with(prefix) {
+                break;
+            }
+            if let Some(value) = read_value(self.rlog.as_ref(), &self.codec, k, *v)? {
+                kvs.push((k.clone(), value));
             }
-            None => Err("Key not found".into()),
         }
+        Ok(kvs)
     }
 }
 
@@ -294,6 +940,7 @@ mod tests {
             .read(true)
             .write(true)
             .create(true)
+            .truncate(true)
             .open(p.join("kvs").to_str().unwrap())
             .unwrap();
         f.write_all(b"dfdf").unwrap();
@@ -312,6 +959,7 @@ mod tests {
             .read(true)
             .write(true)
             .create(true)
+            .truncate(true)
             .open(p.join("kvs").to_str().unwrap())
             .unwrap();
         f.write_all(b"ab_fe_").unwrap();
@@ -0,0 +1,356 @@
+// 并发历史的记录和线性一致性检查
+//
+// History 记录每个操作的调用和返回时刻，Recorder 包装一个 KvsEngine，记录经过它的 set/get/remove
+// 其他客户端（比如通过网络访问 kvs-server）可以直接调用 History::begin 和 end
+//
+// check 把历史按 key 拆开，每个 key 是一个寄存器，用 Wing & Gong 的搜索加 Lowe 的缓存
+// （和 Knossos、Porcupine 相同的算法）寻找一个和实时顺序一致、并且符合寄存器语义的全序
+// 出错的写入不知道是否生效，看作没有返回的操作：可以在调用之后的任何时刻生效，也可以不生效
+use std::{
+    cell::Cell,
+    collections::{BTreeMap, HashSet},
+    fmt,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use super::{
+    CompactionReport, EngineInfo, KvsEngine, KvsSnapshot, Transaction, Watch, KEY_NOT_FOUND,
+};
+use crate::Result;
+
+// 没有返回的操作的返回时刻
+const NEVER: u64 = u64::MAX;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Input {
+    Get,
+    Set(String),
+    Remove,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Output {
+    // get 的结果
+    Value(Option<String>),
+    // set 或者 remove 成功
+    Ok,
+    // remove 不存在的 key
+    NotFound,
+    // 出错或者连接断开，不知道是否生效
+    Unknown,
+}
+
+#[derive(Clone, Debug)]
+pub struct Operation {
+    pub client: usize,
+    pub key: String,
+    pub input: Input,
+    pub output: Output,
+    // History 的逻辑时钟，call < ret
+    pub call: u64,
+    pub ret: u64,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ret = match self.ret {
+            NEVER => "-".to_owned(),
+            t => t.to_string(),
+        };
+        write!(
+            f,
+            "[{}, {}] client{} {} {:?} -> {:?}",
+            self.call, ret, self.client, self.key, self.input, self.output
+        )
+    }
+}
+
+// 进行中的操作，History::end 时记录
+pub struct Call {
+    client: usize,
+    key: String,
+    input: Input,
+    call: u64,
+}
+
+#[derive(Default)]
+pub struct History {
+    clock: AtomicU64,
+    ops: Mutex<Vec<Operation>>,
+}
+
+impl History {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // 在发出请求之前调用
+    pub fn begin(&self, client: usize, key: &str, input: Input) -> Call {
+        Call {
+            client,
+            key: key.to_owned(),
+            input,
+            call: self.clock.fetch_add(1, Ordering::SeqCst),
+        }
+    }
+
+    // 在收到结果之后调用；出错的 get 没有任何信息，不记录
+    pub fn end(&self, c: Call, output: Output) {
+        if c.input == Input::Get && output == Output::Unknown {
+            return;
+        }
+        let ret = match output {
+            Output::Unknown => NEVER,
+            _ => self.clock.fetch_add(1, Ordering::SeqCst),
+        };
+        self.ops.lock().unwrap().push(Operation {
+            client: c.client,
+            key: c.key,
+            input: c.input,
+            output,
+            call: c.call,
+            ret,
+        });
+    }
+
+    pub fn operations(&self) -> Vec<Operation> {
+        self.ops.lock().unwrap().clone()
+    }
+
+    pub fn check(&self) -> Result<()> {
+        check(&self.operations())
+    }
+}
+
+static NEXT_CLIENT: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static CLIENT: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+// 每个线程是一个客户端
+fn client_id() -> usize {
+    CLIENT.with(|c| match c.get() {
+        Some(id) => id,
+        None => {
+            let id = NEXT_CLIENT.fetch_add(1, Ordering::Relaxed);
+            c.set(Some(id));
+            id
+        }
+    })
+}
+
+// 记录经过它的 set/get/remove，其他方法直接转发
+pub struct Recorder<E: KvsEngine> {
+    engine: E,
+    history: Arc<History>,
+}
+
+impl<E: KvsEngine> Recorder<E> {
+    pub fn new(engine: E) -> Self {
+        Recorder {
+            engine,
+            history: Arc::new(History::new()),
+        }
+    }
+
+    pub fn history(&self) -> Arc<History> {
+        self.history.clone()
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for Recorder<E> {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        let c = self
+            .history
+            .begin(client_id(), &key, Input::Set(value.clone()));
+        let r = self.engine.set(key, value);
+        let output = match r {
+            Ok(()) => Output::Ok,
+            Err(_) => Output::Unknown,
+        };
+        self.history.end(c, output);
+        r
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        let c = self.history.begin(client_id(), &key, Input::Get);
+        let r = self.engine.get(key);
+        let output = match &r {
+            Ok(v) => Output::Value(v.clone()),
+            Err(_) => Output::Unknown,
+        };
+        self.history.end(c, output);
+        r
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        let c = self.history.begin(client_id(), &key, Input::Remove);
+        let r = self.engine.remove(key);
+        let output = match &r {
+            Ok(()) => Output::Ok,
+            Err(e) if e.to_string() == KEY_NOT_FOUND => Output::NotFound,
+            Err(_) => Output::Unknown,
+        };
+        self.history.end(c, output);
+        r
+    }
+
+    fn keys(&self, prefix: &str) -> Result<Vec<String>> {
+        self.engine.keys(prefix)
+    }
+
+    fn snapshot(&self) -> Result<Box<dyn KvsSnapshot>> {
+        self.engine.snapshot()
+    }
+
+    fn begin(&self) -> Result<Box<dyn Transaction>> {
+        self.engine.begin()
+    }
+
+    fn watch(&self) -> Result<Arc<Watch>> {
+        self.engine.watch()
+    }
+
+    fn info(&self) -> Result<EngineInfo> {
+        self.engine.info()
+    }
+
+    fn compact(&self) -> Result<CompactionReport> {
+        self.engine.compact()
+    }
+}
+
+// 寄存器语义：在状态 state 上执行 op，结果和记录的一致时返回新的状态
+fn step(state: &Option<String>, op: &Operation) -> Option<Option<String>> {
+    match (&op.input, &op.output) {
+        (Input::Get, Output::Value(v)) if v == state => Some(state.clone()),
+        (Input::Set(v), Output::Ok | Output::Unknown) => Some(Some(v.clone())),
+        (Input::Remove, Output::Ok) if state.is_some() => Some(None),
+        (Input::Remove, Output::NotFound) if state.is_none() => Some(None),
+        (Input::Remove, Output::Unknown) => Some(None),
+        _ => None,
+    }
+}
+
+// 所有 key 一开始都不存在，每个 key 的历史单独检查
+// 不是线性一致时错误信息中包含无法排进全序的操作和与它并发的操作
+pub fn check(ops: &[Operation]) -> Result<()> {
+    let mut keys: BTreeMap<&str, Vec<&Operation>> = BTreeMap::new();
+    for op in ops {
+        keys.entry(&op.key).or_default().push(op);
+    }
+    for (key, ops) in keys {
+        if let Err(stuck) = check_key(&ops) {
+            let op = ops[stuck];
+            let concurrent: Vec<String> = ops
+                .iter()
+                .filter(|o| o.call < op.ret && op.call < o.ret)
+                .take(20)
+                .map(|o| format!("  {}", o))
+                .collect();
+            return Err(format!(
+                "history of key {} is not linearizable ({} operations), no valid order includes {}\noperations overlapping it:\n{}",
+                key,
+                ops.len(),
+                op,
+                concurrent.join("\n")
+            )
+            .into());
+        }
+    }
+    Ok(())
+}
+
+const NONE: usize = usize::MAX;
+
+// 调用和返回事件组成的双向链表，已经排进全序的操作从链表中摘掉，回溯时按相反的顺序放回
+// 失败时返回搜索最深时挡住的操作
+fn check_key(ops: &[&Operation]) -> std::result::Result<(), usize> {
+    // (时刻, 是否是返回, 操作)
+    let mut events: Vec<(u64, bool, usize)> = vec![];
+    for (i, op) in ops.iter().enumerate() {
+        events.push((op.call, false, i));
+        if op.ret != NEVER {
+            events.push((op.ret, true, i));
+        }
+    }
+    events.sort();
+    let n = events.len();
+    // n 是表头
+    let mut next: Vec<usize> = (1..=n).map(|i| if i < n { i } else { NONE }).collect();
+    next.push(if n > 0 { 0 } else { NONE });
+    let mut prev: Vec<usize> = (0..n).map(|i| if i == 0 { n } else { i - 1 }).collect();
+    prev.push(NONE);
+    // 调用事件对应的返回事件
+    let mut ret_of = vec![NONE; n];
+    let mut call_at = vec![NONE; ops.len()];
+    for (e, &(_, is_ret, i)) in events.iter().enumerate() {
+        if is_ret {
+            ret_of[call_at[i]] = e;
+        } else {
+            call_at[i] = e;
+        }
+    }
+
+    let unlink = |next: &mut Vec<usize>, prev: &mut Vec<usize>, e: usize| {
+        next[prev[e]] = next[e];
+        if next[e] != NONE {
+            prev[next[e]] = prev[e];
+        }
+    };
+    let relink = |next: &mut Vec<usize>, prev: &mut Vec<usize>, e: usize| {
+        next[prev[e]] = e;
+        if next[e] != NONE {
+            prev[next[e]] = e;
+        }
+    };
+
+    let mut linearized = vec![0u64; ops.len().div_ceil(64)];
+    let mut cache: HashSet<(Vec<u64>, Option<String>)> = HashSet::new();
+    // (调用事件, 之前的状态)
+    let mut stack: Vec<(usize, Option<String>)> = vec![];
+    let mut state: Option<String> = None;
+    let mut deepest = (0, 0);
+    let mut e = next[n];
+    while e != NONE {
+        let (_, is_ret, i) = events[e];
+        if !is_ret {
+            if let Some(new) = step(&state, ops[i]) {
+                linearized[i / 64] |= 1 << (i % 64);
+                if cache.insert((linearized.clone(), new.clone())) {
+                    stack.push((e, std::mem::replace(&mut state, new)));
+                    unlink(&mut next, &mut prev, e);
+                    if ret_of[e] != NONE {
+                        unlink(&mut next, &mut prev, ret_of[e]);
+                    }
+                    e = next[n];
+                    continue;
+                }
+                linearized[i / 64] &= !(1 << (i % 64));
+            }
+            e = next[e];
+            continue;
+        }
+        // 这个操作已经返回，但是没有办法排在前面，回溯
+        if stack.len() >= deepest.0 {
+            deepest = (stack.len(), i);
+        }
+        let (c, s) = match stack.pop() {
+            Some(top) => top,
+            None => return Err(deepest.1),
+        };
+        let i = events[c].2;
+        linearized[i / 64] &= !(1 << (i % 64));
+        state = s;
+        if ret_of[c] != NONE {
+            relink(&mut next, &mut prev, ret_of[c]);
+        }
+        relink(&mut next, &mut prev, c);
+        e = next[c];
+    }
+    Ok(())
+}
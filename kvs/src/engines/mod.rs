@@ -1,28 +1,199 @@
 use crate::Result;
 use serde::{Deserialize, Serialize};
+use std::sync::Arc;
 
+pub mod conformance;
+pub mod inspect;
 mod kvs;
+pub mod linearizability;
+pub mod record;
 mod sled;
+pub mod storage;
+pub mod watch;
 pub use self::kvs::KvStore;
-pub use self::kvs::LOGFILENAM;
+pub use self::kvs::KvStoreOptions;
+pub use self::kvs::KvStoreSnapshot;
+pub use self::kvs::KvStoreStats;
+pub use self::kvs::KvStoreTransaction;
+pub use self::kvs::{
+    COMPACTION_KEYS, COMPACTION_RATIO, LOCKFILENAM, LOGFILENAM, SHARED_LOCKFILENAM,
+};
+pub use self::record::{Compression, EncryptionKey};
 pub use self::sled::SledKvsEngine;
+pub use self::sled::SledSnapshot;
+pub use self::sled::SledTransaction;
+pub use self::watch::{Event, Watch, Watcher};
 
-pub trait KvsEngine:   Send + 'static {
+pub trait KvsEngine: Send + 'static {
     fn set(&self, key: String, value: String) -> Result<()>;
 
     fn get(&self, key: String) -> Result<Option<String>>;
 
     fn remove(&self, key: String) -> Result<()>;
+
+    // 按顺序返回所有以 prefix 开头的 key
+    fn keys(&self, prefix: &str) -> Result<Vec<String>>;
+
+    // 返回一个只读的快照，之后的写入对快照不可见
+    fn snapshot(&self) -> Result<Box<dyn KvsSnapshot>>;
+
+    // 开始一个多 key 的事务，提交时检测冲突
+    fn begin(&self) -> Result<Box<dyn Transaction>>;
+
+    // 之后的每个 set/rm 都会发布到返回的 Watch，进程内的订阅者和 WATCH 命令从这里读取
+    fn watch(&self) -> Result<Arc<Watch>>;
+
+    // INFO 命令中引擎部分的内容
+    fn info(&self) -> Result<EngineInfo>;
+
+    // 立即整理数据文件，回收被覆盖和删除的记录占用的空间
+    fn compact(&self) -> Result<CompactionReport>;
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct EngineInfo {
+    // kvs、sled 或者 proxy
+    pub engine: String,
+    pub keys: u64,
+    // 数据文件占用的磁盘空间
+    pub disk_bytes: u64,
+}
+
+// 一次 compaction 的结果
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompactionReport {
+    // 数据文件在 compaction 前后的大小
+    pub before_bytes: u64,
+    pub after_bytes: u64,
+    pub reclaimed_bytes: u64,
+    // compaction 之后的 key 数量
+    pub keys: u64,
+    pub elapsed_ms: u64,
+}
+
+// 固定在某个写入序号上的只读视图，用于长时间的 scan 或者导出
+pub trait KvsSnapshot: Send {
+    // 快照对应的写入序号，快照包含序号 <= seq 的所有写入
+    fn seq(&self) -> u64;
+
+    fn get(&self, key: String) -> Result<Option<String>>;
+
+    // 按 key 排序返回所有以 prefix 开头的 key value
+    fn scan(&self, prefix: &str) -> Result<Vec<(String, String)>>;
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+// 删除不存在的 key 返回的错误
+pub const KEY_NOT_FOUND: &str = "Key not found";
+
+// 事务提交时检测到冲突返回的错误，调用方可以重试整个事务
+pub const TXN_CONFLICT: &str = "Transaction conflict";
+
+// 事务中的读写先在本地缓存，commit 时一次性原子写入
+// 被其他写入修改过的 key 会导致 commit 失败并返回 TXN_CONFLICT
+pub trait Transaction: Send {
+    fn get(&mut self, key: String) -> Result<Option<String>>;
+
+    fn set(&mut self, key: String, value: String) -> Result<()>;
+
+    fn remove(&mut self, key: String) -> Result<()>;
+
+    fn commit(self: Box<Self>) -> Result<()>;
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Command {
     Get(String),
     Rm(String),
     Set(String, String),
+    // 事务相关的命令只用于网络协议，不会写入 log
+    Begin,
+    Commit,
+    Abort,
+    // 用户名和密码，没有用户名时第二个参数是 token
+    Auth(Option<String>, String),
+    // replica 请求从 run_id 和 offset 之后开始复制，之后连接上只有 primary 发送的复制消息
+    Sync(Option<String>, u64),
+    // 返回 primary、replica 或者 raft 节点的复制状态
+    Role,
+    // raft 节点之间的 RPC
+    Raft(crate::raft::Message),
+    // 在 raft 集群中增加或者删除一个节点，只能发给 leader
+    AddNode(u64, String),
+    RemoveNode(u64),
+    // 按顺序返回以 prefix 开头的、有读权限的 key，回复是 json 数组
+    Keys(String),
+    // 给 kvs-proxy 增加一个后端，迁移完成之后回复
+    AddBackend(String),
+    // 订阅一个 key 或者以 * 结尾的前缀，可以带上次的 run_id 和序号继续
+    // 之后连接上只有 server 发送的 watch::WatchMessage
+    Watch(String, Option<(String, u64)>),
+    // 发布一条消息，回复收到的订阅者数量
+    Publish(String, String),
+    // 订阅频道或者 glob 模式，之后连接上只有 server 发送的 pubsub::PubSubMessage
+    Subscribe(Vec<String>),
+    PSubscribe(Vec<String>),
+    // 返回每个频道和模式的订阅者数量
+    Channels,
+    // 返回引擎、连接和线程池的状态
+    Info,
+    // 返回最近的最多 n 条慢命令，新的在前，见 slowlog
+    SlowLogGet(Option<usize>),
+    // 立即执行 compaction，回复 CompactionReport
+    Compact,
+}
+
+impl Command {
+    // 指标中使用的命令名
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Get(_) => "get",
+            Command::Rm(_) => "rm",
+            Command::Set(..) => "set",
+            Command::Begin => "begin",
+            Command::Commit => "commit",
+            Command::Abort => "abort",
+            Command::Auth(..) => "auth",
+            Command::Sync(..) => "sync",
+            Command::Role => "role",
+            Command::Raft(_) => "raft",
+            Command::AddNode(..) => "add_node",
+            Command::RemoveNode(_) => "remove_node",
+            Command::Keys(_) => "keys",
+            Command::AddBackend(_) => "add_backend",
+            Command::Watch(..) => "watch",
+            Command::Publish(..) => "publish",
+            Command::Subscribe(_) => "subscribe",
+            Command::PSubscribe(_) => "psubscribe",
+            Command::Channels => "channels",
+            Command::Info => "info",
+            Command::SlowLogGet(_) => "slowlog_get",
+            Command::Compact => "compact",
+        }
+    }
+
+    // 慢日志中记录的 key，KEYS 和 WATCH 记录前缀
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            Command::Get(key)
+            | Command::Rm(key)
+            | Command::Set(key, _)
+            | Command::Keys(key)
+            | Command::Watch(key, _) => Some(key),
+            _ => None,
+        }
+    }
 }
 
+// 学习笔记性质的代码，保留原样
 #[cfg(test)]
+#[allow(
+    dead_code,
+    unused_variables,
+    non_upper_case_globals,
+    static_mut_refs,
+    clippy::needless_lifetimes,
+    clippy::ptr_arg
+)]
 mod test {
 
     fn t1(a: &String) -> &String {
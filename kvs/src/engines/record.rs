@@ -0,0 +1,313 @@
+// kvs.log 的文件格式
+//
+// 文件头：MAGIC(6 字节) + 头部长度(u16 LE) + json 编码的 LogHeader
+// 记录：payload 长度(u32 LE) + flags(u8) + payload
+// payload 是 json 编码的 Command，flags 标记 payload 是否经过了压缩和加密
+// 同时压缩和加密时先压缩再加密：原始长度(u32 LE) + nonce + 密文
+use std::io::{self, Read, Write};
+use std::{env, fs, path::Path};
+
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use serde::{Deserialize, Serialize};
+
+use super::Command;
+use crate::Result;
+
+pub const MAGIC: &[u8; 6] = b"KVSLOG";
+pub const RECORD_HEADER_LEN: usize = 5;
+// payload 使用 lz4 压缩，payload 的前 4 个字节是原始长度
+pub const FLAG_LZ4: u8 = 0x1;
+// payload 使用 ChaCha20-Poly1305 加密，密文前面是 12 字节的 nonce
+pub const FLAG_ENCRYPTED: u8 = 0x2;
+const NONCE_LEN: usize = 12;
+// lz4 的最大压缩比，记录中的原始长度超过这个倍数说明长度字段已经损坏
+const LZ4_MAX_RATIO: usize = 255;
+// 从环境变量读取加密 key，格式和 key 文件相同
+pub const KEY_ENV: &str = "KVS_ENCRYPTION_KEY";
+// 文件在记录中间结束，崩溃时正在追加的记录只写入了一部分
+pub const TRUNCATED_RECORD: &str = "truncated record at the end of the log";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LogHeader {
+    pub version: u32,
+    // 加密整个文件使用的 key id，明文文件没有这个字段
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_id: Option<String>,
+}
+
+impl Default for LogHeader {
+    fn default() -> Self {
+        LogHeader {
+            version: 2,
+            key_id: None,
+        }
+    }
+}
+
+// 加密使用的 key，文本格式是 "<key id>:<64 个十六进制字符>"
+// key id 写在文件头中，轮换 key 时用来判断文件是用哪个 key 加密的
+#[derive(Clone)]
+pub struct EncryptionKey {
+    pub id: String,
+    key: [u8; 32],
+}
+
+// 不打印 key 的内容
+impl std::fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EncryptionKey")
+            .field("id", &self.id)
+            .finish()
+    }
+}
+
+impl EncryptionKey {
+    pub fn parse(s: &str) -> Result<Self> {
+        let (id, hex) = s.trim().split_once(':').ok_or("key must be <id>:<hex>")?;
+        if id.is_empty() || hex.len() != 64 {
+            return Err("key must be <id>:<64 hex chars>".into());
+        }
+        let mut key = [0; 32];
+        for (i, b) in key.iter_mut().enumerate() {
+            *b = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)?;
+        }
+        Ok(EncryptionKey {
+            id: id.to_owned(),
+            key,
+        })
+    }
+
+    pub fn from_file(p: &Path) -> Result<Self> {
+        Self::parse(&fs::read_to_string(p)?)
+    }
+
+    // 优先使用 key 文件，没有指定时读取环境变量，都没有返回 None
+    pub fn load(key_file: Option<&Path>) -> Result<Option<Self>> {
+        match key_file {
+            Some(p) => Ok(Some(Self::from_file(p)?)),
+            None => Self::from_env(),
+        }
+    }
+
+    // 环境变量不存在时返回 None
+    pub fn from_env() -> Result<Option<Self>> {
+        match env::var(KEY_ENV) {
+            Ok(s) => Ok(Some(Self::parse(&s)?)),
+            Err(env::VarError::NotPresent) => Ok(None),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum Compression {
+    #[default]
+    None,
+    Lz4,
+}
+
+// 负责单条记录的编码和解码，KvStore 和快照共用
+#[derive(Clone)]
+pub struct RecordCodec {
+    pub compression: Compression,
+    // payload 超过这个大小才压缩，小记录压缩收益很低
+    pub compression_threshold: usize,
+    pub key: Option<EncryptionKey>,
+}
+
+impl RecordCodec {
+    pub fn key_id(&self) -> Option<String> {
+        self.key.as_ref().map(|k| k.id.clone())
+    }
+
+    pub fn encode(&self, c: &Command) -> Result<Vec<u8>> {
+        let json = serde_json::to_vec(c)?;
+        let mut flags = 0;
+        let mut payload = vec![];
+        let mut body = json;
+        if self.compression == Compression::Lz4 && body.len() >= self.compression_threshold {
+            flags |= FLAG_LZ4;
+            payload.extend_from_slice(&u32::try_from(body.len())?.to_le_bytes());
+            body = lz4_flex::compress(&body);
+        }
+        if let Some(key) = &self.key {
+            flags |= FLAG_ENCRYPTED;
+            let cipher = ChaCha20Poly1305::new(&key.key.into());
+            let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+            // flags 作为附加数据，防止记录头被篡改
+            body = cipher
+                .encrypt(
+                    &nonce,
+                    Payload {
+                        msg: &body,
+                        aad: &[flags],
+                    },
+                )
+                .map_err(|_| "encrypt record failed")?;
+            payload.extend_from_slice(&nonce);
+        }
+        payload.extend_from_slice(&body);
+        let mut buf = Vec::with_capacity(RECORD_HEADER_LEN + payload.len());
+        buf.extend_from_slice(&u32::try_from(payload.len())?.to_le_bytes());
+        buf.push(flags);
+        buf.extend_from_slice(&payload);
+        Ok(buf)
+    }
+
+    // buf 是包含记录头的完整记录，损坏的记录返回错误
+    pub fn decode(&self, buf: &[u8]) -> Result<Command> {
+        if buf.len() < RECORD_HEADER_LEN {
+            return Err("truncated record header".into());
+        }
+        let flags = buf[4];
+        let mut payload = &buf[RECORD_HEADER_LEN..];
+        let mut raw_len = None;
+        if flags & FLAG_LZ4 != 0 {
+            if payload.len() < 4 {
+                return Err("truncated compressed record".into());
+            }
+            let (len, rest) = payload.split_at(4);
+            raw_len = Some(u32::from_le_bytes([len[0], len[1], len[2], len[3]]) as usize);
+            payload = rest;
+        }
+        let plain;
+        if flags & FLAG_ENCRYPTED != 0 {
+            let key = self
+                .key
+                .as_ref()
+                .ok_or("record is encrypted but no key given")?;
+            if payload.len() < NONCE_LEN {
+                return Err("truncated encrypted record".into());
+            }
+            let (nonce, body) = payload.split_at(NONCE_LEN);
+            let cipher = ChaCha20Poly1305::new(&key.key.into());
+            plain = cipher
+                .decrypt(
+                    Nonce::from_slice(nonce),
+                    Payload {
+                        msg: body,
+                        aad: &[flags],
+                    },
+                )
+                .map_err(|_| "decrypt record failed, wrong key or corrupted record")?;
+            payload = &plain;
+        }
+        match raw_len {
+            // 避免按损坏的原始长度分配过大的内存
+            Some(len) if len > payload.len() * LZ4_MAX_RATIO => {
+                Err(format!("invalid uncompressed length {}", len).into())
+            }
+            Some(len) => Ok(serde_json::from_slice(&lz4_flex::decompress(
+                payload, len,
+            )?)?),
+            None => Ok(serde_json::from_slice(payload)?),
+        }
+    }
+}
+
+// 返回记录 (存储的 payload 长度, 原始 payload 长度)，用于统计压缩率
+pub fn payload_sizes(buf: &[u8]) -> (usize, usize) {
+    let stored = buf.len() - RECORD_HEADER_LEN;
+    if buf[4] & FLAG_LZ4 != 0 && stored >= 4 {
+        let mut raw = [0; 4];
+        raw.copy_from_slice(&buf[RECORD_HEADER_LEN..RECORD_HEADER_LEN + 4]);
+        (stored, u32::from_le_bytes(raw) as usize)
+    } else {
+        (stored, stored)
+    }
+}
+
+pub fn write_header<W: Write>(w: &mut W, h: &LogHeader) -> Result<()> {
+    let body = serde_json::to_vec(h)?;
+    let mut buf = MAGIC.to_vec();
+    buf.extend_from_slice(&u16::try_from(body.len())?.to_le_bytes());
+    buf.extend_from_slice(&body);
+    w.write_all(&buf)?;
+    Ok(())
+}
+
+// 读取文件头，返回文件头和文件头占用的字节数
+// 文件不是以 MAGIC 开头时返回 None（空文件或者旧的以 '#' 分隔的格式）
+pub fn read_header<R: Read>(r: &mut R) -> Result<Option<(LogHeader, u64)>> {
+    let mut magic = [0; 8];
+    if let Err(e) = r.read_exact(&mut magic) {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            return Ok(None);
+        }
+        return Err(Box::new(e));
+    }
+    if &magic[..6] != MAGIC {
+        return Ok(None);
+    }
+    let len = u16::from_le_bytes([magic[6], magic[7]]) as usize;
+    let mut body = vec![0; len];
+    r.read_exact(&mut body)?;
+    Ok(Some((serde_json::from_slice(&body)?, 8 + len as u64)))
+}
+
+// 读取下一条完整的记录，文件结束返回 None
+// remaining 是从这条记录开始到文件结束的字节数，记录超出文件结束时返回 TRUNCATED_RECORD，
+// 这时不会按损坏的长度分配内存；其他 IO 错误原样返回
+pub fn read_record<R: Read>(r: &mut R, remaining: u64) -> Result<Option<Vec<u8>>> {
+    let mut header = [0; RECORD_HEADER_LEN];
+    let mut n = 0;
+    while n < RECORD_HEADER_LEN {
+        let s = r.read(&mut header[n..])?;
+        if s == 0 {
+            if n == 0 {
+                return Ok(None);
+            }
+            return Err(TRUNCATED_RECORD.into());
+        }
+        n += s;
+    }
+    let len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    if (RECORD_HEADER_LEN + len) as u64 > remaining {
+        return Err(TRUNCATED_RECORD.into());
+    }
+    let mut buf = vec![0; RECORD_HEADER_LEN + len];
+    buf[..RECORD_HEADER_LEN].copy_from_slice(&header);
+    r.read_exact(&mut buf[RECORD_HEADER_LEN..])?;
+    Ok(Some(buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(flags: u8, payload: &[u8]) -> Vec<u8> {
+        let mut buf = (payload.len() as u32).to_le_bytes().to_vec();
+        buf.push(flags);
+        buf.extend_from_slice(payload);
+        buf
+    }
+
+    #[test]
+    fn decode_corrupt_lz4_records() {
+        let codec = RecordCodec {
+            compression: Compression::Lz4,
+            compression_threshold: 0,
+            key: None,
+        };
+        let c = Command::Set("key".to_owned(), "value".repeat(100));
+        let decoded = codec.decode(&codec.encode(&c).unwrap()).unwrap();
+        assert_eq!(format!("{:?}", decoded), format!("{:?}", c));
+
+        // 原始长度不完整
+        for n in 0..4 {
+            let err = codec.decode(&record(FLAG_LZ4, &[0; 3][..n])).unwrap_err();
+            assert!(err.to_string().contains("truncated"), "{}", err);
+        }
+        assert!(codec.decode(&[0, 0]).is_err());
+        // 原始长度 4 GiB，压缩数据只有几个字节
+        let mut payload = u32::MAX.to_le_bytes().to_vec();
+        payload.extend_from_slice(&[0x10, b'x', 0, 0]);
+        let err = codec.decode(&record(FLAG_LZ4, &payload)).unwrap_err();
+        assert!(err.to_string().contains("uncompressed length"), "{}", err);
+        // 在压缩比范围内但是和压缩数据不符
+        let mut payload = 1000u32.to_le_bytes().to_vec();
+        payload.extend_from_slice(&[0x10, b'x', 0, 0]);
+        assert!(codec.decode(&record(FLAG_LZ4, &payload)).is_err());
+    }
+}
@@ -1,30 +1,79 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::hash::{DefaultHasher, Hash, Hasher};
 use std::path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::time::Instant;
 
-use super::KvsEngine;
+use sled::transaction::{abort, TransactionError};
+use sled::IVec;
+
+use super::{
+    CompactionReport, EngineInfo, KvsEngine, KvsSnapshot, Transaction, Watch, KEY_NOT_FOUND,
+    TXN_CONFLICT,
+};
 use crate::Result;
 
 #[derive(Clone)]
 pub struct SledKvsEngine {
     db: sled::Db,
+    // 写入持有读锁，拍快照时持有写锁，保证导出的数据是某个时间点的一致视图
+    guard: Arc<RwLock<()>>,
+    seq: Arc<AtomicU64>,
+    // 同一个 key 的写入和发布事件在同一个分段锁下进行，这个 key 的事件顺序和 sled 中写入的顺序一致
+    // publisher 只在发布时持有，不同 key 的写入和 flush 可以并发
+    stripes: Arc<Vec<Mutex<()>>>,
+    watch: Arc<Watch>,
+}
+
+const STRIPES: usize = 64;
+
+fn stripe(key: &str) -> usize {
+    let mut h = DefaultHasher::new();
+    key.hash(&mut h);
+    h.finish() as usize % STRIPES
 }
 
 impl SledKvsEngine {
     pub fn open(p: &path::Path) -> Result<Self> {
         let db = sled::open(p);
         match db {
-            Ok(db) => Ok(SledKvsEngine { db }),
+            Ok(db) => Ok(SledKvsEngine {
+                db,
+                guard: Arc::new(RwLock::new(())),
+                seq: Arc::new(AtomicU64::new(0)),
+                stripes: Arc::new((0..STRIPES).map(|_| Mutex::new(())).collect()),
+                watch: Arc::new(Watch::default()),
+            }),
             Err(e) => Err(Box::new(e)),
         }
     }
 }
 
+impl SledKvsEngine {
+    // 按分段的顺序加锁，多个 key 的事务之间不会死锁
+    fn lock<'a>(&self, keys: impl IntoIterator<Item = &'a String>) -> Vec<MutexGuard<'_, ()>> {
+        let stripes: BTreeSet<usize> = keys.into_iter().map(|k| stripe(k)).collect();
+        stripes
+            .into_iter()
+            .map(|i| self.stripes[i].lock().unwrap())
+            .collect()
+    }
+}
+
 impl KvsEngine for SledKvsEngine {
     fn set(&self, key: String, value: String) -> Result<()> {
         // println!("set key: {} value: {}",key,value);
-        let x = self.db.insert(key, value.as_bytes());
+        let _g = self.guard.read().unwrap();
+        let _s = self.lock([&key]);
+        let x = self.db.insert(key.as_bytes(), value.as_bytes());
         self.db.flush().unwrap();
+        self.seq.fetch_add(1, Ordering::SeqCst);
         match x {
-            Ok(_) => Ok(()),
+            Ok(_) => {
+                self.watch.publisher().publish(key, Some(value));
+                Ok(())
+            }
             Err(e) => Err(Box::new(e)),
         }
     }
@@ -49,14 +98,188 @@ impl KvsEngine for SledKvsEngine {
 
     fn remove(&self, key: String) -> Result<()> {
         // println!("rm key: {}",key);
-        let x = self.db.remove(key);
+        let _g = self.guard.read().unwrap();
+        let _s = self.lock([&key]);
+        let x = self.db.remove(key.as_bytes());
         self.db.flush().unwrap();
         match x {
             Ok(v) => match v {
-                Some(_) => Ok(()),
-                None => Err("Key not found".into()),
+                Some(_) => {
+                    self.seq.fetch_add(1, Ordering::SeqCst);
+                    self.watch.publisher().publish(key, None);
+                    Ok(())
+                }
+                None => Err(KEY_NOT_FOUND.into()),
             },
             Err(e) => Err(Box::new(e)),
         }
     }
+
+    fn keys(&self, prefix: &str) -> Result<Vec<String>> {
+        let mut keys = vec![];
+        for k in self.db.scan_prefix(prefix).keys() {
+            keys.push(String::from_utf8(k?.to_vec())?);
+        }
+        Ok(keys)
+    }
+
+    // sled 没有提供可以遍历的快照，这里在写锁下把数据导出到内存
+    fn snapshot(&self) -> Result<Box<dyn KvsSnapshot>> {
+        let _g = self.guard.write().unwrap();
+        let mut data = BTreeMap::new();
+        for kv in self.db.iter() {
+            let (k, v) = kv?;
+            data.insert(
+                String::from_utf8(k.to_vec())?,
+                String::from_utf8(v.to_vec())?,
+            );
+        }
+        Ok(Box::new(SledSnapshot {
+            seq: self.seq.load(Ordering::SeqCst),
+            data,
+        }))
+    }
+
+    fn begin(&self) -> Result<Box<dyn Transaction>> {
+        Ok(Box::new(SledTransaction {
+            engine: self.clone(),
+            reads: HashMap::new(),
+            writes: BTreeMap::new(),
+        }))
+    }
+
+    fn watch(&self) -> Result<Arc<Watch>> {
+        Ok(self.watch.clone())
+    }
+
+    fn info(&self) -> Result<EngineInfo> {
+        Ok(EngineInfo {
+            engine: "sled".to_owned(),
+            keys: self.db.len() as u64,
+            disk_bytes: self.db.size_on_disk()?,
+        })
+    }
+
+    // sled 在后台自己整理文件，这里只把缓存的写入刷到磁盘
+    fn compact(&self) -> Result<CompactionReport> {
+        let start = Instant::now();
+        let before = self.db.size_on_disk()?;
+        self.db.flush()?;
+        let after = self.db.size_on_disk()?;
+        Ok(CompactionReport {
+            before_bytes: before,
+            after_bytes: after,
+            reclaimed_bytes: before.saturating_sub(after),
+            keys: self.db.len() as u64,
+            elapsed_ms: start.elapsed().as_millis() as u64,
+        })
+    }
+}
+
+// 读写缓存在本地，commit 时在 sled 的事务里校验读到的值没有变化再写入
+// 写之前也会记录 key 当前的值，这样 write-write 冲突同样可以被检测到
+pub struct SledTransaction {
+    engine: SledKvsEngine,
+    reads: HashMap<String, Option<IVec>>,
+    writes: BTreeMap<String, Option<String>>,
+}
+
+impl SledTransaction {
+    fn read(&mut self, key: &str) -> Result<Option<IVec>> {
+        if let Some(v) = self.reads.get(key) {
+            return Ok(v.clone());
+        }
+        let v = self.engine.db.get(key)?;
+        self.reads.insert(key.to_owned(), v.clone());
+        Ok(v)
+    }
+}
+
+impl Transaction for SledTransaction {
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        if let Some(v) = self.writes.get(&key) {
+            return Ok(v.clone());
+        }
+        match self.read(&key)? {
+            Some(v) => Ok(Some(String::from_utf8(v.to_vec())?)),
+            None => Ok(None),
+        }
+    }
+
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.read(&key)?;
+        self.writes.insert(key, Some(value));
+        Ok(())
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        if self.get(key.clone())?.is_none() {
+            return Err(KEY_NOT_FOUND.into());
+        }
+        self.writes.insert(key, None);
+        Ok(())
+    }
+
+    fn commit(self: Box<Self>) -> Result<()> {
+        let _g = self.engine.guard.read().unwrap();
+        let _s = self.engine.lock(self.writes.keys());
+        let r = self.engine.db.transaction(|tx| {
+            for (k, v) in &self.reads {
+                if tx.get(k)? != *v {
+                    return abort(());
+                }
+            }
+            for (k, v) in &self.writes {
+                match v {
+                    Some(v) => {
+                        tx.insert(k.as_bytes(), v.as_bytes())?;
+                    }
+                    None => {
+                        tx.remove(k.as_bytes())?;
+                    }
+                }
+            }
+            Ok(())
+        });
+        match r {
+            Ok(()) => {
+                self.engine.db.flush()?;
+                self.engine
+                    .seq
+                    .fetch_add(self.writes.len() as u64, Ordering::SeqCst);
+                // 一个事务的事件是连续的
+                let mut p = self.engine.watch.publisher();
+                for (k, v) in self.writes {
+                    p.publish(k, v);
+                }
+                Ok(())
+            }
+            Err(TransactionError::Abort(())) => Err(TXN_CONFLICT.into()),
+            Err(TransactionError::Storage(e)) => Err(Box::new(e)),
+        }
+    }
+}
+
+pub struct SledSnapshot {
+    seq: u64,
+    data: BTreeMap<String, String>,
+}
+
+impl KvsSnapshot for SledSnapshot {
+    fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self.data.get(&key).cloned())
+    }
+
+    fn scan(&self, prefix: &str) -> Result<Vec<(String, String)>> {
+        Ok(self
+            .data
+            .range(prefix.to_owned()..)
+            .take_while(|(k, _)| k.starts_with(prefix))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect())
+    }
 }
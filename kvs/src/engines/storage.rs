@@ -0,0 +1,484 @@
+// KvStore 读写文件经过的存储层
+//
+// DiskStorage 直接使用本地文件系统；MemStorage 把文件放在内存中，可以注入 short write、
+// 磁盘满、fsync 失败和进程崩溃、断电，用来验证 KvStore 在这些情况下不会丢失已经返回成功的写入，
+// 并且崩溃之后总能重新打开
+use std::{
+    any::Any,
+    collections::HashMap,
+    fmt, fs,
+    io::{self, Read, Seek, SeekFrom, Write},
+    os::unix::prelude::FileExt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use crate::Result;
+
+// 持有期间目录不能被其他进程打开，drop 时释放
+pub type LockGuard = Arc<dyn Any + Send + Sync>;
+
+pub trait Storage: fmt::Debug + Send + Sync {
+    // 读写打开，文件不存在时创建，写入总是追加到文件末尾
+    fn open_append(&self, p: &Path) -> io::Result<Box<dyn StorageFile>>;
+    // 只读打开，文件不存在时返回 NotFound
+    fn open_read(&self, p: &Path) -> io::Result<Box<dyn StorageFile>>;
+    // 原子地替换 to，返回之后替换是持久的
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+    fn remove_file(&self, p: &Path) -> io::Result<()>;
+    // 对目录中的锁文件加锁，见 lock_file
+    fn lock(&self, dir: &Path, name: &str, exclusive: bool) -> Result<LockGuard>;
+}
+
+// 打开的文件，rename 或者删除之后已经打开的文件仍然可以读取
+pub trait StorageFile: Write + Send + Sync {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize>;
+    fn size(&self) -> io::Result<u64>;
+    fn set_len(&self, len: u64) -> io::Result<()>;
+    fn sync_all(&self) -> io::Result<()>;
+
+    fn read_exact_at(&self, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.read_at(buf, offset) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => {
+                    buf = &mut buf[n..];
+                    offset += n as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+// 从头顺序读取文件，回放 log 时套上 BufReader 使用
+pub struct FileReader<'a> {
+    file: &'a dyn StorageFile,
+    pos: u64,
+}
+
+impl<'a> FileReader<'a> {
+    pub fn new(file: &'a dyn StorageFile) -> Self {
+        FileReader { file, pos: 0 }
+    }
+}
+
+impl Read for FileReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.file.read_at(buf, self.pos)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for FileReader<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(p) => Some(p),
+            SeekFrom::End(d) => self.file.size()?.checked_add_signed(d),
+            SeekFrom::Current(d) => self.pos.checked_add_signed(d),
+        };
+        self.pos = pos.ok_or(io::ErrorKind::InvalidInput)?;
+        Ok(self.pos)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DiskStorage;
+
+impl Storage for DiskStorage {
+    fn open_append(&self, p: &Path) -> io::Result<Box<dyn StorageFile>> {
+        let f = fs::OpenOptions::new()
+            .read(true)
+            .create(true)
+            .append(true)
+            .open(p)?;
+        Ok(Box::new(f))
+    }
+
+    fn open_read(&self, p: &Path) -> io::Result<Box<dyn StorageFile>> {
+        Ok(Box::new(fs::File::open(p)?))
+    }
+
+    // rename 之后 fsync 目录，否则断电之后目录项可能还指向旧的文件
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)?;
+        if let Some(dir) = to.parent() {
+            let dir = if dir.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                dir
+            };
+            fs::File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    fn remove_file(&self, p: &Path) -> io::Result<()> {
+        fs::remove_file(p)
+    }
+
+    fn lock(&self, dir: &Path, name: &str, exclusive: bool) -> Result<LockGuard> {
+        Ok(lock_file(dir, name, exclusive)?)
+    }
+}
+
+impl StorageFile for fs::File {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        FileExt::read_at(self, buf, offset)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        fs::File::set_len(self, len)
+    }
+
+    fn sync_all(&self) -> io::Result<()> {
+        fs::File::sync_all(self)
+    }
+}
+
+// 对目录中的锁文件加锁，锁被持有时立即返回错误
+// flock 的锁属于打开的文件，每次打开都重新加锁，同一个进程内第二次可写打开也会失败
+// 同一个 KvStore 的 clone 共享同一把锁
+pub(super) fn lock_file(dir: &Path, name: &str, exclusive: bool) -> Result<Arc<fs::File>> {
+    let p = dir.join(name);
+    let f = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&p)?;
+    let r = if exclusive {
+        f.try_lock()
+    } else {
+        f.try_lock_shared()
+    };
+    match r {
+        Ok(()) => Ok(Arc::new(f)),
+        Err(fs::TryLockError::WouldBlock) => Err(locked(&p)),
+        Err(fs::TryLockError::Error(e)) => Err(Box::new(e)),
+    }
+}
+
+fn locked(p: &Path) -> Box<dyn std::error::Error> {
+    format!(
+        "{} is locked by another process or store, is another kvs or kvs-server using it?",
+        p.display()
+    )
+    .into()
+}
+
+// MemStorage 注入的故障，restart 之后清空
+#[derive(Clone, Debug, Default)]
+pub struct Faults {
+    // 每次 write 最多写入的字节数，0 表示不限制
+    pub max_write: usize,
+    // 所有文件加起来的字节数上限，写满之后 write 返回 StorageFull
+    pub capacity: Option<u64>,
+    // 接下来这么多次 sync_all 返回错误，数据仍然没有持久化
+    pub failed_syncs: u32,
+    // 再执行这么多次修改操作（write、set_len、sync_all、rename、remove_file、创建文件）之后进程崩溃：
+    // 崩溃时的 write 只写入一半，之后所有文件操作都返回错误，直到 restart 或者 power_off
+    pub crash_after: Option<u64>,
+}
+
+// 内存中的文件系统，clone 之后共享同一份文件
+// 崩溃之后用 restart 模拟进程重启，已经写入的数据都还在；用 power_off 模拟断电，没有 sync 的数据丢失
+// 重启之前打开的文件不能再使用，和进程退出时关闭所有文件一样
+#[derive(Clone, Debug, Default)]
+pub struct MemStorage(Arc<Mutex<MemState>>);
+
+#[derive(Debug, Default)]
+struct MemState {
+    files: HashMap<PathBuf, Arc<Mutex<Inode>>>,
+    faults: Faults,
+    crashed: bool,
+    // 每次重启加 1，旧的文件句柄失效
+    generation: u64,
+    // 锁文件 -> (是否独占, 持有者个数)
+    locks: HashMap<PathBuf, (bool, usize)>,
+}
+
+#[derive(Debug, Default)]
+struct Inode {
+    data: Vec<u8>,
+    // 最后一次 sync_all 时的长度，断电时截断到这里
+    synced: usize,
+}
+
+impl MemState {
+    fn check(&self, generation: u64) -> io::Result<()> {
+        if self.crashed {
+            return Err(io::Error::other("simulated crash"));
+        }
+        if generation != self.generation {
+            return Err(io::Error::other("file was opened before the restart"));
+        }
+        Ok(())
+    }
+
+    // 下一次修改操作会触发崩溃
+    fn crashing(&self) -> bool {
+        !self.crashed && self.faults.crash_after == Some(0)
+    }
+
+    // 每次修改操作之前调用，到达 crash_after 时进入崩溃状态
+    fn tick(&mut self, generation: u64) -> io::Result<()> {
+        self.check(generation)?;
+        match &mut self.faults.crash_after {
+            Some(0) => {
+                self.crashed = true;
+                Err(io::Error::other("simulated crash"))
+            }
+            Some(n) => {
+                *n -= 1;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn used(&self) -> u64 {
+        self.files
+            .values()
+            .map(|f| f.lock().unwrap().data.len() as u64)
+            .sum()
+    }
+}
+
+impl MemStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_faults(&self, faults: Faults) {
+        self.0.lock().unwrap().faults = faults;
+    }
+
+    // 立即崩溃
+    pub fn crash(&self) {
+        self.0.lock().unwrap().crashed = true;
+    }
+
+    pub fn crashed(&self) -> bool {
+        self.0.lock().unwrap().crashed
+    }
+
+    // 进程重启：清空故障，写入过的数据都保留
+    pub fn restart(&self) {
+        let mut st = self.0.lock().unwrap();
+        st.crashed = false;
+        st.faults = Faults::default();
+        st.generation += 1;
+        // 进程退出时释放所有的锁
+        st.locks.clear();
+    }
+
+    // 断电重启：没有 sync 的数据丢失
+    pub fn power_off(&self) {
+        let st = self.0.lock().unwrap();
+        for f in st.files.values() {
+            let mut f = f.lock().unwrap();
+            let synced = f.synced;
+            f.data.truncate(synced);
+        }
+        drop(st);
+        self.restart();
+    }
+
+    // 直接读写文件内容，不经过故障注入，写入的内容视为已经 sync
+    pub fn read_file(&self, p: &Path) -> Option<Vec<u8>> {
+        let st = self.0.lock().unwrap();
+        st.files.get(p).map(|f| f.lock().unwrap().data.clone())
+    }
+
+    pub fn write_file(&self, p: &Path, data: Vec<u8>) {
+        let mut st = self.0.lock().unwrap();
+        let synced = data.len();
+        st.files
+            .insert(p.to_owned(), Arc::new(Mutex::new(Inode { data, synced })));
+    }
+
+    fn open(&self, p: &Path, create: bool) -> io::Result<Box<dyn StorageFile>> {
+        let mut st = self.0.lock().unwrap();
+        let generation = st.generation;
+        let inode = match st.files.get(p) {
+            Some(f) => {
+                st.check(generation)?;
+                f.clone()
+            }
+            None if create => {
+                st.tick(generation)?;
+                let f = Arc::new(Mutex::new(Inode::default()));
+                st.files.insert(p.to_owned(), f.clone());
+                f
+            }
+            None => return Err(io::ErrorKind::NotFound.into()),
+        };
+        Ok(Box::new(MemFile {
+            fs: self.clone(),
+            inode,
+            generation,
+            writable: create,
+        }))
+    }
+}
+
+impl Storage for MemStorage {
+    fn open_append(&self, p: &Path) -> io::Result<Box<dyn StorageFile>> {
+        self.open(p, true)
+    }
+
+    fn open_read(&self, p: &Path) -> io::Result<Box<dyn StorageFile>> {
+        self.open(p, false)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut st = self.0.lock().unwrap();
+        let generation = st.generation;
+        st.tick(generation)?;
+        let f = st.files.remove(from).ok_or(io::ErrorKind::NotFound)?;
+        st.files.insert(to.to_owned(), f);
+        Ok(())
+    }
+
+    fn remove_file(&self, p: &Path) -> io::Result<()> {
+        let mut st = self.0.lock().unwrap();
+        let generation = st.generation;
+        st.tick(generation)?;
+        st.files.remove(p).ok_or(io::ErrorKind::NotFound)?;
+        Ok(())
+    }
+
+    // 和 lock_file 一样，独占锁和其他任何锁互斥，共享锁之间不互斥，每次调用都重新加锁
+    fn lock(&self, dir: &Path, name: &str, exclusive: bool) -> Result<LockGuard> {
+        let p = dir.join(name);
+        let mut st = self.0.lock().unwrap();
+        let generation = st.generation;
+        match st.locks.get_mut(&p) {
+            Some((held, n)) if !exclusive && !*held => *n += 1,
+            Some(_) => return Err(locked(&p)),
+            None => {
+                st.locks.insert(p.clone(), (exclusive, 1));
+            }
+        }
+        Ok(Arc::new(MemLock {
+            fs: self.clone(),
+            path: p,
+            generation,
+        }))
+    }
+}
+
+// drop 时释放 MemStorage 中的锁，重启之前拿到的锁在重启时已经释放
+struct MemLock {
+    fs: MemStorage,
+    path: PathBuf,
+    generation: u64,
+}
+
+impl Drop for MemLock {
+    fn drop(&mut self) {
+        let mut st = self.fs.0.lock().unwrap();
+        if st.generation != self.generation {
+            return;
+        }
+        if let Some((_, n)) = st.locks.get_mut(&self.path) {
+            *n -= 1;
+            if *n == 0 {
+                st.locks.remove(&self.path);
+            }
+        }
+    }
+}
+
+struct MemFile {
+    fs: MemStorage,
+    inode: Arc<Mutex<Inode>>,
+    generation: u64,
+    writable: bool,
+}
+
+impl Write for MemFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.writable {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "file is opened read-only",
+            ));
+        }
+        let mut st = self.fs.0.lock().unwrap();
+        let mut n = buf.len();
+        if st.faults.max_write > 0 {
+            n = n.min(st.faults.max_write);
+        }
+        if let Some(capacity) = st.faults.capacity {
+            n = n.min(capacity.saturating_sub(st.used()) as usize);
+        }
+        let torn = st.crashing();
+        if let Err(e) = st.tick(self.generation) {
+            if torn {
+                self.inode
+                    .lock()
+                    .unwrap()
+                    .data
+                    .extend_from_slice(&buf[..n / 2]);
+            }
+            return Err(e);
+        }
+        if n == 0 && !buf.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::StorageFull,
+                "no space left on device",
+            ));
+        }
+        self.inode.lock().unwrap().data.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl StorageFile for MemFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        self.fs.0.lock().unwrap().check(self.generation)?;
+        let inode = self.inode.lock().unwrap();
+        let start = (offset as usize).min(inode.data.len());
+        let n = buf.len().min(inode.data.len() - start);
+        buf[..n].copy_from_slice(&inode.data[start..start + n]);
+        Ok(n)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        self.fs.0.lock().unwrap().check(self.generation)?;
+        Ok(self.inode.lock().unwrap().data.len() as u64)
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        self.fs.0.lock().unwrap().tick(self.generation)?;
+        let mut inode = self.inode.lock().unwrap();
+        inode.data.resize(len as usize, 0);
+        inode.synced = inode.synced.min(len as usize);
+        Ok(())
+    }
+
+    fn sync_all(&self) -> io::Result<()> {
+        let mut st = self.fs.0.lock().unwrap();
+        st.tick(self.generation)?;
+        if st.faults.failed_syncs > 0 {
+            st.faults.failed_syncs -= 1;
+            return Err(io::Error::other("simulated fsync failure"));
+        }
+        let mut inode = self.inode.lock().unwrap();
+        inode.synced = inode.data.len();
+        Ok(())
+    }
+}
@@ -0,0 +1,223 @@
+// 写入事件的广播
+//
+// 引擎在写入的临界区内把每个 set/rm 发布到 Watch，事件的序号和写入的顺序一致
+// Watch 在内存中保留最近 WATCH_HISTORY 个事件，总大小不超过 WATCH_HISTORY_BYTES，订阅者按序号读取，不需要为每个订阅者缓存
+// 订阅者断开之后带着 run_id 和最后看到的序号重新订阅，序号之后的事件还在历史中时从这里继续
+// 进程重启之后 run_id 变化，或者断开太久事件已经被淘汰时，只能从当前位置开始，订阅者需要重新加载数据
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+use serde::{Deserialize, Serialize};
+
+use crate::Result;
+
+pub const WATCH_HISTORY: usize = 10000;
+// 事件中带着完整的 value，只限制条数时大 value 会占用很多内存
+pub const WATCH_HISTORY_BYTES: usize = 16 << 20;
+
+// 订阅者读得太慢，需要的事件已经被淘汰
+pub const WATCH_FELL_BEHIND: &str = "Watcher fell behind the change history";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    pub seq: u64,
+    pub key: String,
+    // None 表示删除
+    pub value: Option<String>,
+}
+
+// WATCH 连接上 server 发送的消息，每行一个
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum WatchMessage {
+    // 订阅开始，resumed 为 false 时之前的事件可能有遗漏
+    Watching {
+        run_id: String,
+        seq: u64,
+        resumed: bool,
+    },
+    Event(Event),
+    // 没有事件时定期发送当前序号，客户端重连时可以从这里继续
+    Ping(u64),
+}
+
+struct History {
+    seq: u64,
+    events: VecDeque<Event>,
+    // events 中 key 和 value 的总字节数
+    bytes: usize,
+    capacity: usize,
+    max_bytes: usize,
+}
+
+impl History {
+    // 历史中最早的事件的序号
+    fn first(&self) -> u64 {
+        self.seq + 1 - self.events.len() as u64
+    }
+}
+
+fn size(e: &Event) -> usize {
+    e.key.len() + e.value.as_ref().map_or(0, String::len)
+}
+
+pub struct Watch {
+    run_id: String,
+    history: Mutex<History>,
+    cond: Condvar,
+}
+
+impl Default for Watch {
+    fn default() -> Self {
+        Self::new(WATCH_HISTORY)
+    }
+}
+
+impl Watch {
+    pub fn new(capacity: usize) -> Self {
+        Self::with_max_bytes(capacity, WATCH_HISTORY_BYTES)
+    }
+
+    // 超过任意一个限制时淘汰最早的事件，最新的一个事件总是保留
+    pub fn with_max_bytes(capacity: usize, max_bytes: usize) -> Self {
+        let mut id = [0; 16];
+        OsRng.fill_bytes(&mut id);
+        Watch {
+            run_id: id.iter().map(|b| format!("{:02x}", b)).collect(),
+            history: Mutex::new(History {
+                seq: 0,
+                events: VecDeque::new(),
+                bytes: 0,
+                capacity: capacity.max(1),
+                max_bytes,
+            }),
+            cond: Condvar::new(),
+        }
+    }
+
+    pub fn run_id(&self) -> &str {
+        &self.run_id
+    }
+
+    // 最后一个事件的序号
+    pub fn seq(&self) -> u64 {
+        self.history.lock().unwrap().seq
+    }
+
+    // 引擎在写入之前拿到 Publisher，写入成功之后发布，释放时通知订阅者
+    // 持有 Publisher 期间其他写入不能发布，保证序号和写入的顺序一致
+    pub fn publisher(&self) -> Publisher<'_> {
+        Publisher {
+            watch: self,
+            history: self.history.lock().unwrap(),
+        }
+    }
+
+    // key 以 * 结尾时订阅这个前缀，否则只订阅这个 key
+    // resume 是之前订阅时的 run_id 和最后看到的序号
+    pub fn subscribe(self: &Arc<Self>, key: &str, resume: Option<(&str, u64)>) -> Watcher {
+        let h = self.history.lock().unwrap();
+        let (next, resumed) = match resume {
+            Some((run_id, seq))
+                if run_id == self.run_id && seq + 1 >= h.first() && seq <= h.seq =>
+            {
+                (seq + 1, true)
+            }
+            _ => (h.seq + 1, false),
+        };
+        let (key, prefix) = match key.strip_suffix('*') {
+            Some(p) => (p.to_owned(), true),
+            None => (key.to_owned(), false),
+        };
+        Watcher {
+            watch: self.clone(),
+            key,
+            prefix,
+            next,
+            resumed,
+        }
+    }
+}
+
+pub struct Publisher<'a> {
+    watch: &'a Watch,
+    history: MutexGuard<'a, History>,
+}
+
+impl Publisher<'_> {
+    pub fn publish(&mut self, key: String, value: Option<String>) {
+        let h = &mut self.history;
+        h.seq += 1;
+        let seq = h.seq;
+        let e = Event { seq, key, value };
+        h.bytes += size(&e);
+        h.events.push_back(e);
+        while h.events.len() > h.capacity || (h.bytes > h.max_bytes && h.events.len() > 1) {
+            let e = h.events.pop_front().unwrap();
+            h.bytes -= size(&e);
+        }
+    }
+}
+
+impl Drop for Publisher<'_> {
+    fn drop(&mut self) {
+        self.watch.cond.notify_all();
+    }
+}
+
+pub struct Watcher {
+    watch: Arc<Watch>,
+    key: String,
+    prefix: bool,
+    next: u64,
+    resumed: bool,
+}
+
+impl Watcher {
+    pub fn run_id(&self) -> &str {
+        &self.watch.run_id
+    }
+
+    // 已经看过的最后一个序号，包括不匹配被跳过的事件
+    pub fn seq(&self) -> u64 {
+        self.next - 1
+    }
+
+    // 是否从 resume 指定的位置继续，没有遗漏事件
+    pub fn resumed(&self) -> bool {
+        self.resumed
+    }
+
+    fn matches(&self, key: &str) -> bool {
+        match self.prefix {
+            true => key.starts_with(&self.key),
+            false => key == self.key,
+        }
+    }
+
+    // 等待下一个匹配的事件，超时返回 None
+    pub fn next(&mut self, timeout: Duration) -> Result<Option<Event>> {
+        let deadline = Instant::now() + timeout;
+        let mut h = self.watch.history.lock().unwrap();
+        loop {
+            if self.next < h.first() {
+                return Err(WATCH_FELL_BEHIND.into());
+            }
+            while self.next <= h.seq {
+                let e = &h.events[(self.next - h.first()) as usize];
+                self.next += 1;
+                if self.matches(&e.key) {
+                    return Ok(Some(e.clone()));
+                }
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            h = self.watch.cond.wait_timeout(h, deadline - now).unwrap().0;
+        }
+    }
+}
@@ -0,0 +1,552 @@
+// HTTP/JSON 网关，和 kvs 协议的 server 共享同一个引擎
+//
+// GET    /keys/{key}        读取，不存在返回 404
+// PUT    /keys/{key}        写入，body 是 {"value": "..."}
+//                           带 "expected" 时是 CAS：当前值不等于 expected 返回 409，expected 为 null 表示 key 必须不存在
+// DELETE /keys/{key}        删除，不存在返回 404
+// GET    /keys?prefix=p     按顺序列出以 p 开头的 key
+// POST   /batch             在一个事务中执行多个操作，全部成功或者全部失败
+// GET    /health            健康检查
+//
+// 开启认证时请求需要带 Authorization: Bearer <token> 或者 Basic 认证，未认证返回 401，没有权限返回 403
+// 密码和 token 在请求里是明文，所以开启认证时必须同时配置 TLS，否则 run 返回错误
+//
+// 只实现了 HTTP/1.1 中需要的部分：支持 keep-alive，body 必须带 Content-Length
+// 配置了 TLS 时和 kvs 协议的 server 使用同一个 rustls 配置；两个请求之间空闲超过 idle_timeout 的连接被关闭
+// 请求行或者 header 超过 MAX_LINE，或者 header 超过 MAX_HEADERS 个时返回 431 并关闭连接
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener},
+    sync::Arc,
+    time::Duration,
+};
+
+use rustls::ServerConfig;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use slog::{debug, error, info, o, Logger};
+
+use crate::{
+    auth::{AuthError, Authenticator, Perm, Session},
+    logging::next_conn_id,
+    net::{is_timeout, Stream, TLS_HANDSHAKE_TIMEOUT},
+    server::IDLE_TIMEOUT,
+    thread_pool::ThreadPool,
+    KvsEngine, Result, KEY_NOT_FOUND, TXN_CONFLICT,
+};
+
+// 请求 body 的上限
+const MAX_BODY: usize = 16 << 20;
+// 请求行和每个 header 的长度上限，以及 header 的个数上限，超过时返回 431
+const MAX_LINE: u64 = 8 << 10;
+const MAX_HEADERS: usize = 100;
+pub const HEADERS_TOO_LARGE: &str = "request header fields too large";
+
+pub struct HttpGateway<P: ThreadPool> {
+    engine: Arc<dyn KvsEngine + Sync>,
+    pool: P,
+    logger: Logger,
+    auth: Option<Arc<Authenticator>>,
+    tls: Option<Arc<ServerConfig>>,
+    idle_timeout: Option<Duration>,
+}
+
+impl<P: ThreadPool> HttpGateway<P> {
+    pub fn new(engine: Arc<dyn KvsEngine + Sync>, pool: P, logger: Logger) -> Self {
+        HttpGateway {
+            engine,
+            pool,
+            logger,
+            auth: None,
+            tls: None,
+            idle_timeout: Some(IDLE_TIMEOUT),
+        }
+    }
+
+    // 每个请求都需要带 Authorization 头，见 handle，需要同时 with_tls
+    pub fn with_auth(mut self, auth: Option<Arc<Authenticator>>) -> Self {
+        self.auth = auth;
+        self
+    }
+
+    pub fn with_tls(mut self, tls: Option<Arc<ServerConfig>>) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    // None 时不关闭空闲的连接
+    pub fn with_idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    pub fn run(&self, addr: SocketAddr) -> Result<()> {
+        if self.auth.is_some() && self.tls.is_none() {
+            return Err("authentication on the http gateway requires TLS".into());
+        }
+        let listener = TcpListener::bind(addr)?;
+        info!(self.logger, "http gateway is started"; "addr" => %addr);
+        for income in listener.incoming() {
+            match income {
+                Ok(stream) => {
+                    let engine = self.engine.clone();
+                    let logger = self.logger.new(o!("conn" => next_conn_id()));
+                    let auth = self.auth.clone();
+                    let tls = self.tls.clone();
+                    let idle = self.idle_timeout;
+                    let scope = logger.clone();
+                    slog_scope::scope(&scope, || {
+                        self.pool.spawn(move || {
+                            let r = accept(Stream::Tcp(stream), tls, idle)
+                                .and_then(|s| serve(engine.as_ref(), auth, s, &logger));
+                            if let Err(e) = r {
+                                error!(logger, "http connection error: {}", e);
+                            }
+                        })
+                    });
+                }
+                Err(e) => error!(self.logger, "accept error: {}", e),
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct Request {
+    pub method: String,
+    // 已经解码的路径
+    pub path: String,
+    pub query: HashMap<String, String>,
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+pub struct Response {
+    pub status: u16,
+    pub body: Value,
+}
+
+impl Response {
+    fn new(status: u16, body: Value) -> Self {
+        Response { status, body }
+    }
+
+    fn error(status: u16, msg: &str) -> Self {
+        Response::new(status, json!({ "error": msg }))
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        431 => "Request Header Fields Too Large",
+        _ => "Internal Server Error",
+    }
+}
+
+// TLS 握手和 kvs 协议的 server 一样在线程池中进行，握手也不能超过空闲超时
+fn accept(
+    stream: Stream,
+    tls: Option<Arc<ServerConfig>>,
+    idle: Option<Duration>,
+) -> Result<Stream> {
+    let stream = match tls {
+        Some(config) => {
+            let timeout = idle.map_or(TLS_HANDSHAKE_TIMEOUT, |t| t.min(TLS_HANDSHAKE_TIMEOUT));
+            stream.accept_tls(config, timeout)?
+        }
+        None => stream,
+    };
+    stream.set_read_timeout(idle)?;
+    Ok(stream)
+}
+
+fn serve(
+    engine: &(dyn KvsEngine + Sync),
+    auth: Option<Arc<Authenticator>>,
+    stream: Stream,
+    logger: &Logger,
+) -> Result<()> {
+    let peer = stream.peer_addr();
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    let mut req_id = 0u64;
+    loop {
+        req_id += 1;
+        let logger = logger.new(o!("req" => req_id));
+        let req = match read_request(&mut reader) {
+            Ok(Some(r)) => r,
+            Ok(None) => return Ok(()),
+            Err(e) if is_timeout(e.as_ref()) => {
+                debug!(logger, "idle connection closed");
+                return Ok(());
+            }
+            Err(e) => {
+                let e = e.to_string();
+                let status = if e == HEADERS_TOO_LARGE { 431 } else { 400 };
+                write_response(&mut writer, &Response::error(status, &e), true)?;
+                return Ok(());
+            }
+        };
+        debug!(logger, "http request"; "method" => &req.method, "path" => &req.path);
+        let close = req
+            .headers
+            .get("connection")
+            .is_some_and(|c| c.eq_ignore_ascii_case("close"));
+        // HTTP 没有连接级别的状态，每个请求单独认证
+        let mut session = Session::new(auth.clone(), peer.clone());
+        let resp = slog_scope::scope(&logger, || handle(engine, &mut session, &req));
+        write_response(&mut writer, &resp, close)?;
+        if close {
+            return Ok(());
+        }
+    }
+}
+
+// 连接在两个请求之间关闭时返回 None
+pub fn read_request<R: BufRead>(r: &mut R) -> Result<Option<Request>> {
+    let mut line = String::new();
+    if read_line(r, &mut line)? == 0 {
+        return Ok(None);
+    }
+    let mut parts = line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(m), Some(t), Some(v)) if v.starts_with("HTTP/1.") => (m.to_owned(), t.to_owned()),
+        _ => return Err(format!("invalid request line {:?}", line.trim_end()).into()),
+    };
+    let mut headers = HashMap::new();
+    for i in 0.. {
+        let mut h = String::new();
+        if read_line(r, &mut h)? == 0 {
+            return Err("connection closed in the middle of headers".into());
+        }
+        let h = h.trim_end();
+        if h.is_empty() {
+            break;
+        }
+        // 同名的 header 只保留最后一个，按行数计算
+        if i >= MAX_HEADERS {
+            return Err(HEADERS_TOO_LARGE.into());
+        }
+        let (k, v) = h.split_once(':').ok_or("invalid header")?;
+        headers.insert(k.trim().to_ascii_lowercase(), v.trim().to_owned());
+    }
+    if headers.contains_key("transfer-encoding") {
+        return Err("chunked request body is not supported, use Content-Length".into());
+    }
+    let len: usize = match headers.get("content-length") {
+        Some(l) => l.parse()?,
+        None => 0,
+    };
+    if len > MAX_BODY {
+        return Err("request body is too large".into());
+    }
+    let mut body = vec![0; len];
+    r.read_exact(&mut body)
+        .map_err(|_| "connection closed in the middle of body")?;
+    let (path, query) = match target.split_once('?') {
+        Some((p, q)) => (p, q),
+        None => (target.as_str(), ""),
+    };
+    let query = query
+        .split('&')
+        .filter(|s| !s.is_empty())
+        .map(|kv| {
+            let (k, v) = kv.split_once('=').unwrap_or((kv, ""));
+            Ok((percent_decode(k, true)?, percent_decode(v, true)?))
+        })
+        .collect::<Result<_>>()?;
+    Ok(Some(Request {
+        method,
+        path: percent_decode(path, false)?,
+        query,
+        headers,
+        body,
+    }))
+}
+
+// 最多读 MAX_LINE 个字节，客户端不能用一个没有换行的行占满内存
+fn read_line<R: BufRead>(r: &mut R, line: &mut String) -> Result<usize> {
+    let n = (&mut *r).take(MAX_LINE).read_line(line)?;
+    if n as u64 == MAX_LINE && !line.ends_with('\n') {
+        return Err(HEADERS_TOO_LARGE.into());
+    }
+    Ok(n)
+}
+
+fn write_response<W: Write>(w: &mut W, resp: &Response, close: bool) -> Result<()> {
+    let body = if resp.status == 204 {
+        vec![]
+    } else {
+        let mut b = serde_json::to_vec(&resp.body)?;
+        b.push(b'\n');
+        b
+    };
+    let mut head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Length: {}\r\n",
+        resp.status,
+        reason(resp.status),
+        body.len()
+    );
+    if !body.is_empty() {
+        head += "Content-Type: application/json\r\n";
+    }
+    if close {
+        head += "Connection: close\r\n";
+    }
+    head += "\r\n";
+    w.write_all(head.as_bytes())?;
+    w.write_all(&body)?;
+    w.flush()?;
+    Ok(())
+}
+
+// 解码 %XX，query 中的 + 还需要解码成空格
+fn percent_decode(s: &str, plus_as_space: bool) -> Result<String> {
+    let b = s.as_bytes();
+    let mut out = Vec::with_capacity(b.len());
+    let mut i = 0;
+    while i < b.len() {
+        match b[i] {
+            b'%' if i + 2 < b.len() && b[i + 1..i + 3].iter().all(u8::is_ascii_hexdigit) => {
+                out.push(u8::from_str_radix(
+                    std::str::from_utf8(&b[i + 1..i + 3])?,
+                    16,
+                )?);
+                i += 3;
+            }
+            b'%' => return Err("invalid percent encoding".into()),
+            b'+' if plus_as_space => {
+                out.push(b' ');
+                i += 1;
+            }
+            c => {
+                out.push(c);
+                i += 1;
+            }
+        }
+    }
+    Ok(String::from_utf8(out)?)
+}
+
+#[derive(Deserialize)]
+struct PutBody {
+    value: String,
+    // 字段不存在表示普通写入，null 表示 key 必须不存在
+    #[serde(default, deserialize_with = "some")]
+    expected: Option<Option<String>>,
+}
+
+fn some<'de, D: serde::Deserializer<'de>>(
+    d: D,
+) -> std::result::Result<Option<Option<String>>, D::Error> {
+    Option::<String>::deserialize(d).map(Some)
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum BatchOp {
+    Get {
+        key: String,
+    },
+    Put {
+        key: String,
+        value: String,
+        #[serde(default, deserialize_with = "some")]
+        expected: Option<Option<String>>,
+    },
+    Delete {
+        key: String,
+    },
+}
+
+#[derive(Deserialize)]
+struct BatchBody {
+    ops: Vec<BatchOp>,
+}
+
+#[derive(Serialize)]
+struct OpResult {
+    key: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<Option<String>>,
+}
+
+// 认证失败返回 401，没有权限返回 403
+pub fn handle(engine: &(dyn KvsEngine + Sync), session: &mut Session, req: &Request) -> Response {
+    let r = match req.headers.get("authorization") {
+        Some(h) => login(session, h).and_then(|_| route(engine, session, req)),
+        None => route(engine, session, req),
+    };
+    r.unwrap_or_else(|e| match e.downcast_ref::<AuthError>() {
+        Some(AuthError::NoPerm { .. }) => Response::error(403, &e.to_string()),
+        Some(_) => Response::error(401, &e.to_string()),
+        None if e.to_string() == TXN_CONFLICT => Response::error(409, TXN_CONFLICT),
+        None => Response::error(500, &e.to_string()),
+    })
+}
+
+// 支持 "Bearer <token>" 和 "Basic base64(user:password)"
+fn login(session: &mut Session, header: &str) -> Result<()> {
+    match header.split_once(' ') {
+        Some(("Bearer", token)) => session.login(None, token.trim())?,
+        Some(("Basic", cred)) => {
+            let cred = String::from_utf8(base64_decode(cred.trim()).ok_or("invalid basic auth")?)?;
+            let (user, password) = cred.split_once(':').ok_or("invalid basic auth")?;
+            session.login(Some(user), password)?
+        }
+        _ => return Err("unsupported authorization scheme".into()),
+    }
+    Ok(())
+}
+
+fn base64_decode(s: &str) -> Option<Vec<u8>> {
+    const TABLE: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = vec![];
+    let (mut buf, mut bits) = (0u32, 0);
+    for c in s.bytes().filter(|c| *c != b'=') {
+        buf = buf << 6 | TABLE.iter().position(|t| *t == c)? as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buf >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+fn route(engine: &(dyn KvsEngine + Sync), session: &Session, req: &Request) -> Result<Response> {
+    match (req.method.as_str(), req.path.as_str()) {
+        ("GET", "/health") => Ok(Response::new(200, json!({ "status": "ok" }))),
+        ("GET", "/keys") => {
+            session.check_auth()?;
+            let prefix = req.query.get("prefix").map(String::as_str).unwrap_or("");
+            let keys: Vec<String> = engine
+                .keys(prefix)?
+                .into_iter()
+                .filter(|k| session.can(Perm::Read, k))
+                .collect();
+            Ok(Response::new(200, json!({ "keys": keys })))
+        }
+        ("POST", "/batch") => batch(engine, session, &req.body),
+        (m, p) if p.starts_with("/keys/") && p.len() > "/keys/".len() => {
+            let key = p["/keys/".len()..].to_owned();
+            let perm = if m == "GET" { Perm::Read } else { Perm::Write };
+            session.check(perm, &key)?;
+            match m {
+                "GET" => engine.get(key.clone()).map(|v| match v {
+                    Some(v) => Response::new(200, json!({ "key": key, "value": v })),
+                    None => Response::error(404, KEY_NOT_FOUND),
+                }),
+                "PUT" => put(engine, key, &req.body),
+                "DELETE" => match engine.remove(key) {
+                    Ok(()) => Ok(Response::new(204, Value::Null)),
+                    Err(e) if e.to_string() == KEY_NOT_FOUND => {
+                        Ok(Response::error(404, KEY_NOT_FOUND))
+                    }
+                    Err(e) => Err(e),
+                },
+                _ => Ok(Response::error(405, "method not allowed")),
+            }
+        }
+        (_, "/health") | (_, "/keys") | (_, "/batch") => {
+            Ok(Response::error(405, "method not allowed"))
+        }
+        _ => Ok(Response::error(404, "not found")),
+    }
+}
+
+fn put(engine: &(dyn KvsEngine + Sync), key: String, body: &[u8]) -> Result<Response> {
+    let body: PutBody = match serde_json::from_slice(body) {
+        Ok(b) => b,
+        Err(e) => return Ok(Response::error(400, &e.to_string())),
+    };
+    match body.expected {
+        None => {
+            engine.set(key.clone(), body.value)?;
+        }
+        Some(expected) => {
+            let mut txn = engine.begin()?;
+            let current = txn.get(key.clone())?;
+            if current != expected {
+                return Ok(Response::new(
+                    409,
+                    json!({ "error": "CAS conflict", "current": current }),
+                ));
+            }
+            txn.set(key.clone(), body.value)?;
+            txn.commit()?;
+        }
+    }
+    Ok(Response::new(200, json!({ "key": key })))
+}
+
+fn batch(engine: &(dyn KvsEngine + Sync), session: &Session, body: &[u8]) -> Result<Response> {
+    let body: BatchBody = match serde_json::from_slice(body) {
+        Ok(b) => b,
+        Err(e) => return Ok(Response::error(400, &e.to_string())),
+    };
+    // 执行之前检查所有操作的权限
+    session.check_auth()?;
+    for op in &body.ops {
+        match op {
+            BatchOp::Get { key } => session.check(Perm::Read, key)?,
+            BatchOp::Put { key, .. } | BatchOp::Delete { key } => {
+                session.check(Perm::Write, key)?
+            }
+        }
+    }
+    let mut txn = engine.begin()?;
+    let mut results = vec![];
+    for op in body.ops {
+        match op {
+            BatchOp::Get { key } => {
+                let value = txn.get(key.clone())?;
+                results.push(OpResult {
+                    key,
+                    value: Some(value),
+                });
+            }
+            BatchOp::Put {
+                key,
+                value,
+                expected,
+            } => {
+                if let Some(expected) = expected {
+                    let current = txn.get(key.clone())?;
+                    if current != expected {
+                        return Ok(Response::new(
+                            409,
+                            json!({ "error": "CAS conflict", "key": key, "current": current }),
+                        ));
+                    }
+                }
+                txn.set(key.clone(), value)?;
+                results.push(OpResult { key, value: None });
+            }
+            BatchOp::Delete { key } => {
+                if let Err(e) = txn.remove(key.clone()) {
+                    if e.to_string() == KEY_NOT_FOUND {
+                        return Ok(Response::new(
+                            404,
+                            json!({ "error": KEY_NOT_FOUND, "key": key }),
+                        ));
+                    }
+                    return Err(e);
+                }
+                results.push(OpResult { key, value: None });
+            }
+        }
+    }
+    txn.commit()?;
+    Ok(Response::new(200, json!({ "results": results })))
+}
@@ -1,7 +1,25 @@
+pub mod auth;
+pub mod bench;
+pub mod compaction;
 mod engines;
+pub mod http;
+pub mod logging;
+pub mod metrics;
+pub mod net;
+pub mod proxy;
+pub mod pubsub;
+pub mod raft;
+pub mod replication;
+pub mod resp;
+mod server;
+pub mod sim;
+pub mod slowlog;
 pub mod thread_pool;
+pub mod tls;
 
 pub use self::engines::*;
+pub use self::http::HttpGateway;
+pub use self::server::{KvsServer, Protocol, ServerOptions, IDLE_TIMEOUT};
 
 use std::error::Error;
 // std::result::Result 是 preinclude 到项目中的，为了防止歧义显示制定了 package
@@ -0,0 +1,51 @@
+// 日志的级别、格式和连接、请求的 id
+//
+// server 给每个连接分配一个 conn id，连接上的每个请求有一个递增的 req id，都作为 key 写进日志
+// 引擎和线程池没有 Logger 参数，通过 slog_scope 取得当前线程上的 Logger：
+// server 在调用引擎之前用 slog_scope::scope 设置请求的 Logger，线程池执行 job 时使用提交 job 时的 Logger，
+// 所以一个慢请求从 accept、线程池到引擎中的 compaction 都可以用 conn 和 req 找到
+use std::{
+    str::FromStr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use slog::Logger;
+use sloggers::{
+    terminal::{Destination, TerminalLoggerBuilder},
+    types::{Format, Severity},
+    Build,
+};
+
+use crate::Result;
+
+static NEXT_CONN_ID: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    // 每条日志一行 json，key 和连接、请求的 id 都是字段
+    Json,
+}
+
+// 日志写到 stderr
+pub fn build_logger(level: Severity, format: LogFormat) -> Result<Logger> {
+    let mut builder = TerminalLoggerBuilder::new();
+    builder.level(level);
+    builder.destination(Destination::Stderr);
+    builder.format(match format {
+        LogFormat::Text => Format::Full,
+        LogFormat::Json => Format::Json,
+    });
+    Ok(builder.build()?)
+}
+
+// trace、debug、info、warning、error 或者 critical
+pub fn parse_level(s: &str) -> std::result::Result<Severity, String> {
+    Severity::from_str(s).map_err(|_| format!("invalid log level '{}'", s))
+}
+
+// 进程内所有监听的端口共用，kvs 协议、RESP 和 HTTP 的连接 id 不会重复
+pub fn next_conn_id() -> u64 {
+    NEXT_CONN_ID.fetch_add(1, Ordering::Relaxed)
+}
@@ -0,0 +1,330 @@
+// server 和引擎的指标，以 Prometheus 的文本格式在 --metrics-addr 上提供
+//
+// GET /metrics    所有指标
+//
+// 请求数和延迟按 Command 类型统计，WATCH、SUBSCRIBE 和 replica 的 Sync 这类长连接命令只计数不统计延迟
+// KvStore 的指标在每次抓取时从 KvStore::stats 读取
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io::{BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use slog::{error, info, Logger};
+
+use crate::{
+    http::{read_request, HEADERS_TOO_LARGE},
+    KvStore, Result,
+};
+
+// 请求延迟的分桶上限，单位秒
+const LATENCY_BUCKETS: [f64; 14] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+];
+
+#[derive(Default)]
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dec(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    // 加一，返回的 guard 被 drop 时减一，job panic 时也不会漏掉
+    pub fn track(&self) -> GaugeGuard<'_> {
+        self.inc();
+        GaugeGuard(self)
+    }
+}
+
+pub struct GaugeGuard<'a>(&'a Gauge);
+
+impl Drop for GaugeGuard<'_> {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+pub struct Histogram {
+    // 每个桶单独计数，最后一个是 +Inf，输出时再累加
+    buckets: Vec<AtomicU64>,
+    sum_nanos: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram {
+            buckets: (0..=LATENCY_BUCKETS.len())
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+            sum_nanos: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Histogram {
+    pub fn observe(&self, d: Duration) {
+        let secs = d.as_secs_f64();
+        let i = LATENCY_BUCKETS
+            .iter()
+            .position(|b| secs <= *b)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos
+            .fetch_add(d.as_nanos() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let mut acc = 0;
+        for (i, b) in self.buckets.iter().enumerate() {
+            acc += b.load(Ordering::Relaxed);
+            let le = LATENCY_BUCKETS
+                .get(i)
+                .map_or_else(|| "+Inf".to_owned(), |b| b.to_string());
+            let _ = writeln!(out, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, le, acc);
+        }
+        let sum = self.sum_nanos.load(Ordering::Relaxed) as f64 / 1e9;
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, sum);
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, count);
+    }
+}
+
+#[derive(Default)]
+struct CommandMetrics {
+    requests: AtomicU64,
+    latency: Histogram,
+}
+
+pub struct Metrics {
+    commands: RwLock<BTreeMap<&'static str, Arc<CommandMetrics>>>,
+    // 正在处理的连接，在线程池队列中等待的连接不算
+    pub connections: Gauge,
+    // 已经提交给线程池还没有开始执行的 job，和正在执行 job 的线程数
+    pub pool_queued: Gauge,
+    pub pool_busy: Gauge,
+    store: Option<KvStore>,
+    started: Instant,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics {
+            commands: RwLock::default(),
+            connections: Gauge::default(),
+            pool_queued: Gauge::default(),
+            pool_busy: Gauge::default(),
+            store: None,
+            started: Instant::now(),
+        }
+    }
+}
+
+impl Metrics {
+    // 使用 kvs 引擎时输出它的 key 数量、可回收的空间和 compaction 的统计
+    pub fn with_store(mut self, store: KvStore) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    fn command(&self, name: &'static str) -> Arc<CommandMetrics> {
+        if let Some(m) = self.commands.read().unwrap().get(name) {
+            return m.clone();
+        }
+        let mut commands = self.commands.write().unwrap();
+        commands.entry(name).or_default().clone()
+    }
+
+    // 创建 Metrics 之后经过的时间，也就是 server 运行的时间
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    // 收到一条命令
+    pub fn request(&self, name: &'static str) {
+        self.command(name).requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // 命令从收到到回复的时间
+    pub fn observe(&self, name: &'static str, d: Duration) {
+        self.command(name).latency.observe(d);
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let commands = self.commands.read().unwrap();
+        header(
+            &mut out,
+            "kvs_requests_total",
+            "counter",
+            "Requests received, by command.",
+        );
+        for (name, m) in commands.iter() {
+            let n = m.requests.load(Ordering::Relaxed);
+            let _ = writeln!(out, "kvs_requests_total{{command=\"{}\"}} {}", name, n);
+        }
+        header(
+            &mut out,
+            "kvs_request_duration_seconds",
+            "histogram",
+            "Time from reading a request to sending its reply, by command.",
+        );
+        for (name, m) in commands.iter() {
+            let labels = format!("command=\"{}\"", name);
+            m.latency
+                .render(&mut out, "kvs_request_duration_seconds", &labels);
+        }
+        drop(commands);
+        let gauges = [
+            (
+                "kvs_connections",
+                "Open client connections.",
+                &self.connections,
+            ),
+            (
+                "kvs_pool_queued_jobs",
+                "Jobs waiting in the thread pool queue.",
+                &self.pool_queued,
+            ),
+            (
+                "kvs_pool_busy_workers",
+                "Thread pool workers running a job.",
+                &self.pool_busy,
+            ),
+        ];
+        for (name, help, g) in gauges {
+            header(&mut out, name, "gauge", help);
+            let _ = writeln!(out, "{} {}", name, g.get());
+        }
+        if let Some(store) = &self.store {
+            let stats = store.stats();
+            let values = [
+                (
+                    "kvs_store_live_keys",
+                    "Keys in the in-memory index.",
+                    stats.live_keys as u64,
+                ),
+                (
+                    "kvs_store_log_keys",
+                    "Records in the log file.",
+                    stats.log_keys as u64,
+                ),
+                (
+                    "kvs_store_stale_bytes",
+                    "Bytes of overwritten and removed records that compaction can reclaim.",
+                    stats.stale_bytes,
+                ),
+            ];
+            for (name, help, v) in values {
+                header(&mut out, name, "gauge", help);
+                let _ = writeln!(out, "{} {}", name, v);
+            }
+            let name = "kvs_store_compression_ratio";
+            header(
+                &mut out,
+                name,
+                "gauge",
+                "Record payload bytes before compression divided by bytes in the log.",
+            );
+            let _ = writeln!(out, "{} {}", name, stats.compression_ratio());
+            let name = "kvs_store_compaction_duration_seconds";
+            header(
+                &mut out,
+                name,
+                "summary",
+                "Compactions run and their duration.",
+            );
+            let secs = stats.compaction_time.as_secs_f64();
+            let _ = writeln!(out, "{}_sum {}", name, secs);
+            let _ = writeln!(out, "{}_count {}", name, stats.compactions);
+        }
+        out
+    }
+
+    // 每个连接一个线程，抓取的频率很低
+    pub fn run(self: &Arc<Self>, addr: SocketAddr, logger: &Logger) -> Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        info!(logger, "metrics endpoint is started"; "addr" => %addr);
+        for income in listener.incoming() {
+            match income {
+                Ok(stream) => {
+                    let metrics = self.clone();
+                    let logger = logger.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = metrics.serve(stream) {
+                            error!(logger, "metrics connection error: {}", e);
+                        }
+                    });
+                }
+                Err(e) => error!(logger, "accept error: {}", e),
+            }
+        }
+        Ok(())
+    }
+
+    fn serve(&self, stream: TcpStream) -> Result<()> {
+        let mut writer = stream.try_clone()?;
+        let mut reader = BufReader::new(stream);
+        loop {
+            let req = match read_request(&mut reader) {
+                Ok(Some(r)) => r,
+                Ok(None) => break,
+                Err(e) => {
+                    let status = if e.to_string() == HEADERS_TOO_LARGE {
+                        "431 Request Header Fields Too Large"
+                    } else {
+                        "400 Bad Request"
+                    };
+                    let head = format!(
+                        "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                        status
+                    );
+                    writer.write_all(head.as_bytes())?;
+                    writer.flush()?;
+                    break;
+                }
+            };
+            let (status, body) = match (req.method.as_str(), req.path.as_str()) {
+                ("GET", "/metrics") => ("200 OK", self.render()),
+                (_, "/metrics") => ("405 Method Not Allowed", String::new()),
+                _ => ("404 Not Found", String::new()),
+            };
+            let head = format!(
+                "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\r\n",
+                status,
+                body.len()
+            );
+            writer.write_all(head.as_bytes())?;
+            writer.write_all(body.as_bytes())?;
+            writer.flush()?;
+            let close = req.headers.get("connection");
+            if close.is_some_and(|c| c.eq_ignore_ascii_case("close")) {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
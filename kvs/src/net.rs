@@ -0,0 +1,318 @@
+// server 和 client 共用的地址和连接类型
+// --addr 可以是 IP:PORT，也可以是 unix:/path/to.sock，TCP 连接上可以再加一层 TLS
+// Sim 只在确定性模拟中使用，见 sim
+use std::{
+    fmt,
+    fs::{self, Permissions},
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    os::unix::{
+        fs::{FileTypeExt, PermissionsExt},
+        net::{UnixListener, UnixStream},
+    },
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use rustls::{
+    pki_types::ServerName, ClientConfig, ClientConnection, ServerConfig, ServerConnection,
+    StreamOwned,
+};
+
+use crate::{
+    sim::net::{self as simnet, SimListener, SimStream},
+    tls, Result,
+};
+
+const UNIX_PREFIX: &str = "unix:";
+// TLS 握手的时间上限，见 Stream::accept_tls
+pub const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const SIM_PREFIX: &str = "sim:";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Addr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+    // 模拟网络中的地址，不能从命令行解析
+    Sim(String),
+}
+
+impl FromStr for Addr {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.strip_prefix(UNIX_PREFIX) {
+            Some("") => Err("unix socket path is empty".to_owned()),
+            Some(p) => Ok(Addr::Unix(PathBuf::from(p))),
+            None => s
+                .parse()
+                .map(Addr::Tcp)
+                .map_err(|e| format!("{}, expected IP:PORT or unix:PATH", e)),
+        }
+    }
+}
+
+impl fmt::Display for Addr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Addr::Tcp(a) => write!(f, "{}", a),
+            Addr::Unix(p) => write!(f, "{}{}", UNIX_PREFIX, p.display()),
+            Addr::Sim(n) => write!(f, "{}{}", SIM_PREFIX, n),
+        }
+    }
+}
+
+impl From<SocketAddr> for Addr {
+    fn from(a: SocketAddr) -> Self {
+        Addr::Tcp(a)
+    }
+}
+
+// TLS 会话不能像 socket 一样复制，读写两端共享同一个会话
+// 读在等待数据的整个过程中持有锁，这期间另一个线程的写会被阻塞，
+// 所以调用者必须交替地读请求和写回复，不能一个线程阻塞在读上同时另一个线程写
+// WATCH 和 SUBSCRIBE 开始之后连接上只有写，复制和 raft 连接也是一问一答
+type Shared<T> = Arc<Mutex<StreamOwned<T, TcpStream>>>;
+
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+    TlsServer(Shared<ServerConnection>),
+    TlsClient(Shared<ClientConnection>),
+    Sim(SimStream),
+}
+
+impl Stream {
+    pub fn connect(addr: &Addr) -> io::Result<Stream> {
+        match addr {
+            Addr::Tcp(a) => TcpStream::connect(a).map(Stream::Tcp),
+            Addr::Unix(p) => UnixStream::connect(p).map(Stream::Unix),
+            Addr::Sim(n) => simnet::connect(n).map(Stream::Sim),
+        }
+    }
+
+    // server_name 为空时用 IP 地址验证 server 的证书
+    pub fn connect_tls(
+        addr: &Addr,
+        config: Arc<ClientConfig>,
+        server_name: Option<&str>,
+    ) -> Result<Stream> {
+        let a = match addr {
+            Addr::Tcp(a) => a,
+            _ => return Err("TLS is only supported on TCP addresses".into()),
+        };
+        Self::start_tls(TcpStream::connect(a)?, config, server_name)
+    }
+
+    // 在已经建立的 TCP 连接上完成客户端的 TLS 握手
+    pub fn start_tls(
+        mut tcp: TcpStream,
+        config: Arc<ClientConfig>,
+        server_name: Option<&str>,
+    ) -> Result<Stream> {
+        let name = match server_name {
+            Some(n) => ServerName::try_from(n.to_owned())?,
+            None => ServerName::from(tcp.peer_addr()?.ip()),
+        };
+        let mut conn = ClientConnection::new(config, name)?;
+        // 在这里完成握手，证书错误在连接时就能发现
+        while conn.is_handshaking() {
+            conn.complete_io(&mut tcp)?;
+        }
+        Ok(Stream::TlsClient(Arc::new(Mutex::new(StreamOwned::new(
+            conn, tcp,
+        )))))
+    }
+
+    // server 端在 accept 之后完成 TLS 握手
+    // 客户端连上之后什么都不发送时握手会一直等待，timeout 之后返回错误，不会一直占用线程
+    pub fn accept_tls(self, config: Arc<ServerConfig>, timeout: Duration) -> Result<Stream> {
+        let mut tcp = match self {
+            Stream::Tcp(s) => s,
+            _ => return Err("TLS is only supported on TCP connections".into()),
+        };
+        let mut conn = ServerConnection::new(config)?;
+        tcp.set_read_timeout(Some(timeout))?;
+        while conn.is_handshaking() {
+            conn.complete_io(&mut tcp)?;
+        }
+        tcp.set_read_timeout(None)?;
+        Ok(Stream::TlsServer(Arc::new(Mutex::new(StreamOwned::new(
+            conn, tcp,
+        )))))
+    }
+
+    // mTLS 时客户端证书的 CN
+    pub fn peer_identity(&self) -> Option<String> {
+        match self {
+            Stream::TlsServer(s) => {
+                let s = s.lock().unwrap();
+                s.conn
+                    .peer_certificates()
+                    .and_then(|c| c.first())
+                    .and_then(tls::common_name)
+            }
+            _ => None,
+        }
+    }
+
+    // 对端地址，用于日志和审计
+    pub fn peer_addr(&self) -> String {
+        let a = match self {
+            Stream::Tcp(s) => s.peer_addr(),
+            Stream::Unix(_) => return "unix".to_owned(),
+            Stream::Sim(_) => return "sim".to_owned(),
+            Stream::TlsServer(s) => s.lock().unwrap().sock.peer_addr(),
+            Stream::TlsClient(s) => s.lock().unwrap().sock.peer_addr(),
+        };
+        a.map(|a| a.to_string()).unwrap_or_default()
+    }
+
+    // 推送消息的连接上客户端长时间不读时，写入超时返回错误
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.set_write_timeout(timeout),
+            Stream::Unix(s) => s.set_write_timeout(timeout),
+            Stream::TlsServer(s) => s.lock().unwrap().sock.set_write_timeout(timeout),
+            Stream::TlsClient(s) => s.lock().unwrap().sock.set_write_timeout(timeout),
+            // 模拟网络的写入不会阻塞
+            Stream::Sim(_) => Ok(()),
+        }
+    }
+
+    // 等待请求的时间超过 timeout 时读取返回错误，见 is_timeout
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.set_read_timeout(timeout),
+            Stream::Unix(s) => s.set_read_timeout(timeout),
+            Stream::TlsServer(s) => s.lock().unwrap().sock.set_read_timeout(timeout),
+            Stream::TlsClient(s) => s.lock().unwrap().sock.set_read_timeout(timeout),
+            // 模拟网络中没有空闲的连接
+            Stream::Sim(_) => Ok(()),
+        }
+    }
+
+    // 读写分别在不同的对象上进行
+    pub fn try_clone(&self) -> io::Result<Stream> {
+        match self {
+            Stream::Tcp(s) => s.try_clone().map(Stream::Tcp),
+            Stream::Unix(s) => s.try_clone().map(Stream::Unix),
+            Stream::TlsServer(s) => Ok(Stream::TlsServer(s.clone())),
+            Stream::TlsClient(s) => Ok(Stream::TlsClient(s.clone())),
+            Stream::Sim(s) => s.try_clone().map(Stream::Sim),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(s) => s.read(buf),
+            Stream::Unix(s) => s.read(buf),
+            Stream::TlsServer(s) => eof_ok(s.lock().unwrap().read(buf)),
+            Stream::TlsClient(s) => eof_ok(s.lock().unwrap().read(buf)),
+            Stream::Sim(s) => s.read(buf),
+        }
+    }
+}
+
+// set_read_timeout 的超时，不同平台上的错误类型不同
+pub fn is_timeout(e: &(dyn std::error::Error + 'static)) -> bool {
+    match e.downcast_ref::<io::Error>() {
+        Some(e) => matches!(
+            e.kind(),
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+        ),
+        None => false,
+    }
+}
+
+// kvs-client 等客户端退出时不会发送 close_notify，按照正常关闭处理
+// 协议本身以行为单位，截断的请求在协议层就能发现
+fn eof_ok(r: io::Result<usize>) -> io::Result<usize> {
+    match r {
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(0),
+        r => r,
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(s) => s.write(buf),
+            Stream::Unix(s) => s.write(buf),
+            Stream::TlsServer(s) => s.lock().unwrap().write(buf),
+            Stream::TlsClient(s) => s.lock().unwrap().write(buf),
+            Stream::Sim(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.flush(),
+            Stream::Unix(s) => s.flush(),
+            Stream::TlsServer(s) => s.lock().unwrap().flush(),
+            Stream::TlsClient(s) => s.lock().unwrap().flush(),
+            Stream::Sim(s) => s.flush(),
+        }
+    }
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    // 退出时删除 socket 文件
+    Unix(UnixListener, PathBuf),
+    Sim(SimListener),
+}
+
+impl Listener {
+    // mode 是 unix socket 文件的权限，TCP 忽略
+    pub fn bind(addr: &Addr, mode: u32) -> Result<Listener> {
+        match addr {
+            Addr::Tcp(a) => Ok(Listener::Tcp(TcpListener::bind(a)?)),
+            Addr::Unix(p) => {
+                remove_stale_socket(p)?;
+                let l = UnixListener::bind(p)?;
+                fs::set_permissions(p, Permissions::from_mode(mode))?;
+                Ok(Listener::Unix(l, p.clone()))
+            }
+            Addr::Sim(n) => Ok(Listener::Sim(simnet::listen(n)?)),
+        }
+    }
+
+    pub fn accept(&self) -> io::Result<Stream> {
+        match self {
+            Listener::Tcp(l) => l.accept().map(|(s, _)| Stream::Tcp(s)),
+            Listener::Unix(l, _) => l.accept().map(|(s, _)| Stream::Unix(s)),
+            Listener::Sim(l) => l.accept().map(Stream::Sim),
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, p) = self {
+            let _ = fs::remove_file(p);
+        }
+    }
+}
+
+// 上一次运行没有正常退出时会留下 socket 文件，bind 会失败
+// 文件存在但是连接不上说明没有 server 在监听，可以直接删除
+fn remove_stale_socket(p: &PathBuf) -> Result<()> {
+    let meta = match fs::symlink_metadata(p) {
+        Ok(m) => m,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(Box::new(e)),
+    };
+    if !meta.file_type().is_socket() {
+        return Err(format!("{} exists and is not a socket", p.display()).into());
+    }
+    if UnixStream::connect(p).is_ok() {
+        return Err(format!("{} is in use by another server", p.display()).into());
+    }
+    fs::remove_file(p)?;
+    Ok(())
+}
@@ -0,0 +1,564 @@
+// 分片代理
+//
+// kvs-proxy 对客户端使用和 kvs-server 相同的协议，每个 key 通过一致性哈希环路由到一个后端 kvs-server
+// 每个后端在环上有 vnodes 个虚拟节点，增加后端时只有大约 1/N 的 key 需要迁移
+// KEYS 等多 key 的命令发给所有后端再合并结果，事务在 key 所属的后端上执行，涉及多个后端的事务提交时返回 CROSSSLOT 错误
+//
+// 增加后端时先把新后端加入环，之后的读写都按新环路由，再把属于新后端的 key 从原来的后端搬过去
+// 迁移期间新后端上没有的 key 再去旧后端读，写入和删除同时删除旧后端上的副本，
+// 每个 key 的迁移和客户端的读写通过分段锁互斥，不会用旧值覆盖新写入
+// 后端列表和进行中的迁移保存在 proxy.json 中，重启之后继续没完成的迁移
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard, RwLock},
+    thread,
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use slog::{info, Logger};
+
+use crate::{
+    net::{Addr, Stream},
+    Command, CompactionReport, EngineInfo, KvsEngine, KvsSnapshot, Result, Transaction, Watch,
+    KEY_NOT_FOUND,
+};
+
+pub const PROXY_STATE: &str = "proxy.json";
+
+// 迁移进行中时不能开始事务，客户端稍后重试
+pub const MIGRATING: &str = "TRYAGAIN Migration in progress";
+
+// 事务中的 key 不在同一个后端上，和 redis cluster 的错误一致
+pub const CROSS_BACKEND: &str = "CROSSSLOT Keys in transaction don't hash to the same backend";
+
+const STRIPES: usize = 64;
+
+fn hash(s: &str) -> u64 {
+    let d = Sha256::digest(s.as_bytes());
+    u64::from_be_bytes(d[..8].try_into().unwrap())
+}
+
+// 一致性哈希环，key 属于顺时针方向的第一个虚拟节点
+#[derive(Clone, Debug)]
+pub struct Ring {
+    vnodes: usize,
+    points: BTreeMap<u64, String>,
+}
+
+impl Ring {
+    pub fn new(vnodes: usize) -> Self {
+        Ring {
+            vnodes: vnodes.max(1),
+            points: BTreeMap::new(),
+        }
+    }
+
+    pub fn add(&mut self, node: &str) {
+        for i in 0..self.vnodes {
+            self.points
+                .insert(hash(&format!("{}#{}", node, i)), node.to_owned());
+        }
+    }
+
+    pub fn remove(&mut self, node: &str) {
+        self.points.retain(|_, n| n != node);
+    }
+
+    pub fn owner(&self, key: &str) -> Option<&str> {
+        let h = hash(key);
+        self.points
+            .range(h..)
+            .next()
+            .or_else(|| self.points.iter().next())
+            .map(|(_, n)| n.as_str())
+    }
+
+    pub fn nodes(&self) -> Vec<String> {
+        let mut nodes: Vec<String> = self.points.values().cloned().collect();
+        nodes.sort();
+        nodes.dedup();
+        nodes
+    }
+}
+
+struct Conn {
+    w: Stream,
+    r: BufReader<Stream>,
+}
+
+impl Conn {
+    fn call(&mut self, c: &Command) -> Result<String> {
+        let s = serde_json::to_string(c)?;
+        self.w.write_all((s + "\n").as_bytes())?;
+        let mut line = String::new();
+        if self.r.read_line(&mut line)? == 0 {
+            return Err("connection closed by backend".into());
+        }
+        Ok(line.trim_end().to_owned())
+    }
+}
+
+fn expect_success(reply: String) -> Result<()> {
+    match reply.as_str() {
+        "Success" => Ok(()),
+        _ => Err(reply.into()),
+    }
+}
+
+fn value(reply: String) -> Option<String> {
+    match reply.as_str() {
+        KEY_NOT_FOUND => None,
+        _ => Some(reply),
+    }
+}
+
+// 一个后端 kvs-server，连接用完之后放回池中复用
+pub struct Remote {
+    addr: Addr,
+    conns: Mutex<Vec<Conn>>,
+}
+
+impl Remote {
+    pub fn new(addr: Addr) -> Self {
+        Remote {
+            addr,
+            conns: Mutex::new(vec![]),
+        }
+    }
+
+    fn connect(&self) -> Result<Conn> {
+        let w = Stream::connect(&self.addr)
+            .map_err(|e| format!("connect backend {}: {}", self.addr, e))?;
+        let r = BufReader::new(w.try_clone()?);
+        Ok(Conn { w, r })
+    }
+
+    // 池中的连接可能已经被后端关闭，失败时换一个新连接重试一次
+    fn call(&self, c: &Command) -> Result<String> {
+        let idle = self.conns.lock().unwrap().pop();
+        let reply = match idle.map(|mut conn| (conn.call(c), conn)) {
+            Some((Ok(r), conn)) => (r, conn),
+            _ => {
+                let mut conn = self.connect()?;
+                (conn.call(c)?, conn)
+            }
+        };
+        self.conns.lock().unwrap().push(reply.1);
+        Ok(reply.0)
+    }
+
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        self.call(&Command::Get(key)).map(value)
+    }
+
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        self.call(&Command::Set(key, value))
+            .and_then(expect_success)
+    }
+
+    pub fn remove(&self, key: String) -> Result<()> {
+        self.call(&Command::Rm(key)).and_then(expect_success)
+    }
+
+    pub fn keys(&self, prefix: &str) -> Result<Vec<String>> {
+        let reply = self.call(&Command::Keys(prefix.to_owned()))?;
+        serde_json::from_str(&reply).map_err(|_| reply.into())
+    }
+
+    pub fn compact(&self) -> Result<CompactionReport> {
+        let reply = self.call(&Command::Compact)?;
+        serde_json::from_str(&reply).map_err(|_| reply.into())
+    }
+
+    // INFO 的回复中除了引擎的字段还有 server 的状态，这里只取引擎的部分
+    pub fn info(&self) -> Result<EngineInfo> {
+        let reply = self.call(&Command::Info)?;
+        serde_json::from_str(&reply).map_err(|_| reply.into())
+    }
+
+    // 事务独占一个连接，提交之后放回池中
+    fn begin(self: &Arc<Self>) -> Result<RemoteTransaction> {
+        let mut conn = self.connect()?;
+        expect_success(conn.call(&Command::Begin)?)?;
+        Ok(RemoteTransaction {
+            remote: self.clone(),
+            conn,
+        })
+    }
+}
+
+struct RemoteTransaction {
+    remote: Arc<Remote>,
+    conn: Conn,
+}
+
+impl RemoteTransaction {
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.conn.call(&Command::Get(key)).map(value)
+    }
+
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.conn
+            .call(&Command::Set(key, value))
+            .and_then(expect_success)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        self.conn.call(&Command::Rm(key)).and_then(expect_success)
+    }
+
+    fn commit(mut self) -> Result<()> {
+        expect_success(self.conn.call(&Command::Commit)?)?;
+        self.remote.conns.lock().unwrap().push(self.conn);
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct ProxyState {
+    backends: Vec<String>,
+    // 正在迁入数据的后端，已经在 backends 中
+    migrating: Option<String>,
+}
+
+struct Backends {
+    ring: Ring,
+    remotes: BTreeMap<String, Arc<Remote>>,
+    // 迁移期间加入新后端之前的环
+    old: Option<Ring>,
+}
+
+impl Backends {
+    fn remote(&self, ring: &Ring, key: &str) -> Result<Arc<Remote>> {
+        let owner = ring.owner(key).ok_or("no backend")?;
+        Ok(self.remotes[owner].clone())
+    }
+
+    // 迁移期间 key 原来所在的后端，和现在的后端相同时为 None
+    fn previous(&self, key: &str) -> Option<Arc<Remote>> {
+        let old = self.old.as_ref()?.owner(key)?;
+        (Some(old) != self.ring.owner(key)).then(|| self.remotes[old].clone())
+    }
+}
+
+pub struct Proxy {
+    backends: RwLock<Backends>,
+    stripes: Vec<Mutex<()>>,
+    // 保存 PROXY_STATE 的目录
+    dir: PathBuf,
+    logger: Logger,
+}
+
+impl Proxy {
+    // 目录中有 PROXY_STATE 时使用其中的后端列表，backends 中新的地址作为新后端加入并迁移数据
+    pub fn open(dir: &Path, backends: &[Addr], vnodes: usize, logger: Logger) -> Result<Proxy> {
+        let state: ProxyState = match fs::read(dir.join(PROXY_STATE)) {
+            Ok(b) => serde_json::from_slice(&b)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => ProxyState {
+                backends: backends.iter().map(|a| a.to_string()).collect(),
+                migrating: None,
+            },
+            Err(e) => return Err(Box::new(e)),
+        };
+        if state.backends.is_empty() {
+            return Err("at least one backend is required".into());
+        }
+        let mut ring = Ring::new(vnodes);
+        let mut remotes = BTreeMap::new();
+        for b in &state.backends {
+            ring.add(b);
+            remotes.insert(b.clone(), Arc::new(Remote::new(b.parse()?)));
+        }
+        let old = state.migrating.as_ref().map(|m| {
+            let mut old = ring.clone();
+            old.remove(m);
+            old
+        });
+        let proxy = Proxy {
+            backends: RwLock::new(Backends { ring, remotes, old }),
+            stripes: (0..STRIPES).map(|_| Mutex::new(())).collect(),
+            dir: dir.to_owned(),
+            logger,
+        };
+        proxy.save()?;
+        if let Some(m) = state.migrating {
+            info!(proxy.logger, "resume migration"; "backend" => &m);
+            proxy.migrate(&m)?;
+        }
+        for b in backends {
+            if !state.backends.contains(&b.to_string()) {
+                proxy.add_backend(b.clone())?;
+            }
+        }
+        Ok(proxy)
+    }
+
+    fn save(&self) -> Result<()> {
+        let b = self.backends.read().unwrap();
+        let state = ProxyState {
+            backends: b.remotes.keys().cloned().collect(),
+            migrating: b.old.as_ref().and_then(|old| {
+                b.ring
+                    .nodes()
+                    .into_iter()
+                    .find(|n| !old.nodes().contains(n))
+            }),
+        };
+        let tmp = self.dir.join(PROXY_STATE.to_owned() + ".tmp");
+        let f = File::create(&tmp)?;
+        serde_json::to_writer(&f, &state)?;
+        f.sync_all()?;
+        fs::rename(&tmp, self.dir.join(PROXY_STATE))?;
+        Ok(())
+    }
+
+    fn lock(&self, key: &str) -> MutexGuard<'_, ()> {
+        self.stripes[hash(key) as usize % STRIPES].lock().unwrap()
+    }
+
+    pub fn backends(&self) -> Vec<String> {
+        self.backends
+            .read()
+            .unwrap()
+            .remotes
+            .keys()
+            .cloned()
+            .collect()
+    }
+
+    // 这个 key 现在路由到的后端
+    pub fn owner(&self, key: &str) -> Option<String> {
+        self.backends
+            .read()
+            .unwrap()
+            .ring
+            .owner(key)
+            .map(Into::into)
+    }
+
+    pub fn status(&self) -> serde_json::Value {
+        let b = self.backends.read().unwrap();
+        json!({
+            "role": "proxy",
+            "backends": b.remotes.keys().collect::<Vec<_>>(),
+            "vnodes": b.ring.vnodes,
+            "migrating": b.old.is_some(),
+        })
+    }
+
+    // 加入新后端，返回迁移过去的 key 的数量
+    pub fn add_backend(&self, addr: Addr) -> Result<usize> {
+        let name = addr.to_string();
+        {
+            let mut b = self.backends.write().unwrap();
+            if b.old.is_some() {
+                return Err(MIGRATING.into());
+            }
+            if b.remotes.contains_key(&name) {
+                return Err(format!("backend {} already exists", name).into());
+            }
+            b.old = Some(b.ring.clone());
+            b.ring.add(&name);
+            b.remotes.insert(name.clone(), Arc::new(Remote::new(addr)));
+        }
+        self.save()?;
+        info!(self.logger, "add backend"; "backend" => &name);
+        self.migrate(&name)
+    }
+
+    // 把原来的后端上现在属于 target 的 key 搬过去，完成后清除迁移状态
+    // 迁移期间环不会变化（add_backend 返回 MIGRATING），复制一份之后不再持有 backends 的锁，
+    // 否则等待写锁的 add_backend 会挡住之后所有的读写
+    fn migrate(&self, target: &str) -> Result<usize> {
+        let (ring, remotes, sources) = {
+            let b = self.backends.read().unwrap();
+            let sources: Vec<String> = b.old.as_ref().map_or(vec![], |o| o.nodes());
+            (b.ring.clone(), b.remotes.clone(), sources)
+        };
+        let to = &remotes[target];
+        let mut moved = 0;
+        for s in sources {
+            let from = &remotes[&s];
+            for key in from.keys("")? {
+                if ring.owner(&key) != Some(target) {
+                    continue;
+                }
+                let _g = self.lock(&key);
+                if let Some(v) = from.get(key.clone())? {
+                    to.set(key.clone(), v)?;
+                    ignore_not_found(from.remove(key))?;
+                    moved += 1;
+                }
+            }
+        }
+        self.backends.write().unwrap().old = None;
+        self.save()?;
+        info!(self.logger, "migration finished"; "backend" => target, "keys" => moved);
+        Ok(moved)
+    }
+}
+
+fn ignore_not_found(r: Result<()>) -> Result<bool> {
+    match r {
+        Ok(()) => Ok(true),
+        Err(e) if e.to_string() == KEY_NOT_FOUND => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+impl KvsEngine for Proxy {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        let b = self.backends.read().unwrap();
+        let _g = self.lock(&key);
+        b.remote(&b.ring, &key)?.set(key.clone(), value)?;
+        if let Some(old) = b.previous(&key) {
+            ignore_not_found(old.remove(key))?;
+        }
+        Ok(())
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        let b = self.backends.read().unwrap();
+        let _g = self.lock(&key);
+        match b.remote(&b.ring, &key)?.get(key.clone())? {
+            None => b.previous(&key).map_or(Ok(None), |old| old.get(key)),
+            v => Ok(v),
+        }
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        let b = self.backends.read().unwrap();
+        let _g = self.lock(&key);
+        let mut found = ignore_not_found(b.remote(&b.ring, &key)?.remove(key.clone()))?;
+        if let Some(old) = b.previous(&key) {
+            found |= ignore_not_found(old.remove(key))?;
+        }
+        if !found {
+            return Err(KEY_NOT_FOUND.into());
+        }
+        Ok(())
+    }
+
+    // 所有后端并行查询再合并
+    fn keys(&self, prefix: &str) -> Result<Vec<String>> {
+        let b = self.backends.read().unwrap();
+        let results: Vec<Result<Vec<String>>> = thread::scope(|s| {
+            let handles: Vec<_> = b
+                .remotes
+                .values()
+                .map(|r| s.spawn(move || r.keys(prefix).map_err(|e| e.to_string())))
+                .collect();
+            handles
+                .into_iter()
+                .map(|h| h.join().unwrap().map_err(Into::into))
+                .collect()
+        });
+        let mut keys = vec![];
+        for r in results {
+            keys.extend(r?);
+        }
+        keys.sort();
+        keys.dedup();
+        Ok(keys)
+    }
+
+    fn snapshot(&self) -> Result<Box<dyn KvsSnapshot>> {
+        Err("Snapshots are not supported by kvs-proxy".into())
+    }
+
+    fn watch(&self) -> Result<Arc<Watch>> {
+        Err("WATCH is not supported by kvs-proxy".into())
+    }
+
+    // 所有后端的总和，迁移期间还没有从旧后端删除的 key 会算两次
+    fn info(&self) -> Result<EngineInfo> {
+        let b = self.backends.read().unwrap();
+        let mut info = EngineInfo {
+            engine: "proxy".to_owned(),
+            keys: 0,
+            disk_bytes: 0,
+        };
+        for r in b.remotes.values() {
+            let i = r.info()?;
+            info.keys += i.keys;
+            info.disk_bytes += i.disk_bytes;
+        }
+        Ok(info)
+    }
+
+    // 依次整理每个后端，耗时是总的耗时
+    fn compact(&self) -> Result<CompactionReport> {
+        let b = self.backends.read().unwrap();
+        let mut total = CompactionReport::default();
+        for r in b.remotes.values() {
+            let c = r.compact()?;
+            total.before_bytes += c.before_bytes;
+            total.after_bytes += c.after_bytes;
+            total.reclaimed_bytes += c.reclaimed_bytes;
+            total.keys += c.keys;
+            total.elapsed_ms += c.elapsed_ms;
+        }
+        Ok(total)
+    }
+
+    fn begin(&self) -> Result<Box<dyn Transaction>> {
+        let b = self.backends.read().unwrap();
+        if b.old.is_some() {
+            return Err(MIGRATING.into());
+        }
+        Ok(Box::new(ProxyTransaction {
+            ring: b.ring.clone(),
+            remotes: b.remotes.clone(),
+            txns: BTreeMap::new(),
+        }))
+    }
+}
+
+// 在 key 所属的后端上开始一个事务，读写都转发过去，由后端检测冲突
+// 提交不能跨后端做到原子，涉及多个后端时不提交任何一个，返回 CROSS_BACKEND
+pub struct ProxyTransaction {
+    ring: Ring,
+    remotes: BTreeMap<String, Arc<Remote>>,
+    txns: BTreeMap<String, RemoteTransaction>,
+}
+
+impl ProxyTransaction {
+    fn txn(&mut self, key: &str) -> Result<&mut RemoteTransaction> {
+        let owner = self.ring.owner(key).ok_or("no backend")?.to_owned();
+        if !self.txns.contains_key(&owner) {
+            let t = self.remotes[&owner].begin()?;
+            self.txns.insert(owner.clone(), t);
+        }
+        Ok(self.txns.get_mut(&owner).unwrap())
+    }
+}
+
+impl Transaction for ProxyTransaction {
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.txn(&key)?.get(key)
+    }
+
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.txn(&key)?.set(key, value)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        self.txn(&key)?.remove(key)
+    }
+
+    // 没有提交的后端事务在连接关闭时丢弃
+    fn commit(self: Box<Self>) -> Result<()> {
+        if self.txns.len() > 1 {
+            return Err(CROSS_BACKEND.into());
+        }
+        for (_, t) in self.txns {
+            t.commit()?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,222 @@
+// 频道的发布和订阅
+//
+// 消息不写入引擎也不保留历史，只发送给发布时已经订阅的连接
+// 每个订阅者有一个有界的缓冲区，发布时不等待订阅者；缓冲区满说明订阅者读得太慢，
+// 直接取消它的所有订阅并断开连接，慢的订阅者不会拖慢发布者，也不会占用越来越多的内存
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{resp::glob_match, Result};
+
+// 每个订阅者最多缓存的消息数
+pub const PUBSUB_BUFFER: usize = 1000;
+
+// 订阅者的缓冲区满了，被断开
+pub const SUBSCRIBER_TOO_SLOW: &str = "Subscriber is too slow, disconnecting";
+
+// SUBSCRIBE 和 PSUBSCRIBE 连接上 server 发送的消息，每行一个
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum PubSubMessage {
+    // 订阅成功，第二个参数是这个连接上订阅的频道和模式的总数
+    Subscribe(String, usize),
+    PSubscribe(String, usize),
+    // 通过 PSUBSCRIBE 收到时 pattern 是匹配的模式
+    Message {
+        channel: String,
+        pattern: Option<String>,
+        data: String,
+    },
+    // 没有消息时定期发送，用来发现断开的连接
+    Ping,
+}
+
+struct Subscriber {
+    id: u64,
+    tx: SyncSender<PubSubMessage>,
+    // 缓冲区满时被取消订阅
+    slow: AtomicBool,
+}
+
+type Subscribers = BTreeMap<String, HashMap<u64, Arc<Subscriber>>>;
+
+#[derive(Default)]
+struct Registry {
+    channels: Subscribers,
+    patterns: Subscribers,
+}
+
+impl Registry {
+    // 不知道订阅者订阅了哪些频道，只在发布时发现慢的订阅者时使用
+    fn remove(&mut self, id: u64) {
+        for subs in [&mut self.channels, &mut self.patterns] {
+            subs.retain(|_, s| {
+                s.remove(&id);
+                !s.is_empty()
+            });
+        }
+    }
+}
+
+pub struct PubSub {
+    buffer: usize,
+    next_id: AtomicU64,
+    registry: Mutex<Registry>,
+}
+
+impl Default for PubSub {
+    fn default() -> Self {
+        Self::new(PUBSUB_BUFFER)
+    }
+}
+
+impl PubSub {
+    pub fn new(buffer: usize) -> Self {
+        PubSub {
+            buffer: buffer.max(1),
+            next_id: AtomicU64::new(0),
+            registry: Mutex::new(Registry::default()),
+        }
+    }
+
+    // 一个连接对应一个 Subscription，drop 时取消所有订阅
+    pub fn subscriber(self: &Arc<Self>) -> Subscription {
+        let (tx, rx) = mpsc::sync_channel(self.buffer);
+        Subscription {
+            pubsub: self.clone(),
+            sub: Arc::new(Subscriber {
+                id: self.next_id.fetch_add(1, Ordering::Relaxed),
+                tx,
+                slow: AtomicBool::new(false),
+            }),
+            rx,
+            channels: BTreeSet::new(),
+            patterns: BTreeSet::new(),
+        }
+    }
+
+    // 返回收到消息的订阅者数量，同一个连接通过多个模式匹配时收到多次
+    // 发送时持有锁，所有订阅者看到的消息顺序相同
+    pub fn publish(&self, channel: &str, data: &str) -> usize {
+        let mut r = self.registry.lock().unwrap();
+        let mut targets = vec![];
+        if let Some(subs) = r.channels.get(channel) {
+            targets.extend(subs.values().map(|s| (s.clone(), None)));
+        }
+        for (p, subs) in &r.patterns {
+            if glob_match(p.as_bytes(), channel.as_bytes()) {
+                targets.extend(subs.values().map(|s| (s.clone(), Some(p.clone()))));
+            }
+        }
+        let mut received = 0;
+        for (s, pattern) in targets {
+            let m = PubSubMessage::Message {
+                channel: channel.to_owned(),
+                pattern,
+                data: data.to_owned(),
+            };
+            match s.tx.try_send(m) {
+                Ok(()) => received += 1,
+                Err(TrySendError::Full(_)) => {
+                    s.slow.store(true, Ordering::SeqCst);
+                    r.remove(s.id);
+                }
+                Err(TrySendError::Disconnected(_)) => r.remove(s.id),
+            }
+        }
+        received
+    }
+
+    // 每个频道和模式的订阅者数量
+    pub fn stats(&self) -> Value {
+        let r = self.registry.lock().unwrap();
+        let count = |subs: &Subscribers| -> BTreeMap<String, usize> {
+            subs.iter().map(|(k, s)| (k.clone(), s.len())).collect()
+        };
+        json!({
+            "channels": count(&r.channels),
+            "patterns": count(&r.patterns),
+        })
+    }
+}
+
+pub struct Subscription {
+    pubsub: Arc<PubSub>,
+    sub: Arc<Subscriber>,
+    rx: Receiver<PubSubMessage>,
+    channels: BTreeSet<String>,
+    patterns: BTreeSet<String>,
+}
+
+impl Subscription {
+    // 返回这个连接上订阅的频道和模式的总数
+    pub fn subscribe(&mut self, channel: &str) -> usize {
+        if self.channels.insert(channel.to_owned()) && !self.slow() {
+            let mut r = self.pubsub.registry.lock().unwrap();
+            let subs = r.channels.entry(channel.to_owned()).or_default();
+            subs.insert(self.sub.id, self.sub.clone());
+        }
+        self.count()
+    }
+
+    // 模式使用 redis 风格的 glob，见 resp::glob_match
+    pub fn psubscribe(&mut self, pattern: &str) -> usize {
+        if self.patterns.insert(pattern.to_owned()) && !self.slow() {
+            let mut r = self.pubsub.registry.lock().unwrap();
+            let subs = r.patterns.entry(pattern.to_owned()).or_default();
+            subs.insert(self.sub.id, self.sub.clone());
+        }
+        self.count()
+    }
+
+    fn count(&self) -> usize {
+        self.channels.len() + self.patterns.len()
+    }
+
+    fn slow(&self) -> bool {
+        self.sub.slow.load(Ordering::SeqCst)
+    }
+
+    // 等待下一条消息，超时返回 None，缓冲区满过之后返回错误，没有读的消息被丢弃
+    pub fn next(&self, timeout: Duration) -> Result<Option<PubSubMessage>> {
+        if self.slow() {
+            return Err(SUBSCRIBER_TOO_SLOW.into());
+        }
+        match self.rx.recv_timeout(timeout) {
+            Ok(m) => Ok(Some(m)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            // Subscription 自己持有 sender，不会断开
+            Err(RecvTimeoutError::Disconnected) => unreachable!(),
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let mut g = self.pubsub.registry.lock().unwrap();
+        let r = &mut *g;
+        let id = self.sub.id;
+        for (subs, names) in [
+            (&mut r.channels, &self.channels),
+            (&mut r.patterns, &self.patterns),
+        ] {
+            for n in names {
+                if let Some(s) = subs.get_mut(n) {
+                    s.remove(&id);
+                    if s.is_empty() {
+                        subs.remove(n);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,1327 @@
+// Raft 集群模式
+//
+// 每个节点的 kvs-server 地址同时用于客户端请求和节点之间的 RPC（Command::Raft）
+// 写入先追加到 leader 的日志，复制到多数节点之后再按顺序应用到本地的 KvsEngine
+// 读只在 leader 上进行：leader 最近 LEASE 时间内得到过多数节点的响应时，这些节点在选举超时之前
+// 不会投票给其他 candidate，不会有新的 leader 提交写入，所以等本地应用到 commit 之后直接读引擎是线性一致的
+// follower 上的读写返回 NOTLEADER 错误，带上 leader 的地址
+//
+// 成员变更每次只增加或者删除一个节点，新的配置在追加到日志时生效
+// 应用过的日志超过 snapshot_threshold 条时用引擎的快照生成 snapshot 并截断日志，落后太多的节点直接安装 snapshot
+//
+// 数据目录中的文件：
+//   state.json      当前 term、投票给的节点和启动时的初始配置
+//   log.json        每行一个日志条目
+//   snapshot.json   第一行是 SnapshotMeta，之后每行一个 [key, value]
+//
+// 节点之间的 RPC 和客户端请求一样经过认证和 TLS，开启认证时 RaftOptions 中的用户需要对所有 key 有写权限，
+// 只配置 password 时作为 token，所有节点共用一个 token 就是集群的共享密钥
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, Write},
+    net::{TcpStream, ToSocketAddrs},
+    path::{Path, PathBuf},
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    thread,
+    time::{Duration, Instant},
+};
+
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+use rustls::ClientConfig;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use slog::{info, warn, Logger};
+
+use crate::net::Stream;
+use crate::{
+    Command, CompactionReport, EngineInfo, KvsEngine, KvsSnapshot, Result, Transaction, Watch,
+    KEY_NOT_FOUND, TXN_CONFLICT,
+};
+
+pub type NodeId = u64;
+
+// 不是 leader 时返回的错误前缀，后面跟着已知的 leader 地址
+pub const NOT_LEADER: &str = "NOTLEADER";
+
+const STATE: &str = "state.json";
+const LOG: &str = "log.json";
+const SNAPSHOT: &str = "snapshot.json";
+
+const TICK: Duration = Duration::from_millis(10);
+const HEARTBEAT: Duration = Duration::from_millis(50);
+// 实际的选举超时在 [ELECTION_TIMEOUT, 2 * ELECTION_TIMEOUT) 之间随机
+const ELECTION_TIMEOUT: Duration = Duration::from_millis(300);
+// 必须小于 ELECTION_TIMEOUT，留出时钟误差
+const LEASE: Duration = Duration::from_millis(250);
+const RPC_TIMEOUT: Duration = Duration::from_millis(500);
+const PROPOSE_TIMEOUT: Duration = Duration::from_secs(3);
+// 一次 AppendEntries 最多携带的条目数
+const MAX_ENTRIES: usize = 500;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum Op {
+    // 新 leader 追加的空条目，提交之后之前 term 的条目也随之提交
+    Noop,
+    Set(String, String),
+    // 和 KvsEngine::remove 一样，key 不存在时返回 KEY_NOT_FOUND
+    Remove(String),
+    // 事务：读到的值在应用时都没有变化才写入，否则返回 TXN_CONFLICT
+    Txn {
+        reads: Vec<(String, Option<String>)>,
+        writes: Vec<Command>,
+    },
+    // 新的成员列表，id -> 地址
+    Config(BTreeMap<NodeId, String>),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LogEntry {
+    pub index: u64,
+    pub term: u64,
+    pub op: Op,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct SnapshotMeta {
+    pub index: u64,
+    pub term: u64,
+    // snapshot 对应位置的成员列表
+    pub config: BTreeMap<NodeId, String>,
+}
+
+// 节点之间的 RPC，请求和回复都是一行 json
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum Message {
+    RequestVote {
+        term: u64,
+        candidate: NodeId,
+        last_index: u64,
+        last_term: u64,
+    },
+    Vote {
+        term: u64,
+        granted: bool,
+    },
+    AppendEntries {
+        term: u64,
+        leader: NodeId,
+        prev_index: u64,
+        prev_term: u64,
+        entries: Vec<LogEntry>,
+        commit: u64,
+    },
+    // 成功时 index 是已经和 leader 一致的最后一条，失败时是建议的下一次的 prev_index
+    Appended {
+        term: u64,
+        success: bool,
+        index: u64,
+    },
+    InstallSnapshot {
+        term: u64,
+        leader: NodeId,
+        meta: SnapshotMeta,
+        data: Vec<(String, String)>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct HardState {
+    term: u64,
+    voted_for: Option<NodeId>,
+    config: BTreeMap<NodeId, String>,
+}
+
+// snapshot 之后的日志，entries[0].index == snapshot.index + 1
+struct Log {
+    dir: PathBuf,
+    file: File,
+    snapshot: SnapshotMeta,
+    entries: Vec<LogEntry>,
+}
+
+impl Log {
+    fn open(dir: &Path, snapshot: SnapshotMeta) -> Result<Log> {
+        let mut entries: Vec<LogEntry> = vec![];
+        if let Ok(f) = File::open(dir.join(LOG)) {
+            for line in BufReader::new(f).lines() {
+                // 最后一行可能没有写完
+                match serde_json::from_str::<LogEntry>(&line?) {
+                    Ok(e) => entries.push(e),
+                    Err(_) => break,
+                }
+            }
+        }
+        entries.retain(|e| e.index > snapshot.index);
+        if entries
+            .iter()
+            .enumerate()
+            .any(|(i, e)| e.index != snapshot.index + 1 + i as u64)
+        {
+            return Err("raft log is not contiguous with the snapshot".into());
+        }
+        let mut log = Log {
+            dir: dir.to_owned(),
+            file: OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(dir.join(LOG))?,
+            snapshot,
+            entries,
+        };
+        log.rewrite()?;
+        Ok(log)
+    }
+
+    fn last_index(&self) -> u64 {
+        self.entries.last().map_or(self.snapshot.index, |e| e.index)
+    }
+
+    fn last_term(&self) -> u64 {
+        self.entries.last().map_or(self.snapshot.term, |e| e.term)
+    }
+
+    fn entry(&self, index: u64) -> Option<&LogEntry> {
+        if index <= self.snapshot.index {
+            return None;
+        }
+        self.entries.get((index - self.snapshot.index - 1) as usize)
+    }
+
+    // 已经被 snapshot 截断的位置返回 None
+    fn term(&self, index: u64) -> Option<u64> {
+        if index == self.snapshot.index {
+            return Some(self.snapshot.term);
+        }
+        self.entry(index).map(|e| e.term)
+    }
+
+    fn append(&mut self, entries: Vec<LogEntry>) -> Result<()> {
+        let mut b = vec![];
+        for e in &entries {
+            serde_json::to_writer(&mut b, e)?;
+            b.push(b'\n');
+        }
+        self.file.write_all(&b)?;
+        self.file.sync_data()?;
+        self.entries.extend(entries);
+        Ok(())
+    }
+
+    // 删除 index 以及之后的条目
+    fn truncate(&mut self, index: u64) -> Result<()> {
+        self.entries
+            .truncate(index.saturating_sub(self.snapshot.index + 1) as usize);
+        self.rewrite()
+    }
+
+    // snapshot 之前的条目不再需要，和 snapshot 冲突的条目全部丢弃
+    fn compact(&mut self, meta: SnapshotMeta) -> Result<()> {
+        if self.term(meta.index) == Some(meta.term) {
+            self.entries.retain(|e| e.index > meta.index);
+        } else {
+            self.entries.clear();
+        }
+        self.snapshot = meta;
+        self.rewrite()
+    }
+
+    // 位置 index 上生效的成员列表
+    fn config_at(&self, index: u64) -> BTreeMap<NodeId, String> {
+        self.entries
+            .iter()
+            .rev()
+            .filter(|e| e.index <= index)
+            .find_map(|e| match &e.op {
+                Op::Config(c) => Some(c.clone()),
+                _ => None,
+            })
+            .unwrap_or_else(|| self.snapshot.config.clone())
+    }
+
+    // 最后一个成员变更条目的位置
+    fn config_index(&self) -> u64 {
+        self.entries
+            .iter()
+            .rev()
+            .find(|e| matches!(e.op, Op::Config(_)))
+            .map_or(self.snapshot.index, |e| e.index)
+    }
+
+    fn rewrite(&mut self) -> Result<()> {
+        let tmp = self.dir.join(LOG.to_owned() + ".tmp");
+        let mut w = BufWriter::new(File::create(&tmp)?);
+        for e in &self.entries {
+            serde_json::to_writer(&mut w, e)?;
+            w.write_all(b"\n")?;
+        }
+        w.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(&tmp, self.dir.join(LOG))?;
+        self.file = OpenOptions::new().append(true).open(self.dir.join(LOG))?;
+        Ok(())
+    }
+}
+
+type ApplyResult = std::result::Result<(), String>;
+
+struct State {
+    role: Role,
+    term: u64,
+    voted_for: Option<NodeId>,
+    leader: Option<NodeId>,
+    log: Log,
+    // 当前生效的成员列表，也就是日志中最后一个成员变更
+    config: BTreeMap<NodeId, String>,
+    // 启动时指定的初始配置，在第一个 snapshot 之前作为 snapshot.config
+    bootstrap: BTreeMap<NodeId, String>,
+    commit: u64,
+    applied: u64,
+    election_deadline: Instant,
+    // 最近一次收到 leader 消息的时间，在此之后 ELECTION_TIMEOUT 内不给其他节点投票
+    heard: Option<Instant>,
+    votes: HashSet<NodeId>,
+    // 以下是 leader 的状态
+    leader_since: Instant,
+    last_heartbeat: Instant,
+    // 当前 term 的第一个条目，提交之后 commit 才是准确的
+    term_start: u64,
+    next: HashMap<NodeId, u64>,
+    matched: HashMap<NodeId, u64>,
+    // 每个 follower 最近一次确认的请求的发送时间，用来计算租约
+    acked: HashMap<NodeId, Instant>,
+    inflight: HashSet<NodeId>,
+    // 等待应用结果的写入，应用之后填上条目的 term 和结果
+    results: HashMap<u64, Option<(u64, ApplyResult)>>,
+    stopped: bool,
+}
+
+impl State {
+    fn quorum(&self) -> usize {
+        self.config.len() / 2 + 1
+    }
+
+    // 不在选举超时内的节点视为没有响应
+    fn acked_within(&self, id: NodeId, d: Duration) -> usize {
+        let mut n = self
+            .config
+            .keys()
+            .filter(|p| self.acked.get(p).is_some_and(|t| t.elapsed() < d))
+            .count();
+        if self.config.contains_key(&id) {
+            n += 1;
+        }
+        n
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct RaftOptions {
+    // 指定时用 TLS 连接其他节点，server_name 为空时用 IP 地址验证证书
+    pub tls: Option<Arc<ClientConfig>>,
+    pub tls_server_name: Option<String>,
+    // 连接之后先 AUTH，没有用户名时 password 作为 token
+    pub user: Option<String>,
+    pub password: Option<String>,
+}
+
+pub struct RaftNode {
+    id: NodeId,
+    engine: Arc<dyn KvsEngine + Sync>,
+    dir: PathBuf,
+    snapshot_threshold: u64,
+    st: Mutex<State>,
+    cond: Condvar,
+    // 应用日志和安装 snapshot 都会修改引擎，需要互斥
+    apply_lock: Mutex<()>,
+    // 测试时模拟网络分区，和这些节点之间的消息都被丢弃
+    blocked: Mutex<HashSet<NodeId>>,
+    // 到其他节点的空闲连接
+    conns: Mutex<HashMap<NodeId, Vec<BufReader<Stream>>>>,
+    opts: RaftOptions,
+    logger: Logger,
+}
+
+fn election_timeout() -> Duration {
+    ELECTION_TIMEOUT + Duration::from_millis(OsRng.next_u64() % ELECTION_TIMEOUT.as_millis() as u64)
+}
+
+impl RaftNode {
+    // peers 是包括自己在内的初始成员，只在第一次启动时使用
+    // 加入已有集群的新节点 peers 为空，等 leader 把它加入配置之后再参与选举
+    pub fn start(
+        id: NodeId,
+        peers: BTreeMap<NodeId, String>,
+        engine: Arc<dyn KvsEngine + Sync>,
+        dir: &Path,
+        snapshot_threshold: u64,
+        logger: Logger,
+    ) -> Result<Arc<RaftNode>> {
+        let opts = RaftOptions::default();
+        Self::with_options(id, peers, engine, dir, snapshot_threshold, logger, opts)
+    }
+
+    pub fn with_options(
+        id: NodeId,
+        peers: BTreeMap<NodeId, String>,
+        engine: Arc<dyn KvsEngine + Sync>,
+        dir: &Path,
+        snapshot_threshold: u64,
+        logger: Logger,
+        opts: RaftOptions,
+    ) -> Result<Arc<RaftNode>> {
+        fs::create_dir_all(dir)?;
+        let hard = match fs::read(dir.join(STATE)) {
+            Ok(b) => serde_json::from_slice(&b)?,
+            // 第一次启动，引擎中已有的数据不在日志中，其他节点上没有
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                if !engine.keys("")?.is_empty() {
+                    return Err("the engine already has data, a raft node must start empty".into());
+                }
+                HardState {
+                    config: peers,
+                    ..HardState::default()
+                }
+            }
+            Err(e) => return Err(Box::new(e)),
+        };
+        let (meta, data) = match read_snapshot(dir)? {
+            Some((meta, data)) => (meta, Some(data)),
+            None => (
+                SnapshotMeta {
+                    config: hard.config.clone(),
+                    ..SnapshotMeta::default()
+                },
+                None,
+            ),
+        };
+        // 引擎中的数据不一定都已经落盘，也包含 snapshot 之后已经应用过的条目，
+        // 重放这些条目时事务的读检查会失败，所以先恢复到 snapshot（没有时是空的）再重放之后提交的日志
+        restore(engine.as_ref(), data.unwrap_or_default())?;
+        let log = Log::open(dir, meta)?;
+        let now = Instant::now();
+        let node = Arc::new(RaftNode {
+            id,
+            engine,
+            dir: dir.to_owned(),
+            snapshot_threshold: snapshot_threshold.max(1),
+            st: Mutex::new(State {
+                role: Role::Follower,
+                term: hard.term,
+                voted_for: hard.voted_for,
+                leader: None,
+                config: log.config_at(log.last_index()),
+                bootstrap: hard.config,
+                commit: log.snapshot.index,
+                applied: log.snapshot.index,
+                log,
+                election_deadline: now + election_timeout(),
+                heard: None,
+                votes: HashSet::new(),
+                leader_since: now,
+                last_heartbeat: now,
+                term_start: 0,
+                next: HashMap::new(),
+                matched: HashMap::new(),
+                acked: HashMap::new(),
+                inflight: HashSet::new(),
+                results: HashMap::new(),
+                stopped: false,
+            }),
+            cond: Condvar::new(),
+            apply_lock: Mutex::new(()),
+            blocked: Mutex::new(HashSet::new()),
+            conns: Mutex::new(HashMap::new()),
+            opts,
+            logger,
+        });
+        node.save_hard(&node.st.lock().unwrap())?;
+        let n = node.clone();
+        thread::spawn(move || n.tick_loop());
+        let n = node.clone();
+        thread::spawn(move || n.apply_loop());
+        Ok(node)
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    pub fn role(&self) -> Role {
+        self.st.lock().unwrap().role
+    }
+
+    pub fn leader(&self) -> Option<NodeId> {
+        self.st.lock().unwrap().leader
+    }
+
+    pub fn members(&self) -> BTreeMap<NodeId, String> {
+        self.st.lock().unwrap().config.clone()
+    }
+
+    pub fn status(&self) -> serde_json::Value {
+        let st = self.st.lock().unwrap();
+        json!({
+            "role": format!("{:?}", st.role).to_lowercase(),
+            "id": self.id,
+            "term": st.term,
+            "leader": st.leader,
+            "commit": st.commit,
+            "applied": st.applied,
+            "last_index": st.log.last_index(),
+            "snapshot_index": st.log.snapshot.index,
+            "members": st.config,
+        })
+    }
+
+    // 模拟和 peers 之间的网络分区，双向的消息都会丢弃
+    pub fn partition(&self, peers: &[NodeId]) {
+        self.blocked.lock().unwrap().extend(peers);
+        let mut conns = self.conns.lock().unwrap();
+        for p in peers {
+            conns.remove(p);
+        }
+    }
+
+    pub fn heal(&self) {
+        self.blocked.lock().unwrap().clear();
+    }
+
+    // 停止参与集群，模拟节点宕机
+    pub fn stop(&self) {
+        self.st.lock().unwrap().stopped = true;
+        self.cond.notify_all();
+    }
+
+    fn is_blocked(&self, peer: NodeId) -> bool {
+        self.blocked.lock().unwrap().contains(&peer)
+    }
+
+    fn save_hard(&self, st: &State) -> Result<()> {
+        let hard = HardState {
+            term: st.term,
+            voted_for: st.voted_for,
+            config: st.bootstrap.clone(),
+        };
+        let tmp = self.dir.join(STATE.to_owned() + ".tmp");
+        let f = File::create(&tmp)?;
+        serde_json::to_writer(&f, &hard)?;
+        f.sync_all()?;
+        fs::rename(&tmp, self.dir.join(STATE))?;
+        Ok(())
+    }
+
+    fn not_leader(&self, st: &State) -> Box<dyn std::error::Error> {
+        match st.leader.and_then(|l| st.config.get(&l)) {
+            Some(addr) if st.leader != Some(self.id) => format!("{} {}", NOT_LEADER, addr).into(),
+            _ => NOT_LEADER.into(),
+        }
+    }
+
+    fn step_down(&self, st: &mut State, term: u64) {
+        if term > st.term {
+            st.term = term;
+            st.voted_for = None;
+            st.leader = None;
+            if let Err(e) = self.save_hard(st) {
+                warn!(self.logger, "save raft state: {}", e);
+            }
+        }
+        if st.role != Role::Follower {
+            info!(self.logger, "become follower"; "term" => st.term);
+        }
+        st.role = Role::Follower;
+        st.election_deadline = Instant::now() + election_timeout();
+        self.cond.notify_all();
+    }
+
+    fn tick_loop(self: Arc<Self>) {
+        loop {
+            thread::sleep(TICK);
+            let mut st = self.st.lock().unwrap();
+            if st.stopped {
+                return;
+            }
+            match st.role {
+                Role::Leader => {
+                    // 连续两个选举超时都联系不上多数节点，说明自己被分区了
+                    let lost = st.leader_since.elapsed() > ELECTION_TIMEOUT * 2
+                        && st.acked_within(self.id, ELECTION_TIMEOUT * 2) < st.quorum();
+                    if lost {
+                        warn!(self.logger, "lost contact with the majority, stepping down");
+                        st.leader = None;
+                        let term = st.term;
+                        self.step_down(&mut st, term);
+                    } else if st.last_heartbeat.elapsed() >= HEARTBEAT {
+                        self.replicate(&mut st);
+                    }
+                }
+                _ => {
+                    if Instant::now() >= st.election_deadline && st.config.contains_key(&self.id) {
+                        self.start_election(&mut st);
+                    }
+                }
+            }
+        }
+    }
+
+    fn start_election(self: &Arc<Self>, st: &mut MutexGuard<State>) {
+        st.term += 1;
+        st.role = Role::Candidate;
+        st.voted_for = Some(self.id);
+        st.leader = None;
+        st.votes = HashSet::from([self.id]);
+        st.election_deadline = Instant::now() + election_timeout();
+        if let Err(e) = self.save_hard(st) {
+            warn!(self.logger, "save raft state: {}", e);
+            return;
+        }
+        info!(self.logger, "start election"; "term" => st.term);
+        if st.votes.len() >= st.quorum() {
+            self.become_leader(st);
+            return;
+        }
+        let req = Message::RequestVote {
+            term: st.term,
+            candidate: self.id,
+            last_index: st.log.last_index(),
+            last_term: st.log.last_term(),
+        };
+        for (&peer, addr) in st.config.iter().filter(|(p, _)| **p != self.id) {
+            let (node, addr, req, term) = (self.clone(), addr.clone(), req.clone(), st.term);
+            thread::spawn(move || {
+                if let Ok(Message::Vote { term: t, granted }) = node.rpc(peer, &addr, req) {
+                    let mut st = node.st.lock().unwrap();
+                    if t > st.term {
+                        node.step_down(&mut st, t);
+                    } else if granted && st.role == Role::Candidate && st.term == term {
+                        st.votes.insert(peer);
+                        if st.votes.len() >= st.quorum() {
+                            node.become_leader(&mut st);
+                        }
+                    }
+                }
+            });
+        }
+    }
+
+    fn become_leader(self: &Arc<Self>, st: &mut MutexGuard<State>) {
+        info!(self.logger, "become leader"; "term" => st.term);
+        st.role = Role::Leader;
+        st.leader = Some(self.id);
+        st.leader_since = Instant::now();
+        let next = st.log.last_index() + 1;
+        st.next = st.config.keys().map(|p| (*p, next)).collect();
+        st.matched.clear();
+        st.acked.clear();
+        let entry = LogEntry {
+            index: next,
+            term: st.term,
+            op: Op::Noop,
+        };
+        st.term_start = next;
+        if let Err(e) = st.log.append(vec![entry]) {
+            warn!(self.logger, "append raft log: {}", e);
+        }
+        self.replicate(st);
+        self.advance_commit(st);
+    }
+
+    // 给每个没有进行中请求的 follower 发送缺少的条目，没有新条目时就是心跳
+    fn replicate(self: &Arc<Self>, st: &mut MutexGuard<State>) {
+        st.last_heartbeat = Instant::now();
+        let peers: Vec<(NodeId, String)> = st
+            .config
+            .iter()
+            .filter(|(p, _)| **p != self.id && !st.inflight.contains(p))
+            .map(|(p, a)| (*p, a.clone()))
+            .collect();
+        let last = st.log.last_index();
+        for (peer, addr) in peers {
+            let next = *st.next.entry(peer).or_insert(last + 1);
+            // 需要的条目已经被截断，改为发送 snapshot，snapshot 在线程中读取
+            let req = match st.log.term(next - 1) {
+                Some(prev_term) => {
+                    let end = st.log.last_index().min(next + MAX_ENTRIES as u64 - 1);
+                    Some(Message::AppendEntries {
+                        term: st.term,
+                        leader: self.id,
+                        prev_index: next - 1,
+                        prev_term,
+                        entries: (next..=end)
+                            .filter_map(|i| st.log.entry(i).cloned())
+                            .collect(),
+                        commit: st.commit,
+                    })
+                }
+                None => None,
+            };
+            st.inflight.insert(peer);
+            let (node, term) = (self.clone(), st.term);
+            thread::spawn(move || {
+                let sent = Instant::now();
+                let r = match req {
+                    Some(req) => node.rpc(peer, &addr, req),
+                    None => read_snapshot(&node.dir).and_then(|s| {
+                        let (meta, data) = s.ok_or("snapshot is missing")?;
+                        let req = Message::InstallSnapshot {
+                            term,
+                            leader: node.id,
+                            meta,
+                            data,
+                        };
+                        node.rpc(peer, &addr, req)
+                    }),
+                };
+                let mut st = node.st.lock().unwrap();
+                st.inflight.remove(&peer);
+                if let Ok(Message::Appended {
+                    term: t,
+                    success,
+                    index,
+                }) = r
+                {
+                    node.on_appended(&mut st, peer, term, sent, t, success, index);
+                }
+            });
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn on_appended(
+        self: &Arc<Self>,
+        st: &mut MutexGuard<State>,
+        peer: NodeId,
+        term: u64,
+        sent: Instant,
+        reply_term: u64,
+        success: bool,
+        index: u64,
+    ) {
+        if reply_term > st.term {
+            self.step_down(st, reply_term);
+            return;
+        }
+        if st.role != Role::Leader || st.term != term {
+            return;
+        }
+        // follower 接受了这个 term，可以计入租约
+        let acked = st.acked.entry(peer).or_insert(sent);
+        *acked = (*acked).max(sent);
+        if success {
+            let m = st.matched.entry(peer).or_insert(0);
+            *m = (*m).max(index);
+            let m = *m;
+            st.next.insert(peer, m + 1);
+            self.advance_commit(st);
+        } else {
+            st.next.insert(peer, index + 1);
+        }
+        if st.next[&peer] <= st.log.last_index() {
+            self.replicate(st);
+        }
+    }
+
+    // 多数节点都已经有的、当前 term 的条目可以提交
+    fn advance_commit(&self, st: &mut State) {
+        let last = st.log.last_index();
+        let mut matched: Vec<u64> = st
+            .config
+            .keys()
+            .map(|p| {
+                if *p == self.id {
+                    last
+                } else {
+                    st.matched.get(p).copied().unwrap_or(0)
+                }
+            })
+            .collect();
+        if matched.is_empty() {
+            return;
+        }
+        matched.sort_unstable_by(|a, b| b.cmp(a));
+        let n = matched[st.quorum() - 1];
+        if n > st.commit && st.log.term(n) == Some(st.term) {
+            st.commit = n;
+            self.cond.notify_all();
+        }
+        // 自己被移出集群的配置提交之后退出 leader
+        if !st.config.contains_key(&self.id) && st.commit >= st.log.config_index() {
+            info!(self.logger, "removed from the cluster");
+            st.leader = None;
+            let term = st.term;
+            self.step_down(st, term);
+        }
+    }
+
+    // 处理其他节点发来的 RPC
+    pub fn handle(&self, m: Message) -> Result<Message> {
+        let from = match &m {
+            Message::RequestVote { candidate, .. } => *candidate,
+            Message::AppendEntries { leader, .. } | Message::InstallSnapshot { leader, .. } => {
+                *leader
+            }
+            _ => return Err("unexpected raft message".into()),
+        };
+        if self.is_blocked(from) {
+            return Err("partitioned".into());
+        }
+        let mut st = self.st.lock().unwrap();
+        if st.stopped {
+            return Err("node is stopped".into());
+        }
+        match m {
+            Message::RequestVote {
+                term,
+                candidate,
+                last_index,
+                last_term,
+            } => {
+                // 还在 leader 的租约内，拒绝投票并且不更新 term，避免分区恢复的节点打断集群
+                let leased = st.role == Role::Leader
+                    || st.heard.is_some_and(|t| t.elapsed() < ELECTION_TIMEOUT);
+                if term < st.term || (leased && st.leader != Some(candidate)) {
+                    return Ok(Message::Vote {
+                        term: st.term,
+                        granted: false,
+                    });
+                }
+                if term > st.term {
+                    self.step_down(&mut st, term);
+                }
+                let up_to_date =
+                    (last_term, last_index) >= (st.log.last_term(), st.log.last_index());
+                let granted = up_to_date && st.voted_for.is_none_or(|v| v == candidate);
+                if granted {
+                    st.voted_for = Some(candidate);
+                    self.save_hard(&st)?;
+                    st.election_deadline = Instant::now() + election_timeout();
+                }
+                Ok(Message::Vote {
+                    term: st.term,
+                    granted,
+                })
+            }
+            Message::AppendEntries {
+                term,
+                leader,
+                prev_index,
+                prev_term,
+                entries,
+                commit,
+            } => {
+                if !self.follow(&mut st, term, leader) {
+                    return Ok(Message::Appended {
+                        term: st.term,
+                        success: false,
+                        index: 0,
+                    });
+                }
+                let last_new = prev_index + entries.len() as u64;
+                // prev 之后的一部分条目已经在 snapshot 中，它们一定已经提交，从 snapshot 之后开始比较
+                let (mut prev_index, mut prev_term, mut entries) = (prev_index, prev_term, entries);
+                if prev_index < st.log.snapshot.index {
+                    entries.retain(|e| e.index > st.log.snapshot.index);
+                    prev_index = st.log.snapshot.index;
+                    prev_term = st.log.snapshot.term;
+                }
+                if prev_index > st.log.last_index() {
+                    return Ok(Message::Appended {
+                        term: st.term,
+                        success: false,
+                        index: st.log.last_index(),
+                    });
+                }
+                if st.log.term(prev_index) != Some(prev_term) {
+                    return Ok(Message::Appended {
+                        term: st.term,
+                        success: false,
+                        index: prev_index.saturating_sub(1).max(st.commit),
+                    });
+                }
+                let mut new = vec![];
+                for e in entries {
+                    match st.log.term(e.index) {
+                        Some(t) if t == e.term => continue,
+                        Some(_) => {
+                            // 冲突的条目一定没有提交
+                            st.log.truncate(e.index)?;
+                            new.push(e);
+                        }
+                        None => new.push(e),
+                    }
+                }
+                if !new.is_empty() {
+                    st.log.append(new)?;
+                }
+                st.config = st.log.config_at(st.log.last_index());
+                let c = commit.min(last_new);
+                if c > st.commit {
+                    st.commit = c;
+                    self.cond.notify_all();
+                }
+                Ok(Message::Appended {
+                    term: st.term,
+                    success: true,
+                    index: last_new,
+                })
+            }
+            Message::InstallSnapshot {
+                term,
+                leader,
+                meta,
+                data,
+            } => {
+                if !self.follow(&mut st, term, leader) {
+                    return Ok(Message::Appended {
+                        term: st.term,
+                        success: false,
+                        index: 0,
+                    });
+                }
+                let index = meta.index;
+                if index > st.applied {
+                    drop(st);
+                    self.install(meta, data)?;
+                    st = self.st.lock().unwrap();
+                }
+                Ok(Message::Appended {
+                    term: st.term,
+                    success: true,
+                    index,
+                })
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    // 收到 leader 的消息，term 过期时返回 false
+    fn follow(&self, st: &mut State, term: u64, leader: NodeId) -> bool {
+        if term < st.term {
+            return false;
+        }
+        if term > st.term || st.role != Role::Follower {
+            self.step_down(st, term);
+        }
+        st.leader = Some(leader);
+        st.heard = Some(Instant::now());
+        st.election_deadline = Instant::now() + election_timeout();
+        true
+    }
+
+    fn install(&self, meta: SnapshotMeta, data: Vec<(String, String)>) -> Result<()> {
+        let _a = self.apply_lock.lock().unwrap();
+        if meta.index <= self.st.lock().unwrap().applied {
+            return Ok(());
+        }
+        info!(self.logger, "install snapshot"; "index" => meta.index);
+        write_snapshot(&self.dir, &meta, &data)?;
+        restore(self.engine.as_ref(), data)?;
+        let mut st = self.st.lock().unwrap();
+        st.log.compact(meta)?;
+        st.commit = st.commit.max(st.log.snapshot.index);
+        st.applied = st.log.snapshot.index;
+        st.config = st.log.config_at(st.log.last_index());
+        self.cond.notify_all();
+        Ok(())
+    }
+
+    fn apply_loop(self: Arc<Self>) {
+        loop {
+            {
+                let mut st = self.st.lock().unwrap();
+                while st.applied >= st.commit {
+                    if st.stopped {
+                        return;
+                    }
+                    st = self.cond.wait_timeout(st, HEARTBEAT).unwrap().0;
+                }
+            }
+            let _a = self.apply_lock.lock().unwrap();
+            let entries: Vec<LogEntry> = {
+                let st = self.st.lock().unwrap();
+                (st.applied + 1..=st.commit)
+                    .filter_map(|i| st.log.entry(i).cloned())
+                    .collect()
+            };
+            for e in entries {
+                let r = apply(self.engine.as_ref(), &e.op).map_err(|e| e.to_string());
+                let mut st = self.st.lock().unwrap();
+                st.applied = e.index;
+                if let Some(slot) = st.results.get_mut(&e.index) {
+                    *slot = Some((e.term, r));
+                }
+                self.cond.notify_all();
+            }
+            if let Err(e) = self.maybe_snapshot() {
+                warn!(self.logger, "create raft snapshot: {}", e);
+            }
+        }
+    }
+
+    // 调用方持有 apply_lock，此时引擎中的数据正好对应 applied
+    fn maybe_snapshot(&self) -> Result<()> {
+        let meta = {
+            let st = self.st.lock().unwrap();
+            if st.applied - st.log.snapshot.index < self.snapshot_threshold {
+                return Ok(());
+            }
+            SnapshotMeta {
+                index: st.applied,
+                term: st.log.term(st.applied).ok_or("applied entry is missing")?,
+                config: st.log.config_at(st.applied),
+            }
+        };
+        let data = self.engine.snapshot()?.scan("")?;
+        write_snapshot(&self.dir, &meta, &data)?;
+        info!(self.logger, "raft snapshot"; "index" => meta.index, "keys" => data.len());
+        self.st.lock().unwrap().log.compact(meta)
+    }
+
+    // 追加一个条目并等待它被应用，返回应用的结果
+    fn propose(self: &Arc<Self>, op: Op) -> Result<()> {
+        self.propose_with(|_| Ok(op))
+    }
+
+    fn propose_with(self: &Arc<Self>, f: impl FnOnce(&State) -> Result<Op>) -> Result<()> {
+        let mut st = self.st.lock().unwrap();
+        if st.role != Role::Leader || st.stopped {
+            return Err(self.not_leader(&st));
+        }
+        let op = f(&st)?;
+        let term = st.term;
+        let index = st.log.last_index() + 1;
+        if let Op::Config(c) = &op {
+            if st.log.config_index() > st.commit {
+                return Err("another membership change is in progress".into());
+            }
+            st.config = c.clone();
+        }
+        st.log.append(vec![LogEntry { index, term, op }])?;
+        st.results.insert(index, None);
+        self.replicate(&mut st);
+        self.advance_commit(&mut st);
+        let deadline = Instant::now() + PROPOSE_TIMEOUT;
+        loop {
+            if let Some(Some(_)) = st.results.get(&index) {
+                let (t, r) = st.results.remove(&index).flatten().unwrap();
+                if t != term {
+                    return Err("leadership lost, the write was not applied".into());
+                }
+                return r.map_err(Into::into);
+            }
+            if st.term != term || st.stopped || Instant::now() >= deadline {
+                st.results.remove(&index);
+                return Err("leadership lost, the write may not be applied".into());
+            }
+            st = self.cond.wait_timeout(st, HEARTBEAT).unwrap().0;
+        }
+    }
+
+    // 等到可以在本地线性一致地读取：是 leader、持有租约，并且应用到了当前的 commit
+    fn read_barrier(&self) -> Result<()> {
+        let deadline = Instant::now() + PROPOSE_TIMEOUT;
+        let mut st = self.st.lock().unwrap();
+        let mut read_index = None;
+        loop {
+            if st.role != Role::Leader || st.stopped {
+                return Err(self.not_leader(&st));
+            }
+            if read_index.is_none()
+                && st.commit >= st.term_start
+                && st.acked_within(self.id, LEASE) >= st.quorum()
+            {
+                read_index = Some(st.commit);
+            }
+            if read_index.is_some_and(|i| st.applied >= i) {
+                return Ok(());
+            }
+            if Instant::now() >= deadline {
+                return Err(self.not_leader(&st));
+            }
+            st = self.cond.wait_timeout(st, TICK).unwrap().0;
+        }
+    }
+
+    pub fn add_node(self: &Arc<Self>, id: NodeId, addr: String) -> Result<()> {
+        self.propose_with(|st| {
+            if st.config.contains_key(&id) {
+                return Err(format!("node {} is already a member", id).into());
+            }
+            let mut c = st.config.clone();
+            c.insert(id, addr);
+            Ok(Op::Config(c))
+        })
+    }
+
+    pub fn remove_node(self: &Arc<Self>, id: NodeId) -> Result<()> {
+        self.propose_with(|st| {
+            let mut c = st.config.clone();
+            if c.remove(&id).is_none() {
+                return Err(format!("node {} is not a member", id).into());
+            }
+            if c.is_empty() {
+                return Err("cannot remove the last member".into());
+            }
+            Ok(Op::Config(c))
+        })
+    }
+
+    // 对外提供的引擎，所有读写都经过 raft
+    pub fn engine(self: &Arc<Self>) -> Arc<dyn KvsEngine + Sync> {
+        Arc::new(RaftEngine(self.clone()))
+    }
+
+    fn rpc(&self, peer: NodeId, addr: &str, req: Message) -> Result<Message> {
+        if self.is_blocked(peer) || self.st.lock().unwrap().stopped {
+            return Err("partitioned".into());
+        }
+        let idle = self
+            .conns
+            .lock()
+            .unwrap()
+            .get_mut(&peer)
+            .and_then(|c| c.pop());
+        let mut conn = match idle {
+            Some(c) => c,
+            None => self.connect(addr)?,
+        };
+        let s = call(&mut conn, &Command::Raft(req))?;
+        let reply: Message = serde_json::from_str(&s).map_err(|_| s.trim_end().to_owned())?;
+        // 请求进行中发生了分区，回复也要丢弃
+        if self.is_blocked(peer) {
+            return Err("partitioned".into());
+        }
+        self.conns
+            .lock()
+            .unwrap()
+            .entry(peer)
+            .or_default()
+            .push(conn);
+        Ok(reply)
+    }
+
+    fn connect(&self, addr: &str) -> Result<BufReader<Stream>> {
+        let a = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or("address resolves to nothing")?;
+        let s = TcpStream::connect_timeout(&a, RPC_TIMEOUT)?;
+        s.set_read_timeout(Some(RPC_TIMEOUT))?;
+        s.set_write_timeout(Some(RPC_TIMEOUT))?;
+        s.set_nodelay(true)?;
+        let s = match &self.opts.tls {
+            Some(config) => {
+                Stream::start_tls(s, config.clone(), self.opts.tls_server_name.as_deref())?
+            }
+            None => Stream::Tcp(s),
+        };
+        let mut conn = BufReader::new(s);
+        if let Some(password) = &self.opts.password {
+            let auth = Command::Auth(self.opts.user.clone(), password.clone());
+            let reply = call(&mut conn, &auth)?;
+            if reply.trim_end() != "Success" {
+                return Err(format!("authenticate to {}: {}", addr, reply.trim_end()).into());
+            }
+        }
+        Ok(conn)
+    }
+}
+
+// 发送一个请求，返回一行回复
+fn call(conn: &mut BufReader<Stream>, c: &Command) -> Result<String> {
+    let mut line = serde_json::to_vec(c)?;
+    line.push(b'\n');
+    conn.get_mut().write_all(&line)?;
+    let mut s = String::new();
+    if conn.read_line(&mut s)? == 0 {
+        return Err("connection closed".into());
+    }
+    Ok(s)
+}
+
+fn apply(engine: &dyn KvsEngine, op: &Op) -> Result<()> {
+    match op {
+        Op::Noop | Op::Config(_) => Ok(()),
+        Op::Set(k, v) => engine.set(k.clone(), v.clone()),
+        Op::Remove(k) => engine.remove(k.clone()),
+        Op::Txn { reads, writes } => {
+            for (k, v) in reads {
+                if engine.get(k.clone())? != *v {
+                    return Err(TXN_CONFLICT.into());
+                }
+            }
+            for c in writes {
+                match c {
+                    Command::Set(k, v) => engine.set(k.clone(), v.clone())?,
+                    // 事务中先 set 再 rm 的 key 原来可能不存在
+                    Command::Rm(k) => match engine.remove(k.clone()) {
+                        Err(e) if e.to_string() == KEY_NOT_FOUND => {}
+                        r => r?,
+                    },
+                    _ => return Err("unexpected command in transaction".into()),
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+// 用 snapshot 的数据替换引擎中的所有数据
+fn restore(engine: &dyn KvsEngine, data: Vec<(String, String)>) -> Result<()> {
+    let keys: HashSet<&String> = data.iter().map(|(k, _)| k).collect();
+    for k in engine.keys("")? {
+        if !keys.contains(&k) {
+            engine.remove(k)?;
+        }
+    }
+    for (k, v) in data {
+        if engine.get(k.clone())?.as_ref() != Some(&v) {
+            engine.set(k, v)?;
+        }
+    }
+    Ok(())
+}
+
+fn write_snapshot(dir: &Path, meta: &SnapshotMeta, data: &[(String, String)]) -> Result<()> {
+    let tmp = dir.join(SNAPSHOT.to_owned() + ".tmp");
+    let mut w = BufWriter::new(File::create(&tmp)?);
+    serde_json::to_writer(&mut w, meta)?;
+    w.write_all(b"\n")?;
+    for kv in data {
+        serde_json::to_writer(&mut w, kv)?;
+        w.write_all(b"\n")?;
+    }
+    w.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    fs::rename(&tmp, dir.join(SNAPSHOT))?;
+    Ok(())
+}
+
+type SnapshotData = (SnapshotMeta, Vec<(String, String)>);
+
+fn read_snapshot(dir: &Path) -> Result<Option<SnapshotData>> {
+    let f = match File::open(dir.join(SNAPSHOT)) {
+        Ok(f) => f,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(Box::new(e)),
+    };
+    let mut lines = BufReader::new(f).lines();
+    let meta: SnapshotMeta = match lines.next() {
+        Some(l) => serde_json::from_str(&l?)?,
+        None => return Err("snapshot is empty".into()),
+    };
+    let mut data = vec![];
+    for l in lines {
+        data.push(serde_json::from_str(&l?)?);
+    }
+    Ok(Some((meta, data)))
+}
+
+pub struct RaftEngine(Arc<RaftNode>);
+
+impl KvsEngine for RaftEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.0.propose(Op::Set(key, value))
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.0.read_barrier()?;
+        self.0.engine.get(key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.0.propose(Op::Remove(key))
+    }
+
+    fn keys(&self, prefix: &str) -> Result<Vec<String>> {
+        self.0.read_barrier()?;
+        self.0.engine.keys(prefix)
+    }
+
+    fn snapshot(&self) -> Result<Box<dyn KvsSnapshot>> {
+        self.0.read_barrier()?;
+        self.0.engine.snapshot()
+    }
+
+    fn begin(&self) -> Result<Box<dyn Transaction>> {
+        Ok(Box::new(RaftTransaction {
+            node: self.0.clone(),
+            reads: BTreeMap::new(),
+            writes: BTreeMap::new(),
+        }))
+    }
+
+    // 事件在日志应用到本地引擎时发布，follower 上也可以订阅，只是可能落后于 leader
+    fn watch(&self) -> Result<Arc<Watch>> {
+        self.0.engine.watch()
+    }
+
+    // 本地引擎的状态，follower 上的 key 数量可能落后于 leader
+    fn info(&self) -> Result<EngineInfo> {
+        self.0.engine.info()
+    }
+
+    // 只整理本地引擎的数据文件，raft 日志由 snapshot 截断
+    fn compact(&self) -> Result<CompactionReport> {
+        self.0.engine.compact()
+    }
+}
+
+// 读到的值和写入都缓存在本地，提交时作为一个 Op::Txn 条目，应用时再检查读到的值有没有变化
+struct RaftTransaction {
+    node: Arc<RaftNode>,
+    reads: BTreeMap<String, Option<String>>,
+    writes: BTreeMap<String, Option<String>>,
+}
+
+impl Transaction for RaftTransaction {
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        if let Some(v) = self.writes.get(&key) {
+            return Ok(v.clone());
+        }
+        if let Some(v) = self.reads.get(&key) {
+            return Ok(v.clone());
+        }
+        self.node.read_barrier()?;
+        let v = self.node.engine.get(key.clone())?;
+        self.reads.insert(key, v.clone());
+        Ok(v)
+    }
+
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.writes.insert(key, Some(value));
+        Ok(())
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        if self.get(key.clone())?.is_none() {
+            return Err(KEY_NOT_FOUND.into());
+        }
+        self.writes.insert(key, None);
+        Ok(())
+    }
+
+    fn commit(self: Box<Self>) -> Result<()> {
+        if self.writes.is_empty() {
+            return Ok(());
+        }
+        let writes = self
+            .writes
+            .into_iter()
+            .map(|(k, v)| match v {
+                Some(v) => Command::Set(k, v),
+                None => Command::Rm(k),
+            })
+            .collect();
+        self.node.propose(Op::Txn {
+            reads: self.reads.into_iter().collect(),
+            writes,
+        })
+    }
+}
@@ -0,0 +1,660 @@
+// 主从复制
+//
+// primary 的写入都经过 Primary，在写入引擎的同时追加到内存中的 backlog，每条写入有递增的 offset
+// 复制的是 set/rm 的变更流而不是 log 文件本身，所以 kvs 和 sled 两种引擎都可以复制
+// replica 连接之后发送 Command::Sync(run_id, offset)：
+//   run_id 和 primary 一致并且 offset 之后的写入还在 backlog 中时从 offset 继续（部分同步）
+//   否则先发送一个快照（全量同步），再从快照对应的 offset 继续
+// primary 重启之后 run_id 会变化，replica 重新全量同步
+//
+// replica 把应用到的 run_id 和 offset 保存在数据目录的 REPLICA_STATE 文件中，断线或者重启之后从这里继续
+// 重复应用 set/rm 的结果不变，所以 offset 在应用之后保存，崩溃时最多重放一部分写入
+// primary 开启了 TLS 或者认证时，replica 用 ReplicaOptions 中的 CA 和用户连接，用户需要能读所有的 key
+use std::{
+    collections::{HashSet, VecDeque},
+    fs,
+    io::{BufRead, BufReader, Write},
+    net::{Shutdown, TcpStream},
+    path::{Path, PathBuf},
+    sync::{Arc, Condvar, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+use rustls::ClientConfig;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use slog::{info, warn, Logger};
+
+use crate::net::Stream;
+use crate::raft::RaftNode;
+use crate::{
+    Command, CompactionReport, EngineInfo, KvsEngine, KvsSnapshot, Result, Transaction, Watch,
+    KEY_NOT_FOUND,
+};
+
+pub const REPLICA_STATE: &str = "replica.json";
+// replica 上的写操作返回的错误
+pub const READ_ONLY_REPLICA: &str = "READONLY You can't write against a read only replica";
+// 没有写入时 primary 发送心跳的间隔，replica 超过 TIMEOUT 没有收到任何消息就重连
+const HEARTBEAT: Duration = Duration::from_secs(1);
+const TIMEOUT: Duration = Duration::from_secs(5);
+// 一次最多发送的写入条数
+const BATCH: usize = 1000;
+
+// primary 发给 replica 的消息，每行一个 json
+#[derive(Serialize, Deserialize, Debug)]
+pub enum ReplMessage {
+    // 全量同步，之后是快照中所有的 key value，以 SnapshotEnd 结束，快照包含 offset 之前的所有写入
+    FullSync { run_id: String, offset: u64 },
+    Snapshot(String, String),
+    SnapshotEnd,
+    // 部分同步，从 replica 给出的 offset 之后继续
+    Continue { run_id: String },
+    // 一条写入和写入之后的 offset
+    Write(u64, Command),
+    // primary 当前的 offset，每批写入之前和空闲时发送，replica 用来计算延迟
+    Ping(u64),
+}
+
+struct Backlog {
+    run_id: String,
+    // 最后一条写入的 offset
+    offset: u64,
+    entries: VecDeque<(u64, Command)>,
+    capacity: usize,
+    replicas: usize,
+}
+
+impl Backlog {
+    fn push(&mut self, c: Command) {
+        self.offset += 1;
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back((self.offset, c));
+    }
+
+    // backlog 可以从 offset 之后继续发送
+    fn covers(&self, offset: u64) -> bool {
+        let start = self.entries.front().map_or(self.offset, |e| e.0 - 1);
+        start <= offset && offset <= self.offset
+    }
+}
+
+struct Shared {
+    backlog: Mutex<Backlog>,
+    cond: Condvar,
+}
+
+// 包装 primary 的引擎，写入引擎和追加 backlog 在同一把锁下完成
+// 所以 backlog 中的顺序和引擎中的顺序一致，快照也对应一个确定的 offset
+#[derive(Clone)]
+pub struct Primary {
+    engine: Arc<dyn KvsEngine + Sync>,
+    shared: Arc<Shared>,
+}
+
+impl Primary {
+    // backlog 保存最近 capacity 条写入，断开时间更长的 replica 需要全量同步
+    pub fn new(engine: Arc<dyn KvsEngine + Sync>, capacity: usize) -> Self {
+        let mut id = [0; 16];
+        OsRng.fill_bytes(&mut id);
+        Primary {
+            engine,
+            shared: Arc::new(Shared {
+                backlog: Mutex::new(Backlog {
+                    run_id: id.iter().map(|b| format!("{:02x}", b)).collect(),
+                    offset: 0,
+                    entries: VecDeque::new(),
+                    capacity: capacity.max(1),
+                    replicas: 0,
+                }),
+                cond: Condvar::new(),
+            }),
+        }
+    }
+
+    fn write<T>(&self, cmds: Vec<Command>, f: impl FnOnce() -> Result<T>) -> Result<T> {
+        let mut b = self.shared.backlog.lock().unwrap();
+        let r = f()?;
+        for c in cmds {
+            b.push(c);
+        }
+        self.shared.cond.notify_all();
+        Ok(r)
+    }
+
+    pub fn role(&self) -> serde_json::Value {
+        let b = self.shared.backlog.lock().unwrap();
+        json!({
+            "role": "primary",
+            "run_id": b.run_id,
+            "offset": b.offset,
+            "backlog": b.entries.len(),
+            "replicas": b.replicas,
+        })
+    }
+
+    // 处理一个 replica 的 Sync 请求，之后一直发送写入直到连接断开
+    pub fn serve_replica(
+        &self,
+        run_id: Option<String>,
+        offset: u64,
+        mut w: impl Write,
+        logger: &Logger,
+    ) -> Result<()> {
+        let (mut next, snapshot) = {
+            let mut b = self.shared.backlog.lock().unwrap();
+            b.replicas += 1;
+            if run_id.as_deref() == Some(b.run_id.as_str()) && b.covers(offset) {
+                (offset, None)
+            } else {
+                (b.offset, Some((b.run_id.clone(), self.engine.snapshot())))
+            }
+        };
+        let r = self.stream(&mut next, snapshot, &mut w, logger);
+        self.shared.backlog.lock().unwrap().replicas -= 1;
+        info!(logger, "replica disconnected"; "offset" => next);
+        r
+    }
+
+    fn stream(
+        &self,
+        next: &mut u64,
+        snapshot: Option<(String, Result<Box<dyn KvsSnapshot>>)>,
+        w: &mut impl Write,
+        logger: &Logger,
+    ) -> Result<()> {
+        match snapshot {
+            Some((run_id, snapshot)) => {
+                info!(logger, "full sync"; "offset" => *next);
+                let offset = *next;
+                send(w, &ReplMessage::FullSync { run_id, offset })?;
+                for (k, v) in snapshot?.scan("")? {
+                    send(w, &ReplMessage::Snapshot(k, v))?;
+                }
+                send(w, &ReplMessage::SnapshotEnd)?;
+            }
+            None => {
+                info!(logger, "partial sync"; "offset" => *next);
+                let run_id = self.shared.backlog.lock().unwrap().run_id.clone();
+                send(w, &ReplMessage::Continue { run_id })?;
+            }
+        }
+        w.flush()?;
+        loop {
+            let (current, batch) = {
+                let b = self.shared.backlog.lock().unwrap();
+                let (b, _) = self
+                    .shared
+                    .cond
+                    .wait_timeout_while(b, HEARTBEAT, |b| b.offset == *next)
+                    .unwrap();
+                // replica 太慢，需要的写入已经被挤出 backlog，断开之后它会重新全量同步
+                if !b.covers(*next) {
+                    return Err("replica fell behind the replication backlog".into());
+                }
+                let batch: Vec<(u64, Command)> = b
+                    .entries
+                    .iter()
+                    .skip_while(|e| e.0 <= *next)
+                    .take(BATCH)
+                    .map(|(o, c)| (*o, c.clone()))
+                    .collect();
+                (b.offset, batch)
+            };
+            send(w, &ReplMessage::Ping(current))?;
+            for (o, c) in batch {
+                *next = o;
+                send(w, &ReplMessage::Write(o, c))?;
+            }
+            w.flush()?;
+        }
+    }
+}
+
+fn send(w: &mut impl Write, m: &ReplMessage) -> Result<()> {
+    let mut line = serde_json::to_vec(m)?;
+    line.push(b'\n');
+    w.write_all(&line)?;
+    Ok(())
+}
+
+impl KvsEngine for Primary {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        let c = Command::Set(key.clone(), value.clone());
+        self.write(vec![c], || self.engine.set(key, value))
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.engine.get(key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        let c = Command::Rm(key.clone());
+        self.write(vec![c], || self.engine.remove(key))
+    }
+
+    fn keys(&self, prefix: &str) -> Result<Vec<String>> {
+        self.engine.keys(prefix)
+    }
+
+    fn snapshot(&self) -> Result<Box<dyn KvsSnapshot>> {
+        self.engine.snapshot()
+    }
+
+    fn begin(&self) -> Result<Box<dyn Transaction>> {
+        Ok(Box::new(PrimaryTransaction {
+            primary: self.clone(),
+            txn: self.engine.begin()?,
+            writes: vec![],
+        }))
+    }
+
+    fn watch(&self) -> Result<Arc<Watch>> {
+        self.engine.watch()
+    }
+
+    fn info(&self) -> Result<EngineInfo> {
+        self.engine.info()
+    }
+
+    // 不改变数据，不需要复制给 replica
+    fn compact(&self) -> Result<CompactionReport> {
+        self.engine.compact()
+    }
+}
+
+// 记录事务中的写入，提交成功之后一起追加到 backlog
+struct PrimaryTransaction {
+    primary: Primary,
+    txn: Box<dyn Transaction>,
+    writes: Vec<Command>,
+}
+
+impl Transaction for PrimaryTransaction {
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.txn.get(key)
+    }
+
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.txn.set(key.clone(), value.clone())?;
+        self.writes.push(Command::Set(key, value));
+        Ok(())
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        self.txn.remove(key.clone())?;
+        self.writes.push(Command::Rm(key));
+        Ok(())
+    }
+
+    fn commit(self: Box<Self>) -> Result<()> {
+        let PrimaryTransaction {
+            primary,
+            txn,
+            writes,
+        } = *self;
+        primary.write(writes, || txn.commit())
+    }
+}
+
+// replica 对外提供的引擎，读操作直接转发，写操作返回 READ_ONLY_REPLICA
+pub struct ReadOnly(pub Arc<dyn KvsEngine + Sync>);
+
+impl KvsEngine for ReadOnly {
+    fn set(&self, _key: String, _value: String) -> Result<()> {
+        Err(READ_ONLY_REPLICA.into())
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.0.get(key)
+    }
+
+    fn remove(&self, _key: String) -> Result<()> {
+        Err(READ_ONLY_REPLICA.into())
+    }
+
+    fn keys(&self, prefix: &str) -> Result<Vec<String>> {
+        self.0.keys(prefix)
+    }
+
+    fn snapshot(&self) -> Result<Box<dyn KvsSnapshot>> {
+        self.0.snapshot()
+    }
+
+    fn begin(&self) -> Result<Box<dyn Transaction>> {
+        Ok(Box::new(ReadOnlyTransaction(self.0.begin()?)))
+    }
+
+    // 复制过来的写入同样会发布，可以在 replica 上订阅
+    fn watch(&self) -> Result<Arc<Watch>> {
+        self.0.watch()
+    }
+
+    fn info(&self) -> Result<EngineInfo> {
+        self.0.info()
+    }
+
+    // replica 上的数据文件单独整理
+    fn compact(&self) -> Result<CompactionReport> {
+        self.0.compact()
+    }
+}
+
+struct ReadOnlyTransaction(Box<dyn Transaction>);
+
+impl Transaction for ReadOnlyTransaction {
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.0.get(key)
+    }
+
+    fn set(&mut self, _key: String, _value: String) -> Result<()> {
+        Err(READ_ONLY_REPLICA.into())
+    }
+
+    fn remove(&mut self, _key: String) -> Result<()> {
+        Err(READ_ONLY_REPLICA.into())
+    }
+
+    fn commit(self: Box<Self>) -> Result<()> {
+        Ok(())
+    }
+}
+
+// 保存在 REPLICA_STATE 中的复制进度
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+struct ReplicaState {
+    run_id: Option<String>,
+    offset: u64,
+}
+
+#[derive(Serialize, Default, Clone, Debug)]
+pub struct ReplicaStatus {
+    pub primary: String,
+    pub connected: bool,
+    pub run_id: Option<String>,
+    // 已经应用的 offset
+    pub offset: u64,
+    // 最近一次从 primary 得知的 offset
+    pub primary_offset: u64,
+    pub full_syncs: u64,
+    pub partial_syncs: u64,
+    // 距离上次收到 primary 消息的毫秒数
+    pub last_contact_ms: Option<u64>,
+}
+
+impl ReplicaStatus {
+    // 落后 primary 的写入条数
+    pub fn lag(&self) -> u64 {
+        self.primary_offset.saturating_sub(self.offset)
+    }
+}
+
+// replica 连接 primary 的方式，默认是不认证的明文 TCP
+#[derive(Clone, Default)]
+pub struct ReplicaOptions {
+    // 指定时用 TLS 连接，server_name 为空时用 IP 地址验证 primary 的证书
+    pub tls: Option<Arc<ClientConfig>>,
+    pub tls_server_name: Option<String>,
+    // 连接之后先 AUTH，没有用户名时 password 作为 token
+    pub user: Option<String>,
+    pub password: Option<String>,
+}
+
+pub struct Replica {
+    primary: String,
+    opts: ReplicaOptions,
+    engine: Arc<dyn KvsEngine + Sync>,
+    state_file: PathBuf,
+    status: Mutex<ReplicaStatus>,
+    last_contact: Mutex<Option<Instant>>,
+    // 当前到 primary 的连接，stop 时关闭
+    conn: Mutex<Option<TcpStream>>,
+    stopped: Mutex<bool>,
+}
+
+impl Replica {
+    // 在后台线程中从 primary 复制数据到 engine，断开之后每秒重连一次
+    // dir 是 engine 的数据目录，复制进度保存在其中的 REPLICA_STATE 文件
+    pub fn start(
+        primary: &str,
+        engine: Arc<dyn KvsEngine + Sync>,
+        dir: &Path,
+        logger: Logger,
+    ) -> Result<Arc<Replica>> {
+        Self::with_options(primary, engine, dir, logger, ReplicaOptions::default())
+    }
+
+    pub fn with_options(
+        primary: &str,
+        engine: Arc<dyn KvsEngine + Sync>,
+        dir: &Path,
+        logger: Logger,
+        opts: ReplicaOptions,
+    ) -> Result<Arc<Replica>> {
+        let state_file = dir.join(REPLICA_STATE);
+        let state: ReplicaState = match fs::read(&state_file) {
+            Ok(b) => serde_json::from_slice(&b)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => ReplicaState::default(),
+            Err(e) => return Err(Box::new(e)),
+        };
+        let replica = Arc::new(Replica {
+            primary: primary.to_owned(),
+            opts,
+            engine,
+            state_file,
+            status: Mutex::new(ReplicaStatus {
+                primary: primary.to_owned(),
+                run_id: state.run_id,
+                offset: state.offset,
+                primary_offset: state.offset,
+                ..ReplicaStatus::default()
+            }),
+            last_contact: Mutex::new(None),
+            conn: Mutex::new(None),
+            stopped: Mutex::new(false),
+        });
+        let r = replica.clone();
+        thread::spawn(move || loop {
+            if *r.stopped.lock().unwrap() {
+                return;
+            }
+            if let Err(e) = r.sync(&logger) {
+                warn!(logger, "replication from {} stopped: {}", r.primary, e);
+            }
+            r.status.lock().unwrap().connected = false;
+            thread::sleep(Duration::from_secs(1));
+        });
+        Ok(replica)
+    }
+
+    pub fn status(&self) -> ReplicaStatus {
+        let mut s = self.status.lock().unwrap().clone();
+        s.last_contact_ms = self
+            .last_contact
+            .lock()
+            .unwrap()
+            .map(|t| t.elapsed().as_millis() as u64);
+        s
+    }
+
+    pub fn role(&self) -> serde_json::Value {
+        let s = self.status();
+        let mut v = json!(s);
+        v["role"] = "replica".into();
+        v["lag"] = s.lag().into();
+        v
+    }
+
+    // 停止复制并断开连接，已经应用的数据保留
+    pub fn stop(&self) {
+        *self.stopped.lock().unwrap() = true;
+        if let Some(c) = self.conn.lock().unwrap().take() {
+            let _ = c.shutdown(Shutdown::Both);
+        }
+    }
+
+    fn sync(&self, logger: &Logger) -> Result<()> {
+        let tcp = TcpStream::connect(&self.primary)?;
+        tcp.set_read_timeout(Some(TIMEOUT))?;
+        // 保留底层的 TCP 连接，TLS 连接上的读持有锁，stop 时直接关闭 socket
+        *self.conn.lock().unwrap() = Some(tcp.try_clone()?);
+        if *self.stopped.lock().unwrap() {
+            return Ok(());
+        }
+        let stream = match &self.opts.tls {
+            Some(config) => {
+                Stream::start_tls(tcp, config.clone(), self.opts.tls_server_name.as_deref())?
+            }
+            None => Stream::Tcp(tcp),
+        };
+        let mut w = stream.try_clone()?;
+        let mut r = BufReader::new(stream);
+        let mut send = |c: &Command| -> Result<()> {
+            let mut line = serde_json::to_vec(c)?;
+            line.push(b'\n');
+            w.write_all(&line)?;
+            Ok(())
+        };
+        if let Some(password) = &self.opts.password {
+            send(&Command::Auth(self.opts.user.clone(), password.clone()))?;
+            let mut reply = String::new();
+            r.read_line(&mut reply)?;
+            if reply.trim_end() != "Success" {
+                return Err(format!("authenticate to primary: {}", reply.trim_end()).into());
+            }
+        }
+        let (run_id, offset) = {
+            let s = self.status.lock().unwrap();
+            (s.run_id.clone(), s.offset)
+        };
+        send(&Command::Sync(run_id, offset))?;
+
+        // 进行中的全量同步：快照对应的 run_id、offset 和收到的 key
+        // 收到 SnapshotEnd 之后才更新 status，中途断开时 status 中没有 run_id，下次重新全量同步
+        let mut snapshot: Option<(String, u64, HashSet<String>)> = None;
+        let mut saved = Instant::now();
+        loop {
+            let mut s = String::new();
+            if r.read_line(&mut s)? == 0 {
+                return Err("connection closed by primary".into());
+            }
+            *self.last_contact.lock().unwrap() = Some(Instant::now());
+            let m: ReplMessage = match serde_json::from_str(&s) {
+                Ok(m) => m,
+                // 不是复制消息，一般是认证失败之类的错误回复
+                Err(_) => return Err(s.trim_end().into()),
+            };
+            match m {
+                ReplMessage::FullSync { run_id, offset } => {
+                    info!(logger, "full sync from {}", self.primary; "offset" => offset);
+                    // 全量同步完成之前本地数据不对应任何 offset
+                    self.save(&ReplicaState::default())?;
+                    let mut st = self.status.lock().unwrap();
+                    st.connected = true;
+                    st.full_syncs += 1;
+                    st.run_id = None;
+                    st.primary_offset = offset;
+                    snapshot = Some((run_id, offset, HashSet::new()));
+                }
+                ReplMessage::Snapshot(k, v) => {
+                    let keys = &mut snapshot.as_mut().ok_or("snapshot without full sync")?.2;
+                    keys.insert(k.clone());
+                    self.engine.set(k, v)?;
+                }
+                ReplMessage::SnapshotEnd => {
+                    let (run_id, offset, keys) =
+                        snapshot.take().ok_or("snapshot end without full sync")?;
+                    for k in self.engine.keys("")? {
+                        if !keys.contains(&k) {
+                            apply(self.engine.as_ref(), Command::Rm(k))?;
+                        }
+                    }
+                    {
+                        let mut st = self.status.lock().unwrap();
+                        st.run_id = Some(run_id);
+                        st.offset = offset;
+                    }
+                    self.save_status()?;
+                    saved = Instant::now();
+                }
+                ReplMessage::Continue { run_id } => {
+                    info!(logger, "partial sync from {}", self.primary; "offset" => offset);
+                    let mut st = self.status.lock().unwrap();
+                    st.connected = true;
+                    st.partial_syncs += 1;
+                    st.run_id = Some(run_id);
+                }
+                ReplMessage::Write(offset, c) => {
+                    if snapshot.is_some() {
+                        return Err("write before the end of the snapshot".into());
+                    }
+                    apply(self.engine.as_ref(), c)?;
+                    self.status.lock().unwrap().offset = offset;
+                    if saved.elapsed() > Duration::from_millis(100) {
+                        self.save_status()?;
+                        saved = Instant::now();
+                    }
+                }
+                ReplMessage::Ping(offset) => {
+                    self.status.lock().unwrap().primary_offset = offset;
+                    self.save_status()?;
+                    saved = Instant::now();
+                }
+            }
+        }
+    }
+
+    fn save_status(&self) -> Result<()> {
+        let st = {
+            let s = self.status.lock().unwrap();
+            ReplicaState {
+                run_id: s.run_id.clone(),
+                offset: s.offset,
+            }
+        };
+        self.save(&st)
+    }
+
+    // 先写临时文件再 rename，避免崩溃时留下不完整的文件
+    fn save(&self, st: &ReplicaState) -> Result<()> {
+        let tmp = self.state_file.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec(st)?)?;
+        fs::rename(&tmp, &self.state_file)?;
+        Ok(())
+    }
+}
+
+// 重放时删除已经不存在的 key 不是错误
+fn apply(engine: &dyn KvsEngine, c: Command) -> Result<()> {
+    match c {
+        Command::Set(k, v) => engine.set(k, v),
+        Command::Rm(k) => match engine.remove(k) {
+            Err(e) if e.to_string() == KEY_NOT_FOUND => Ok(()),
+            r => r,
+        },
+        c => Err(format!("unexpected command in replication stream: {:?}", c).into()),
+    }
+}
+
+// server 在复制中的角色
+#[derive(Clone)]
+pub enum Replication {
+    Primary(Primary),
+    Replica(Arc<Replica>),
+    // raft 集群中的一个节点
+    Raft(Arc<RaftNode>),
+}
+
+impl Replication {
+    pub fn role(&self) -> serde_json::Value {
+        match self {
+            Replication::Primary(p) => p.role(),
+            Replication::Replica(r) => r.role(),
+            Replication::Raft(n) => n.status(),
+        }
+    }
+}
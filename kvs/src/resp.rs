@@ -0,0 +1,632 @@
+// Redis RESP2 协议兼容模式，redis-cli 和 redis 的客户端库可以直接访问 kvs-server
+// 支持 GET SET DEL EXISTS PING ECHO KEYS SCAN EXPIRE TTL MGET MSET AUTH
+// EXPIRE 设置的过期时间只保存在 server 内存中，server 重启后失效，过期的 key 在下一次访问时删除
+use std::{
+    collections::HashMap,
+    io::{BufRead, Read, Write},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    auth::{AuthError, Perm, Session},
+    net::is_timeout,
+    KvsEngine, Result, KEY_NOT_FOUND, TXN_CONFLICT,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Resp {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Option<Vec<Resp>>),
+}
+
+impl Resp {
+    pub fn ok() -> Resp {
+        Resp::Simple("OK".to_owned())
+    }
+
+    pub fn err(msg: &str) -> Resp {
+        Resp::Error(format!("ERR {}", msg))
+    }
+
+    pub fn bulk(s: String) -> Resp {
+        Resp::Bulk(Some(s.into_bytes()))
+    }
+
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Resp::Simple(s) => out.extend_from_slice(format!("+{}\r\n", s).as_bytes()),
+            Resp::Error(s) => out.extend_from_slice(format!("-{}\r\n", s).as_bytes()),
+            Resp::Integer(i) => out.extend_from_slice(format!(":{}\r\n", i).as_bytes()),
+            Resp::Bulk(None) => out.extend_from_slice(b"$-1\r\n"),
+            Resp::Bulk(Some(b)) => {
+                out.extend_from_slice(format!("${}\r\n", b.len()).as_bytes());
+                out.extend_from_slice(b);
+                out.extend_from_slice(b"\r\n");
+            }
+            Resp::Array(None) => out.extend_from_slice(b"*-1\r\n"),
+            Resp::Array(Some(items)) => {
+                out.extend_from_slice(format!("*{}\r\n", items.len()).as_bytes());
+                for i in items {
+                    i.encode(out);
+                }
+            }
+        }
+    }
+}
+
+// 和 redis 的 proto-max-bulk-len 相同
+pub const MAX_BULK_LEN: i64 = 512 * 1024 * 1024;
+// 一个数组最多的元素个数
+pub const MAX_MULTIBULK_LEN: i64 = 1024 * 1024;
+// 一行最长的字节数，包括 inline command
+const MAX_LINE_LEN: u64 = 64 * 1024;
+// 请求是字符串的数组，回复最多嵌套两层，更深的嵌套说明请求不合法
+const MAX_DEPTH: usize = 8;
+
+// 长度都在分配内存之前检查，客户端给出的长度不能让 server 分配过多的内存
+fn read_line<R: BufRead>(r: &mut R) -> Result<Option<String>> {
+    let mut buf = vec![];
+    if (&mut *r).take(MAX_LINE_LEN).read_until(b'\n', &mut buf)? == 0 {
+        return Ok(None);
+    }
+    if !buf.ends_with(b"\n") && buf.len() as u64 == MAX_LINE_LEN {
+        return Err("too big inline request".into());
+    }
+    if buf.ends_with(b"\r\n") {
+        buf.truncate(buf.len() - 2);
+    } else if buf.ends_with(b"\n") {
+        buf.pop();
+    }
+    Ok(Some(String::from_utf8(buf)?))
+}
+
+// 读取一个 RESP 值，连接关闭返回 None
+// 不是以类型符号开头的行按照 inline command 处理，方便用 telnet 调试
+pub fn read_resp<R: BufRead>(r: &mut R) -> Result<Option<Resp>> {
+    read_value(r, 0)
+}
+
+fn read_value<R: BufRead>(r: &mut R, depth: usize) -> Result<Option<Resp>> {
+    if depth > MAX_DEPTH {
+        return Err("too deeply nested arrays".into());
+    }
+    let line = match read_line(r)? {
+        Some(l) => l,
+        None => return Ok(None),
+    };
+    let (t, rest) = match line.chars().next() {
+        Some(t) => (t, &line[t.len_utf8()..]),
+        None => return Ok(Some(Resp::Array(Some(vec![])))),
+    };
+    let v = match t {
+        '+' => Resp::Simple(rest.to_owned()),
+        '-' => Resp::Error(rest.to_owned()),
+        ':' => Resp::Integer(rest.parse()?),
+        '$' => {
+            let n: i64 = rest.parse()?;
+            if n > MAX_BULK_LEN {
+                return Err("invalid bulk length".into());
+            }
+            if n < 0 {
+                Resp::Bulk(None)
+            } else {
+                // 按实际收到的数据增长，不按客户端给出的长度预先分配
+                let mut buf = vec![];
+                (&mut *r).take(n as u64 + 2).read_to_end(&mut buf)?;
+                if buf.len() < n as usize + 2 {
+                    return Err("connection closed in the middle of a bulk string".into());
+                }
+                buf.truncate(n as usize);
+                Resp::Bulk(Some(buf))
+            }
+        }
+        '*' => {
+            let n: i64 = rest.parse()?;
+            if n > MAX_MULTIBULK_LEN {
+                return Err("invalid multibulk length".into());
+            }
+            if n < 0 {
+                Resp::Array(None)
+            } else {
+                let mut items = vec![];
+                for _ in 0..n {
+                    match read_value(r, depth + 1)? {
+                        Some(v) => items.push(v),
+                        None => return Err("connection closed in the middle of an array".into()),
+                    }
+                }
+                Resp::Array(Some(items))
+            }
+        }
+        _ => Resp::Array(Some(
+            line.split_whitespace()
+                .map(|s| Resp::Bulk(Some(s.as_bytes().to_vec())))
+                .collect(),
+        )),
+    };
+    Ok(Some(v))
+}
+
+pub struct RespHandler {
+    engine: Arc<dyn KvsEngine + Sync>,
+    // key -> 过期时间
+    expires: Mutex<HashMap<String, Instant>>,
+}
+
+impl RespHandler {
+    pub fn new(engine: Arc<dyn KvsEngine + Sync>) -> Self {
+        RespHandler {
+            engine,
+            expires: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // 处理一个连接上的所有命令，直到连接关闭或者收到 QUIT
+    pub fn serve<R: BufRead, W: Write>(
+        &self,
+        mut reader: R,
+        mut writer: W,
+        mut session: Session,
+    ) -> Result<()> {
+        loop {
+            let req = match read_resp(&mut reader) {
+                Ok(Some(r)) => r,
+                Ok(None) => return Ok(()),
+                // 空闲超时直接关闭连接
+                Err(e) if is_timeout(e.as_ref()) => return Err(e),
+                Err(e) => {
+                    let mut out = vec![];
+                    Resp::err(&format!("Protocol error: {}", e)).encode(&mut out);
+                    writer.write_all(&out)?;
+                    return Ok(());
+                }
+            };
+            let args = match req {
+                Resp::Array(Some(items)) => items
+                    .into_iter()
+                    .map(|i| match i {
+                        Resp::Bulk(Some(b)) => Ok(b),
+                        Resp::Simple(s) => Ok(s.into_bytes()),
+                        Resp::Integer(i) => Ok(i.to_string().into_bytes()),
+                        _ => Err(()),
+                    })
+                    .collect::<std::result::Result<Vec<_>, _>>(),
+                _ => Err(()),
+            };
+            let (reply, quit) = match args {
+                Ok(args) if args.is_empty() => continue,
+                Ok(args) => {
+                    let quit = args[0].eq_ignore_ascii_case(b"quit");
+                    (self.call(args, &mut session), quit)
+                }
+                Err(()) => (
+                    Resp::err("Protocol error: expected an array of bulk strings"),
+                    false,
+                ),
+            };
+            let mut out = vec![];
+            reply.encode(&mut out);
+            writer.write_all(&out)?;
+            writer.flush()?;
+            if quit {
+                return Ok(());
+            }
+        }
+    }
+
+    // 执行一条命令，args[0] 是命令名
+    pub fn call(&self, args: Vec<Vec<u8>>, session: &mut Session) -> Resp {
+        let name = String::from_utf8_lossy(&args[0]).to_ascii_lowercase();
+        let args = match args[1..]
+            .iter()
+            .map(|a| String::from_utf8(a.clone()))
+            .collect::<std::result::Result<Vec<String>, _>>()
+        {
+            Ok(a) => a,
+            Err(_) => return Resp::err("keys and values must be valid UTF-8"),
+        };
+        if name == "auth" {
+            let r = match args.as_slice() {
+                [secret] => session.login(None, secret),
+                [user, secret] => session.login(Some(user), secret),
+                _ => return Resp::err("wrong number of arguments for 'auth' command"),
+            };
+            return r.map_or_else(|e| Resp::Error(e.to_string()), |_| Resp::ok());
+        }
+        let r = authorize(session, &name, &args).and_then(|_| self.dispatch(&name, args, session));
+        match r {
+            Ok(r) => r,
+            // 认证错误本身带有错误类型，不需要 ERR 前缀
+            Err(e) if e.is::<AuthError>() => Resp::Error(e.to_string()),
+            Err(e) => Resp::err(&e.to_string()),
+        }
+    }
+
+    fn dispatch(&self, name: &str, mut args: Vec<String>, session: &Session) -> Result<Resp> {
+        let arity_err = || Resp::err(&format!("wrong number of arguments for '{}' command", name));
+        let r = match name {
+            "ping" => match args.len() {
+                0 => Resp::Simple("PONG".to_owned()),
+                1 => Resp::bulk(args.remove(0)),
+                _ => arity_err(),
+            },
+            "echo" if args.len() == 1 => Resp::bulk(args.remove(0)),
+            "quit" => Resp::ok(),
+            // redis-cli 启动时会发送 COMMAND DOCS，返回空数组即可
+            "command" => Resp::Array(Some(vec![])),
+            "select" if args.len() == 1 => match args[0].as_str() {
+                "0" => Resp::ok(),
+                _ => Resp::err("DB index is out of range"),
+            },
+            "get" if args.len() == 1 => Resp::Bulk(self.get(&args[0])?.map(String::into_bytes)),
+            "set" if args.len() >= 2 => self.set(args)?,
+            "del" if !args.is_empty() => {
+                let mut n = 0;
+                for k in args {
+                    if self.remove(k)? {
+                        n += 1;
+                    }
+                }
+                Resp::Integer(n)
+            }
+            "exists" if !args.is_empty() => {
+                let mut n = 0;
+                for k in &args {
+                    if self.get(k)?.is_some() {
+                        n += 1;
+                    }
+                }
+                Resp::Integer(n)
+            }
+            "mget" if !args.is_empty() => {
+                let mut items = vec![];
+                for k in &args {
+                    items.push(Resp::Bulk(self.get(k)?.map(String::into_bytes)));
+                }
+                Resp::Array(Some(items))
+            }
+            "mset" if !args.is_empty() && args.len().is_multiple_of(2) => {
+                let mut pairs = vec![];
+                let mut it = args.into_iter();
+                while let (Some(k), Some(v)) = (it.next(), it.next()) {
+                    pairs.push((k, v));
+                }
+                self.mset(pairs)?;
+                Resp::ok()
+            }
+            "expire" | "pexpire" if args.len() == 2 => {
+                let n: i64 = match args[1].parse() {
+                    Ok(n) => n,
+                    Err(_) => return Ok(Resp::err("value is not an integer or out of range")),
+                };
+                let ms = if name == "expire" {
+                    n.checked_mul(1000)
+                } else {
+                    Some(n)
+                };
+                // 不大于 0 时立即删除，Some(None)
+                let deadline = ms.and_then(|ms| match ms {
+                    ..=0 => Some(None),
+                    ms => deadline(ms as u64).map(Some),
+                });
+                match deadline {
+                    Some(d) => Resp::Integer(self.expire(&args[0], d)? as i64),
+                    None => Resp::err(&format!("invalid expire time in '{}' command", name)),
+                }
+            }
+            "ttl" | "pttl" if args.len() == 1 => {
+                if self.get(&args[0])?.is_none() {
+                    return Ok(Resp::Integer(-2));
+                }
+                match self.expires.lock().unwrap().get(&args[0]) {
+                    Some(t) => {
+                        let left = t.saturating_duration_since(Instant::now());
+                        Resp::Integer(if name == "ttl" {
+                            left.as_secs() as i64
+                        } else {
+                            left.as_millis() as i64
+                        })
+                    }
+                    None => Resp::Integer(-1),
+                }
+            }
+            "keys" if args.len() == 1 => Resp::Array(Some(
+                self.keys(&args[0])?
+                    .into_iter()
+                    .filter(|k| session.can(Perm::Read, k))
+                    .map(Resp::bulk)
+                    .collect(),
+            )),
+            "scan" if !args.is_empty() => self.scan(args, session)?,
+            "echo" | "select" | "get" | "set" | "del" | "exists" | "mget" | "mset" | "expire"
+            | "pexpire" | "ttl" | "pttl" | "keys" | "scan" => arity_err(),
+            _ => Resp::err(&format!("unknown command '{}'", name)),
+        };
+        Ok(r)
+    }
+
+    // key 已经过期时删除并返回 true
+    fn expired(&self, key: &str) -> Result<bool> {
+        let mut expires = self.expires.lock().unwrap();
+        match expires.get(key) {
+            Some(t) if *t <= Instant::now() => {
+                expires.remove(key);
+                drop(expires);
+                self.remove_from_engine(key.to_owned())?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        if self.expired(key)? {
+            return Ok(None);
+        }
+        self.engine.get(key.to_owned())
+    }
+
+    // key 存在并被删除时返回 true
+    fn remove(&self, key: String) -> Result<bool> {
+        if self.expired(&key)? {
+            return Ok(false);
+        }
+        self.expires.lock().unwrap().remove(&key);
+        self.remove_from_engine(key)
+    }
+
+    fn remove_from_engine(&self, key: String) -> Result<bool> {
+        match self.engine.remove(key) {
+            Ok(()) => Ok(true),
+            Err(e) if e.to_string() == KEY_NOT_FOUND => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    // SET key value [NX|XX] [EX seconds|PX milliseconds]
+    fn set(&self, mut args: Vec<String>) -> Result<Resp> {
+        let opts: Vec<String> = args.drain(2..).collect();
+        let value = args.pop().unwrap();
+        let key = args.pop().unwrap();
+        let (mut nx, mut xx, mut ttl) = (false, false, None);
+        let mut it = opts.iter();
+        while let Some(o) = it.next() {
+            match o.to_ascii_lowercase().as_str() {
+                "nx" => nx = true,
+                "xx" => xx = true,
+                unit @ ("ex" | "px") => {
+                    let n: u64 = match it.next().map(|n| n.parse()) {
+                        Some(Ok(n)) if n > 0 => n,
+                        _ => return Ok(Resp::err("invalid expire time in 'set' command")),
+                    };
+                    let ms = if unit == "ex" {
+                        n.checked_mul(1000)
+                    } else {
+                        Some(n)
+                    };
+                    ttl = match ms.and_then(deadline) {
+                        Some(t) => Some(t),
+                        None => return Ok(Resp::err("invalid expire time in 'set' command")),
+                    };
+                }
+                _ => return Ok(Resp::err("syntax error")),
+            }
+        }
+        if nx && xx {
+            return Ok(Resp::err("syntax error"));
+        }
+        if nx || xx {
+            // 检查和写入需要是原子的，用事务实现
+            self.expired(&key)?;
+            let written = retry_txn(|| {
+                let mut txn = self.engine.begin()?;
+                let exists = txn.get(key.clone())?.is_some();
+                if exists == nx {
+                    return Ok(false);
+                }
+                txn.set(key.clone(), value.clone())?;
+                txn.commit()?;
+                Ok(true)
+            })?;
+            if !written {
+                return Ok(Resp::Bulk(None));
+            }
+        } else {
+            self.engine.set(key.clone(), value)?;
+        }
+        let mut expires = self.expires.lock().unwrap();
+        match ttl {
+            Some(t) => expires.insert(key, t),
+            None => expires.remove(&key),
+        };
+        Ok(Resp::ok())
+    }
+
+    // 所有的 key 在一个事务中写入
+    fn mset(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        retry_txn(|| {
+            let mut txn = self.engine.begin()?;
+            for (k, v) in &pairs {
+                txn.set(k.clone(), v.clone())?;
+            }
+            txn.commit()
+        })?;
+        let mut expires = self.expires.lock().unwrap();
+        for (k, _) in &pairs {
+            expires.remove(k);
+        }
+        Ok(())
+    }
+
+    // deadline 为 None 时立即删除
+    fn expire(&self, key: &str, deadline: Option<Instant>) -> Result<bool> {
+        if self.get(key)?.is_none() {
+            return Ok(false);
+        }
+        match deadline {
+            Some(t) => self.expires.lock().unwrap().insert(key.to_owned(), t),
+            None => return self.remove(key.to_owned()),
+        };
+        Ok(true)
+    }
+
+    fn keys(&self, pattern: &str) -> Result<Vec<String>> {
+        let mut keys = vec![];
+        for k in self.engine.keys(glob_prefix(pattern))? {
+            if glob_match(pattern.as_bytes(), k.as_bytes()) && !self.expired(&k)? {
+                keys.push(k);
+            }
+        }
+        Ok(keys)
+    }
+
+    // SCAN cursor [MATCH pattern] [COUNT count]，cursor 是有序 key 列表中的下标
+    fn scan(&self, args: Vec<String>, session: &Session) -> Result<Resp> {
+        let cursor: usize = match args[0].parse() {
+            Ok(c) => c,
+            Err(_) => return Ok(Resp::err("invalid cursor")),
+        };
+        let (mut pattern, mut count) = ("*".to_owned(), 10);
+        let mut it = args[1..].iter();
+        while let Some(o) = it.next() {
+            match (o.to_ascii_lowercase().as_str(), it.next()) {
+                ("match", Some(p)) => pattern = p.clone(),
+                ("count", Some(c)) => match c.parse() {
+                    Ok(c) if c > 0 => count = c,
+                    _ => return Ok(Resp::err("value is not an integer or out of range")),
+                },
+                _ => return Ok(Resp::err("syntax error")),
+            }
+        }
+        let all = self.engine.keys(glob_prefix(&pattern))?;
+        let end = (cursor + count).min(all.len());
+        let mut keys = vec![];
+        for k in all.iter().take(end).skip(cursor) {
+            if glob_match(pattern.as_bytes(), k.as_bytes())
+                && session.can(Perm::Read, k)
+                && !self.expired(k)?
+            {
+                keys.push(Resp::bulk(k.clone()));
+            }
+        }
+        let next = if end >= all.len() { 0 } else { end };
+        Ok(Resp::Array(Some(vec![
+            Resp::bulk(next.to_string()),
+            Resp::Array(Some(keys)),
+        ])))
+    }
+}
+
+// 检查命令访问的每个 key 的权限，KEYS 和 SCAN 只返回有读权限的 key
+fn authorize(session: &Session, name: &str, args: &[String]) -> Result<()> {
+    let (perm, keys): (Perm, Vec<&String>) = match name {
+        "quit" | "ping" | "command" => return Ok(()),
+        "get" | "exists" | "mget" | "ttl" | "pttl" => (Perm::Read, args.iter().collect()),
+        "del" => (Perm::Write, args.iter().collect()),
+        "set" | "expire" | "pexpire" => (Perm::Write, args.iter().take(1).collect()),
+        "mset" => (Perm::Write, args.iter().step_by(2).collect()),
+        _ => (Perm::Read, vec![]),
+    };
+    session.check_auth()?;
+    for k in keys {
+        session.check(perm, k)?;
+    }
+    Ok(())
+}
+
+// 过期时间太大时返回 None，回复 invalid expire time
+// 和 redis 一样，过期时刻的 unix 毫秒时间戳需要在 i64 的范围内
+fn deadline(ms: u64) -> Option<Instant> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64);
+    if ms.checked_add(now)? > i64::MAX as u64 {
+        return None;
+    }
+    Instant::now().checked_add(Duration::from_millis(ms))
+}
+
+// 事务冲突时重试
+fn retry_txn<T>(mut f: impl FnMut() -> Result<T>) -> Result<T> {
+    let mut n = 0;
+    loop {
+        match f() {
+            Err(e) if e.to_string() == TXN_CONFLICT && n < 10 => n += 1,
+            r => return r,
+        }
+    }
+}
+
+// pattern 中第一个通配符之前的部分，用来缩小扫描的范围
+fn glob_prefix(pattern: &str) -> &str {
+    let end = pattern.find(['*', '?', '[', '\\']).unwrap_or(pattern.len());
+    &pattern[..end]
+}
+
+// redis 风格的 glob 匹配，支持 * ? [abc] [^a] [a-z] 和 \ 转义
+// 失配时只回到最后一个 * 多吞一个字符，时间是 O(模式长度 × 字符串长度)
+pub fn glob_match(p: &[u8], s: &[u8]) -> bool {
+    let (mut pi, mut si) = (0, 0);
+    // 最后一个 * 之后的模式位置，和这个 * 目前匹配到的字符串位置
+    let mut star = None;
+    while si < s.len() {
+        if p.get(pi) == Some(&b'*') {
+            pi += 1;
+            star = Some((pi, si));
+            continue;
+        }
+        if let Some(n) = match_one(&p[pi..], s[si]) {
+            pi += n;
+            si += 1;
+            continue;
+        }
+        match star {
+            Some((sp, ss)) => {
+                pi = sp;
+                si = ss + 1;
+                star = Some((sp, si));
+            }
+            None => return false,
+        }
+    }
+    p[pi..].iter().all(|c| *c == b'*')
+}
+
+// p 开头的一个元素（不是 *）是否匹配字符 c，匹配时返回这个元素在模式中的长度
+fn match_one(p: &[u8], c: u8) -> Option<usize> {
+    match p.first()? {
+        b'?' => Some(1),
+        b'[' => {
+            let mut i = 1;
+            let negate = p.get(1) == Some(&b'^');
+            if negate {
+                i += 1;
+            }
+            let mut matched = false;
+            while i < p.len() && p[i] != b']' {
+                if p[i] == b'\\' && i + 1 < p.len() {
+                    matched |= p[i + 1] == c;
+                    i += 2;
+                } else if i + 2 < p.len() && p[i + 1] == b'-' && p[i + 2] != b']' {
+                    let (lo, hi) = (p[i].min(p[i + 2]), p[i].max(p[i + 2]));
+                    matched |= lo <= c && c <= hi;
+                    i += 3;
+                } else {
+                    matched |= p[i] == c;
+                    i += 1;
+                }
+            }
+            // 没有闭合的 [ 按照普通字符处理
+            if i >= p.len() {
+                return (c == b'[').then_some(1);
+            }
+            (matched != negate).then_some(i + 1)
+        }
+        b'\\' if p.len() > 1 => (p[1] == c).then_some(2),
+        l => (*l == c).then_some(1),
+    }
+}
@@ -0,0 +1,568 @@
+use std::{
+    io::{BufRead, BufReader, BufWriter, Write},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use rustls::ServerConfig;
+use serde_json::json;
+use slog::{debug, error, info, o, trace, Logger};
+
+use crate::{
+    auth::{Authenticator, Perm, Session},
+    logging::next_conn_id,
+    metrics::Metrics,
+    net::{is_timeout, Addr, Listener, Stream, TLS_HANDSHAKE_TIMEOUT},
+    proxy::Proxy,
+    pubsub::{PubSub, PubSubMessage},
+    replication::Replication,
+    resp::RespHandler,
+    slowlog::SlowLog,
+    thread_pool::ThreadPool,
+    watch::WatchMessage,
+    Command, KvsEngine, Result, Transaction, Watcher, KEY_NOT_FOUND,
+};
+
+// WATCH 和 SUBSCRIBE 连接上没有消息时发送 Ping 的间隔
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+
+// SUBSCRIBE 连接上客户端这么久没有读取时断开
+const PUBSUB_WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// 连接上这么久没有新的请求时关闭，空闲的连接不会一直占用线程池
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+// 客户端和 server 之间使用的协议
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum Protocol {
+    // 每行一个 json 编码的 Command，kvs-client 使用
+    #[default]
+    Kvs,
+    // redis 的 RESP2 协议
+    Resp,
+}
+
+#[derive(Clone)]
+pub struct ServerOptions {
+    pub protocol: Protocol,
+    // unix socket 文件的权限，只有有权限的用户可以连接
+    pub socket_mode: u32,
+    // 不为空时 TCP 连接使用 TLS，见 tls::server_config
+    pub tls: Option<Arc<ServerConfig>>,
+    // 不为空时客户端需要先认证，并且只能访问有权限的 key
+    pub auth: Option<Arc<Authenticator>>,
+    // primary 接受 replica 的 Sync，两者都可以用 Role 查询复制状态
+    pub replication: Option<Replication>,
+    // kvs-proxy 接受 AddBackend，Role 返回后端列表
+    pub proxy: Option<Arc<Proxy>>,
+    // 所有连接共享的频道，Publish 发布的消息发送给这里的订阅者
+    pub pubsub: Arc<PubSub>,
+    // 请求数、延迟、连接数和线程池的状态，见 metrics
+    pub metrics: Arc<Metrics>,
+    // 超过阈值的命令，SLOWLOG GET 读取
+    pub slowlog: Arc<SlowLog>,
+    // 等待下一个请求的最长时间，None 时不关闭空闲的连接
+    pub idle_timeout: Option<Duration>,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        ServerOptions {
+            protocol: Protocol::Kvs,
+            socket_mode: 0o660,
+            tls: None,
+            auth: None,
+            replication: None,
+            proxy: None,
+            pubsub: Arc::new(PubSub::default()),
+            metrics: Arc::new(Metrics::default()),
+            slowlog: Arc::new(SlowLog::default()),
+            idle_timeout: Some(IDLE_TIMEOUT),
+        }
+    }
+}
+
+pub struct KvsServer<P: ThreadPool> {
+    engine: Arc<dyn KvsEngine + Sync>,
+    pool: P,
+    logger: Logger,
+    opts: ServerOptions,
+}
+
+impl<P: ThreadPool> KvsServer<P> {
+    pub fn new(engine: Arc<dyn KvsEngine + Sync>, pool: P, logger: Logger) -> Self {
+        Self::with_options(engine, pool, logger, ServerOptions::default())
+    }
+
+    pub fn with_options(
+        engine: Arc<dyn KvsEngine + Sync>,
+        pool: P,
+        logger: Logger,
+        opts: ServerOptions,
+    ) -> Self {
+        KvsServer {
+            engine,
+            pool,
+            logger,
+            opts,
+        }
+    }
+
+    pub fn run(&self, addr: Addr) -> Result<()> {
+        if self.opts.tls.is_some() && matches!(addr, Addr::Unix(_)) {
+            return Err("TLS is only supported on TCP addresses".into());
+        }
+        let listener = Listener::bind(&addr, self.opts.socket_mode)?;
+        info!(self.logger, "server is started"; "addr" => %addr, "protocol" => ?self.opts.protocol);
+        // 过期时间等 RESP 的状态在所有连接之间共享
+        let resp = Arc::new(RespHandler::new(self.engine.clone()));
+        loop {
+            match listener.accept() {
+                Ok(stream) => {
+                    // 通过原子引用计数在多线程共享数据
+                    let engine = self.engine.clone();
+                    let logger = self.logger.new(o!("conn" => next_conn_id()));
+                    let resp = resp.clone();
+                    let opts = self.opts.clone();
+                    let session = Session::new(self.opts.auth.clone(), stream.peer_addr());
+                    let metrics = self.opts.metrics.clone();
+                    metrics.pool_queued.inc();
+                    let scope = logger.clone();
+                    // 线程池在连接的日志上下文中执行 job，见 logging
+                    slog_scope::scope(&scope, || {
+                        self.pool.spawn(move || {
+                            metrics.pool_queued.dec();
+                            let _busy = metrics.pool_busy.track();
+                            let _conn = metrics.connections.track();
+                            let r = connection(engine, &resp, stream, session, opts, &logger);
+                            if let Err(e) = r {
+                                error!(logger, "connection error: {}", e);
+                            }
+                        })
+                    });
+                }
+                Err(e) => error!(self.logger, "accept error: {}", e),
+            }
+        }
+    }
+}
+
+// TLS 握手在线程池中进行，慢的客户端不会阻塞 accept
+// 一个连接在线程池中占用一个线程，直到连接关闭、空闲超时或者变成推送消息的长连接
+fn connection(
+    engine: Arc<dyn KvsEngine + Sync>,
+    resp: &RespHandler,
+    stream: Stream,
+    mut session: Session,
+    opts: ServerOptions,
+    logger: &Logger,
+) -> Result<()> {
+    let stream = match &opts.tls {
+        Some(config) => {
+            // 空闲超时比握手的上限还短时，握手也不能超过空闲超时
+            let timeout = opts
+                .idle_timeout
+                .map_or(TLS_HANDSHAKE_TIMEOUT, |t| t.min(TLS_HANDSHAKE_TIMEOUT));
+            stream.accept_tls(config.clone(), timeout)?
+        }
+        None => stream,
+    };
+    stream.set_read_timeout(opts.idle_timeout)?;
+    // mTLS 时用客户端证书的 CN 标识这个连接，CN 是已知用户时不需要再 AUTH
+    let logger = match stream.peer_identity() {
+        Some(id) => {
+            session.login_identity(&id);
+            logger.new(o!("identity" => id))
+        }
+        None => logger.clone(),
+    };
+    debug!(logger, "connection accepted"; "peer" => session.peer());
+    let writer = stream.try_clone()?;
+    let reader = BufReader::new(stream);
+    let r = match opts.protocol {
+        Protocol::Kvs => serve(engine, reader, writer, session, opts, logger.clone(), false),
+        Protocol::Resp => resp.serve(reader, writer, session),
+    };
+    match r {
+        Err(e) if is_timeout(e.as_ref()) => {
+            debug!(logger, "idle connection closed");
+            Ok(())
+        }
+        r => r,
+    }
+}
+
+// WATCH、SUBSCRIBE、复制和 raft 节点之间的连接会一直存在，在单独的线程中处理，不占用线程池
+fn dedicated(
+    name: &str,
+    opts: &ServerOptions,
+    logger: Logger,
+    f: impl FnOnce(&Logger) -> Result<()> + Send + 'static,
+) -> Result<()> {
+    let metrics = opts.metrics.clone();
+    thread::Builder::new()
+        .name(name.to_owned())
+        .spawn(move || {
+            let _conn = metrics.connections.track();
+            match f(&logger) {
+                Err(e) if is_timeout(e.as_ref()) => debug!(logger, "idle connection closed"),
+                Err(e) => error!(logger, "connection error: {}", e),
+                Ok(()) => {}
+            }
+        })?;
+    Ok(())
+}
+
+// 一个连接上可以发送多条命令，每条命令一行 json，每个回复也是一行
+// 连接上最多有一个进行中的事务，连接断开时未提交的事务直接丢弃
+// raft 节点之间的连接在第一个 RPC 之后转到单独的线程，on_thread 表示已经在这样的线程中
+fn serve(
+    engine: Arc<dyn KvsEngine + Sync>,
+    mut bf: BufReader<Stream>,
+    mut writer: Stream,
+    mut session: Session,
+    opts: ServerOptions,
+    conn_logger: Logger,
+    on_thread: bool,
+) -> Result<()> {
+    let repl = opts.replication.clone();
+    let repl = repl.as_ref();
+    let mut txn: Option<Box<dyn Transaction>> = None;
+    let mut req_id = 0u64;
+    loop {
+        req_id += 1;
+        let logger = conn_logger.new(o!("req" => req_id));
+        let mut raft_peer = false;
+        let mut s = String::new();
+        if bf.read_line(&mut s)? == 0 {
+            return Ok(());
+        }
+        let start = Instant::now();
+        let mut name = None;
+        let mut key = None;
+        let reply = match serde_json::from_str::<Command>(&s) {
+            Ok(command) => {
+                opts.metrics.request(command.name());
+                name = Some(command.name());
+                key = command.key().map(str::to_owned);
+                // 不把密码和 token 写进日志
+                match &command {
+                    Command::Auth(user, _) => {
+                        debug!(logger, "request"; "command" => "auth", "user" => ?user)
+                    }
+                    // 心跳太频繁，只在 trace 级别记录
+                    Command::Raft(_) => trace!(logger, "request"; "command" => "raft"),
+                    _ => {
+                        debug!(logger, "request"; "command" => command.name(), "data" => s.trim_end())
+                    }
+                }
+                match (command, repl) {
+                    // 之后这个连接只用来给 replica 发送复制消息，需要能读所有的 key
+                    (Command::Sync(run_id, offset), Some(Replication::Primary(p))) => {
+                        match session.check(Perm::Read, "") {
+                            Ok(()) => {
+                                let logger = logger.new(o!("replica" => session.peer().to_owned()));
+                                let p = p.clone();
+                                return dedicated("replica", &opts, logger, move |l| {
+                                    p.serve_replica(run_id, offset, BufWriter::new(writer), l)
+                                });
+                            }
+                            Err(e) => e.to_string(),
+                        }
+                    }
+                    // 之后这个连接只用来推送事件
+                    (Command::Watch(key, resume), _) => {
+                        let prefix = key.strip_suffix('*').unwrap_or(&key);
+                        let allowed = session.check(Perm::Read, prefix).map_err(Into::into);
+                        match allowed.and_then(|()| engine.watch()) {
+                            Ok(w) => {
+                                let resume = resume.as_ref().map(|(r, s)| (r.as_str(), *s));
+                                let watcher = w.subscribe(&key, resume);
+                                return dedicated("watch", &opts, logger, move |_| {
+                                    watch(watcher, &session, BufWriter::new(writer))
+                                });
+                            }
+                            Err(e) => e.to_string(),
+                        }
+                    }
+                    // 之后这个连接只用来推送消息
+                    (c @ (Command::Subscribe(_) | Command::PSubscribe(_)), _) => {
+                        match session.check_auth() {
+                            Ok(()) => {
+                                let pubsub = opts.pubsub.clone();
+                                return dedicated("subscribe", &opts, logger, move |_| {
+                                    subscribe(&pubsub, c, writer)
+                                });
+                            }
+                            Err(e) => e.to_string(),
+                        }
+                    }
+                    (Command::Publish(channel, data), _) => match session.check_auth() {
+                        Ok(()) => opts.pubsub.publish(&channel, &data).to_string(),
+                        Err(e) => e.to_string(),
+                    },
+                    (Command::Channels, _) => match session.check_auth() {
+                        Ok(()) => opts.pubsub.stats().to_string(),
+                        Err(e) => e.to_string(),
+                    },
+                    (Command::Info, _) => match session.check_auth() {
+                        Ok(()) => info(engine.as_ref(), &opts).unwrap_or_else(|e| e.to_string()),
+                        Err(e) => e.to_string(),
+                    },
+                    // 慢日志中有 key，需要能读所有的 key
+                    (Command::SlowLogGet(count), _) => match session.check(Perm::Read, "") {
+                        Ok(()) => serde_json::to_string(&opts.slowlog.get(count))?,
+                        Err(e) => e.to_string(),
+                    },
+                    (Command::Sync(..), _) => {
+                        "Replication is not enabled on this server".to_owned()
+                    }
+                    (Command::Role, _) => match (session.check_auth(), &opts.proxy) {
+                        (Ok(()), Some(p)) => p.status().to_string(),
+                        (Ok(()), None) => repl
+                            .map_or_else(|| json!({"role": "standalone"}), |r| r.role())
+                            .to_string(),
+                        (Err(e), _) => e.to_string(),
+                    },
+                    (Command::AddBackend(addr), _) => match (&opts.proxy, addr.parse()) {
+                        (None, _) => "This server is not a kvs-proxy".to_owned(),
+                        (Some(_), Err(e)) => e,
+                        (Some(p), Ok(addr)) => match session.check(Perm::Write, "") {
+                            Ok(()) => p.add_backend(addr).map_or_else(
+                                |e| e.to_string(),
+                                |n| format!("Success, {} keys moved", n),
+                            ),
+                            Err(e) => e.to_string(),
+                        },
+                    },
+                    // 节点之间的 RPC 和成员变更需要对所有 key 的写权限
+                    (Command::Raft(m), Some(Replication::Raft(n))) => {
+                        match session.check(Perm::Write, "") {
+                            Ok(()) => match n.handle(m) {
+                                Ok(r) => {
+                                    raft_peer = true;
+                                    serde_json::to_string(&r)?
+                                }
+                                Err(e) => e.to_string(),
+                            },
+                            Err(e) => e.to_string(),
+                        }
+                    }
+                    (Command::AddNode(id, addr), Some(Replication::Raft(n))) => {
+                        match session.check(Perm::Write, "") {
+                            Ok(()) => n.add_node(id, addr).map_or_else(|e| e.to_string(), success),
+                            Err(e) => e.to_string(),
+                        }
+                    }
+                    (Command::RemoveNode(id), Some(Replication::Raft(n))) => {
+                        match session.check(Perm::Write, "") {
+                            Ok(()) => n.remove_node(id).map_or_else(|e| e.to_string(), success),
+                            Err(e) => e.to_string(),
+                        }
+                    }
+                    (Command::Raft(_) | Command::AddNode(..) | Command::RemoveNode(_), _) => {
+                        "Cluster mode is not enabled on this server".to_owned()
+                    }
+                    // 引擎中的日志带着这个请求的 id
+                    (command, _) => slog_scope::scope(&logger, || {
+                        handle(engine.as_ref(), &mut session, &mut txn, command)
+                    }),
+                }
+            }
+            Err(e) => {
+                debug!(logger, "invalid request"; "data" => s.trim_end(), "error" => %e);
+                e.to_string()
+            }
+        };
+        if let Some(name) = name {
+            let elapsed = start.elapsed();
+            opts.metrics.observe(name, elapsed);
+            if name != "raft" {
+                debug!(logger, "reply"; "elapsed_us" => elapsed.as_micros() as u64);
+            }
+            if opts
+                .slowlog
+                .record(name, key.as_deref(), elapsed, session.peer())
+            {
+                info!(logger, "slow command"; "command" => name, "key" => key,
+                    "elapsed_us" => elapsed.as_micros() as u64, "client" => session.peer());
+            }
+        }
+        writer.write_all((reply + "\n").as_bytes())?;
+        if raft_peer && !on_thread {
+            return dedicated("raft-peer", &opts.clone(), conn_logger.clone(), move |_| {
+                serve(engine, bf, writer, session, opts, conn_logger, true)
+            });
+        }
+    }
+}
+
+fn handle(
+    engine: &(dyn KvsEngine + Sync),
+    session: &mut Session,
+    txn: &mut Option<Box<dyn Transaction>>,
+    command: Command,
+) -> String {
+    let allowed = match &command {
+        Command::Get(key) => session.check(Perm::Read, key),
+        Command::Set(key, _) | Command::Rm(key) => session.check(Perm::Write, key),
+        Command::Begin | Command::Commit | Command::Abort | Command::Keys(_) => {
+            session.check_auth()
+        }
+        Command::Auth(..) => Ok(()),
+        Command::Compact => session.check(Perm::Write, ""),
+        // 在 serve 中处理
+        Command::Sync(..)
+        | Command::Role
+        | Command::Raft(_)
+        | Command::AddNode(..)
+        | Command::RemoveNode(_)
+        | Command::AddBackend(_)
+        | Command::Watch(..)
+        | Command::Publish(..)
+        | Command::Subscribe(_)
+        | Command::PSubscribe(_)
+        | Command::Channels
+        | Command::Info
+        | Command::SlowLogGet(_) => unreachable!(),
+    };
+    if let Err(e) = allowed {
+        return e.to_string();
+    }
+    let r = match (command, txn.as_mut()) {
+        (Command::Auth(user, secret), _) => session
+            .login(user.as_deref(), &secret)
+            .map(success)
+            .map_err(Into::into),
+        (Command::Get(key), Some(t)) => t.get(key).map(value_reply),
+        (Command::Get(key), None) => engine.get(key).map(value_reply),
+        (Command::Set(key, value), Some(t)) => t.set(key, value).map(success),
+        (Command::Set(key, value), None) => engine.set(key, value).map(success),
+        (Command::Rm(key), Some(t)) => t.remove(key).map(success),
+        (Command::Rm(key), None) => engine.remove(key).map(success),
+        (Command::Begin, Some(_)) => Err("Transaction already started".into()),
+        (Command::Begin, None) => engine.begin().map(|t| {
+            *txn = Some(t);
+            success(())
+        }),
+        (Command::Commit, _) => match txn.take() {
+            Some(t) => t.commit().map(success),
+            None => Err("No transaction".into()),
+        },
+        (Command::Keys(prefix), _) => engine.keys(&prefix).and_then(|keys| {
+            let keys: Vec<String> = keys
+                .into_iter()
+                .filter(|k| session.can(Perm::Read, k))
+                .collect();
+            Ok(serde_json::to_string(&keys)?)
+        }),
+        (Command::Compact, _) => engine
+            .compact()
+            .and_then(|r| Ok(serde_json::to_string(&r)?)),
+        (Command::Abort, _) => match txn.take() {
+            Some(_) => Ok(success(())),
+            None => Err("No transaction".into()),
+        },
+        (
+            Command::Sync(..)
+            | Command::Role
+            | Command::Raft(_)
+            | Command::AddNode(..)
+            | Command::RemoveNode(_)
+            | Command::AddBackend(_)
+            | Command::Watch(..)
+            | Command::Publish(..)
+            | Command::Subscribe(_)
+            | Command::PSubscribe(_)
+            | Command::Channels
+            | Command::Info
+            | Command::SlowLogGet(_),
+            _,
+        ) => unreachable!(),
+    };
+    r.unwrap_or_else(|e| e.to_string())
+}
+
+// 推送匹配并且有读权限的事件，没有事件时定期发送 Ping，客户端断开时写入失败返回
+fn watch(mut watcher: Watcher, session: &Session, mut w: impl Write) -> Result<()> {
+    let mut m = WatchMessage::Watching {
+        run_id: watcher.run_id().to_owned(),
+        seq: watcher.seq(),
+        resumed: watcher.resumed(),
+    };
+    loop {
+        serde_json::to_writer(&mut w, &m)?;
+        w.write_all(b"\n")?;
+        w.flush()?;
+        m = match watcher.next(PING_INTERVAL) {
+            Ok(Some(e)) if session.can(Perm::Read, &e.key) => WatchMessage::Event(e),
+            Ok(_) => WatchMessage::Ping(watcher.seq()),
+            // 客户端带着最后的序号重连之后从当前位置开始
+            Err(e) => {
+                w.write_all((e.to_string() + "\n").as_bytes())?;
+                return w.flush().map_err(Into::into);
+            }
+        };
+    }
+}
+
+// 推送订阅的频道上的消息，没有消息时定期发送 Ping
+// 客户端断开、长时间不读或者缓冲区满时返回，连接被关闭
+fn subscribe(pubsub: &Arc<PubSub>, command: Command, writer: Stream) -> Result<()> {
+    writer.set_write_timeout(Some(PUBSUB_WRITE_TIMEOUT))?;
+    let mut w = BufWriter::new(writer);
+    let mut sub = pubsub.subscriber();
+    let acks: Vec<PubSubMessage> = match command {
+        Command::Subscribe(channels) => channels
+            .into_iter()
+            .map(|c| {
+                let n = sub.subscribe(&c);
+                PubSubMessage::Subscribe(c, n)
+            })
+            .collect(),
+        Command::PSubscribe(patterns) => patterns
+            .into_iter()
+            .map(|p| {
+                let n = sub.psubscribe(&p);
+                PubSubMessage::PSubscribe(p, n)
+            })
+            .collect(),
+        _ => unreachable!(),
+    };
+    for m in &acks {
+        serde_json::to_writer(&mut w, m)?;
+        w.write_all(b"\n")?;
+    }
+    loop {
+        w.flush()?;
+        let m = match sub.next(PING_INTERVAL) {
+            Ok(Some(m)) => m,
+            Ok(None) => PubSubMessage::Ping,
+            Err(e) => {
+                w.write_all((e.to_string() + "\n").as_bytes())?;
+                return w.flush().map_err(Into::into);
+            }
+        };
+        serde_json::to_writer(&mut w, &m)?;
+        w.write_all(b"\n")?;
+    }
+}
+
+// 引擎的状态加上 server 运行的时间、连接数和线程池的状态
+fn info(engine: &(dyn KvsEngine + Sync), opts: &ServerOptions) -> Result<String> {
+    let mut v = serde_json::to_value(engine.info()?)?;
+    let m = &opts.metrics;
+    v["uptime_secs"] = m.uptime().as_secs().into();
+    v["connections"] = m.connections.get().into();
+    v["pool"] = json!({"queued": m.pool_queued.get(), "busy": m.pool_busy.get()});
+    Ok(v.to_string())
+}
+
+fn value_reply(v: Option<String>) -> String {
+    v.unwrap_or_else(|| KEY_NOT_FOUND.to_owned())
+}
+
+fn success(_: ()) -> String {
+    "Success".to_owned()
+}
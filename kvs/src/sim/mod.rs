@@ -0,0 +1,378 @@
+// 确定性模拟
+//
+// 模拟中的每个线程都是真实的线程，但同一时刻只有一个在运行：线程只在网络读写、accept、connect、
+// sleep 时让出，由调度器用 seed 决定下一个运行的线程，同一个 seed 每次的执行顺序都相同
+// 时间也是模拟的，所有线程都在等待时直接跳到最早的超时时刻
+// 线程不会在持有引擎的锁时让出，引擎中的 Mutex 不会阻塞
+//
+// 网络见 net，文件系统使用 storage::MemStorage，工作负载和模型检查见 workload
+// 节点被 kill 时它的线程在下一次让出时 unwind 退出，和进程崩溃一样不会再执行任何操作
+use std::{
+    cell::RefCell,
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    thread,
+    time::Duration,
+};
+
+use crate::Result;
+
+pub mod net;
+mod workload;
+
+pub use self::net::NetOptions;
+pub use self::workload::{run, SimOptions, SimReport};
+
+// SplitMix64，模拟中所有的随机数都来自这里
+#[derive(Clone, Debug)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    // [0, n)，n 为 0 时返回 0
+    pub fn below(&mut self, n: u64) -> u64 {
+        if n == 0 {
+            return 0;
+        }
+        self.next_u64() % n
+    }
+
+    // [0, 1)
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    pub fn chance(&mut self, p: f64) -> bool {
+        self.next_f64() < p
+    }
+}
+
+// 节点被 kill 时线程 unwind 使用的 payload，resume_unwind 不会调用 panic hook
+struct Killed;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Runnable,
+    // 等待条件满足，或者到达模拟时间（微秒）
+    Blocked(Option<u64>),
+    Done,
+}
+
+struct SimThread {
+    name: String,
+    node: Option<usize>,
+    status: Status,
+    // 每个线程一个 Condvar，切换时只唤醒下一个运行的线程
+    cv: Arc<Condvar>,
+}
+
+pub(crate) struct State {
+    pub(crate) rng: Rng,
+    // 模拟时间，微秒
+    pub(crate) now: u64,
+    threads: Vec<SimThread>,
+    running: Option<usize>,
+    killed: Vec<bool>,
+    // 线程 panic 或者死锁时的错误，之后所有线程都退出
+    failure: Option<String>,
+    pub(crate) net: net::Network,
+}
+
+struct Inner {
+    state: Mutex<State>,
+    // 所有线程都结束时通知 run
+    done: Condvar,
+}
+
+#[derive(Clone)]
+pub struct Sim(Arc<Inner>);
+
+pub(crate) enum Wait<T> {
+    Ready(T),
+    // 条件还不满足，Some 是最晚的唤醒时间
+    Pending(Option<u64>),
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<(Sim, usize)>> = const { RefCell::new(None) };
+}
+
+// 当前线程所在的模拟和线程 id，不在模拟中时返回 None
+pub(crate) fn current() -> Option<(Sim, usize)> {
+    CURRENT.with(|c| c.borrow().clone())
+}
+
+impl Sim {
+    // 用 seed 创建一个模拟，在其中运行 f，所有模拟线程都结束之后返回
+    // 任何线程 panic 或者所有线程互相等待时返回错误
+    pub fn run<F>(seed: u64, net: NetOptions, f: F) -> Result<Duration>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let sim = Sim(Arc::new(Inner {
+            state: Mutex::new(State {
+                rng: Rng::new(seed),
+                now: 0,
+                threads: vec![],
+                running: None,
+                killed: vec![],
+                failure: None,
+                net: net::Network::new(net),
+            }),
+            done: Condvar::new(),
+        }));
+        let main = sim.spawn_on(None, "main", f);
+        let mut st = sim.lock();
+        st.running = Some(main);
+        st.threads[main].cv.notify_one();
+        while st.threads.iter().any(|t| t.status != Status::Done) {
+            st = sim.0.done.wait(st).unwrap();
+        }
+        match st.failure.take() {
+            Some(e) => Err(e.into()),
+            None => Ok(Duration::from_micros(st.now)),
+        }
+    }
+
+    pub(crate) fn lock(&self) -> MutexGuard<'_, State> {
+        // 线程在 unwind 时不会持有锁，这里只是防御
+        self.0.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // 节点是一组可以一起 kill 的线程和连接，相当于一个进程
+    pub fn add_node(&self) -> usize {
+        let mut st = self.lock();
+        st.killed.push(false);
+        st.killed.len() - 1
+    }
+
+    // 新线程在调度器选中它之后才开始运行
+    pub fn spawn_on<F>(&self, node: Option<usize>, name: &str, f: F) -> usize
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let mut st = self.lock();
+        let id = st.threads.len();
+        let cv = Arc::new(Condvar::new());
+        st.threads.push(SimThread {
+            name: name.to_owned(),
+            node,
+            status: Status::Runnable,
+            cv: cv.clone(),
+        });
+        drop(st);
+        let sim = self.clone();
+        thread::spawn(move || {
+            CURRENT.with(|c| *c.borrow_mut() = Some((sim.clone(), id)));
+            let mut st = sim.lock();
+            while st.running != Some(id) && st.failure.is_none() {
+                st = cv.wait(st).unwrap_or_else(|e| e.into_inner());
+            }
+            let killed = st.failure.is_some() || node.is_some_and(|n| st.killed[n]);
+            drop(st);
+            let r = if killed {
+                Ok(())
+            } else {
+                panic::catch_unwind(AssertUnwindSafe(f))
+            };
+            let mut st = sim.lock();
+            if let Err(p) = r {
+                if !p.is::<Killed>() && st.failure.is_none() {
+                    let msg = p
+                        .downcast_ref::<String>()
+                        .cloned()
+                        .or_else(|| p.downcast_ref::<&str>().map(|s| s.to_string()))
+                        .unwrap_or_default();
+                    st.failure = Some(format!("thread {} panicked: {}", st.threads[id].name, msg));
+                }
+            }
+            st.threads[id].status = Status::Done;
+            if st.running == Some(id) {
+                sim.pick_next(&mut st);
+            }
+            if st.failure.is_some() {
+                // 唤醒所有线程退出
+                for t in &st.threads {
+                    t.cv.notify_one();
+                }
+            }
+            sim.0.done.notify_one();
+        });
+        id
+    }
+
+    // kill 之后节点的线程在下一次让出时退出，节点的连接被重置，监听的地址被释放
+    pub fn kill(&self, node: usize) {
+        let mut st = self.lock();
+        st.killed[node] = true;
+        st.net.kill(node);
+        for t in st.threads.iter_mut() {
+            if t.node == Some(node) && matches!(t.status, Status::Blocked(_)) {
+                t.status = Status::Runnable;
+            }
+        }
+    }
+
+    // 选出下一个运行的线程并唤醒它
+    fn pick_next(&self, st: &mut State) {
+        loop {
+            let runnable: Vec<usize> = (0..st.threads.len())
+                .filter(|i| st.threads[*i].status == Status::Runnable)
+                .collect();
+            if !runnable.is_empty() {
+                let next = runnable[st.rng.below(runnable.len() as u64) as usize];
+                // 两次让出之间的计算也要花一点时间
+                st.now += st.rng.below(10);
+                st.running = Some(next);
+                st.threads[next].cv.notify_one();
+                return;
+            }
+            let deadline = st
+                .threads
+                .iter()
+                .filter_map(|t| match t.status {
+                    Status::Blocked(d) => d,
+                    _ => None,
+                })
+                .min();
+            match deadline {
+                Some(d) => {
+                    st.now = st.now.max(d);
+                    let now = st.now;
+                    for t in st.threads.iter_mut() {
+                        if matches!(t.status, Status::Blocked(Some(d)) if d <= now) {
+                            t.status = Status::Runnable;
+                        }
+                    }
+                }
+                None => {
+                    st.running = None;
+                    if st.threads.iter().any(|t| t.status != Status::Done) {
+                        let blocked: Vec<&str> = st
+                            .threads
+                            .iter()
+                            .filter(|t| t.status != Status::Done)
+                            .map(|t| t.name.as_str())
+                            .collect();
+                        st.failure = Some(format!("deadlock, blocked threads: {:?}", blocked));
+                        for t in &st.threads {
+                            t.cv.notify_one();
+                        }
+                    }
+                    return;
+                }
+            }
+        }
+    }
+
+    // 让出给下一个线程，轮到自己时返回；节点已经被 kill 或者模拟失败时 unwind
+    fn switch<'a>(&'a self, mut st: MutexGuard<'a, State>, me: usize) -> MutexGuard<'a, State> {
+        self.pick_next(&mut st);
+        let cv = st.threads[me].cv.clone();
+        while st.running != Some(me) && st.failure.is_none() {
+            st = cv.wait(st).unwrap_or_else(|e| e.into_inner());
+        }
+        let killed = st.threads[me].node.is_some_and(|n| st.killed[n]);
+        if st.failure.is_some() || killed {
+            drop(st);
+            panic::resume_unwind(Box::new(Killed));
+        }
+        st
+    }
+
+    // 等待 poll 返回 Ready，每次状态变化（notify）之后重新检查
+    pub(crate) fn wait<T>(&self, me: usize, mut poll: impl FnMut(&mut State) -> Wait<T>) -> T {
+        let mut st = self.lock();
+        loop {
+            match poll(&mut st) {
+                Wait::Ready(v) => return v,
+                Wait::Pending(deadline) => {
+                    st.threads[me].status = Status::Blocked(deadline);
+                    st = self.switch(st, me);
+                }
+            }
+        }
+    }
+
+    // 可能切换到其他线程
+    pub(crate) fn yield_now(&self, me: usize) {
+        let st = self.lock();
+        drop(self.switch(st, me));
+    }
+}
+
+impl State {
+    // 状态变化之后唤醒所有等待条件的线程，让它们重新检查
+    pub(crate) fn notify(&mut self) {
+        for t in self.threads.iter_mut() {
+            if matches!(t.status, Status::Blocked(_)) {
+                t.status = Status::Runnable;
+            }
+        }
+    }
+}
+
+// 当前线程不在模拟中时 panic
+fn me() -> (Sim, usize) {
+    current().expect("not running in a simulation")
+}
+
+// 模拟时间，从模拟开始计算
+pub fn now() -> Duration {
+    let (sim, _) = me();
+    let now = sim.lock().now;
+    Duration::from_micros(now)
+}
+
+pub fn sleep(d: Duration) {
+    let (sim, id) = me();
+    if thread::panicking() {
+        return;
+    }
+    let deadline = sim.lock().now + d.as_micros() as u64;
+    sim.wait(id, |st| {
+        if st.now >= deadline {
+            Wait::Ready(())
+        } else {
+            Wait::Pending(Some(deadline))
+        }
+    });
+}
+
+// 在当前线程所在的节点上创建线程
+pub fn spawn<F>(name: &str, f: F)
+where
+    F: FnOnce() + Send + 'static,
+{
+    let (sim, id) = me();
+    let node = sim.lock().threads[id].node;
+    sim.spawn_on(node, name, f);
+}
+
+// 从模拟的随机数生成器中取 [0, n)
+pub fn random(n: u64) -> u64 {
+    let (sim, _) = me();
+    let r = sim.lock().rng.below(n);
+    r
+}
+
+pub fn chance(p: f64) -> bool {
+    let (sim, _) = me();
+    let r = sim.lock().rng.chance(p);
+    r
+}
+
+pub fn sim() -> Sim {
+    me().0
+}
@@ -0,0 +1,294 @@
+// 模拟的网络
+//
+// 每个连接是两个方向的字节流，写入的数据经过随机的延迟之后才能读到，同一个方向上保持顺序
+// 读取可能只返回一部分数据，连接可能被随机重置；节点被 kill 时它的连接都被重置
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io::{self, Read, Write},
+    thread,
+    time::Duration,
+};
+
+use super::{current, Sim, Wait};
+
+#[derive(Clone, Debug)]
+pub struct NetOptions {
+    // 每次写入的延迟在这个范围内随机
+    pub min_latency: Duration,
+    pub max_latency: Duration,
+    // 每次读取只返回一部分数据的概率
+    pub short_read_rate: f64,
+    // 每次写入时重置连接的概率
+    pub reset_rate: f64,
+}
+
+impl Default for NetOptions {
+    fn default() -> Self {
+        NetOptions {
+            min_latency: Duration::from_micros(50),
+            max_latency: Duration::from_millis(2),
+            short_read_rate: 0.2,
+            reset_rate: 0.002,
+        }
+    }
+}
+
+// 一个方向的字节流
+struct Pipe {
+    // (可以读取的模拟时间, 数据)
+    chunks: VecDeque<(u64, Vec<u8>)>,
+    readers: usize,
+    writers: usize,
+    reset: bool,
+    // 两端所在的节点
+    nodes: [Option<usize>; 2],
+}
+
+struct Listen {
+    id: u64,
+    node: Option<usize>,
+    // 已经建立、等待 accept 的连接，(读的 pipe, 写的 pipe)
+    backlog: VecDeque<(usize, usize)>,
+}
+
+pub(crate) struct Network {
+    opts: NetOptions,
+    // 连接 i 的两个方向是 2i 和 2i + 1
+    pipes: Vec<Pipe>,
+    listeners: BTreeMap<String, Listen>,
+    next_listener: u64,
+}
+
+impl Network {
+    pub(crate) fn new(opts: NetOptions) -> Self {
+        Network {
+            opts,
+            pipes: vec![],
+            listeners: BTreeMap::new(),
+            next_listener: 0,
+        }
+    }
+
+    pub(crate) fn kill(&mut self, node: usize) {
+        self.listeners.retain(|_, l| l.node != Some(node));
+        for p in self.pipes.iter_mut() {
+            if p.nodes.contains(&Some(node)) {
+                p.reset = true;
+            }
+        }
+    }
+
+    fn reset(&mut self, pipe: usize) {
+        let conn = pipe / 2 * 2;
+        self.pipes[conn].reset = true;
+        self.pipes[conn + 1].reset = true;
+    }
+}
+
+fn not_in_sim() -> io::Error {
+    io::Error::other("not running in a simulation")
+}
+
+fn reset_error() -> io::Error {
+    io::ErrorKind::ConnectionReset.into()
+}
+
+pub struct SimListener {
+    sim: Sim,
+    addr: String,
+    id: u64,
+}
+
+// 监听 addr，同一个地址同时只能有一个监听者
+pub fn listen(addr: &str) -> io::Result<SimListener> {
+    let (sim, me) = current().ok_or_else(not_in_sim)?;
+    let mut st = sim.lock();
+    if st.net.listeners.contains_key(addr) {
+        return Err(io::ErrorKind::AddrInUse.into());
+    }
+    let node = st.threads[me].node;
+    let id = st.net.next_listener;
+    st.net.next_listener += 1;
+    st.net.listeners.insert(
+        addr.to_owned(),
+        Listen {
+            id,
+            node,
+            backlog: VecDeque::new(),
+        },
+    );
+    st.notify();
+    drop(st);
+    Ok(SimListener {
+        sim,
+        addr: addr.to_owned(),
+        id,
+    })
+}
+
+impl SimListener {
+    pub fn accept(&self) -> io::Result<SimStream> {
+        let (sim, me) = current().ok_or_else(not_in_sim)?;
+        if thread::panicking() {
+            return Err(reset_error());
+        }
+        sim.yield_now(me);
+        let (rx, tx) = sim.wait(me, |st| match st.net.listeners.get_mut(&self.addr) {
+            Some(l) if l.id == self.id => match l.backlog.pop_front() {
+                Some(c) => Wait::Ready(Ok(c)),
+                None => Wait::Pending(None),
+            },
+            _ => Wait::Ready(Err(io::Error::from(io::ErrorKind::NotConnected))),
+        })?;
+        Ok(SimStream { sim, rx, tx })
+    }
+}
+
+impl Drop for SimListener {
+    fn drop(&mut self) {
+        let mut st = self.sim.lock();
+        if st.net.listeners.get(&self.addr).map(|l| l.id) == Some(self.id) {
+            st.net.listeners.remove(&self.addr);
+        }
+    }
+}
+
+pub fn connect(addr: &str) -> io::Result<SimStream> {
+    let (sim, me) = current().ok_or_else(not_in_sim)?;
+    if thread::panicking() {
+        return Err(reset_error());
+    }
+    sim.yield_now(me);
+    let mut st = sim.lock();
+    let client = st.threads[me].node;
+    let server = match st.net.listeners.get(addr) {
+        Some(l) => l.node,
+        None => return Err(io::ErrorKind::ConnectionRefused.into()),
+    };
+    let c = st.net.pipes.len();
+    for nodes in [[server, client], [client, server]] {
+        st.net.pipes.push(Pipe {
+            chunks: VecDeque::new(),
+            readers: 0,
+            writers: 0,
+            reset: false,
+            nodes,
+        });
+    }
+    // 2c 是 client 到 server，2c + 1 是 server 到 client
+    st.net
+        .listeners
+        .get_mut(addr)
+        .unwrap()
+        .backlog
+        .push_back((c, c + 1));
+    // accept 之前 server 端的引用先算上，client 关闭时 server 仍然可以读完已经发送的数据
+    st.net.pipes[c].readers += 1;
+    st.net.pipes[c + 1].writers += 1;
+    st.notify();
+    drop(st);
+    Ok(SimStream::new(sim, c + 1, c))
+}
+
+pub struct SimStream {
+    sim: Sim,
+    rx: usize,
+    tx: usize,
+}
+
+impl SimStream {
+    // 增加两个方向的引用计数，accept 得到的 server 端在 connect 时已经计数
+    fn new(sim: Sim, rx: usize, tx: usize) -> Self {
+        {
+            let mut st = sim.lock();
+            st.net.pipes[rx].readers += 1;
+            st.net.pipes[tx].writers += 1;
+        }
+        SimStream { sim, rx, tx }
+    }
+
+    pub fn try_clone(&self) -> io::Result<SimStream> {
+        Ok(SimStream::new(self.sim.clone(), self.rx, self.tx))
+    }
+}
+
+impl Read for SimStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (sim, me) = current().ok_or_else(not_in_sim)?;
+        if thread::panicking() {
+            return Err(reset_error());
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        sim.yield_now(me);
+        sim.wait(me, |st| {
+            let short = st.rng.chance(st.net.opts.short_read_rate);
+            let n = st.rng.next_u64();
+            let now = st.now;
+            let p = &mut st.net.pipes[self.rx];
+            if p.reset {
+                return Wait::Ready(Err(reset_error()));
+            }
+            match p.chunks.front_mut() {
+                Some((t, _)) if *t > now => Wait::Pending(Some(*t)),
+                Some((_, data)) => {
+                    let mut len = buf.len().min(data.len());
+                    if short {
+                        len = 1 + (n % len as u64) as usize;
+                    }
+                    buf[..len].copy_from_slice(&data[..len]);
+                    data.drain(..len);
+                    if data.is_empty() {
+                        p.chunks.pop_front();
+                    }
+                    Wait::Ready(Ok(len))
+                }
+                None if p.writers == 0 => Wait::Ready(Ok(0)),
+                None => Wait::Pending(None),
+            }
+        })
+    }
+}
+
+impl Write for SimStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let (sim, me) = current().ok_or_else(not_in_sim)?;
+        if thread::panicking() {
+            return Err(reset_error());
+        }
+        sim.yield_now(me);
+        let mut st = sim.lock();
+        if st.net.pipes[self.tx].reset || st.net.pipes[self.tx].readers == 0 {
+            return Err(reset_error());
+        }
+        let opts = st.net.opts.clone();
+        if st.rng.chance(opts.reset_rate) {
+            st.net.reset(self.tx);
+            st.notify();
+            return Err(reset_error());
+        }
+        let range = (opts.max_latency - opts.min_latency).as_micros() as u64;
+        let mut at = st.now + opts.min_latency.as_micros() as u64 + st.rng.below(range + 1);
+        let p = &mut st.net.pipes[self.tx];
+        if let Some((last, _)) = p.chunks.back() {
+            at = at.max(*last);
+        }
+        p.chunks.push_back((at, buf.to_vec()));
+        st.notify();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for SimStream {
+    fn drop(&mut self) {
+        let mut st = self.sim.lock();
+        st.net.pipes[self.rx].readers -= 1;
+        st.net.pipes[self.tx].writers -= 1;
+        st.notify();
+    }
+}
@@ -0,0 +1,342 @@
+// 模拟中运行的工作负载：一个 kvs-server 节点、若干客户端和一个随机让节点崩溃的控制线程
+//
+// 每个客户端只读写自己的 key，请求是串行的，所以每个 key 的历史是线性的，可以用一个模型检查
+// 客户端看到的结果；写入失败或者没有收到回复时不知道是否生效，这样的写入之后读到哪个值都可以
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::{self, BufRead, BufReader, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use slog::{o, Discard, Logger};
+
+use super::{NetOptions, Sim};
+use crate::{
+    net::{Addr, Stream},
+    sim,
+    storage::{Faults, MemStorage},
+    thread_pool::{SimThreadPool, ThreadPool},
+    Command, KvStore, KvStoreOptions, KvsEngine, KvsServer, Result, KEY_NOT_FOUND,
+};
+
+const ADDR: &str = "kvs";
+const DIR: &str = "/kvs";
+// 失败时错误信息中包含的最后几条 trace
+const TRACE_TAIL: usize = 20;
+
+#[derive(Clone, Debug)]
+pub struct SimOptions {
+    pub clients: usize,
+    // 每个客户端完成的请求数，连接失败不算
+    pub ops: usize,
+    // 每个客户端的 key 数
+    pub keys: usize,
+    // 控制线程每次醒来时让 server 崩溃的概率，一半是进程崩溃，一半是断电
+    pub crash_rate: f64,
+    // 控制线程每次醒来时给文件系统注入故障的概率
+    pub fault_rate: f64,
+    pub net: NetOptions,
+}
+
+impl Default for SimOptions {
+    fn default() -> Self {
+        SimOptions {
+            clients: 4,
+            ops: 200,
+            keys: 5,
+            crash_rate: 0.05,
+            fault_rate: 0.05,
+            net: NetOptions::default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SimReport {
+    pub seed: u64,
+    pub ops: usize,
+    pub crashes: usize,
+    // 模拟时间
+    pub elapsed: Duration,
+    // 每个请求和崩溃一行，同一个 seed 的 trace 完全相同
+    pub trace: Vec<String>,
+}
+
+// 一个 key 可能的值，None 表示不存在
+#[derive(Debug)]
+struct KeyModel {
+    // 最近一次确定的值，崩溃之后可能是其中任何一个
+    possible: BTreeSet<Option<String>>,
+    // 之后不确定是否生效的写入
+    pending: BTreeSet<Option<String>>,
+}
+
+impl KeyModel {
+    fn new() -> Self {
+        KeyModel {
+            possible: BTreeSet::from([None]),
+            pending: BTreeSet::new(),
+        }
+    }
+
+    fn allows(&self, v: &Option<String>) -> bool {
+        self.possible.contains(v) || self.pending.contains(v)
+    }
+
+    // 读到 v，不确定的写入之后崩溃时仍然可能出现，保留
+    fn observe(&mut self, v: Option<String>) -> bool {
+        if !self.allows(&v) {
+            return false;
+        }
+        self.possible = BTreeSet::from([v]);
+        true
+    }
+
+    // 成功的写入覆盖之前所有的写入，包括不确定的
+    fn write(&mut self, v: Option<String>) {
+        self.possible = BTreeSet::from([v]);
+        self.pending.clear();
+    }
+
+    // 重启之后不确定的写入可能在 log 中，也可能不在
+    fn restart(&mut self) {
+        let pending = std::mem::take(&mut self.pending);
+        self.possible.extend(pending);
+    }
+}
+
+#[derive(Default)]
+struct Shared {
+    model: BTreeMap<String, KeyModel>,
+    trace: Vec<String>,
+    ops: usize,
+    crashes: usize,
+    finished: usize,
+}
+
+impl Shared {
+    fn trace(&mut self, line: String) {
+        self.trace
+            .push(format!("{:>10} {}", sim::now().as_micros(), line));
+    }
+}
+
+type SharedRef = Arc<Mutex<Shared>>;
+
+// 用 seed 运行一次模拟，失败时错误信息中包含重放这个 seed 的方法
+pub fn run(seed: u64, opts: &SimOptions) -> Result<SimReport> {
+    let shared = SharedRef::default();
+    let s = shared.clone();
+    let o = opts.clone();
+    let r = Sim::run(seed, opts.net.clone(), move || controller(o, s));
+    let mut shared = shared.lock().unwrap_or_else(|e| e.into_inner());
+    match r {
+        Ok(elapsed) => Ok(SimReport {
+            seed,
+            ops: shared.ops,
+            crashes: shared.crashes,
+            elapsed,
+            trace: std::mem::take(&mut shared.trace),
+        }),
+        Err(e) => {
+            let tail = &shared.trace[shared.trace.len().saturating_sub(TRACE_TAIL)..];
+            Err(format!(
+                "simulation failed with seed {}: {}\nlast events:\n{}\nreplay with KVS_SIM_SEED={} cargo test --test sim",
+                seed,
+                e,
+                tail.join("\n"),
+                seed
+            )
+            .into())
+        }
+    }
+}
+
+fn open(storage: &MemStorage) -> Result<KvStore> {
+    let opts = KvStoreOptions {
+        sync: true,
+        // 让 compaction 经常发生，崩溃可能落在其中
+        compaction_keys: 20,
+        storage: Arc::new(storage.clone()),
+        ..KvStoreOptions::default()
+    };
+    KvStore::open_with_options(Path::new(DIR), opts)
+}
+
+// 在新的节点上打开引擎并启动 server，返回节点
+fn start(sim: &Sim, storage: &MemStorage) -> usize {
+    let engine = open(storage).unwrap_or_else(|e| panic!("reopen after crash: {}", e));
+    let node = sim.add_node();
+    sim.spawn_on(Some(node), "server", move || {
+        let pool = SimThreadPool::new(0).unwrap();
+        let server = KvsServer::new(Arc::new(engine), pool, Logger::root(Discard, o!()));
+        if let Err(e) = server.run(Addr::Sim(ADDR.to_owned())) {
+            panic!("server: {}", e);
+        }
+    });
+    node
+}
+
+fn controller(opts: SimOptions, shared: SharedRef) {
+    let sim = sim::sim();
+    let storage = MemStorage::new();
+    let mut node = start(&sim, &storage);
+    for c in 0..opts.clients {
+        let opts = opts.clone();
+        let shared = shared.clone();
+        sim.spawn_on(None, &format!("client{}", c), move || {
+            client(c, &opts, &shared)
+        });
+    }
+    while shared.lock().unwrap().finished < opts.clients {
+        sim::sleep(Duration::from_micros(1000 + sim::random(20_000)));
+        if storage.crashed() || sim::chance(opts.crash_rate) {
+            crash(&sim, node, &storage, &shared);
+            node = start(&sim, &storage);
+        } else if sim::chance(opts.fault_rate) {
+            let faults = match sim::random(3) {
+                0 => Faults {
+                    crash_after: Some(sim::random(30)),
+                    ..Faults::default()
+                },
+                1 => Faults {
+                    failed_syncs: 1,
+                    ..Faults::default()
+                },
+                _ => Faults {
+                    max_write: 1 + sim::random(16) as usize,
+                    ..Faults::default()
+                },
+            };
+            shared.lock().unwrap().trace(format!("faults {:?}", faults));
+            storage.set_faults(faults);
+        }
+    }
+
+    // 最后一次崩溃之后直接打开 log，每个 key 都必须是模型允许的值
+    crash(&sim, node, &storage, &shared);
+    let store = open(&storage).unwrap_or_else(|e| panic!("reopen after crash: {}", e));
+    let shared = shared.lock().unwrap();
+    for (k, m) in shared.model.iter() {
+        let v = store.get(k.clone()).unwrap();
+        assert!(
+            m.allows(&v),
+            "{} is {:?} after the last crash, model {:?}",
+            k,
+            v,
+            m
+        );
+    }
+    for k in store.keys("").unwrap() {
+        assert!(shared.model.contains_key(&k), "unexpected key {}", k);
+    }
+}
+
+// 让节点崩溃，随机选择进程崩溃或者断电
+fn crash(sim: &Sim, node: usize, storage: &MemStorage, shared: &SharedRef) {
+    sim.kill(node);
+    storage.crash();
+    let power_off = sim::chance(0.5);
+    if power_off {
+        storage.power_off();
+    } else {
+        storage.restart();
+    }
+    let mut shared = shared.lock().unwrap();
+    shared.crashes += 1;
+    for m in shared.model.values_mut() {
+        m.restart();
+    }
+    shared.trace(format!("crash, power off: {}", power_off));
+}
+
+struct Conn {
+    w: Stream,
+    r: BufReader<Stream>,
+}
+
+impl Conn {
+    fn connect() -> io::Result<Conn> {
+        let w = Stream::connect(&Addr::Sim(ADDR.to_owned()))?;
+        let r = BufReader::new(w.try_clone()?);
+        Ok(Conn { w, r })
+    }
+
+    fn call(&mut self, c: &Command) -> io::Result<String> {
+        let s = serde_json::to_string(c)? + "\n";
+        self.w.write_all(s.as_bytes())?;
+        let mut line = String::new();
+        if self.r.read_line(&mut line)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(line.trim_end().to_owned())
+    }
+}
+
+fn client(c: usize, opts: &SimOptions, shared: &SharedRef) {
+    let mut slot = None;
+    let mut done = 0;
+    while done < opts.ops {
+        let conn = match &mut slot {
+            Some(conn) => conn,
+            None => match Conn::connect() {
+                Ok(c) => slot.insert(c),
+                Err(_) => {
+                    // server 正在重启
+                    sim::sleep(Duration::from_millis(1 + sim::random(10)));
+                    continue;
+                }
+            },
+        };
+        let key = format!("c{}-k{}", c, sim::random(opts.keys as u64));
+        let command = match sim::random(100) {
+            0..=49 => Command::Get(key.clone()),
+            50..=84 => Command::Set(key.clone(), format!("value-{}-{}", c, done)),
+            _ => Command::Rm(key.clone()),
+        };
+        let reply = conn.call(&command);
+        done += 1;
+
+        let mut shared = shared.lock().unwrap();
+        shared.ops += 1;
+        shared.trace(format!("client{} {:?} -> {:?}", c, command, reply));
+        let m = shared
+            .model
+            .entry(key.clone())
+            .or_insert_with(KeyModel::new);
+        let ok = match (command, reply.as_deref()) {
+            (Command::Get(_), Ok(r)) if r == KEY_NOT_FOUND => m.observe(None),
+            (Command::Get(_), Ok(r)) if r.starts_with("value-") => m.observe(Some(r.to_owned())),
+            (Command::Set(_, v), Ok("Success")) => {
+                m.write(Some(v));
+                true
+            }
+            (Command::Rm(_), Ok("Success")) => {
+                let existed = m.possible.iter().chain(&m.pending).any(Option::is_some);
+                m.write(None);
+                existed
+            }
+            (Command::Rm(_), Ok(r)) if r == KEY_NOT_FOUND => m.observe(None),
+            // 读取失败不改变任何东西，写入失败不知道是否生效
+            (Command::Get(_), _) => true,
+            (Command::Set(_, v), _) => {
+                m.pending.insert(Some(v));
+                true
+            }
+            (Command::Rm(_), _) => {
+                m.pending.insert(None);
+                true
+            }
+            _ => unreachable!(),
+        };
+        assert!(ok, "{}: unexpected reply, model {:?}", key, m);
+        drop(shared);
+        if reply.is_err() {
+            slot = None;
+        }
+        sim::sleep(Duration::from_micros(sim::random(1000)));
+    }
+    shared.lock().unwrap().finished += 1;
+}
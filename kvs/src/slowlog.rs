@@ -0,0 +1,98 @@
+// 慢命令日志
+//
+// 从读到请求到发送回复超过阈值的命令记录在内存中，只保留最近的 capacity 条，SLOWLOG GET 读取
+// WATCH、SUBSCRIBE 和 replica 的 Sync 这类长连接命令不会记录
+use std::{
+    collections::VecDeque,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+// 默认的阈值和保留的条数
+pub const SLOWLOG_THRESHOLD: Duration = Duration::from_millis(10);
+pub const SLOWLOG_LEN: usize = 128;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SlowEntry {
+    // 递增的编号，SLOWLOG GET 的结果被截断时可以看出漏掉了多少条
+    pub id: u64,
+    // 命令完成时的 unix 时间，单位秒
+    pub time: u64,
+    pub duration_us: u64,
+    // 命令名，见 Command::name
+    pub command: String,
+    pub key: Option<String>,
+    // 客户端的地址
+    pub client: String,
+}
+
+struct Entries {
+    next_id: u64,
+    entries: VecDeque<SlowEntry>,
+}
+
+pub struct SlowLog {
+    threshold: Duration,
+    capacity: usize,
+    entries: Mutex<Entries>,
+}
+
+impl Default for SlowLog {
+    fn default() -> Self {
+        Self::new(SLOWLOG_THRESHOLD, SLOWLOG_LEN)
+    }
+}
+
+impl SlowLog {
+    // capacity 为 0 时不记录
+    pub fn new(threshold: Duration, capacity: usize) -> Self {
+        SlowLog {
+            threshold,
+            capacity,
+            entries: Mutex::new(Entries {
+                next_id: 0,
+                entries: VecDeque::new(),
+            }),
+        }
+    }
+
+    // 没有超过阈值时什么也不做，返回是否记录
+    pub fn record(
+        &self,
+        command: &str,
+        key: Option<&str>,
+        elapsed: Duration,
+        client: &str,
+    ) -> bool {
+        if elapsed < self.threshold || self.capacity == 0 {
+            return false;
+        }
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let mut e = self.entries.lock().unwrap();
+        let id = e.next_id;
+        e.next_id += 1;
+        if e.entries.len() == self.capacity {
+            e.entries.pop_back();
+        }
+        e.entries.push_front(SlowEntry {
+            id,
+            time,
+            duration_us: elapsed.as_micros() as u64,
+            command: command.to_owned(),
+            key: key.map(str::to_owned),
+            client: client.to_owned(),
+        });
+        true
+    }
+
+    // 最近的 count 条，新的在前，不指定时返回全部
+    pub fn get(&self, count: Option<usize>) -> Vec<SlowEntry> {
+        let e = self.entries.lock().unwrap();
+        let n = count.unwrap_or(e.entries.len());
+        e.entries.iter().take(n).cloned().collect()
+    }
+}
@@ -2,9 +2,20 @@ use crate::Result;
 mod naive;
 mod rayon;
 mod shared_queue;
+mod sim;
 pub use self::naive::NaiveThreadPool;
 pub use self::rayon::RayonThreadPool;
 pub use self::shared_queue::SharedQueueThreadPool;
+pub use self::sim::SimThreadPool;
+// job 在提交它的线程的日志上下文中执行，job 中通过 slog_scope 取得的 Logger 带着连接和请求的 id
+fn with_logger<F>(job: F) -> impl FnOnce() + Send + 'static
+where
+    F: FnOnce() + Send + 'static,
+{
+    let logger = slog_scope::logger();
+    move || slog_scope::scope(&logger, job)
+}
+
 pub trait ThreadPool {
     fn new(threads: u32) -> Result<Self>
     where
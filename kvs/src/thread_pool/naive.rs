@@ -1,20 +1,20 @@
 use std::thread;
 
-use super::ThreadPool;
+use super::{with_logger, ThreadPool};
 use crate::Result;
 pub struct NaiveThreadPool {}
 impl ThreadPool for NaiveThreadPool {
-    fn new(threads: u32) -> Result<Self>
+    fn new(_threads: u32) -> Result<Self>
     where
         Self: Sized,
     {
-        return Ok(NaiveThreadPool{})
+        Ok(NaiveThreadPool {})
     }
 
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        thread::spawn(job);
+        thread::spawn(with_logger(job));
     }
 }
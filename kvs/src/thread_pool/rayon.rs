@@ -1,19 +1,24 @@
-use super::ThreadPool;
+use super::{with_logger, ThreadPool};
 use crate::Result;
-pub struct RayonThreadPool {}
+pub struct RayonThreadPool {
+    pool: rayon::ThreadPool,
+}
 
 impl ThreadPool for RayonThreadPool {
     fn new(threads: u32) -> Result<Self>
     where
         Self: Sized,
     {
-        todo!()
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads as usize)
+            .build()?;
+        Ok(RayonThreadPool { pool })
     }
 
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        todo!()
+        self.pool.spawn(with_logger(job));
     }
 }
@@ -1,18 +1,68 @@
-use super::ThreadPool;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use slog::warn;
+
+use super::{with_logger, ThreadPool};
 use crate::Result;
-pub struct SharedQueueThreadPool {}
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+pub struct SharedQueueThreadPool {
+    tx: Sender<Job>,
+}
+
 impl ThreadPool for SharedQueueThreadPool {
     fn new(threads: u32) -> Result<Self>
     where
         Self: Sized,
     {
-        todo!()
+        let (tx, rx) = mpsc::channel::<Job>();
+        let rx = Arc::new(Mutex::new(rx));
+        for _ in 0..threads {
+            spawn_worker(rx.clone())?;
+        }
+        Ok(SharedQueueThreadPool { tx })
     }
 
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        todo!()
+        self.tx.send(Box::new(with_logger(job))).unwrap();
     }
 }
+
+// 每个 worker 持有一个 Worker，job panic 时 drop 中重新拉起一个线程，保证线程数不变
+struct Worker {
+    rx: Arc<Mutex<Receiver<Job>>>,
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            warn!(
+                slog_scope::logger(),
+                "thread pool job panicked, restarting the worker"
+            );
+            let _ = spawn_worker(self.rx.clone());
+        }
+    }
+}
+
+fn spawn_worker(rx: Arc<Mutex<Receiver<Job>>>) -> Result<()> {
+    thread::Builder::new().spawn(move || {
+        let worker = Worker { rx };
+        loop {
+            // 取到 job 后立即释放锁，避免执行 job 时阻塞其他 worker
+            let job = worker.rx.lock().unwrap().recv();
+            match job {
+                Ok(job) => job(),
+                // pool 被 drop，sender 关闭，worker 退出
+                Err(_) => break,
+            }
+        }
+    })?;
+    Ok(())
+}
@@ -0,0 +1,21 @@
+use super::{with_logger, ThreadPool};
+use crate::{sim, Result};
+
+// 确定性模拟中使用，每个 job 一个模拟线程，和创建线程池的线程属于同一个节点
+pub struct SimThreadPool {}
+
+impl ThreadPool for SimThreadPool {
+    fn new(_threads: u32) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        Ok(SimThreadPool {})
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        sim::spawn("worker", with_logger(job));
+    }
+}
@@ -0,0 +1,80 @@
+// 基于 rustls 的 TLS 配置
+// server 指定 client CA 时要求客户端提供证书（mTLS），证书的 CN 作为客户端的身份
+use std::{fs::File, io::BufReader, path::Path, sync::Arc};
+
+use rustls::{
+    client::WebPkiServerVerifier,
+    crypto::ring,
+    pki_types::{CertificateDer, PrivateKeyDer},
+    server::WebPkiClientVerifier,
+    ClientConfig, RootCertStore, ServerConfig,
+};
+
+use crate::Result;
+
+fn load_certs(p: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let mut r = BufReader::new(File::open(p)?);
+    let certs = rustls_pemfile::certs(&mut r).collect::<std::result::Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(format!("no certificate found in {}", p.display()).into());
+    }
+    Ok(certs)
+}
+
+fn load_key(p: &Path) -> Result<PrivateKeyDer<'static>> {
+    let mut r = BufReader::new(File::open(p)?);
+    rustls_pemfile::private_key(&mut r)?
+        .ok_or_else(|| format!("no private key found in {}", p.display()).into())
+}
+
+fn load_roots(p: &Path) -> Result<Arc<RootCertStore>> {
+    let mut roots = RootCertStore::empty();
+    for c in load_certs(p)? {
+        roots.add(c)?;
+    }
+    Ok(Arc::new(roots))
+}
+
+// client_ca 不为空时开启 mTLS，没有合法证书的客户端在握手时被拒绝
+pub fn server_config(
+    cert: &Path,
+    key: &Path,
+    client_ca: Option<&Path>,
+) -> Result<Arc<ServerConfig>> {
+    let provider = Arc::new(ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+    let builder = match client_ca {
+        Some(ca) => {
+            let verifier =
+                WebPkiClientVerifier::builder_with_provider(load_roots(ca)?, provider).build()?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    Ok(Arc::new(
+        builder.with_single_cert(load_certs(cert)?, load_key(key)?)?,
+    ))
+}
+
+// ca 用来验证 server 的证书，cert_key 是 mTLS 时客户端自己的证书和私钥
+pub fn client_config(ca: &Path, cert_key: Option<(&Path, &Path)>) -> Result<Arc<ClientConfig>> {
+    let provider = Arc::new(ring::default_provider());
+    let verifier =
+        WebPkiServerVerifier::builder_with_provider(load_roots(ca)?, provider.clone()).build()?;
+    let builder = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .with_webpki_verifier(verifier);
+    let config = match cert_key {
+        Some((cert, key)) => builder.with_client_auth_cert(load_certs(cert)?, load_key(key)?)?,
+        None => builder.with_no_client_auth(),
+    };
+    Ok(Arc::new(config))
+}
+
+// 证书 subject 中的 CN
+pub fn common_name(cert: &CertificateDer) -> Option<String> {
+    let (_, c) = x509_parser::parse_x509_certificate(cert.as_ref()).ok()?;
+    let cn = c.subject().iter_common_name().next()?;
+    cn.as_str().ok().map(str::to_owned)
+}
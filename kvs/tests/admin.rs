@@ -0,0 +1,190 @@
+use assert_cmd::prelude::*;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use kvs::compaction::CompactionWindow;
+use kvs::slowlog::{SlowEntry, SlowLog};
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{Command, CompactionReport, KvStore, KvStoreOptions, KvsServer, ServerOptions};
+use serde_json::Value;
+use slog::{o, Discard, Logger};
+use tempfile::TempDir;
+
+fn request(s: &mut TcpStream, r: &mut BufReader<TcpStream>, c: &Command) -> String {
+    let c = serde_json::to_string(c).unwrap();
+    s.write_all((c + "\n").as_bytes()).unwrap();
+    let mut line = String::new();
+    r.read_line(&mut line).unwrap();
+    line.trim_end().to_owned()
+}
+
+#[test]
+fn slowlog_keeps_newest() {
+    let log = SlowLog::new(Duration::from_millis(10), 2);
+    let fast = Duration::from_millis(1);
+    let slow = Duration::from_millis(20);
+    assert!(!log.record("get", Some("a"), fast, "c1"));
+    assert!(log.record("get", Some("a"), slow, "c1"));
+    assert!(log.record("set", Some("b"), slow, "c2"));
+    assert!(log.record("keys", None, slow, "c3"));
+    let entries = log.get(None);
+    let ids: Vec<u64> = entries.iter().map(|e| e.id).collect();
+    assert_eq!(ids, [2, 1]);
+    assert_eq!(entries[1].key.as_deref(), Some("b"));
+    assert_eq!(entries[1].client, "c2");
+    assert_eq!(entries[1].duration_us, 20000);
+    assert_eq!(log.get(Some(1)).len(), 1);
+
+    let off = SlowLog::new(Duration::ZERO, 0);
+    assert!(!off.record("get", Some("a"), slow, "c1"));
+    assert!(off.get(None).is_empty());
+}
+
+#[test]
+fn server_info_and_slowlog() {
+    let dir = TempDir::new().unwrap();
+    let store = KvStore::open(dir.path()).unwrap();
+    // 阈值为 0，所有命令都会被记录
+    let opts = ServerOptions {
+        slowlog: Arc::new(SlowLog::new(Duration::ZERO, 2)),
+        ..ServerOptions::default()
+    };
+    let server = KvsServer::with_options(
+        Arc::new(store),
+        SharedQueueThreadPool::new(2).unwrap(),
+        Logger::root(Discard, o!()),
+        opts,
+    );
+    thread::spawn(move || server.run("127.0.0.1:4080".parse().unwrap()).unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    let mut s = TcpStream::connect("127.0.0.1:4080").unwrap();
+    let local = s.local_addr().unwrap().to_string();
+    let mut r = BufReader::new(s.try_clone().unwrap());
+    let set = Command::Set("a".into(), "1".into());
+    assert_eq!(request(&mut s, &mut r, &set), "Success");
+    assert_eq!(request(&mut s, &mut r, &Command::Get("a".into())), "1");
+
+    let info: Value = serde_json::from_str(&request(&mut s, &mut r, &Command::Info)).unwrap();
+    assert_eq!(info["engine"], "kvs");
+    assert_eq!(info["keys"], 1);
+    assert!(info["disk_bytes"].as_u64().unwrap() > 0);
+    assert!(info["uptime_secs"].is_u64());
+    assert_eq!(info["connections"], 1);
+    assert_eq!(info["pool"]["busy"], 1);
+    assert_eq!(info["pool"]["queued"], 0);
+
+    // 只保留最近的两条：info 和 get
+    let reply = request(&mut s, &mut r, &Command::SlowLogGet(None));
+    let entries: Vec<SlowEntry> = serde_json::from_str(&reply).unwrap();
+    assert_eq!(entries.len(), 2);
+    assert_eq!((entries[0].id, entries[0].command.as_str()), (2, "info"));
+    assert_eq!(entries[0].key, None);
+    assert_eq!((entries[1].id, entries[1].command.as_str()), (1, "get"));
+    assert_eq!(entries[1].key.as_deref(), Some("a"));
+    assert_eq!(entries[1].client, local);
+
+    let reply = request(&mut s, &mut r, &Command::SlowLogGet(Some(1)));
+    let entries: Vec<SlowEntry> = serde_json::from_str(&reply).unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].command, "slowlog_get");
+}
+
+#[test]
+fn compaction_window() {
+    let w: CompactionWindow = "01:30-05:00".parse().unwrap();
+    assert!(w.contains(90) && w.contains(299));
+    assert!(!w.contains(89) && !w.contains(300));
+    // 跨过午夜
+    let night: CompactionWindow = "22:00-04:00".parse().unwrap();
+    assert!(night.contains(23 * 60) && night.contains(0) && night.contains(239));
+    assert!(!night.contains(240) && !night.contains(12 * 60));
+    assert_eq!(night.to_string(), "22:00-04:00");
+    for bad in ["22:00", "24:00-01:00", "01:60-02:00", "a:b-c:d"] {
+        assert!(bad.parse::<CompactionWindow>().is_err(), "{}", bad);
+    }
+}
+
+#[test]
+fn server_compact() {
+    let dir = TempDir::new().unwrap();
+    let opts = KvStoreOptions {
+        compaction_ratio: 0,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(dir.path(), opts).unwrap();
+    let server = KvsServer::new(
+        Arc::new(store.clone()),
+        SharedQueueThreadPool::new(2).unwrap(),
+        Logger::root(Discard, o!()),
+    );
+    thread::spawn(move || server.run("127.0.0.1:4082".parse().unwrap()).unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    let mut s = TcpStream::connect("127.0.0.1:4082").unwrap();
+    let mut r = BufReader::new(s.try_clone().unwrap());
+    for i in 0..100 {
+        let set = Command::Set("a".into(), i.to_string());
+        assert_eq!(request(&mut s, &mut r, &set), "Success");
+    }
+    let reply = request(&mut s, &mut r, &Command::Compact);
+    let report: CompactionReport = serde_json::from_str(&reply).unwrap();
+    assert_eq!(report.keys, 1);
+    assert!(report.reclaimed_bytes > 0);
+    assert_eq!(store.stats().compactions, 1);
+    assert_eq!(request(&mut s, &mut r, &Command::Get("a".into())), "99");
+}
+
+fn client(dir: &Path, args: &[&str]) -> std::process::Command {
+    let mut cmd = std::process::Command::cargo_bin("kvs-client").unwrap();
+    cmd.args(args)
+        .args(["--addr", "127.0.0.1:4081"])
+        .current_dir(dir);
+    cmd
+}
+
+#[test]
+fn cli_admin() {
+    let dir = TempDir::new().unwrap();
+    let d = dir.path();
+    let mut srv = std::process::Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4081", "--engine", "sled"])
+        .args(["--slowlog-threshold-us", "0", "--slowlog-len", "8"])
+        .current_dir(d)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    client(d, &["set", "k1", "v1"]).assert().success();
+    client(d, &["set", "k2", "v2"]).assert().success();
+    for sub in ["info", "stats"] {
+        let out = client(d, &["admin", sub]).output().unwrap();
+        assert!(out.status.success());
+        let info: Value = serde_json::from_slice(&out.stdout).unwrap();
+        assert_eq!(info["engine"], "sled");
+        assert_eq!(info["keys"], 2);
+        assert_eq!(info["connections"], 1);
+    }
+
+    let out = client(d, &["admin", "slowlog", "get", "3"])
+        .output()
+        .unwrap();
+    assert!(out.status.success());
+    let entries: Vec<SlowEntry> = String::from_utf8(out.stdout)
+        .unwrap()
+        .lines()
+        .map(|l| serde_json::from_str(l).unwrap())
+        .collect();
+    let commands: Vec<&str> = entries.iter().map(|e| e.command.as_str()).collect();
+    assert_eq!(commands, ["info", "info", "set"]);
+    assert_eq!(entries[2].key.as_deref(), Some("k2"));
+    assert!(entries[2].client.starts_with("127.0.0.1:"));
+
+    srv.kill().unwrap();
+    srv.wait().unwrap();
+}
@@ -0,0 +1,352 @@
+use assert_cmd::prelude::*;
+use predicates::str::contains;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use kvs::auth::{hash_secret, hash_token, verify_secret, Authenticator};
+use kvs::thread_pool::{NaiveThreadPool, ThreadPool};
+use kvs::{HttpGateway, KvStore, KvsServer, Protocol, Result, ServerOptions};
+use serde_json::json;
+use slog::{o, Discard, Logger};
+use tempfile::TempDir;
+
+// alice 用密码认证，可以读所有 key，只能写 app/ 下的 key
+// bob 用 token 认证，只能读 pub/ 下的 key
+fn write_users(dir: &Path) -> PathBuf {
+    let users = json!({"users": [
+        {
+            "name": "alice",
+            "password": hash_secret("secret"),
+            "acl": [{"prefix": "app/", "perm": "rw"}, {"prefix": "", "perm": "r"}],
+        },
+        {
+            "name": "bob",
+            "tokens": [hash_token("bob-token")],
+            "acl": [{"prefix": "pub/", "perm": "r"}],
+        },
+    ]});
+    let p = dir.join("users.json");
+    fs::write(&p, users.to_string()).unwrap();
+    p
+}
+
+fn load_auth(dir: &Path) -> Arc<Authenticator> {
+    let users = write_users(dir);
+    Arc::new(Authenticator::load(&users, Some(&dir.join("audit.log"))).unwrap())
+}
+
+fn start(dir: &Path, addr: &'static str, protocol: Protocol) {
+    let engine = Arc::new(KvStore::open(dir).unwrap());
+    let opts = ServerOptions {
+        protocol,
+        auth: Some(load_auth(dir)),
+        ..ServerOptions::default()
+    };
+    let server = KvsServer::with_options(
+        engine,
+        NaiveThreadPool::new(4).unwrap(),
+        Logger::root(Discard, o!()),
+        opts,
+    );
+    thread::spawn(move || server.run(addr.parse().unwrap()).unwrap());
+    thread::sleep(Duration::from_millis(500));
+}
+
+fn request(stream: &mut TcpStream, reader: &mut impl BufRead, line: &str) -> String {
+    stream.write_all(format!("{}\n", line).as_bytes()).unwrap();
+    let mut s = String::new();
+    reader.read_line(&mut s).unwrap();
+    s.trim_end().to_owned()
+}
+
+#[test]
+fn hash_and_verify_secret() {
+    let h = hash_secret("secret");
+    assert!(h.starts_with("$argon2id$"));
+    assert!(verify_secret(&h, "secret"));
+    assert!(!verify_secret(&h, "Secret"));
+    assert!(!verify_secret("secret", "secret"));
+    // 每次的 salt 不同
+    assert_ne!(h, hash_secret("secret"));
+    // token 是一次 SHA-256，没有 salt
+    assert_eq!(hash_token("t"), hash_token("t"));
+    assert!(!verify_secret(&hash_token("secret"), "secret"));
+}
+
+#[test]
+fn authenticate_users_and_tokens() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let auth = load_auth(temp_dir.path());
+    let name = |u: Option<Arc<kvs::auth::User>>| u.map(|u| u.name.clone());
+    assert_eq!(
+        name(auth.authenticate(Some("alice"), "secret")),
+        Some("alice".to_owned())
+    );
+    assert_eq!(name(auth.authenticate(Some("alice"), "wrong")), None);
+    assert_eq!(
+        name(auth.authenticate(None, "bob-token")),
+        Some("bob".to_owned())
+    );
+    assert_eq!(name(auth.authenticate(None, "secret")), None);
+    // 用户不存在，或者用户没有密码
+    assert_eq!(name(auth.authenticate(Some("nobody"), "")), None);
+    assert_eq!(name(auth.authenticate(Some("bob"), "")), None);
+    assert_eq!(name(auth.authenticate(Some("bob"), "bob-token")), None);
+
+    // 旧格式的哈希和重复的 token 在加载时报错
+    let load = |users: serde_json::Value| {
+        let p = temp_dir.path().join("bad.json");
+        fs::write(&p, users.to_string()).unwrap();
+        match Authenticator::load(&p, None) {
+            Ok(_) => String::new(),
+            Err(e) => e.to_string(),
+        }
+    };
+    let legacy = "sha256$10000$00$".to_owned() + &"0".repeat(64);
+    let e = load(json!({"users": [{"name": "a", "password": legacy}]}));
+    assert!(e.contains("kvs hash-password"), "{}", e);
+    let e = load(json!({"users": [{"name": "a", "tokens": [hash_secret("t")]}]}));
+    assert!(e.contains("kvs hash-password --token"), "{}", e);
+    let t = hash_token("t");
+    let e = load(json!({"users": [{"name": "a", "tokens": [&t]}, {"name": "b", "tokens": [&t]}]}));
+    assert!(e.contains("used by another user"), "{}", e);
+    Ok(())
+}
+
+#[test]
+fn server_auth_and_acl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    start(temp_dir.path(), "127.0.0.1:4019", Protocol::Kvs);
+
+    let mut c = TcpStream::connect("127.0.0.1:4019")?;
+    let mut r = BufReader::new(c.try_clone()?);
+    assert!(request(&mut c, &mut r, r#"{"Get":"app/x"}"#).starts_with("NOAUTH "));
+    assert!(request(&mut c, &mut r, r#""Begin""#).starts_with("NOAUTH "));
+    assert!(request(&mut c, &mut r, r#"{"Auth":["alice","wrong"]}"#).starts_with("WRONGPASS "));
+    assert!(request(&mut c, &mut r, r#"{"Auth":["nobody","secret"]}"#).starts_with("WRONGPASS "));
+    assert_eq!(
+        request(&mut c, &mut r, r#"{"Auth":["alice","secret"]}"#),
+        "Success"
+    );
+    assert_eq!(
+        request(&mut c, &mut r, r#"{"Set":["app/x","1"]}"#),
+        "Success"
+    );
+    assert_eq!(request(&mut c, &mut r, r#"{"Get":"app/x"}"#), "1");
+    assert_eq!(
+        request(&mut c, &mut r, r#"{"Set":["pub/x","1"]}"#),
+        "NOPERM user 'alice' has no write permission on key 'pub/x'"
+    );
+    assert!(request(&mut c, &mut r, r#"{"Rm":"other"}"#).starts_with("NOPERM "));
+    assert_eq!(
+        request(&mut c, &mut r, r#"{"Get":"pub/x"}"#),
+        "Key not found"
+    );
+
+    // 事务中的每个操作同样检查权限
+    assert_eq!(request(&mut c, &mut r, r#""Begin""#), "Success");
+    assert!(request(&mut c, &mut r, r#"{"Set":["pub/y","1"]}"#).starts_with("NOPERM "));
+    assert_eq!(
+        request(&mut c, &mut r, r#"{"Set":["app/y","2"]}"#),
+        "Success"
+    );
+    assert_eq!(request(&mut c, &mut r, r#""Commit""#), "Success");
+
+    // token 认证
+    let mut c2 = TcpStream::connect("127.0.0.1:4019")?;
+    let mut r2 = BufReader::new(c2.try_clone()?);
+    assert_eq!(
+        request(&mut c2, &mut r2, r#"{"Auth":[null,"bob-token"]}"#),
+        "Success"
+    );
+    assert_eq!(
+        request(&mut c2, &mut r2, r#"{"Get":"pub/x"}"#),
+        "Key not found"
+    );
+    assert!(request(&mut c2, &mut r2, r#"{"Get":"app/x"}"#).starts_with("NOPERM "));
+
+    // 被拒绝的操作写入审计日志
+    let audit = fs::read_to_string(temp_dir.path().join("audit.log"))?;
+    let records: Vec<serde_json::Value> = audit
+        .lines()
+        .map(|l| serde_json::from_str(l).unwrap())
+        .collect();
+    assert_eq!(records.len(), 8);
+    assert!(records[0]["error"].as_str().unwrap().starts_with("NOAUTH "));
+    assert_eq!(records[2]["user"], "alice");
+    assert!(records[2]["error"]
+        .as_str()
+        .unwrap()
+        .starts_with("WRONGPASS "));
+    assert_eq!(records[4]["user"], "alice");
+    assert!(records[4]["error"].as_str().unwrap().contains("'pub/x'"));
+    assert_eq!(records[7]["user"], "bob");
+    assert!(records[7]["peer"]
+        .as_str()
+        .unwrap()
+        .starts_with("127.0.0.1:"));
+    Ok(())
+}
+
+fn resp_call(c: &mut TcpStream, r: &mut impl BufRead, args: &[&str]) -> String {
+    let mut buf = format!("*{}\r\n", args.len());
+    for a in args {
+        buf += &format!("${}\r\n{}\r\n", a.len(), a);
+    }
+    c.write_all(buf.as_bytes()).unwrap();
+    let mut line = String::new();
+    r.read_line(&mut line).unwrap();
+    // 多行的回复只读出用到的部分
+    if let Some(n) = line.strip_prefix('*') {
+        let n: usize = n.trim_end().parse().unwrap();
+        for _ in 0..n * 2 {
+            r.read_line(&mut line).unwrap();
+        }
+    }
+    line
+}
+
+#[test]
+fn resp_auth_and_acl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    start(temp_dir.path(), "127.0.0.1:4020", Protocol::Resp);
+
+    let mut c = TcpStream::connect("127.0.0.1:4020")?;
+    let mut r = BufReader::new(c.try_clone()?);
+    assert_eq!(resp_call(&mut c, &mut r, &["PING"]), "+PONG\r\n");
+    assert!(resp_call(&mut c, &mut r, &["GET", "app/x"]).starts_with("-NOAUTH "));
+    assert!(resp_call(&mut c, &mut r, &["AUTH", "alice", "x"]).starts_with("-WRONGPASS "));
+    assert_eq!(
+        resp_call(&mut c, &mut r, &["AUTH", "alice", "secret"]),
+        "+OK\r\n"
+    );
+    assert_eq!(resp_call(&mut c, &mut r, &["SET", "app/x", "1"]), "+OK\r\n");
+    assert!(resp_call(&mut c, &mut r, &["SET", "pub/x", "1"]).starts_with("-NOPERM "));
+    assert!(
+        resp_call(&mut c, &mut r, &["MSET", "app/y", "1", "pub/y", "2"]).starts_with("-NOPERM ")
+    );
+    assert!(resp_call(&mut c, &mut r, &["DEL", "app/x", "other"]).starts_with("-NOPERM "));
+    assert_eq!(resp_call(&mut c, &mut r, &["GET", "app/y"]), "$-1\r\n");
+
+    // KEYS 只返回有读权限的 key
+    let mut c2 = TcpStream::connect("127.0.0.1:4020")?;
+    let mut r2 = BufReader::new(c2.try_clone()?);
+    assert_eq!(
+        resp_call(&mut c2, &mut r2, &["AUTH", "bob-token"]),
+        "+OK\r\n"
+    );
+    assert_eq!(resp_call(&mut c2, &mut r2, &["KEYS", "*"]), "*0\r\n");
+    assert_eq!(
+        resp_call(&mut c, &mut r, &["KEYS", "*"]),
+        "*1\r\n$5\r\napp/x\r\n"
+    );
+    Ok(())
+}
+
+// 密码和 token 不能在明文连接上传输，开启认证但是没有 TLS 的网关不会启动
+// 通过 TLS 的认证见 tests/tls.rs 的 https_auth_and_acl
+#[test]
+fn http_auth_requires_tls() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let gateway = HttpGateway::new(
+        Arc::new(KvStore::open(temp_dir.path())?),
+        NaiveThreadPool::new(2)?,
+        Logger::root(Discard, o!()),
+    )
+    .with_auth(Some(load_auth(temp_dir.path())));
+    let e = gateway.run("127.0.0.1:4021".parse()?).unwrap_err();
+    assert!(e.to_string().contains("requires TLS"), "{}", e);
+
+    let users = write_users(temp_dir.path());
+    assert_cmd::Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4106", "--http-addr", "127.0.0.1:4107"])
+        .arg("--users-file")
+        .arg(&users)
+        .current_dir(&temp_dir)
+        .timeout(Duration::from_secs(5))
+        .assert()
+        .code(1);
+    Ok(())
+}
+
+#[test]
+fn cli_auth() {
+    let temp_dir = TempDir::new().unwrap();
+    let out = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["hash-password", "pw"])
+        .current_dir(&temp_dir)
+        .output()
+        .unwrap();
+    let hash = String::from_utf8(out.stdout).unwrap();
+    let out = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["hash-password", "--token", "carol-token"])
+        .current_dir(&temp_dir)
+        .output()
+        .unwrap();
+    let token = String::from_utf8(out.stdout).unwrap();
+    let users = json!({"users": [
+        {"name": "carol", "password": hash.trim(), "tokens": [token.trim()],
+         "acl": [{"prefix": "c/", "perm": "rw"}]},
+    ]});
+    fs::write(temp_dir.path().join("users.json"), users.to_string()).unwrap();
+
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args([
+            "--engine",
+            "kvs",
+            "--addr",
+            "127.0.0.1:4022",
+            "--users-file",
+            "users.json",
+        ])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args)
+            .args(["--addr", "127.0.0.1:4022"])
+            .current_dir(&temp_dir);
+        cmd
+    };
+    client(&["set", "c/1", "v"])
+        .assert()
+        .failure()
+        .stderr(contains("NOAUTH"));
+    client(&["set", "c/1", "v", "--user", "carol", "--password", "nope"])
+        .assert()
+        .failure()
+        .stderr(contains("WRONGPASS"));
+    client(&["set", "c/1", "v", "--user", "carol", "--password", "pw"])
+        .assert()
+        .success();
+    client(&["get", "c/1", "--user", "carol", "--password", "pw"])
+        .assert()
+        .success()
+        .stdout(contains("v"));
+    client(&["get", "c/1", "--token", "carol-token"])
+        .assert()
+        .success()
+        .stdout(contains("v"));
+    client(&["get", "d/1", "--user", "carol", "--password", "pw"])
+        .assert()
+        .failure()
+        .stderr(contains("NOPERM"));
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+    let audit = fs::read_to_string(temp_dir.path().join("audit.log")).unwrap();
+    assert_eq!(audit.lines().count(), 3);
+}
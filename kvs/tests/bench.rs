@@ -0,0 +1,207 @@
+use assert_cmd::prelude::*;
+use predicates::str::contains;
+use std::process::Command;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use kvs::bench::{
+    self, BenchClient, BenchOptions, Embedded, LatencyHistogram, Preset, Workload, Zipfian,
+};
+use kvs::sim::Rng;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, KvsEngine, KvsServer, Result};
+use slog::{o, Discard, Logger};
+use tempfile::TempDir;
+
+#[test]
+fn zipfian_is_skewed() {
+    const N: u64 = 1000;
+    let z = Zipfian::new(N, bench::ZIPF_THETA);
+    let mut rng = Rng::new(1);
+    let mut counts = vec![0u64; N as usize];
+    let mut ranks = vec![0u64; N as usize];
+    for _ in 0..100_000 {
+        counts[z.next(&mut rng) as usize] += 1;
+        ranks[z.rank(&mut rng) as usize] += 1;
+    }
+    // 排名越靠前越热，最热的 key 远多于均匀分布的 100 次
+    assert!(ranks[0] > ranks[1] && ranks[1] > ranks[10] && ranks[10] > ranks[500]);
+    assert!(ranks[0] > 10_000, "{}", ranks[0]);
+    // 打散之后最热的 key 不是 0
+    let hottest = (0..N as usize).max_by_key(|i| counts[*i]).unwrap();
+    assert!(counts[hottest] > 10_000);
+    assert_ne!(hottest, 0);
+    // 哈希之后取模有碰撞，大约 1 - 1/e 的 key 会被访问到
+    assert!(counts.iter().filter(|c| **c > 0).count() > 550);
+}
+
+#[test]
+fn latency_percentiles() {
+    let mut h = LatencyHistogram::default();
+    assert_eq!(h.percentile(0.5), Duration::ZERO);
+    for us in 1..=1000 {
+        h.record(Duration::from_micros(us));
+    }
+    let mut other = LatencyHistogram::default();
+    other.record(Duration::from_secs(1));
+    h.merge(&other);
+    assert_eq!(h.count(), 1001);
+    let near = |d: Duration, us: f64| (d.as_secs_f64() * 1e6 - us).abs() <= us * 0.01;
+    assert!(near(h.percentile(0.5), 501.0), "{:?}", h.percentile(0.5));
+    assert!(near(h.percentile(0.99), 991.0), "{:?}", h.percentile(0.99));
+    assert!(
+        near(h.percentile(0.999), 1000.0),
+        "{:?}",
+        h.percentile(0.999)
+    );
+    assert_eq!(h.percentile(1.0), Duration::from_secs(1));
+    let s = h.summary();
+    assert_eq!(s.max_us, 1_000_000.0);
+}
+
+#[test]
+fn run_embedded_engine() -> Result<()> {
+    let dir = TempDir::new()?;
+    let store: Arc<dyn KvsEngine + Sync> = Arc::new(KvStore::open(dir.path())?);
+    let mut workload = Workload::preset(Preset::B);
+    workload.keys = 100;
+    let opts = BenchOptions {
+        workload,
+        concurrency: 4,
+        duration: Duration::from_millis(200),
+        load: true,
+    };
+    let s = store.clone();
+    let report = bench::run("kvs", &opts, || {
+        Ok(Box::new(Embedded(s.clone())) as Box<dyn BenchClient>)
+    })?;
+    assert_eq!(store.keys("")?.len(), 100);
+    assert_eq!(report.errors, 0);
+    assert!(report.ops > 0);
+    let read = report.latency["read"].ops as f64;
+    let update = report.latency["update"].ops as f64;
+    assert!(read / (read + update) > 0.9);
+    assert!(!report.latency.contains_key("rmw"));
+    assert!(report
+        .to_text()
+        .contains("b: 95% read, 5% update, zipfian over 100 keys"));
+
+    // 比例相加超过 1
+    let mut bad = opts.clone();
+    bad.workload.rmw = 0.6;
+    assert!(bench::run("kvs", &bad, || Ok(
+        Box::new(Embedded(s.clone())) as Box<dyn BenchClient>
+    ))
+    .is_err());
+    Ok(())
+}
+
+#[test]
+fn cli_embedded_json() {
+    let output = Command::cargo_bin("kvs-bench")
+        .unwrap()
+        .args(["--engine", "sled", "--preset", "f", "--keys", "100"])
+        .args([
+            "--concurrency",
+            "2",
+            "--duration",
+            "0.2",
+            "--format",
+            "json",
+        ])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(report["target"], "sled (embedded)");
+    assert_eq!(report["workload"]["name"], "f");
+    assert_eq!(report["workload"]["distribution"], "zipfian");
+    assert_eq!(report["errors"], 0);
+    assert!(report["throughput"].as_f64().unwrap() > 0.0);
+    for kind in ["all", "read", "rmw"] {
+        let l = &report["latency"][kind];
+        assert!(l["p50_us"].as_f64().unwrap() <= l["p999_us"].as_f64().unwrap());
+    }
+}
+
+#[test]
+fn cli_embedded_server_with_pool() {
+    Command::cargo_bin("kvs-bench")
+        .unwrap()
+        .args([
+            "--pool",
+            "shared-queue",
+            "--pool-threads",
+            "2",
+            "--keys",
+            "100",
+        ])
+        .args([
+            "--read-ratio",
+            "0.9",
+            "--concurrency",
+            "2",
+            "--duration",
+            "0.2",
+        ])
+        .assert()
+        .success()
+        .stdout(contains(
+            "kvs via kvs-server with shared-queue pool of 2 threads",
+        ))
+        .stdout(contains("90% read, 10% update, uniform"))
+        .stdout(contains("p999"));
+}
+
+#[test]
+fn cli_running_server() {
+    let dir = TempDir::new().unwrap();
+    let store = Arc::new(KvStore::open(dir.path()).unwrap());
+    let server = KvsServer::new(
+        store.clone(),
+        SharedQueueThreadPool::new(4).unwrap(),
+        Logger::root(Discard, o!()),
+    );
+    thread::spawn(move || server.run("127.0.0.1:4083".parse().unwrap()).unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    Command::cargo_bin("kvs-bench")
+        .unwrap()
+        .args([
+            "--addr",
+            "127.0.0.1:4083",
+            "--keys",
+            "50",
+            "--value-size",
+            "10",
+        ])
+        .args(["--concurrency", "2", "--duration", "0.2"])
+        .assert()
+        .success()
+        .stdout(contains("kvs-server at 127.0.0.1:4083"))
+        .stdout(contains("(0 errors)"));
+    assert_eq!(store.keys("").unwrap().len(), 50);
+    assert_eq!(
+        store
+            .get("user0000000000".to_owned())
+            .unwrap()
+            .unwrap()
+            .len(),
+        10
+    );
+
+    // 没有 server 在监听
+    Command::cargo_bin("kvs-bench")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4084", "--duration", "0.2"])
+        .assert()
+        .failure()
+        .stderr(contains("connect 127.0.0.1:4084"));
+    // --addr 和进程内的引擎不能同时指定
+    Command::cargo_bin("kvs-bench")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4083", "--engine", "sled"])
+        .assert()
+        .failure();
+}
@@ -0,0 +1,20 @@
+use std::path::Path;
+
+use kvs::{
+    engine_conformance_tests, Compression, EncryptionKey, KvStore, KvStoreOptions, Result,
+    SledKvsEngine,
+};
+
+fn open_kvs_lz4_encrypted(p: &Path) -> Result<KvStore> {
+    let opts = KvStoreOptions {
+        compression: Compression::Lz4,
+        compression_threshold: 64,
+        encryption_key: Some(EncryptionKey::parse(&format!("k1:{}", "ab".repeat(32)))?),
+        ..KvStoreOptions::default()
+    };
+    KvStore::open_with_options(p, opts)
+}
+
+engine_conformance_tests!(kv_store, |p: &Path| KvStore::open(p));
+engine_conformance_tests!(kv_store_lz4_encrypted, open_kvs_lz4_encrypted);
+engine_conformance_tests!(sled_engine, |p: &Path| SledKvsEngine::open(p));
@@ -0,0 +1,322 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use kvs::thread_pool::{NaiveThreadPool, SharedQueueThreadPool, ThreadPool};
+use kvs::{HttpGateway, KvStore, KvsEngine, KvsServer, Result, SledKvsEngine};
+use serde_json::{json, Value};
+use slog::{o, Discard, Logger};
+use tempfile::TempDir;
+
+// 一个 keep-alive 连接上的 HTTP 客户端
+struct Client {
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+}
+
+impl Client {
+    fn connect(addr: &str) -> Client {
+        let stream = TcpStream::connect(addr).unwrap();
+        let reader = BufReader::new(stream.try_clone().unwrap());
+        Client { stream, reader }
+    }
+
+    fn request(&mut self, method: &str, path: &str, body: Option<Value>) -> (u16, Value) {
+        let body = body.map(|b| b.to_string()).unwrap_or_default();
+        let req = format!(
+            "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}",
+            method,
+            path,
+            body.len(),
+            body
+        );
+        self.stream.write_all(req.as_bytes()).unwrap();
+
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap();
+        let status: u16 = line.split_whitespace().nth(1).unwrap().parse().unwrap();
+        let mut len = 0;
+        loop {
+            let mut h = String::new();
+            self.reader.read_line(&mut h).unwrap();
+            let h = h.trim_end();
+            if h.is_empty() {
+                break;
+            }
+            let (k, v) = h.split_once(':').unwrap();
+            if k.eq_ignore_ascii_case("content-length") {
+                len = v.trim().parse().unwrap();
+            }
+        }
+        let mut buf = vec![0; len];
+        self.reader.read_exact(&mut buf).unwrap();
+        if buf.is_empty() {
+            (status, Value::Null)
+        } else {
+            (status, serde_json::from_slice(&buf).unwrap())
+        }
+    }
+}
+
+fn http_gateway(engine: Arc<dyn KvsEngine + Sync>, addr: &'static str) {
+    let gateway = HttpGateway::new(
+        engine.clone(),
+        NaiveThreadPool::new(4).unwrap(),
+        Logger::root(Discard, o!()),
+    );
+    thread::spawn(move || gateway.run(addr.parse().unwrap()).unwrap());
+    thread::sleep(Duration::from_millis(500));
+    let mut c = Client::connect(addr);
+
+    assert_eq!(
+        c.request("GET", "/health", None),
+        (200, json!({"status": "ok"}))
+    );
+    assert_eq!(
+        c.request("GET", "/keys/key1", None),
+        (404, json!({"error": "Key not found"}))
+    );
+    assert_eq!(
+        c.request("PUT", "/keys/key1", Some(json!({"value": "value1"}))),
+        (200, json!({"key": "key1"}))
+    );
+    assert_eq!(
+        c.request("GET", "/keys/key1", None),
+        (200, json!({"key": "key1", "value": "value1"}))
+    );
+    assert_eq!(
+        engine.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+
+    // key 中的特殊字符需要编码
+    c.request("PUT", "/keys/a%20b%2Fc", Some(json!({"value": "x+y"})));
+    assert_eq!(
+        engine.get("a b/c".to_owned()).unwrap(),
+        Some("x+y".to_owned())
+    );
+    // % 后面必须是两个十六进制数字，不能带符号
+    for path in ["/keys/%+1", "/keys/%-1", "/keys/%g1"] {
+        assert_eq!(Client::connect(addr).request("GET", path, None).0, 400);
+    }
+
+    // CAS
+    assert_eq!(
+        c.request(
+            "PUT",
+            "/keys/key1",
+            Some(json!({"value": "value2", "expected": "wrong"}))
+        ),
+        (409, json!({"error": "CAS conflict", "current": "value1"}))
+    );
+    assert_eq!(
+        c.request(
+            "PUT",
+            "/keys/key1",
+            Some(json!({"value": "value2", "expected": "value1"}))
+        )
+        .0,
+        200
+    );
+    assert_eq!(
+        c.request(
+            "PUT",
+            "/keys/key1",
+            Some(json!({"value": "value3", "expected": null}))
+        )
+        .0,
+        409
+    );
+    assert_eq!(
+        c.request(
+            "PUT",
+            "/keys/key2",
+            Some(json!({"value": "value3", "expected": null}))
+        )
+        .0,
+        200
+    );
+    assert_eq!(
+        c.request("PUT", "/keys/key1", Some(json!({"val": "x"}))).0,
+        400
+    );
+
+    assert_eq!(
+        c.request("GET", "/keys?prefix=key", None),
+        (200, json!({"keys": ["key1", "key2"]}))
+    );
+    assert_eq!(
+        c.request("GET", "/keys", None),
+        (200, json!({"keys": ["a b/c", "key1", "key2"]}))
+    );
+
+    assert_eq!(c.request("DELETE", "/keys/key2", None), (204, Value::Null));
+    assert_eq!(c.request("DELETE", "/keys/key2", None).0, 404);
+
+    // batch 在一个事务中执行
+    let (status, body) = c.request(
+        "POST",
+        "/batch",
+        Some(json!({"ops": [
+            {"op": "get", "key": "key1"},
+            {"op": "put", "key": "key3", "value": "v3"},
+            {"op": "get", "key": "key3"},
+            {"op": "delete", "key": "key1"},
+            {"op": "get", "key": "key1"},
+        ]})),
+    );
+    assert_eq!(status, 200);
+    assert_eq!(
+        body,
+        json!({"results": [
+            {"key": "key1", "value": "value2"},
+            {"key": "key3"},
+            {"key": "key3", "value": "v3"},
+            {"key": "key1"},
+            {"key": "key1", "value": null},
+        ]})
+    );
+    assert_eq!(engine.get("key1".to_owned()).unwrap(), None);
+
+    // 失败的 batch 不会留下任何写入
+    let (status, _) = c.request(
+        "POST",
+        "/batch",
+        Some(json!({"ops": [
+            {"op": "put", "key": "key4", "value": "v4"},
+            {"op": "put", "key": "key3", "value": "x", "expected": "wrong"},
+        ]})),
+    );
+    assert_eq!(status, 409);
+    let (status, _) = c.request(
+        "POST",
+        "/batch",
+        Some(json!({"ops": [
+            {"op": "put", "key": "key4", "value": "v4"},
+            {"op": "delete", "key": "missing"},
+        ]})),
+    );
+    assert_eq!(status, 404);
+    assert_eq!(engine.get("key4".to_owned()).unwrap(), None);
+
+    assert_eq!(c.request("GET", "/nope", None).0, 404);
+    assert_eq!(c.request("POST", "/keys/key1", None).0, 405);
+    assert_eq!(c.request("DELETE", "/health", None).0, 405);
+}
+
+#[test]
+fn kvs_http_gateway() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    http_gateway(Arc::new(KvStore::open(temp_dir.path())?), "127.0.0.1:4011");
+    Ok(())
+}
+
+#[test]
+fn sled_http_gateway() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    http_gateway(
+        Arc::new(SledKvsEngine::open(temp_dir.path())?),
+        "127.0.0.1:4012",
+    );
+    Ok(())
+}
+
+// 网关和 kvs 协议的 server 共享同一个引擎
+#[test]
+fn http_shares_engine_with_server() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine: Arc<dyn KvsEngine + Sync> = Arc::new(KvStore::open(temp_dir.path())?);
+    let server = KvsServer::new(
+        engine.clone(),
+        NaiveThreadPool::new(2)?,
+        Logger::root(Discard, o!()),
+    );
+    let gateway = HttpGateway::new(
+        engine,
+        NaiveThreadPool::new(2)?,
+        Logger::root(Discard, o!()),
+    );
+    thread::spawn(move || server.run("127.0.0.1:4013".parse().unwrap()).unwrap());
+    thread::spawn(move || gateway.run("127.0.0.1:4014".parse().unwrap()).unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    let mut tcp = TcpStream::connect("127.0.0.1:4013")?;
+    tcp.write_all(b"{\"Set\":[\"shared\",\"yes\"]}\n")?;
+    let mut reply = String::new();
+    BufReader::new(tcp.try_clone()?).read_line(&mut reply)?;
+    assert_eq!(reply.trim_end(), "Success");
+
+    let mut c = Client::connect("127.0.0.1:4014");
+    assert_eq!(
+        c.request("GET", "/keys/shared", None),
+        (200, json!({"key": "shared", "value": "yes"}))
+    );
+    Ok(())
+}
+
+// 过长的请求行、过长的 header 和过多的 header 返回 431，gateway 继续服务其他连接
+#[test]
+fn oversized_request_headers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4094";
+    let gateway = HttpGateway::new(
+        Arc::new(KvStore::open(temp_dir.path())?),
+        NaiveThreadPool::new(4)?,
+        Logger::root(Discard, o!()),
+    );
+    thread::spawn(move || gateway.run(addr.parse().unwrap()).unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    // 发送的数据恰好是 gateway 读到的部分，连接关闭时不会被 reset
+    let long_line = "GET /".to_owned() + &"k".repeat(8 << 10);
+    let long_header = "GET /health HTTP/1.1\r\nX: ".to_owned() + &"v".repeat(8 << 10);
+    let many_headers = "GET /health HTTP/1.1\r\n".to_owned() + &"X: v\r\n".repeat(101);
+    for raw in [
+        &long_line[..8 << 10],
+        &long_header[..22 + (8 << 10)],
+        &many_headers,
+    ] {
+        let mut s = TcpStream::connect(addr)?;
+        s.write_all(raw.as_bytes())?;
+        let mut status = String::new();
+        BufReader::new(s).read_line(&mut status)?;
+        assert!(
+            status.starts_with("HTTP/1.1 431 "),
+            "{:?}",
+            status.trim_end()
+        );
+        let mut c = Client::connect(addr);
+        assert_eq!(
+            c.request("GET", "/health", None),
+            (200, json!({"status": "ok"}))
+        );
+    }
+    Ok(())
+}
+
+// 空闲的 keep-alive 连接在 idle_timeout 之后关闭，不会一直占用线程池
+#[test]
+fn idle_http_connections() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4104";
+    let gateway = HttpGateway::new(
+        Arc::new(KvStore::open(temp_dir.path())?),
+        SharedQueueThreadPool::new(1)?,
+        Logger::root(Discard, o!()),
+    )
+    .with_idle_timeout(Some(Duration::from_millis(500)));
+    thread::spawn(move || gateway.run(addr.parse().unwrap()).unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    let mut idle = Client::connect(addr);
+    assert_eq!(idle.request("GET", "/health", None).0, 200);
+    let start = Instant::now();
+    let mut c = Client::connect(addr);
+    assert_eq!(c.request("GET", "/health", None).0, 200);
+    assert!(start.elapsed() < Duration::from_secs(5));
+    let mut buf = vec![];
+    assert_eq!(idle.reader.read_to_end(&mut buf)?, 0);
+    Ok(())
+}
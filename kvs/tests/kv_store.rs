@@ -1,7 +1,11 @@
-use kvs::{KvStore, KvsEngine, Result};
-use std::path::Path;
+use kvs::{
+    Compression, EncryptionKey, KvStore, KvStoreOptions, KvsEngine, Result, SledKvsEngine,
+    LOGFILENAM,
+};
+use std::fs;
 use std::sync::{Arc, Barrier};
 use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -133,17 +137,18 @@ fn concurrent_set() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::open(temp_dir.path())?;
     // let store = KvStore::open(Path::new("./dd"))?;
-    let barrier = Arc::new(Barrier::new(1000));
+    let barrier = Arc::new(Barrier::new(1001));
 
+    let mut handles = Vec::new();
     for i in 0..1000 {
         let store = store.clone();
         let barrier = barrier.clone();
-        thread::spawn(move || {
+        handles.push(thread::spawn(move || {
             store
                 .set(format!("key{}", i), format!("value{}", i))
                 .unwrap();
             barrier.wait();
-        });
+        }));
         // .join().unwrap();
     }
     barrier.wait();
@@ -153,6 +158,10 @@ fn concurrent_set() -> Result<()> {
     }
 
     // Open from disk again and check persistent data
+    // 所有线程退出之后 store 的副本才全部释放，之后才能再次打开
+    for handle in handles {
+        handle.join().unwrap();
+    }
     drop(store);
     let store = KvStore::open(temp_dir.path())?;
     for i in 0..1000 {
@@ -213,3 +222,322 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// Snapshot should not see writes made after it was taken
+#[test]
+fn snapshot_isolation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let snapshot = store.snapshot()?;
+
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.set("key3".to_owned(), "value4".to_owned())?;
+
+    assert_eq!(snapshot.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(snapshot.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(snapshot.get("key3".to_owned())?, None);
+    assert_eq!(
+        snapshot.scan("key")?,
+        vec![
+            ("key1".to_owned(), "value1".to_owned()),
+            ("key2".to_owned(), "value2".to_owned()),
+        ]
+    );
+    assert!(store.snapshot()?.seq() > snapshot.seq());
+    Ok(())
+}
+
+// A live snapshot should still read its data after compaction rewrites the log
+#[test]
+fn snapshot_survives_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), "old".to_owned())?;
+    }
+    let snapshot = store.snapshot()?;
+    for iter in 0..5 {
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+
+    for key_id in 0..1000 {
+        assert_eq!(
+            snapshot.get(format!("key{}", key_id))?,
+            Some("old".to_owned())
+        );
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("4".to_owned()));
+    }
+    assert_eq!(snapshot.scan("")?.len(), 1000);
+    Ok(())
+}
+
+#[test]
+fn sled_snapshot_isolation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvsEngine::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let snapshot = store.snapshot()?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+
+    assert_eq!(snapshot.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(snapshot.get("key2".to_owned())?, None);
+    assert_eq!(
+        snapshot.scan("")?,
+        vec![("key1".to_owned(), "value1".to_owned())]
+    );
+    Ok(())
+}
+
+// Large values should be compressed on disk and read back transparently
+#[test]
+fn compressed_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let opts = KvStoreOptions {
+        compression: Compression::Lz4,
+        compression_threshold: 64,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), opts.clone())?;
+
+    let value = r#"{"name":"kvs","tags":["a","b","c"]}"#.repeat(100);
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), value.clone())?;
+    }
+    store.set("small".to_owned(), "value".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some(value.clone()));
+    assert!(store.stats().compression_ratio() > 5.0);
+    let size = fs::metadata(temp_dir.path().join(LOGFILENAM))?.len();
+    assert!(size < (100 * value.len() / 5) as u64);
+
+    // 重新打开时不压缩新的写入，旧的压缩记录仍然可以读取，compaction 之后也保持压缩
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key99".to_owned())?, Some(value.clone()));
+    assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+    for _ in 0..11 {
+        for key_id in 0..100 {
+            store.set(format!("other{}", key_id), "x".to_owned())?;
+        }
+    }
+    let stats = store.stats();
+    assert!(stats.log_keys < 1201, "compaction should have run");
+    assert!(stats.compression_ratio() > 5.0);
+    assert_eq!(store.get("key42".to_owned())?, Some(value));
+    Ok(())
+}
+
+// Overwritten and removed records are counted as stale until compaction reclaims them
+#[test]
+fn stale_bytes_and_compactions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.stats().stale_bytes, 0);
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    let stale = store.stats().stale_bytes;
+    assert!(stale > 0);
+
+    // 重新打开时从 log 中恢复
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stats().stale_bytes, stale);
+    assert_eq!(store.stats().compactions, 0);
+    for i in 0..1000 {
+        store.set("key1".to_owned(), format!("value{}", i))?;
+    }
+    let stats = store.stats();
+    assert!(stats.compactions >= 1);
+    assert!(stats.compaction_time > Duration::ZERO);
+    assert!(stats.stale_bytes < stale * 100);
+    Ok(())
+}
+
+// Compaction thresholds are set at open time, and compact() runs on demand
+#[test]
+fn manual_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let opts = KvStoreOptions {
+        compaction_ratio: 0,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), opts)?;
+    for i in 0..2000 {
+        store.set(format!("key{}", i % 10), format!("value{}", i))?;
+    }
+    store.remove("key9".to_owned())?;
+    // 自动 compaction 被关闭
+    assert_eq!(store.stats().compactions, 0);
+
+    let mut calls = vec![];
+    let report = store.compact_with_progress(|copied, total| calls.push((copied, total)))?;
+    assert_eq!(calls.len(), 9);
+    assert_eq!(calls.last(), Some(&(9, 9)));
+    assert_eq!(report.keys, 9);
+    assert!(report.after_bytes < report.before_bytes);
+    assert_eq!(
+        report.reclaimed_bytes,
+        report.before_bytes - report.after_bytes
+    );
+    let stats = store.stats();
+    assert_eq!(
+        (stats.compactions, stats.stale_bytes, stats.log_keys),
+        (1, 0, 9)
+    );
+    assert_eq!(store.get("key3".to_owned())?, Some("value1993".to_owned()));
+
+    // 没有可以回收的记录时不重写
+    let again = store.compact()?;
+    assert_eq!(again.reclaimed_bytes, 0);
+    assert_eq!(again.before_bytes, report.after_bytes);
+    assert_eq!(store.stats().compactions, 1);
+    drop(store);
+
+    let opts = KvStoreOptions {
+        compaction_ratio: 2,
+        compaction_keys: 10,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), opts)?;
+    for i in 0..20 {
+        store.set("key0".to_owned(), format!("value{}", i))?;
+    }
+    assert!(store.stats().compactions >= 1);
+    Ok(())
+}
+
+// A log written in the old '#'-delimited format should be upgraded on open
+#[test]
+fn upgrade_legacy_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join(LOGFILENAM),
+        r#"{"Set":["key1","value1"]}#{"Set":["key2","value2"]}#{"Rm":"key1"}#{"Set":["key2","value3"]}#"#,
+    )?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+    store.set("key3".to_owned(), "a#b".to_owned())?;
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("a#b".to_owned()));
+    Ok(())
+}
+
+fn key_options(id: &str, byte: &str) -> KvStoreOptions {
+    KvStoreOptions {
+        encryption_key: Some(EncryptionKey::parse(&format!("{}:{}", id, byte.repeat(32))).unwrap()),
+        ..KvStoreOptions::default()
+    }
+}
+
+// Encrypted records should not leak plaintext and need the right key to open
+#[test]
+fn encrypted_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(temp_dir.path(), key_options("k1", "ab"))?;
+    store.set("key1".to_owned(), "secret-value".to_owned())?;
+    store.set("key2".to_owned(), "another-secret".to_owned())?;
+    drop(store);
+
+    let content = fs::read(temp_dir.path().join(LOGFILENAM))?;
+    let text = String::from_utf8_lossy(&content);
+    assert!(!text.contains("secret-value"));
+    assert!(!text.contains("key1"));
+
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    assert!(KvStore::open_with_options(temp_dir.path(), key_options("k2", "cd")).is_err());
+    let store = KvStore::open_with_options(temp_dir.path(), key_options("k1", "ab"))?;
+    assert_eq!(
+        store.get("key1".to_owned())?,
+        Some("secret-value".to_owned())
+    );
+    Ok(())
+}
+
+// Rekey should rewrite the log under the new key id
+#[test]
+fn rekey_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(KvStore::open_with_options(temp_dir.path(), key_options("k1", "ab")).is_err());
+
+    store.rekey(key_options("k1", "ab").encryption_key)?;
+    let snapshot = store.snapshot()?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), key_options("k1", "ab"))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.rekey(key_options("k2", "cd").encryption_key)?;
+    // 快照仍然使用旧的 key 读取旧的文件
+    assert_eq!(snapshot.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+
+    assert!(KvStore::open_with_options(temp_dir.path(), key_options("k1", "ab")).is_err());
+    let store = KvStore::open_with_options(temp_dir.path(), key_options("k2", "cd"))?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.rekey(None)?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// A read-only store should read existing data and reject every write
+#[test]
+fn read_only_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let read_only = KvStoreOptions {
+        read_only: true,
+        ..KvStoreOptions::default()
+    };
+    assert!(KvStore::open_with_options(temp_dir.path(), read_only.clone()).is_err());
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let reader = KvStore::open_with_options(temp_dir.path(), read_only)?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(reader.set("key2".to_owned(), "value2".to_owned()).is_err());
+    assert!(reader.remove("key1".to_owned()).is_err());
+    let mut txn = reader.begin()?;
+    txn.set("key3".to_owned(), "value3".to_owned())?;
+    assert!(txn.commit().is_err());
+    assert!(reader.rekey(None).is_err());
+    Ok(())
+}
+
+// A second writable open in the same process should fail like one from another process,
+// while clones of the open store keep sharing its lock
+#[test]
+fn second_open_in_same_process() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let clone = store.clone();
+    match KvStore::open(temp_dir.path()) {
+        Ok(_) => panic!("second open succeeded"),
+        Err(e) => assert!(e.to_string().contains("is locked"), "{}", e),
+    }
+
+    drop(store);
+    clone.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    drop(clone);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
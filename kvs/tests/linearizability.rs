@@ -0,0 +1,266 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::Duration;
+
+use kvs::linearizability::{check, History, Input, Operation, Output, Recorder};
+use kvs::net::Addr;
+use kvs::proxy::Remote;
+use kvs::thread_pool::{RayonThreadPool, SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    CompactionReport, EngineInfo, KvStore, KvsEngine, KvsServer, KvsSnapshot, Result,
+    SledKvsEngine, Transaction, Watch, KEY_NOT_FOUND,
+};
+use slog::{o, Discard, Logger};
+use tempfile::TempDir;
+
+const THREADS: usize = 8;
+const OPS: usize = 300;
+const KEYS: u64 = 4;
+
+fn op(key: &str, input: Input, output: Output, call: u64, ret: u64) -> Operation {
+    Operation {
+        client: 0,
+        key: key.to_owned(),
+        input,
+        output,
+        call,
+        ret,
+    }
+}
+
+fn set(v: &str, call: u64, ret: u64) -> Operation {
+    op("k", Input::Set(v.to_owned()), Output::Ok, call, ret)
+}
+
+fn get(v: Option<&str>, call: u64, ret: u64) -> Operation {
+    op(
+        "k",
+        Input::Get,
+        Output::Value(v.map(str::to_owned)),
+        call,
+        ret,
+    )
+}
+
+#[test]
+fn register_histories() {
+    // 顺序执行
+    assert!(check(&[set("a", 0, 1), get(Some("a"), 2, 3)]).is_ok());
+    // 读到被覆盖的旧值
+    assert!(check(&[set("a", 0, 1), set("b", 2, 3), get(Some("a"), 4, 5)]).is_err());
+    // 和写入并发的读可以读到旧值或者新值，但是读到新值之后不能再读到旧值
+    assert!(check(&[set("a", 0, 5), get(None, 1, 2), get(Some("a"), 3, 4)]).is_ok());
+    assert!(check(&[set("a", 0, 5), get(Some("a"), 1, 2), get(None, 3, 4)]).is_err());
+    // 删除不存在的 key 和删除存在的 key
+    let rm = |output, call, ret| op("k", Input::Remove, output, call, ret);
+    assert!(check(&[
+        rm(Output::NotFound, 0, 1),
+        set("a", 2, 3),
+        rm(Output::Ok, 4, 5)
+    ])
+    .is_ok());
+    assert!(check(&[rm(Output::Ok, 0, 1)]).is_err());
+    // 不同 key 的历史互不影响
+    assert!(check(&[
+        set("a", 0, 1),
+        op("j", Input::Get, Output::Value(None), 2, 3),
+        get(Some("a"), 4, 5),
+    ])
+    .is_ok());
+}
+
+#[test]
+fn unknown_writes() {
+    let h = History::new();
+    // 出错的写入可能在调用之后的任何时刻生效，也可能不生效
+    let w = h.begin(0, "k", Input::Set("a".to_owned()));
+    h.end(w, Output::Unknown);
+    let r = h.begin(1, "k", Input::Get);
+    h.end(r, Output::Value(None));
+    let r = h.begin(1, "k", Input::Get);
+    h.end(r, Output::Value(Some("a".to_owned())));
+    // 出错的读取不记录
+    let r = h.begin(1, "k", Input::Get);
+    h.end(r, Output::Unknown);
+    assert_eq!(h.operations().len(), 3);
+    h.check().unwrap();
+
+    // 生效之后不能再消失
+    let r = h.begin(1, "k", Input::Get);
+    h.end(r, Output::Value(None));
+    let err = h.check().unwrap_err().to_string();
+    assert!(err.contains("not linearizable"), "{}", err);
+}
+
+// xorshift，每个线程用不同的种子
+struct Rng(u64);
+
+impl Rng {
+    fn below(&mut self, n: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % n
+    }
+}
+
+// 多个线程在少量 key 上随机读写，值都不相同
+fn stress(run: impl Fn(usize, &str, u64, String) + Sync) {
+    let barrier = Barrier::new(THREADS);
+    thread::scope(|s| {
+        for t in 0..THREADS {
+            let (run, barrier) = (&run, &barrier);
+            s.spawn(move || {
+                let mut rng = Rng(t as u64 * 7919 + 1);
+                barrier.wait();
+                for i in 0..OPS {
+                    let key = format!("key{}", rng.below(KEYS));
+                    run(t, &key, rng.below(100), format!("value-{}-{}", t, i));
+                }
+            });
+        }
+    });
+}
+
+fn stress_engine<E: KvsEngine + Sync>(engine: E) -> Result<()> {
+    let engine = Recorder::new(engine);
+    stress(|_, key, r, value| {
+        let key = key.to_owned();
+        match r {
+            0..=49 => drop(engine.get(key)),
+            50..=84 => drop(engine.set(key, value)),
+            _ => drop(engine.remove(key)),
+        }
+    });
+    let history = engine.history();
+    assert_eq!(history.operations().len(), THREADS * OPS);
+    history.check()
+}
+
+#[test]
+fn kv_store_is_linearizable() -> Result<()> {
+    let dir = TempDir::new()?;
+    stress_engine(KvStore::open(dir.path())?)
+}
+
+#[test]
+fn sled_is_linearizable() -> Result<()> {
+    let dir = TempDir::new()?;
+    stress_engine(SledKvsEngine::open(dir.path())?)
+}
+
+// 每个线程缓存自己读到的值，其他线程的写入之后仍然返回旧值
+struct StaleReads(KvStore);
+
+thread_local! {
+    static CACHE: RefCell<HashMap<String, Option<String>>> = RefCell::new(HashMap::new());
+}
+
+impl KvsEngine for StaleReads {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.0.set(key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        if let Some(v) = CACHE.with(|c| c.borrow().get(&key).cloned()) {
+            return Ok(v);
+        }
+        let v = self.0.get(key.clone())?;
+        CACHE.with(|c| c.borrow_mut().insert(key, v.clone()));
+        Ok(v)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.0.remove(key)
+    }
+
+    fn keys(&self, prefix: &str) -> Result<Vec<String>> {
+        self.0.keys(prefix)
+    }
+
+    fn snapshot(&self) -> Result<Box<dyn KvsSnapshot>> {
+        self.0.snapshot()
+    }
+
+    fn begin(&self) -> Result<Box<dyn Transaction>> {
+        self.0.begin()
+    }
+
+    fn watch(&self) -> Result<Arc<Watch>> {
+        self.0.watch()
+    }
+
+    fn info(&self) -> Result<EngineInfo> {
+        self.0.info()
+    }
+
+    fn compact(&self) -> Result<CompactionReport> {
+        self.0.compact()
+    }
+}
+
+#[test]
+fn stale_reads_are_detected() -> Result<()> {
+    let dir = TempDir::new()?;
+    let err = stress_engine(StaleReads(KvStore::open(dir.path())?)).unwrap_err();
+    assert!(err.to_string().contains("not linearizable"), "{}", err);
+    Ok(())
+}
+
+fn stress_server<P: ThreadPool + Send + 'static>(port: u16) -> Result<()> {
+    let dir = TempDir::new()?;
+    let store = Arc::new(KvStore::open(dir.path())?);
+    let server = KvsServer::new(store, P::new(THREADS as u32)?, Logger::root(Discard, o!()));
+    let addr: Addr = format!("127.0.0.1:{}", port).parse()?;
+    let a = addr.clone();
+    thread::spawn(move || server.run(a).unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    // 每个线程一个连接
+    let remotes: Vec<Remote> = (0..THREADS).map(|_| Remote::new(addr.clone())).collect();
+    let history = History::new();
+    stress(|t, key, r, value| {
+        let remote = &remotes[t];
+        match r {
+            0..=49 => {
+                let c = history.begin(t, key, Input::Get);
+                let output = match remote.get(key.to_owned()) {
+                    Ok(v) => Output::Value(v),
+                    Err(_) => Output::Unknown,
+                };
+                history.end(c, output);
+            }
+            50..=84 => {
+                let c = history.begin(t, key, Input::Set(value.clone()));
+                let output = match remote.set(key.to_owned(), value) {
+                    Ok(()) => Output::Ok,
+                    Err(_) => Output::Unknown,
+                };
+                history.end(c, output);
+            }
+            _ => {
+                let c = history.begin(t, key, Input::Remove);
+                let output = match remote.remove(key.to_owned()) {
+                    Ok(()) => Output::Ok,
+                    Err(e) if e.to_string() == KEY_NOT_FOUND => Output::NotFound,
+                    Err(_) => Output::Unknown,
+                };
+                history.end(c, output);
+            }
+        }
+    });
+    assert_eq!(history.operations().len(), THREADS * OPS);
+    history.check()
+}
+
+#[test]
+fn server_with_shared_queue_pool_is_linearizable() -> Result<()> {
+    stress_server::<SharedQueueThreadPool>(4090)
+}
+
+#[test]
+fn server_with_rayon_pool_is_linearizable() -> Result<()> {
+    stress_server::<RayonThreadPool>(4091)
+}
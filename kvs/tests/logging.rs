@@ -0,0 +1,119 @@
+use assert_cmd::prelude::*;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, KvsServer};
+use serde_json::Value;
+use slog::{o, Drain, Key, Logger, Never, OwnedKVList, Record, KV};
+use tempfile::TempDir;
+
+type Records = Arc<Mutex<Vec<(String, BTreeMap<String, String>)>>>;
+
+// 记下每条日志的消息和所有 key
+struct Collect(Records);
+
+struct Fields(BTreeMap<String, String>);
+
+impl slog::Serializer for Fields {
+    fn emit_arguments(&mut self, key: Key, val: &fmt::Arguments) -> slog::Result {
+        self.0.insert(key.to_string(), val.to_string());
+        Ok(())
+    }
+}
+
+impl Drain for Collect {
+    type Ok = ();
+    type Err = Never;
+
+    fn log(&self, r: &Record, values: &OwnedKVList) -> Result<(), Never> {
+        let mut f = Fields(BTreeMap::new());
+        r.kv().serialize(r, &mut f).unwrap();
+        values.serialize(r, &mut f).unwrap();
+        self.0.lock().unwrap().push((r.msg().to_string(), f.0));
+        Ok(())
+    }
+}
+
+#[test]
+fn request_ids_reach_engine() {
+    let dir = TempDir::new().unwrap();
+    let records = Records::default();
+    let logger = Logger::root(Collect(records.clone()).fuse(), o!());
+    let store = KvStore::open(dir.path()).unwrap();
+    let server = KvsServer::new(
+        Arc::new(store),
+        SharedQueueThreadPool::new(2).unwrap(),
+        logger,
+    );
+    thread::spawn(move || server.run("127.0.0.1:4075".parse().unwrap()).unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    let mut s = TcpStream::connect("127.0.0.1:4075").unwrap();
+    let mut r = BufReader::new(s.try_clone().unwrap());
+    for i in 0..1100 {
+        let c = kvs::Command::Set("key".to_owned(), format!("value{}", i));
+        s.write_all((serde_json::to_string(&c).unwrap() + "\n").as_bytes())
+            .unwrap();
+        let mut line = String::new();
+        r.read_line(&mut line).unwrap();
+        assert_eq!(line, "Success\n");
+    }
+
+    // 线程池中执行的请求触发的 compaction 带着连接和请求的 id
+    let records = records.lock().unwrap();
+    let (_, compaction) = records
+        .iter()
+        .find(|(m, _)| m == "compaction finished")
+        .expect("compaction should have run");
+    assert!(compaction["reclaimed_bytes"].parse::<u64>().unwrap() > 0);
+    let (conn, req) = (&compaction["conn"], &compaction["req"]);
+    let request = records
+        .iter()
+        .find(|(m, f)| m == "request" && &f["conn"] == conn && &f["req"] == req)
+        .unwrap();
+    assert_eq!(request.1["command"], "set");
+    assert!(records
+        .iter()
+        .any(|(m, f)| m == "reply" && &f["req"] == req && f.contains_key("elapsed_us")));
+}
+
+#[test]
+fn json_log_format() {
+    let dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4076", "--log-format", "json"])
+        .args(["--log-level", "info"])
+        .current_dir(dir.path())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", "127.0.0.1:4076"])
+        .assert()
+        .success();
+    child.kill().unwrap();
+    child.wait().unwrap();
+    let mut out = String::new();
+    child.stderr.unwrap().read_to_string(&mut out).unwrap();
+
+    let lines: Vec<Value> = out
+        .lines()
+        .map(|l| serde_json::from_str(l).unwrap())
+        .collect();
+    assert!(lines
+        .iter()
+        .any(|l| l["msg"] == "log replayed" && l["records"] == 0));
+    assert!(lines.iter().any(|l| l["msg"] == "server is started"));
+    // debug 级别的请求日志被过滤
+    assert!(lines.iter().all(|l| l["msg"] != "request"));
+}
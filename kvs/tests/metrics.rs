@@ -0,0 +1,157 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use kvs::metrics::Metrics;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{Command, Compression, KvStore, KvStoreOptions, KvsServer, ServerOptions};
+use slog::{o, Discard, Logger};
+use tempfile::TempDir;
+
+fn scrape(path: &str) -> (String, String) {
+    scrape_from("127.0.0.1:4072", path)
+}
+
+fn scrape_from(addr: &str, path: &str) -> (String, String) {
+    let mut s = TcpStream::connect(addr).unwrap();
+    let req = format!(
+        "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        path
+    );
+    s.write_all(req.as_bytes()).unwrap();
+    let mut resp = String::new();
+    s.read_to_string(&mut resp).unwrap();
+    let (head, body) = resp.split_once("\r\n\r\n").unwrap();
+    (head.lines().next().unwrap().to_owned(), body.to_owned())
+}
+
+fn request(s: &mut TcpStream, r: &mut BufReader<TcpStream>, c: &Command) -> String {
+    let c = serde_json::to_string(c).unwrap();
+    s.write_all((c + "\n").as_bytes()).unwrap();
+    let mut line = String::new();
+    r.read_line(&mut line).unwrap();
+    line.trim_end().to_owned()
+}
+
+#[test]
+fn server_metrics() {
+    let dir = TempDir::new().unwrap();
+    let store = KvStore::open(dir.path()).unwrap();
+    let metrics = Arc::new(Metrics::default().with_store(store.clone()));
+    let opts = ServerOptions {
+        metrics: metrics.clone(),
+        ..ServerOptions::default()
+    };
+    let server = KvsServer::with_options(
+        Arc::new(store),
+        SharedQueueThreadPool::new(2).unwrap(),
+        Logger::root(Discard, o!()),
+        opts,
+    );
+    thread::spawn(move || server.run("127.0.0.1:4071".parse().unwrap()).unwrap());
+    thread::spawn(move || {
+        metrics
+            .run(
+                "127.0.0.1:4072".parse().unwrap(),
+                &Logger::root(Discard, o!()),
+            )
+            .unwrap()
+    });
+    thread::sleep(Duration::from_millis(500));
+
+    let mut s = TcpStream::connect("127.0.0.1:4071").unwrap();
+    let mut r = BufReader::new(s.try_clone().unwrap());
+    let set = |k: &str, v: &str| Command::Set(k.to_owned(), v.to_owned());
+    for (k, v) in [("a", "1"), ("b", "2"), ("a", "3")] {
+        assert_eq!(request(&mut s, &mut r, &set(k, v)), "Success");
+    }
+    assert_eq!(request(&mut s, &mut r, &Command::Get("a".into())), "3");
+    assert_eq!(request(&mut s, &mut r, &Command::Rm("b".into())), "Success");
+
+    let (status, body) = scrape("/metrics");
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    for line in [
+        r#"kvs_requests_total{command="set"} 3"#,
+        r#"kvs_requests_total{command="get"} 1"#,
+        r#"kvs_request_duration_seconds_bucket{command="set",le="+Inf"} 3"#,
+        r#"kvs_request_duration_seconds_count{command="rm"} 1"#,
+        "kvs_connections 1",
+        "kvs_pool_busy_workers 1",
+        "kvs_pool_queued_jobs 0",
+        "kvs_store_live_keys 1",
+        "kvs_store_log_keys 4",
+        "kvs_store_compaction_duration_seconds_count 0",
+        "kvs_store_compression_ratio 1",
+    ] {
+        assert!(body.lines().any(|l| l == line), "missing {}", line);
+    }
+    let stale = body
+        .lines()
+        .find_map(|l| l.strip_prefix("kvs_store_stale_bytes "))
+        .unwrap();
+    assert!(stale.parse::<u64>().unwrap() > 0);
+
+    // 连接关闭之后不再计数
+    drop((s, r));
+    thread::sleep(Duration::from_millis(200));
+    let (_, body) = scrape("/metrics");
+    assert!(body.lines().any(|l| l == "kvs_connections 0"));
+    assert_eq!(scrape("/other").0, "HTTP/1.1 404 Not Found");
+
+    // 过长的请求行返回 431，只发送 endpoint 会读取的字节数，避免连接被 reset
+    let mut s = TcpStream::connect("127.0.0.1:4072").unwrap();
+    let line = "GET /".to_owned() + &"m".repeat((8 << 10) - 5);
+    s.write_all(line.as_bytes()).unwrap();
+    let mut resp = String::new();
+    s.read_to_string(&mut resp).unwrap();
+    assert!(resp.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+    assert_eq!(scrape("/metrics").0, "HTTP/1.1 200 OK");
+}
+
+// 压缩的 store 通过 server 写入之后，压缩比出现在 metrics 中
+#[test]
+fn compression_ratio_metric() {
+    let dir = TempDir::new().unwrap();
+    let opts = KvStoreOptions {
+        compression: Compression::Lz4,
+        compression_threshold: 64,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(dir.path(), opts).unwrap();
+    let metrics = Arc::new(Metrics::default().with_store(store.clone()));
+    let opts = ServerOptions {
+        metrics: metrics.clone(),
+        ..ServerOptions::default()
+    };
+    let server = KvsServer::with_options(
+        Arc::new(store),
+        SharedQueueThreadPool::new(2).unwrap(),
+        Logger::root(Discard, o!()),
+        opts,
+    );
+    thread::spawn(move || server.run("127.0.0.1:4108".parse().unwrap()).unwrap());
+    thread::spawn(move || {
+        metrics
+            .run(
+                "127.0.0.1:4109".parse().unwrap(),
+                &Logger::root(Discard, o!()),
+            )
+            .unwrap()
+    });
+    thread::sleep(Duration::from_millis(500));
+
+    let mut s = TcpStream::connect("127.0.0.1:4108").unwrap();
+    let mut r = BufReader::new(s.try_clone().unwrap());
+    for i in 0..10 {
+        let c = Command::Set(format!("key{}", i), "x".repeat(4096));
+        assert_eq!(request(&mut s, &mut r, &c), "Success");
+    }
+    let (_, body) = scrape_from("127.0.0.1:4109", "/metrics");
+    let ratio = body
+        .lines()
+        .find_map(|l| l.strip_prefix("kvs_store_compression_ratio "))
+        .unwrap();
+    assert!(ratio.parse::<f64>().unwrap() > 5.0, "{}", ratio);
+}
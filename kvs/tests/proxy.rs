@@ -0,0 +1,246 @@
+use assert_cmd::prelude::*;
+use predicates::str::contains;
+use std::path::Path;
+use std::process::{Child, Command};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use kvs::net::Addr;
+use kvs::proxy::{Proxy, Ring, CROSS_BACKEND, PROXY_STATE};
+use kvs::thread_pool::{NaiveThreadPool, ThreadPool};
+use kvs::{KvStore, KvsEngine, KvsServer};
+use slog::{o, Discard, Logger};
+use tempfile::TempDir;
+
+#[test]
+fn ring_balance_and_minimal_movement() {
+    let mut ring = Ring::new(160);
+    for n in ["a", "b", "c"] {
+        ring.add(n);
+    }
+    let keys: Vec<String> = (0..10000).map(|i| format!("key{}", i)).collect();
+    let before: Vec<String> = keys
+        .iter()
+        .map(|k| ring.owner(k).unwrap().to_owned())
+        .collect();
+    for n in ["a", "b", "c"] {
+        let count = before.iter().filter(|o| *o == n).count();
+        assert!((2500..4200).contains(&count), "{} owns {} keys", n, count);
+    }
+
+    // 新节点只从其他节点拿走 key，其他 key 的位置不变
+    ring.add("d");
+    let mut moved = 0;
+    for (k, old) in keys.iter().zip(&before) {
+        let new = ring.owner(k).unwrap();
+        if new != old {
+            assert_eq!(new, "d");
+            moved += 1;
+        }
+    }
+    assert!((1800..3300).contains(&moved), "moved {} keys", moved);
+    assert_eq!(ring.nodes(), ["a", "b", "c", "d"]);
+}
+
+fn backend(port: u16) -> (Addr, Arc<KvStore>, TempDir) {
+    let dir = TempDir::new().unwrap();
+    let store = Arc::new(KvStore::open(dir.path()).unwrap());
+    let server = KvsServer::new(
+        store.clone(),
+        NaiveThreadPool::new(4).unwrap(),
+        Logger::root(Discard, o!()),
+    );
+    let addr: Addr = format!("127.0.0.1:{}", port).parse().unwrap();
+    let a = addr.clone();
+    thread::spawn(move || server.run(a).unwrap());
+    (addr, store, dir)
+}
+
+#[test]
+fn proxy_routing_and_migration() {
+    let backends: Vec<_> = (4050..4054).map(backend).collect();
+    thread::sleep(Duration::from_millis(500));
+    let dir = TempDir::new().unwrap();
+    let addrs: Vec<Addr> = backends[..3].iter().map(|b| b.0.clone()).collect();
+    let proxy =
+        Arc::new(Proxy::open(dir.path(), &addrs, 160, Logger::root(Discard, o!())).unwrap());
+
+    for i in 0..300 {
+        proxy
+            .set(format!("key{}", i), format!("value{}", i))
+            .unwrap();
+    }
+    // 每个 key 只存在于它所属的后端
+    let mut total = 0;
+    for (addr, store, _) in &backends[..3] {
+        let keys = store.keys("").unwrap();
+        assert!(!keys.is_empty());
+        for k in &keys {
+            assert_eq!(proxy.owner(k).unwrap(), addr.to_string());
+        }
+        total += keys.len();
+    }
+    assert_eq!(total, 300);
+    assert_eq!(
+        proxy.get("key42".to_owned()).unwrap(),
+        Some("value42".to_owned())
+    );
+    assert_eq!(proxy.get("missing".to_owned()).unwrap(), None);
+
+    // KEYS 合并所有后端的结果
+    let keys = proxy.keys("").unwrap();
+    assert_eq!(keys.len(), 300);
+    assert!(keys.windows(2).all(|w| w[0] < w[1]));
+    assert_eq!(proxy.keys("key29").unwrap().len(), 11);
+    // INFO 汇总所有后端
+    let info = proxy.info().unwrap();
+    assert_eq!((info.engine.as_str(), info.keys), ("proxy", 300));
+    assert!(info.disk_bytes > 0);
+    proxy.remove("key299".to_owned()).unwrap();
+    assert_eq!(
+        proxy.remove("key299".to_owned()).unwrap_err().to_string(),
+        "Key not found"
+    );
+
+    // 事务中的 key 都在同一个后端上时在那个后端上提交
+    let home = proxy.owner("key0").unwrap();
+    let (local, remote): (Vec<String>, Vec<String>) = (0..100)
+        .map(|i| format!("txn{}", i))
+        .partition(|k| proxy.owner(k).unwrap() == home);
+    let mut txn = proxy.begin().unwrap();
+    for k in &local[..3] {
+        txn.set(k.clone(), "t".to_owned()).unwrap();
+    }
+    txn.remove("key0".to_owned()).unwrap();
+    txn.commit().unwrap();
+    assert_eq!(proxy.keys("txn").unwrap(), local[..3]);
+    assert_eq!(proxy.get("key0".to_owned()).unwrap(), None);
+
+    // 跨后端的事务不能原子地提交，整个事务被拒绝
+    let mut txn = proxy.begin().unwrap();
+    txn.set(local[3].clone(), "t".to_owned()).unwrap();
+    txn.set(remote[0].clone(), "t".to_owned()).unwrap();
+    assert_eq!(txn.commit().unwrap_err().to_string(), CROSS_BACKEND);
+    assert_eq!(proxy.keys("txn").unwrap(), local[..3]);
+
+    // 迁移期间继续写入，迁移结束之后每个 key 都是最新的值，并且只在一个后端上
+    let writer = {
+        let proxy = proxy.clone();
+        thread::spawn(move || {
+            for i in 0..100 {
+                proxy.set(format!("key{}", i), "new".to_owned()).unwrap();
+            }
+        })
+    };
+    let moved = proxy.add_backend(backends[3].0.clone()).unwrap();
+    writer.join().unwrap();
+    assert!(moved > 0);
+    assert!(!backends[3].1.keys("").unwrap().is_empty());
+    let mut total = 0;
+    for (addr, store, _) in &backends {
+        for k in store.keys("").unwrap() {
+            assert_eq!(proxy.owner(&k).unwrap(), addr.to_string());
+            total += 1;
+        }
+    }
+    // key0 被事务删除之后又被重新写入
+    assert_eq!(total, 300 - 1 + 3);
+    for i in 0..299 {
+        let expected = if i < 100 {
+            "new".to_owned()
+        } else {
+            format!("value{}", i)
+        };
+        assert_eq!(proxy.get(format!("key{}", i)).unwrap(), Some(expected));
+    }
+    let e = proxy.add_backend(backends[3].0.clone()).unwrap_err();
+    assert!(e.to_string().contains("already exists"));
+
+    // 重新打开时使用保存的后端列表
+    let state = std::fs::read_to_string(dir.path().join(PROXY_STATE)).unwrap();
+    assert!(state.contains(&backends[3].0.to_string()));
+    let reopened = Proxy::open(dir.path(), &[], 160, Logger::root(Discard, o!())).unwrap();
+    assert_eq!(reopened.backends().len(), 4);
+    assert_eq!(
+        reopened.get("key150".to_owned()).unwrap(),
+        Some("value150".to_owned())
+    );
+}
+
+fn server(dir: &Path, bin: &str, args: &[&str]) -> Child {
+    let child = Command::cargo_bin(bin)
+        .unwrap()
+        .args(args)
+        .current_dir(dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    child
+}
+
+fn client(dir: &Path, args: &[&str]) -> Command {
+    let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+    cmd.args(args)
+        .args(["--addr", "127.0.0.1:4056"])
+        .current_dir(dir);
+    cmd
+}
+
+#[test]
+fn cli_proxy() {
+    let dirs: Vec<TempDir> = (0..4).map(|_| TempDir::new().unwrap()).collect();
+    let mut children = vec![
+        server(dirs[0].path(), "kvs-server", &["--addr", "127.0.0.1:4054"]),
+        server(dirs[1].path(), "kvs-server", &["--addr", "127.0.0.1:4055"]),
+        server(dirs[2].path(), "kvs-server", &["--addr", "127.0.0.1:4057"]),
+    ];
+    let proxy_args = [
+        "--addr",
+        "127.0.0.1:4056",
+        "--backend",
+        "127.0.0.1:4054,127.0.0.1:4055",
+    ];
+    let p = dirs[3].path();
+    let mut proxy = server(p, "kvs-proxy", &proxy_args);
+
+    for i in 0..20 {
+        client(p, &["set", &format!("key{:02}", i), "v"])
+            .assert()
+            .success();
+    }
+    client(p, &["get", "key07"])
+        .assert()
+        .success()
+        .stdout("v\n");
+    client(p, &["keys", "key1"])
+        .assert()
+        .success()
+        .stdout((10..20).map(|i| format!("key{}\n", i)).collect::<String>());
+    client(p, &["rm", "key19"]).assert().success();
+    client(p, &["add-backend", "127.0.0.1:4057"])
+        .assert()
+        .success()
+        .stdout(contains("keys moved"));
+    client(p, &["role"]).assert().success().stdout(contains(
+        r#""backends":["127.0.0.1:4054","127.0.0.1:4055","127.0.0.1:4057"]"#,
+    ));
+
+    // 重启之后后端列表从 proxy.json 恢复
+    proxy.kill().unwrap();
+    proxy.wait().unwrap();
+    let mut proxy = server(p, "kvs-proxy", &["--addr", "127.0.0.1:4056"]);
+    let out = client(p, &["keys"]).output().unwrap();
+    assert_eq!(String::from_utf8_lossy(&out.stdout).lines().count(), 19);
+    client(p, &["get", "key07"])
+        .assert()
+        .success()
+        .stdout("v\n");
+
+    proxy.kill().unwrap();
+    proxy.wait().unwrap();
+    for c in &mut children {
+        c.kill().unwrap();
+        c.wait().unwrap();
+    }
+}
@@ -0,0 +1,198 @@
+use assert_cmd::prelude::*;
+use predicates::str::contains;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::process::{Child, ChildStdout, Command, Stdio};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use kvs::pubsub::{PubSub, PubSubMessage, SUBSCRIBER_TOO_SLOW};
+use serde_json::json;
+use tempfile::TempDir;
+
+const WAIT: Duration = Duration::from_secs(5);
+
+fn message(channel: &str, pattern: Option<&str>, data: &str) -> PubSubMessage {
+    PubSubMessage::Message {
+        channel: channel.to_owned(),
+        pattern: pattern.map(Into::into),
+        data: data.to_owned(),
+    }
+}
+
+#[test]
+fn channels_and_patterns() {
+    let hub = Arc::new(PubSub::default());
+    let mut exact = hub.subscriber();
+    let mut glob = hub.subscriber();
+    assert_eq!(exact.subscribe("news"), 1);
+    assert_eq!(exact.subscribe("news"), 1);
+    assert_eq!(glob.psubscribe("news.*"), 1);
+    assert_eq!(glob.psubscribe("n[eo]ws*"), 2);
+
+    assert_eq!(hub.publish("news", "a"), 2);
+    assert_eq!(exact.next(WAIT).unwrap(), Some(message("news", None, "a")));
+    assert_eq!(
+        glob.next(WAIT).unwrap(),
+        Some(message("news", Some("n[eo]ws*"), "a"))
+    );
+    // 匹配多个模式时每个模式收到一次
+    assert_eq!(hub.publish("news.tech", "b"), 2);
+    assert_eq!(hub.publish("other", "c"), 0);
+    let mut got = vec![];
+    while let Some(m) = glob.next(Duration::from_millis(100)).unwrap() {
+        got.push(m);
+    }
+    assert_eq!(got.len(), 2);
+    assert!(got.contains(&message("news.tech", Some("news.*"), "b")));
+    assert_eq!(exact.next(Duration::from_millis(100)).unwrap(), None);
+
+    assert_eq!(
+        hub.stats(),
+        json!({"channels": {"news": 1}, "patterns": {"news.*": 1, "n[eo]ws*": 1}})
+    );
+    // 连接断开时取消所有订阅
+    drop(glob);
+    assert_eq!(
+        hub.stats(),
+        json!({"channels": {"news": 1}, "patterns": {}})
+    );
+}
+
+#[test]
+fn slow_subscriber_dropped() {
+    let hub = Arc::new(PubSub::new(3));
+    let mut slow = hub.subscriber();
+    let mut fast = hub.subscriber();
+    slow.subscribe("ch");
+    fast.subscribe("ch");
+    for i in 0..3 {
+        assert_eq!(hub.publish("ch", &i.to_string()), 2);
+        fast.next(WAIT).unwrap().unwrap();
+    }
+    // 缓冲区满之后慢的订阅者被取消订阅，不影响其他订阅者
+    assert_eq!(hub.publish("ch", "3"), 1);
+    assert_eq!(fast.next(WAIT).unwrap(), Some(message("ch", None, "3")));
+    assert_eq!(hub.publish("ch", "4"), 1);
+    let e = slow.next(WAIT).unwrap_err();
+    assert_eq!(e.to_string(), SUBSCRIBER_TOO_SLOW);
+    slow.subscribe("other");
+    assert_eq!(hub.stats(), json!({"channels": {"ch": 1}, "patterns": {}}));
+}
+
+const ADDR: &str = "127.0.0.1:4070";
+
+fn client(dir: &Path, args: &[&str]) -> Command {
+    let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+    cmd.args(args).args(["--addr", ADDR]).current_dir(dir);
+    cmd
+}
+
+fn subscriber(dir: &Path, args: &[&str]) -> (Child, BufReader<ChildStdout>) {
+    let mut child = client(dir, args).stdout(Stdio::piped()).spawn().unwrap();
+    let out = BufReader::new(child.stdout.take().unwrap());
+    (child, out)
+}
+
+fn next(out: &mut impl BufRead) -> PubSubMessage {
+    let mut line = String::new();
+    out.read_line(&mut line).unwrap();
+    serde_json::from_str(&line).unwrap()
+}
+
+#[test]
+fn cli_pubsub() {
+    let dir = TempDir::new().unwrap();
+    let d = dir.path();
+    let mut srv = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", ADDR, "--pubsub-buffer", "16"])
+        .current_dir(d)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let (mut s1, mut out1) = subscriber(d, &["subscribe", "news", "sport"]);
+    assert_eq!(next(&mut out1), PubSubMessage::Subscribe("news".into(), 1));
+    assert_eq!(next(&mut out1), PubSubMessage::Subscribe("sport".into(), 2));
+    let (mut s2, mut out2) = subscriber(d, &["psubscribe", "news.*"]);
+    assert_eq!(
+        next(&mut out2),
+        PubSubMessage::PSubscribe("news.*".into(), 1)
+    );
+
+    client(d, &["publish", "news", "hello"])
+        .assert()
+        .success()
+        .stdout("1\n");
+    client(d, &["publish", "news.tech", "rust"])
+        .assert()
+        .success()
+        .stdout("1\n");
+    assert_eq!(next(&mut out1), message("news", None, "hello"));
+    assert_eq!(
+        next(&mut out2),
+        message("news.tech", Some("news.*"), "rust")
+    );
+    client(d, &["channels"]).assert().success().stdout(contains(
+        r#"{"channels":{"news":1,"sport":1},"patterns":{"news.*":1}}"#,
+    ));
+
+    // 不读取的订阅者在缓冲区满之后被断开，发布者不受影响
+    let mut slow = TcpStream::connect(ADDR).unwrap();
+    let c = serde_json::to_string(&kvs::Command::Subscribe(vec!["bulk".into()])).unwrap();
+    slow.write_all((c + "\n").as_bytes()).unwrap();
+    let mut slow = BufReader::new(slow);
+    assert_eq!(next(&mut slow), PubSubMessage::Subscribe("bulk".into(), 1));
+    let publisher = TcpStream::connect(ADDR).unwrap();
+    let mut reader = BufReader::new(publisher.try_clone().unwrap());
+    let mut publisher = publisher;
+    let data = "x".repeat(64 * 1024);
+    let mut dropped = false;
+    for _ in 0..2000 {
+        let c = kvs::Command::Publish("bulk".into(), data.clone());
+        let c = serde_json::to_string(&c).unwrap();
+        publisher.write_all((c + "\n").as_bytes()).unwrap();
+        let mut reply = String::new();
+        reader.read_line(&mut reply).unwrap();
+        if reply.trim_end() == "0" {
+            dropped = true;
+            break;
+        }
+        assert_eq!(reply.trim_end(), "1");
+    }
+    assert!(dropped);
+    let mut lines = 0;
+    let mut line = String::new();
+    while slow.read_line(&mut line).unwrap() > 0 {
+        if line.trim_end() == SUBSCRIBER_TOO_SLOW {
+            break;
+        }
+        lines += 1;
+        line.clear();
+    }
+    assert_eq!(line.trim_end(), SUBSCRIBER_TOO_SLOW);
+    assert!(lines > 0);
+    line.clear();
+    assert_eq!(slow.read_line(&mut line).unwrap(), 0);
+    client(d, &["channels"])
+        .assert()
+        .success()
+        .stdout(contains(r#""channels":{"news":1,"sport":1}"#));
+
+    // 订阅者断开之后，server 在下一次发送 Ping 失败时取消订阅
+    s1.kill().unwrap();
+    s1.wait().unwrap();
+    let unsubscribed = (0..50).any(|_| {
+        thread::sleep(Duration::from_millis(100));
+        let out = client(d, &["publish", "news", "bye"]).output().unwrap();
+        out.stdout == b"0\n"
+    });
+    assert!(unsubscribed);
+    s2.kill().unwrap();
+    s2.wait().unwrap();
+    srv.kill().unwrap();
+    srv.wait().unwrap();
+}
@@ -0,0 +1,419 @@
+use assert_cmd::prelude::*;
+use predicates::str::contains;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::process::{Child, Command};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use kvs::raft::{NodeId, RaftNode, Role, NOT_LEADER};
+use kvs::replication::Replication;
+use kvs::thread_pool::{NaiveThreadPool, ThreadPool};
+use kvs::{KvStore, KvsEngine, KvsServer, ServerOptions};
+use slog::{o, Discard, Logger};
+use tempfile::TempDir;
+
+// 选举和复制都是异步的，等待条件成立，最多等 10 秒
+fn wait_for(mut f: impl FnMut() -> bool) {
+    let start = Instant::now();
+    while !f() {
+        assert!(start.elapsed() < Duration::from_secs(10), "timed out");
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+struct Node {
+    raft: Arc<RaftNode>,
+    engine: Arc<dyn KvsEngine + Sync>,
+    // raft 之下的本地引擎，不经过 leader 检查直接读
+    store: Arc<dyn KvsEngine + Sync>,
+    _dir: TempDir,
+}
+
+fn addr(port: u16) -> String {
+    format!("127.0.0.1:{}", port)
+}
+
+fn peers(ports: &[(NodeId, u16)]) -> BTreeMap<NodeId, String> {
+    ports.iter().map(|(id, port)| (*id, addr(*port))).collect()
+}
+
+fn start(id: NodeId, port: u16, peers: BTreeMap<NodeId, String>, threshold: u64) -> Node {
+    let dir = TempDir::new().unwrap();
+    let store: Arc<dyn KvsEngine + Sync> = Arc::new(KvStore::open(dir.path()).unwrap());
+    let logger = Logger::root(Discard, o!());
+    let raft = RaftNode::start(
+        id,
+        peers,
+        store.clone(),
+        &dir.path().join("raft"),
+        threshold,
+        logger.clone(),
+    )
+    .unwrap();
+    let engine = raft.engine();
+    let opts = ServerOptions {
+        replication: Some(Replication::Raft(raft.clone())),
+        ..ServerOptions::default()
+    };
+    let server = KvsServer::with_options(
+        engine.clone(),
+        NaiveThreadPool::new(4).unwrap(),
+        logger,
+        opts,
+    );
+    thread::spawn(move || server.run(addr(port).parse().unwrap()).unwrap());
+    Node {
+        raft,
+        engine,
+        store,
+        _dir: dir,
+    }
+}
+
+// 等 nodes 中选出一个 leader，并且其他节点都知道它
+fn leader(nodes: &[&Node]) -> usize {
+    let mut found = 0;
+    wait_for(|| {
+        let leaders: Vec<usize> = (0..nodes.len())
+            .filter(|i| nodes[*i].raft.role() == Role::Leader)
+            .collect();
+        if leaders.len() != 1 {
+            return false;
+        }
+        found = leaders[0];
+        let id = nodes[found].raft.id();
+        nodes.iter().all(|n| n.raft.leader() == Some(id))
+    });
+    found
+}
+
+fn wait_value(n: &Node, key: &str, value: Option<&str>) {
+    wait_for(|| n.store.get(key.to_owned()).unwrap().as_deref() == value);
+}
+
+#[test]
+fn failover() {
+    let ports = [(1, 4030), (2, 4031), (3, 4032)];
+    let nodes: Vec<Node> = ports
+        .iter()
+        .map(|(id, port)| start(*id, *port, peers(&ports), 10000))
+        .collect();
+    let all: Vec<&Node> = nodes.iter().collect();
+    let l = leader(&all);
+    let leader_id = nodes[l].raft.id();
+    nodes[l]
+        .engine
+        .set("key1".to_owned(), "value1".to_owned())
+        .unwrap();
+    assert_eq!(
+        nodes[l].engine.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    for n in &nodes {
+        wait_value(n, "key1", Some("value1"));
+    }
+
+    // follower 拒绝读写，并告诉客户端 leader 的地址
+    let f = (l + 1) % 3;
+    let redirect = format!("{} {}", NOT_LEADER, addr(ports[l].1));
+    let e = nodes[f]
+        .engine
+        .set("key2".to_owned(), "x".to_owned())
+        .unwrap_err();
+    assert_eq!(e.to_string(), redirect);
+    let e = nodes[f].engine.get("key1".to_owned()).unwrap_err();
+    assert_eq!(e.to_string(), redirect);
+
+    // 事务在 leader 上作为一个条目提交
+    let mut txn = nodes[l].engine.begin().unwrap();
+    txn.set("t1".to_owned(), "a".to_owned()).unwrap();
+    txn.remove("key1".to_owned()).unwrap();
+    txn.commit().unwrap();
+    for n in &nodes {
+        wait_value(n, "t1", Some("a"));
+        wait_value(n, "key1", None);
+    }
+
+    // 把 leader 和其他节点分开，多数一侧选出新的 leader
+    let others: Vec<usize> = (0..3).filter(|i| *i != l).collect();
+    let other_ids: Vec<NodeId> = others.iter().map(|i| nodes[*i].raft.id()).collect();
+    nodes[l].raft.partition(&other_ids);
+    for i in &others {
+        nodes[*i].raft.partition(&[leader_id]);
+    }
+    let majority: Vec<&Node> = others.iter().map(|i| &nodes[*i]).collect();
+    let nl = others[leader(&majority)];
+    nodes[nl]
+        .engine
+        .set("key2".to_owned(), "value2".to_owned())
+        .unwrap();
+
+    // 旧 leader 联系不上多数节点，写入不能提交，也不能读
+    // 写入时可能还没发现自己被分区，提交超时或者退出 leader 都会返回错误
+    assert!(nodes[l]
+        .engine
+        .set("key3".to_owned(), "lost".to_owned())
+        .is_err());
+    assert!(nodes[l].engine.get("key2".to_owned()).is_err());
+    assert_ne!(nodes[l].raft.role(), Role::Leader);
+
+    // 恢复之后旧 leader 追上新 leader，没提交的写入被丢弃
+    for n in &nodes {
+        n.raft.heal();
+    }
+    nodes[nl]
+        .engine
+        .set("key4".to_owned(), "value4".to_owned())
+        .unwrap();
+    for n in &nodes {
+        wait_value(n, "key2", Some("value2"));
+        wait_value(n, "key4", Some("value4"));
+        assert_eq!(n.store.get("key3".to_owned()).unwrap(), None);
+    }
+    assert_eq!(leader(&all), nl);
+    for n in &nodes {
+        n.raft.stop();
+    }
+}
+
+#[test]
+fn membership_and_snapshot() {
+    let ports = [(1, 4033), (2, 4034), (3, 4035)];
+    let mut nodes: Vec<Node> = ports
+        .iter()
+        .map(|(id, port)| start(*id, *port, peers(&ports), 20))
+        .collect();
+    let all: Vec<&Node> = nodes.iter().collect();
+    let l = leader(&all);
+    for i in 0..100 {
+        nodes[l]
+            .engine
+            .set(format!("key{}", i), format!("value{}", i))
+            .unwrap();
+    }
+    wait_for(|| nodes[l].raft.status()["snapshot_index"].as_u64() > Some(20));
+
+    // 新节点不带初始配置启动，加入之后从 leader 安装 snapshot
+    for (id, port) in [(4, 4036), (5, 4037)] {
+        nodes.push(start(id, port, BTreeMap::new(), 20));
+        nodes[l].raft.add_node(id, addr(port)).unwrap();
+    }
+    assert_eq!(nodes[l].raft.members().len(), 5);
+    let e = nodes[l].raft.add_node(4, addr(4036)).unwrap_err();
+    assert_eq!(e.to_string(), "node 4 is already a member");
+    for n in &nodes[3..] {
+        wait_value(n, "key0", Some("value0"));
+        wait_value(n, "key99", Some("value99"));
+        wait_for(|| n.raft.members().len() == 5);
+    }
+
+    // 五个节点中隔离两个，剩下的三个仍然可以写入
+    let isolated: Vec<usize> = (0..5).filter(|i| *i != l).take(2).collect();
+    let isolated_ids: Vec<NodeId> = isolated.iter().map(|i| nodes[*i].raft.id()).collect();
+    for (i, n) in nodes.iter().enumerate() {
+        if isolated.contains(&i) {
+            let rest: Vec<NodeId> = (1..=5).filter(|id| !isolated_ids.contains(id)).collect();
+            n.raft.partition(&rest);
+        } else {
+            n.raft.partition(&isolated_ids);
+        }
+    }
+    nodes[l]
+        .engine
+        .set("minority".to_owned(), "ok".to_owned())
+        .unwrap();
+    for n in &nodes {
+        n.raft.heal();
+    }
+    for n in &nodes {
+        wait_value(n, "minority", Some("ok"));
+    }
+
+    // 删除一个 follower，再删除 leader 自己，剩下的节点选出新 leader
+    let removed = isolated[0];
+    nodes[l].raft.remove_node(nodes[removed].raft.id()).unwrap();
+    assert_eq!(nodes[l].raft.members().len(), 4);
+    let leader_id = nodes[l].raft.id();
+    nodes[l].raft.remove_node(leader_id).unwrap();
+    let rest: Vec<&Node> = (0..5)
+        .filter(|i| *i != l && *i != removed)
+        .map(|i| &nodes[i])
+        .collect();
+    let nl = leader(&rest);
+    assert_eq!(rest[nl].raft.members().len(), 3);
+    rest[nl]
+        .engine
+        .set("after".to_owned(), "removal".to_owned())
+        .unwrap();
+    for n in &rest {
+        wait_value(n, "after", Some("removal"));
+    }
+    assert_eq!(nodes[removed].store.get("after".to_owned()).unwrap(), None);
+    for n in &nodes {
+        n.raft.stop();
+    }
+}
+
+fn server(dir: &Path, id: u64, port: u16, peers: &str) -> Child {
+    let child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", &addr(port)])
+        .args(["--raft-id", &id.to_string(), "--raft-peers", peers])
+        .current_dir(dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    child
+}
+
+fn client(dir: &Path, port: u16, args: &[&str]) -> Command {
+    let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+    cmd.args(args)
+        .args(["--addr", &addr(port)])
+        .current_dir(dir);
+    cmd
+}
+
+fn role(dir: &Path, port: u16) -> serde_json::Value {
+    let out = client(dir, port, &["role"]).output().unwrap();
+    assert!(out.status.success());
+    serde_json::from_slice(&out.stdout).unwrap()
+}
+
+#[test]
+fn cli_cluster() {
+    let ports = [4040, 4041, 4042];
+    let peers = "1=127.0.0.1:4040,2=127.0.0.1:4041,3=127.0.0.1:4042";
+    let dirs: Vec<TempDir> = (0..3).map(|_| TempDir::new().unwrap()).collect();
+    let mut children: Vec<Child> = (0..3)
+        .map(|i| server(dirs[i].path(), i as u64 + 1, ports[i], peers))
+        .collect();
+    let d = dirs[0].path();
+    let mut l = 0;
+    wait_for(
+        || match (0..3).find(|i| role(d, ports[*i])["role"] == "leader") {
+            Some(i) => {
+                l = i;
+                true
+            }
+            None => false,
+        },
+    );
+    let f = (l + 1) % 3;
+    assert_eq!(role(d, ports[f])["role"], "follower");
+    assert_eq!(role(d, ports[f])["leader"], l as u64 + 1);
+
+    // 发给 follower 的命令由 kvs-client 转发到 leader
+    client(d, ports[f], &["set", "key1", "value1"])
+        .assert()
+        .success()
+        .stdout("Success\n");
+    client(d, ports[f], &["get", "key1"])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    client(d, ports[f], &["remove-node", "9"])
+        .assert()
+        .success()
+        .stdout(contains("node 9 is not a member"));
+    client(d, ports[f], &["add-node", "1", "127.0.0.1:4040"])
+        .assert()
+        .success()
+        .stdout(contains("node 1 is already a member"));
+
+    // leader 宕机之后剩下的两个节点选出新 leader，数据还在
+    children[l].kill().unwrap();
+    children[l].wait().unwrap();
+    wait_for(|| {
+        let out = client(d, ports[f], &["get", "key1"]).output().unwrap();
+        String::from_utf8_lossy(&out.stdout).trim() == "value1"
+    });
+    client(d, ports[f], &["set", "key2", "value2"])
+        .assert()
+        .success();
+
+    // 旧 leader 重启之后作为 follower 追上
+    children[l] = server(dirs[l].path(), l as u64 + 1, ports[l], peers);
+    wait_for(|| role(d, ports[l])["applied"] == role(d, ports[f])["applied"]);
+    assert_eq!(role(d, ports[l])["role"], "follower");
+    client(d, ports[l], &["get", "key2"])
+        .assert()
+        .success()
+        .stdout("value2\n");
+
+    for c in &mut children {
+        c.kill().unwrap();
+        c.wait().unwrap();
+    }
+}
+
+// 没有 snapshot 时重启从头重放日志，引擎中已经应用过的结果不能影响事务的读检查
+#[test]
+fn restart_without_snapshot() {
+    let dir = TempDir::new().unwrap();
+    let start = || {
+        // 上一个节点的线程退出之后才能再次打开
+        let mut store = None;
+        wait_for(|| {
+            store = KvStore::open(dir.path()).ok();
+            store.is_some()
+        });
+        let store: Arc<dyn KvsEngine + Sync> = Arc::new(store.unwrap());
+        let peers = peers(&[(1, 4098)]);
+        let raft = RaftNode::start(
+            1,
+            peers,
+            store.clone(),
+            &dir.path().join("raft"),
+            1000,
+            Logger::root(Discard, o!()),
+        )
+        .unwrap();
+        // 等到成为 leader 并且应用完所有的日志
+        wait_for(|| {
+            let st = raft.status();
+            raft.role() == Role::Leader && st["applied"] == st["last_index"]
+        });
+        (raft, store)
+    };
+
+    let (raft, store) = start();
+    let engine = raft.engine();
+    engine.set("a".to_owned(), "0".to_owned()).unwrap();
+    let mut txn = engine.begin().unwrap();
+    assert_eq!(txn.get("b".to_owned()).unwrap(), None);
+    txn.set("a".to_owned(), "1".to_owned()).unwrap();
+    txn.commit().unwrap();
+    engine.set("b".to_owned(), "1".to_owned()).unwrap();
+    assert_eq!(store.get("a".to_owned()).unwrap(), Some("1".to_owned()));
+    raft.stop();
+    drop((raft, engine, store));
+
+    let (raft, store) = start();
+    assert_eq!(raft.status()["snapshot_index"], 0);
+    assert_eq!(store.get("a".to_owned()).unwrap(), Some("1".to_owned()));
+    assert_eq!(store.get("b".to_owned()).unwrap(), Some("1".to_owned()));
+    raft.stop();
+}
+
+// 第一次启动时引擎中的数据不在日志中，拒绝启动
+#[test]
+fn start_with_existing_data() {
+    let dir = TempDir::new().unwrap();
+    let store: Arc<dyn KvsEngine + Sync> = Arc::new(KvStore::open(dir.path()).unwrap());
+    store.set("a".to_owned(), "0".to_owned()).unwrap();
+    let r = RaftNode::start(
+        1,
+        peers(&[(1, 4099)]),
+        store,
+        &dir.path().join("raft"),
+        1000,
+        Logger::root(Discard, o!()),
+    );
+    match r {
+        Ok(_) => panic!("started on an engine with data"),
+        Err(e) => assert!(e.to_string().contains("must start empty"), "{}", e),
+    }
+}
@@ -0,0 +1,286 @@
+use assert_cmd::prelude::*;
+use predicates::str::contains;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::process::{Child, Command};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use kvs::replication::{Primary, ReadOnly, ReplMessage, Replica, Replication, READ_ONLY_REPLICA};
+use kvs::thread_pool::{NaiveThreadPool, ThreadPool};
+use kvs::{KvStore, KvsEngine, KvsServer, ServerOptions, SledKvsEngine};
+use slog::{o, Discard, Logger};
+use tempfile::TempDir;
+
+// 复制是异步的，等待条件成立，最多等 10 秒
+fn wait_for(mut f: impl FnMut() -> bool) {
+    let start = Instant::now();
+    while !f() {
+        assert!(start.elapsed() < Duration::from_secs(10), "timed out");
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+fn offset(p: &Primary) -> u64 {
+    p.role()["offset"].as_u64().unwrap()
+}
+
+fn start_replica(addr: &str, engine: &Arc<dyn KvsEngine + Sync>, dir: &Path) -> Arc<Replica> {
+    Replica::start(addr, engine.clone(), dir, Logger::root(Discard, o!())).unwrap()
+}
+
+fn replicate(
+    primary: Arc<dyn KvsEngine + Sync>,
+    replica: Arc<dyn KvsEngine + Sync>,
+    replica_dir: &Path,
+    addr: &'static str,
+) {
+    let p = Primary::new(primary, 100);
+    for i in 0..10 {
+        p.set(format!("key{}", i), format!("value{}", i)).unwrap();
+    }
+    // replica 上原有的数据在全量同步之后被删除
+    replica.set("stale".to_owned(), "x".to_owned()).unwrap();
+    let opts = ServerOptions {
+        replication: Some(Replication::Primary(p.clone())),
+        ..ServerOptions::default()
+    };
+    let server = KvsServer::with_options(
+        Arc::new(p.clone()),
+        NaiveThreadPool::new(4).unwrap(),
+        Logger::root(Discard, o!()),
+        opts,
+    );
+    thread::spawn(move || server.run(addr.parse().unwrap()).unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    let r = start_replica(addr, &replica, replica_dir);
+    wait_for(|| r.status().offset == 10);
+    assert_eq!(replica.keys("").unwrap().len(), 10);
+    assert_eq!(replica.get("stale".to_owned()).unwrap(), None);
+    let st = r.status();
+    assert!(st.connected);
+    assert_eq!((st.full_syncs, st.partial_syncs), (1, 0));
+
+    // 之后的写入和事务持续复制过来
+    p.set("key1".to_owned(), "new".to_owned()).unwrap();
+    p.remove("key2".to_owned()).unwrap();
+    let mut txn = p.begin().unwrap();
+    txn.set("t1".to_owned(), "a".to_owned()).unwrap();
+    txn.remove("key3".to_owned()).unwrap();
+    txn.commit().unwrap();
+    wait_for(|| r.status().offset == offset(&p));
+    assert_eq!(r.status().lag(), 0);
+    assert_eq!(
+        replica.get("key1".to_owned()).unwrap(),
+        Some("new".to_owned())
+    );
+    assert_eq!(replica.get("key2".to_owned()).unwrap(), None);
+    assert_eq!(replica.get("key3".to_owned()).unwrap(), None);
+    assert_eq!(replica.get("t1".to_owned()).unwrap(), Some("a".to_owned()));
+
+    // 对外只读
+    let ro = ReadOnly(replica.clone());
+    let e = ro.set("key1".to_owned(), "x".to_owned()).unwrap_err();
+    assert_eq!(e.to_string(), READ_ONLY_REPLICA);
+    assert_eq!(ro.get("key1".to_owned()).unwrap(), Some("new".to_owned()));
+
+    // 断开期间的写入还在 backlog 中，重连之后部分同步
+    r.stop();
+    thread::sleep(Duration::from_millis(200));
+    p.set("key4".to_owned(), "after".to_owned()).unwrap();
+    let r = start_replica(addr, &replica, replica_dir);
+    wait_for(|| r.status().offset == offset(&p));
+    assert_eq!((r.status().full_syncs, r.status().partial_syncs), (0, 1));
+    assert_eq!(
+        replica.get("key4".to_owned()).unwrap(),
+        Some("after".to_owned())
+    );
+
+    // 断开期间的写入超过了 backlog，只能全量同步
+    r.stop();
+    thread::sleep(Duration::from_millis(200));
+    for i in 0..200 {
+        p.set(format!("many{}", i), "v".to_owned()).unwrap();
+    }
+    let r = start_replica(addr, &replica, replica_dir);
+    wait_for(|| r.status().offset == offset(&p));
+    assert_eq!((r.status().full_syncs, r.status().partial_syncs), (1, 0));
+    assert_eq!(
+        replica.keys("").unwrap(),
+        p.keys("").unwrap(),
+        "replica should match the primary"
+    );
+    r.stop();
+}
+
+#[test]
+fn kvs_replication() {
+    let primary_dir = TempDir::new().unwrap();
+    let replica_dir = TempDir::new().unwrap();
+    replicate(
+        Arc::new(KvStore::open(primary_dir.path()).unwrap()),
+        Arc::new(KvStore::open(replica_dir.path()).unwrap()),
+        replica_dir.path(),
+        "127.0.0.1:4024",
+    );
+}
+
+#[test]
+fn sled_replication() {
+    let primary_dir = TempDir::new().unwrap();
+    let replica_dir = TempDir::new().unwrap();
+    replicate(
+        Arc::new(SledKvsEngine::open(primary_dir.path()).unwrap()),
+        Arc::new(SledKvsEngine::open(replica_dir.path()).unwrap()),
+        replica_dir.path(),
+        "127.0.0.1:4025",
+    );
+}
+
+fn server(dir: &Path, args: &[&str]) -> Child {
+    let child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs"])
+        .args(args)
+        .current_dir(dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    child
+}
+
+fn client(dir: &Path, addr: &str, args: &[&str]) -> Command {
+    let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+    cmd.args(args).args(["--addr", addr]).current_dir(dir);
+    cmd
+}
+
+fn role(dir: &Path, addr: &str) -> serde_json::Value {
+    let out = client(dir, addr, &["role"]).output().unwrap();
+    assert!(out.status.success());
+    serde_json::from_slice(&out.stdout).unwrap()
+}
+
+fn wait_value(dir: &Path, addr: &str, key: &str, value: &str) {
+    wait_for(|| {
+        let out = client(dir, addr, &["get", key]).output().unwrap();
+        String::from_utf8_lossy(&out.stdout).trim() == value
+    });
+}
+
+#[test]
+fn cli_replica_resume() {
+    let primary_dir = TempDir::new().unwrap();
+    let replica_dir = TempDir::new().unwrap();
+    let (p, r) = (primary_dir.path(), replica_dir.path());
+    let (paddr, raddr) = ("127.0.0.1:4026", "127.0.0.1:4027");
+    let mut primary = server(p, &["--addr", paddr]);
+    let replica_args = ["--addr", raddr, "--replica-of", paddr];
+    let mut replica = server(r, &replica_args);
+
+    client(p, paddr, &["set", "key1", "value1"])
+        .assert()
+        .success();
+    wait_value(r, raddr, "key1", "value1");
+    client(r, raddr, &["set", "key1", "x"])
+        .assert()
+        .failure()
+        .stderr(contains("READONLY"));
+    assert_eq!(role(p, paddr)["role"], "primary");
+    assert_eq!(role(p, paddr)["replicas"], 1);
+    let st = role(r, raddr);
+    assert_eq!(st["role"], "replica");
+    assert_eq!(st["primary"], paddr);
+    assert_eq!(st["connected"], true);
+    assert_eq!(st["lag"], 0);
+
+    // replica 重启之后从保存的 offset 继续
+    replica.kill().unwrap();
+    replica.wait().unwrap();
+    client(p, paddr, &["set", "key2", "value2"])
+        .assert()
+        .success();
+    let mut replica = server(r, &replica_args);
+    wait_value(r, raddr, "key2", "value2");
+    let st = role(r, raddr);
+    assert_eq!(
+        (st["full_syncs"].as_u64(), st["partial_syncs"].as_u64()),
+        (Some(0), Some(1))
+    );
+    assert_eq!(st["offset"], 2);
+
+    // primary 重启之后 run_id 变化，replica 自动重连并全量同步
+    primary.kill().unwrap();
+    primary.wait().unwrap();
+    let mut primary = server(p, &["--addr", paddr]);
+    client(p, paddr, &["set", "key3", "value3"])
+        .assert()
+        .success();
+    wait_value(r, raddr, "key3", "value3");
+    wait_value(r, raddr, "key1", "value1");
+    assert_eq!(role(r, raddr)["full_syncs"], 1);
+
+    replica.kill().unwrap();
+    replica.wait().unwrap();
+    primary.kill().unwrap();
+    primary.wait().unwrap();
+}
+
+// 全量同步的快照传到一半时连接断开，replica 不能用快照的 run_id 部分同步
+#[test]
+fn interrupted_full_sync() {
+    let dir = TempDir::new().unwrap();
+    let engine: Arc<dyn KvsEngine + Sync> = Arc::new(KvStore::open(dir.path()).unwrap());
+    let listener = TcpListener::bind("127.0.0.1:4096").unwrap();
+    let send = |s: &mut TcpStream, m: &ReplMessage| {
+        s.write_all((serde_json::to_string(m).unwrap() + "\n").as_bytes())
+            .unwrap();
+    };
+    let accept = || {
+        let (s, _) = listener.accept().unwrap();
+        let mut line = String::new();
+        BufReader::new(s.try_clone().unwrap())
+            .read_line(&mut line)
+            .unwrap();
+        (s, serde_json::from_str::<kvs::Command>(&line).unwrap())
+    };
+
+    let r = start_replica("127.0.0.1:4096", &engine, dir.path());
+    let (mut s, sync) = accept();
+    assert!(matches!(sync, kvs::Command::Sync(None, 0)), "{:?}", sync);
+    let full = ReplMessage::FullSync {
+        run_id: "r1".to_owned(),
+        offset: 2,
+    };
+    send(&mut s, &full);
+    send(
+        &mut s,
+        &ReplMessage::Snapshot("a".to_owned(), "1".to_owned()),
+    );
+    drop(s);
+
+    // 重连时仍然请求全量同步
+    let (mut s, sync) = accept();
+    assert!(matches!(sync, kvs::Command::Sync(None, _)), "{:?}", sync);
+    assert_eq!(r.status().run_id, None);
+    send(&mut s, &full);
+    send(
+        &mut s,
+        &ReplMessage::Snapshot("a".to_owned(), "1".to_owned()),
+    );
+    send(
+        &mut s,
+        &ReplMessage::Snapshot("b".to_owned(), "2".to_owned()),
+    );
+    send(&mut s, &ReplMessage::SnapshotEnd);
+    send(&mut s, &ReplMessage::Ping(2));
+    wait_for(|| r.status().run_id.is_some());
+    let st = r.status();
+    assert_eq!((st.run_id.as_deref(), st.offset), (Some("r1"), 2));
+    assert_eq!(st.full_syncs, 2);
+    assert_eq!(engine.get("b".to_owned()).unwrap(), Some("2".to_owned()));
+    r.stop();
+}
@@ -0,0 +1,315 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use kvs::resp::glob_match;
+use kvs::thread_pool::{NaiveThreadPool, ThreadPool};
+use kvs::{KvStore, KvsEngine, KvsServer, Protocol, Result, ServerOptions, SledKvsEngine};
+use slog::{o, Discard, Logger};
+use tempfile::TempDir;
+
+// 测试用的 RESP 客户端，直接拼协议字节，不依赖 server 端的编码实现
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<String>),
+    Array(Vec<Reply>),
+}
+
+struct Client {
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+}
+
+impl Client {
+    fn connect(addr: &str) -> Client {
+        let stream = TcpStream::connect(addr).unwrap();
+        let reader = BufReader::new(stream.try_clone().unwrap());
+        Client { stream, reader }
+    }
+
+    fn call(&mut self, args: &[&str]) -> Reply {
+        let mut buf = format!("*{}\r\n", args.len());
+        for a in args {
+            buf += &format!("${}\r\n{}\r\n", a.len(), a);
+        }
+        self.stream.write_all(buf.as_bytes()).unwrap();
+        self.read()
+    }
+
+    // inline command，redis-cli 以外的简单工具（telnet、nc）会这样发送
+    fn inline(&mut self, line: &str) -> Reply {
+        self.stream
+            .write_all(format!("{}\r\n", line).as_bytes())
+            .unwrap();
+        self.read()
+    }
+
+    fn read(&mut self) -> Reply {
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap();
+        let line = line.trim_end_matches("\r\n");
+        let (t, rest) = line.split_at(1);
+        match t {
+            "+" => Reply::Simple(rest.to_owned()),
+            "-" => Reply::Error(rest.to_owned()),
+            ":" => Reply::Integer(rest.parse().unwrap()),
+            "$" => {
+                let n: i64 = rest.parse().unwrap();
+                if n < 0 {
+                    return Reply::Bulk(None);
+                }
+                let mut buf = vec![0; n as usize + 2];
+                self.reader.read_exact(&mut buf).unwrap();
+                assert_eq!(&buf[n as usize..], b"\r\n");
+                buf.truncate(n as usize);
+                Reply::Bulk(Some(String::from_utf8(buf).unwrap()))
+            }
+            "*" => {
+                let n: usize = rest.parse().unwrap();
+                Reply::Array((0..n).map(|_| self.read()).collect())
+            }
+            _ => panic!("unexpected reply {:?}", line),
+        }
+    }
+}
+
+fn ok() -> Reply {
+    Reply::Simple("OK".to_owned())
+}
+
+fn bulk(s: &str) -> Reply {
+    Reply::Bulk(Some(s.to_owned()))
+}
+
+fn start(engine: Arc<dyn KvsEngine + Sync>, addr: &'static str) {
+    let opts = ServerOptions {
+        protocol: Protocol::Resp,
+        ..ServerOptions::default()
+    };
+    let server = KvsServer::with_options(
+        engine,
+        NaiveThreadPool::new(4).unwrap(),
+        Logger::root(Discard, o!()),
+        opts,
+    );
+    thread::spawn(move || server.run(addr.parse().unwrap()).unwrap());
+    thread::sleep(Duration::from_millis(500));
+}
+
+fn resp_commands(engine: Arc<dyn KvsEngine + Sync>, addr: &'static str) {
+    start(engine.clone(), addr);
+    let mut c = Client::connect(addr);
+
+    assert_eq!(c.call(&["PING"]), Reply::Simple("PONG".to_owned()));
+    assert_eq!(c.call(&["ping", "hi"]), bulk("hi"));
+    assert_eq!(c.call(&["GET", "key1"]), Reply::Bulk(None));
+    assert_eq!(c.call(&["SET", "key1", "value1"]), ok());
+    assert_eq!(c.call(&["GET", "key1"]), bulk("value1"));
+    // 通过 RESP 写入的数据和 kvs 协议共享同一个引擎
+    assert_eq!(
+        engine.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    assert_eq!(c.inline("GET key1"), bulk("value1"));
+
+    // 值中可以包含空格和换行
+    assert_eq!(c.call(&["SET", "key2", "a b\r\nc"]), ok());
+    assert_eq!(c.call(&["GET", "key2"]), bulk("a b\r\nc"));
+
+    assert_eq!(
+        c.call(&["EXISTS", "key1", "key2", "key3"]),
+        Reply::Integer(2)
+    );
+    assert_eq!(c.call(&["DEL", "key2", "key3"]), Reply::Integer(1));
+    assert_eq!(c.call(&["EXISTS", "key2"]), Reply::Integer(0));
+
+    assert_eq!(c.call(&["SET", "key1", "v", "NX"]), Reply::Bulk(None));
+    assert_eq!(c.call(&["SET", "key2", "v", "XX"]), Reply::Bulk(None));
+    assert_eq!(c.call(&["SET", "key2", "v", "NX"]), ok());
+
+    assert_eq!(
+        c.call(&["MSET", "user:1", "a", "user:2", "b", "user:10", "c"]),
+        ok()
+    );
+    assert_eq!(
+        c.call(&["MGET", "user:1", "nope", "user:2"]),
+        Reply::Array(vec![bulk("a"), Reply::Bulk(None), bulk("b")])
+    );
+    assert_eq!(
+        c.call(&["KEYS", "user:*"]),
+        Reply::Array(vec![bulk("user:1"), bulk("user:10"), bulk("user:2")])
+    );
+    assert_eq!(
+        c.call(&["KEYS", "user:?"]),
+        Reply::Array(vec![bulk("user:1"), bulk("user:2")])
+    );
+    assert_eq!(
+        c.call(&["KEYS", "key[12]"]),
+        Reply::Array(vec![bulk("key1"), bulk("key2")])
+    );
+
+    // SCAN 分页遍历所有 key，cursor 为 0 表示遍历结束
+    let mut cursor = "0".to_owned();
+    let mut seen = vec![];
+    loop {
+        match c.call(&["SCAN", &cursor, "MATCH", "*", "COUNT", "2"]) {
+            Reply::Array(mut r) => {
+                let keys = r.pop().unwrap();
+                let next = r.pop().unwrap();
+                match keys {
+                    Reply::Array(keys) => seen.extend(keys),
+                    r => panic!("unexpected reply {:?}", r),
+                }
+                match next {
+                    Reply::Bulk(Some(n)) => cursor = n,
+                    r => panic!("unexpected reply {:?}", r),
+                }
+            }
+            r => panic!("unexpected reply {:?}", r),
+        }
+        if cursor == "0" {
+            break;
+        }
+    }
+    assert_eq!(seen.len(), 5);
+
+    // 过期
+    assert_eq!(c.call(&["TTL", "key1"]), Reply::Integer(-1));
+    assert_eq!(c.call(&["TTL", "missing"]), Reply::Integer(-2));
+    assert_eq!(c.call(&["EXPIRE", "missing", "10"]), Reply::Integer(0));
+    assert_eq!(c.call(&["EXPIRE", "key1", "100"]), Reply::Integer(1));
+    match c.call(&["TTL", "key1"]) {
+        Reply::Integer(n) => assert!(n > 90 && n <= 100),
+        r => panic!("unexpected reply {:?}", r),
+    }
+    // 重新 SET 会清除过期时间
+    assert_eq!(c.call(&["SET", "key1", "value1"]), ok());
+    assert_eq!(c.call(&["TTL", "key1"]), Reply::Integer(-1));
+    assert_eq!(c.call(&["SET", "tmp", "x", "PX", "200"]), ok());
+    assert_eq!(c.call(&["PEXPIRE", "key2", "200"]), Reply::Integer(1));
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(c.call(&["GET", "tmp"]), Reply::Bulk(None));
+    assert_eq!(c.call(&["EXISTS", "key2"]), Reply::Integer(0));
+    assert_eq!(engine.get("tmp".to_owned()).unwrap(), None);
+
+    // 过期时间溢出时回复错误，不影响之后的命令
+    let invalid = |cmd: &str| Reply::Error(format!("ERR invalid expire time in '{}' command", cmd));
+    for unit in ["EX", "PX"] {
+        assert_eq!(
+            c.call(&["SET", "tmp", "x", unit, "18446744073709551615"]),
+            invalid("set")
+        );
+    }
+    assert_eq!(
+        c.call(&["EXPIRE", "key1", "9223372036854776"]),
+        invalid("expire")
+    );
+    assert_eq!(
+        c.call(&["PEXPIRE", "key1", "9223372036854775807"]),
+        invalid("pexpire")
+    );
+    assert_eq!(c.call(&["TTL", "key1"]), Reply::Integer(-1));
+    assert_eq!(c.call(&["GET", "tmp"]), Reply::Bulk(None));
+
+    // 另一个连接看到相同的数据
+    let mut c2 = Client::connect(addr);
+    assert_eq!(c2.call(&["GET", "key1"]), bulk("value1"));
+
+    assert!(matches!(c.call(&["FOO"]), Reply::Error(e) if e.contains("unknown command")));
+    assert!(matches!(c.call(&["GET"]), Reply::Error(e) if e.contains("wrong number")));
+    assert!(matches!(c.call(&["MSET", "a"]), Reply::Error(e) if e.contains("wrong number")));
+    assert_eq!(c.call(&["QUIT"]), ok());
+}
+
+#[test]
+fn kvs_resp_commands() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    resp_commands(Arc::new(KvStore::open(temp_dir.path())?), "127.0.0.1:4009");
+    Ok(())
+}
+
+#[test]
+fn sled_resp_commands() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    resp_commands(
+        Arc::new(SledKvsEngine::open(temp_dir.path())?),
+        "127.0.0.1:4010",
+    );
+    Ok(())
+}
+
+// 客户端给出的长度过大时回复协议错误并关闭连接，server 继续服务其他连接
+#[test]
+fn oversized_lengths() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4093";
+    start(Arc::new(KvStore::open(temp_dir.path())?), addr);
+
+    // 发送的数据恰好是 server 读到的部分，连接关闭时没有未读的数据，不会被 reset
+    let long_line = "x".repeat(64 * 1024);
+    for (raw, error) in [
+        ("$9223372036854775000\r\n", "invalid bulk length"),
+        ("*1\r\n$536870913\r\n", "invalid bulk length"),
+        ("*4000000000000\r\n", "invalid multibulk length"),
+        (&long_line, "too big inline request"),
+        (&"*1\r\n".repeat(9), "too deeply nested"),
+    ] {
+        let mut c = Client::connect(addr);
+        c.stream.write_all(raw.as_bytes())?;
+        match c.read() {
+            Reply::Error(e) => assert!(e.contains(error), "{}", e),
+            r => panic!("unexpected reply {:?}", r),
+        }
+        let mut rest = String::new();
+        assert_eq!(c.reader.read_line(&mut rest)?, 0);
+        assert_eq!(
+            Client::connect(addr).call(&["PING"]),
+            Reply::Simple("PONG".to_owned())
+        );
+    }
+
+    // 长度在限制之内但是数据没有发完就断开
+    let mut c = Client::connect(addr);
+    c.stream
+        .write_all(b"*2\r\n$3\r\nGET\r\n$536870912\r\nabc")?;
+    c.stream.shutdown(std::net::Shutdown::Write)?;
+    assert!(matches!(c.read(), Reply::Error(e) if e.contains("in the middle")));
+
+    let mut c = Client::connect(addr);
+    let big = "v".repeat(1024 * 1024);
+    assert_eq!(c.call(&["SET", "big", &big]), ok());
+    assert_eq!(c.call(&["GET", "big"]), bulk(&big));
+    Ok(())
+}
+
+#[test]
+fn glob_patterns() {
+    let m = |p: &str, s: &str| glob_match(p.as_bytes(), s.as_bytes());
+    assert!(m("*", ""));
+    assert!(m("h?llo", "hello"));
+    assert!(!m("h?llo", "hllo"));
+    assert!(m("h*llo", "heeeello"));
+    assert!(m("h*llo*", "hello world"));
+    assert!(!m("h*llo", "hello world"));
+    assert!(m("h[ae]llo", "hallo"));
+    assert!(!m("h[^e]llo", "hello"));
+    assert!(m("h[a-b]llo", "hbllo"));
+    assert!(m("h[b-a]llo", "hallo"));
+    assert!(m("a\\*b", "a*b"));
+    assert!(!m("a\\*b", "axb"));
+    assert!(m("a[b", "a[b"));
+    assert!(m("a\\", "a\\"));
+    assert!(m("*a*b*c", "xxaxxbxxc"));
+    assert!(!m("*a*b*c", "xxaxxcxxb"));
+
+    // 每个 * 都尝试所有的切分位置时是指数时间
+    let start = Instant::now();
+    let p = "*a".repeat(30) + "b";
+    assert!(!m(&p, &"a".repeat(10_000)));
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
@@ -0,0 +1,68 @@
+use std::env;
+
+use kvs::sim::{self, SimOptions};
+use kvs::Result;
+
+// 默认运行的 seed 数量，KVS_SIM_SEEDS 可以改成更多
+const SEEDS: u64 = 20;
+
+// KVS_SIM_SEED 只运行一个 seed，用来重放失败的模拟
+fn seeds() -> Vec<u64> {
+    if let Ok(s) = env::var("KVS_SIM_SEED") {
+        return vec![s.parse().expect("KVS_SIM_SEED")];
+    }
+    let n = env::var("KVS_SIM_SEEDS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(SEEDS);
+    (0..n).collect()
+}
+
+#[test]
+fn random_workloads() -> Result<()> {
+    let mut crashes = 0;
+    for seed in seeds() {
+        let report = sim::run(seed, &SimOptions::default())?;
+        assert_eq!(report.ops, 800);
+        crashes += report.crashes;
+    }
+    // 除了最后检查时的崩溃之外，工作负载中间也发生过崩溃
+    assert!(crashes > seeds().len());
+    Ok(())
+}
+
+// 同一个 seed 的两次运行完全相同，不同 seed 不同
+#[test]
+fn replay() -> Result<()> {
+    let opts = SimOptions {
+        crash_rate: 0.2,
+        fault_rate: 0.2,
+        ..SimOptions::default()
+    };
+    let a = sim::run(7, &opts)?;
+    let b = sim::run(7, &opts)?;
+    assert_eq!(a.trace, b.trace);
+    assert_eq!(a.elapsed, b.elapsed);
+    let c = sim::run(8, &opts)?;
+    assert_ne!(a.trace, c.trace);
+    Ok(())
+}
+
+// 网络很不稳定时也不会读到错误的值
+#[test]
+fn unreliable_network() -> Result<()> {
+    let mut opts = SimOptions::default();
+    opts.net.reset_rate = 0.05;
+    opts.net.short_read_rate = 0.8;
+    for seed in 100..105 {
+        sim::run(seed, &opts)?;
+    }
+    Ok(())
+}
+
+// 模拟之外不能使用模拟的网络
+#[test]
+fn outside_simulation() {
+    let addr = kvs::net::Addr::Sim("kvs".to_owned());
+    assert!(kvs::net::Stream::connect(&addr).is_err());
+}
@@ -0,0 +1,304 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use kvs::storage::{Faults, MemStorage};
+use kvs::{KvStore, KvStoreOptions, KvsEngine, Result};
+
+fn open(storage: &MemStorage, sync: bool) -> Result<KvStore> {
+    let opts = KvStoreOptions {
+        sync,
+        compaction_keys: 20,
+        storage: Arc::new(storage.clone()),
+        ..KvStoreOptions::default()
+    };
+    KvStore::open_with_options(Path::new("/db"), opts)
+}
+
+fn check(store: &KvStore, model: &BTreeMap<String, String>) -> Result<()> {
+    assert_eq!(store.keys("")?, model.keys().cloned().collect::<Vec<_>>());
+    for (k, v) in model {
+        assert_eq!(store.get(k.clone())?.as_ref(), Some(v));
+    }
+    Ok(())
+}
+
+#[test]
+fn short_writes() -> Result<()> {
+    let storage = MemStorage::new();
+    storage.set_faults(Faults {
+        max_write: 3,
+        ..Faults::default()
+    });
+    let store = open(&storage, false)?;
+    let mut model = BTreeMap::new();
+    for i in 0..100 {
+        let (k, v) = (format!("key{}", i % 10), format!("value{}", i));
+        store.set(k.clone(), v.clone())?;
+        model.insert(k, v);
+    }
+    assert!(store.stats().compactions > 0);
+    check(&store, &model)?;
+    drop(store);
+    check(&open(&storage, false)?, &model)
+}
+
+#[test]
+fn disk_full() -> Result<()> {
+    let storage = MemStorage::new();
+    let store = open(&storage, false)?;
+    let mut model = BTreeMap::new();
+    for i in 0..10 {
+        store.set(format!("key{}", i), "v".repeat(100))?;
+        model.insert(format!("key{}", i), "v".repeat(100));
+    }
+    // 剩余的空间只够写入半条记录
+    let used = storage.read_file(Path::new("/db/kvs.log")).unwrap().len() as u64;
+    storage.set_faults(Faults {
+        capacity: Some(used + 60),
+        ..Faults::default()
+    });
+    let err = store.set("key0".to_owned(), "x".repeat(100)).unwrap_err();
+    assert!(err.to_string().contains("no space left"), "{}", err);
+    assert_eq!(
+        storage.read_file(Path::new("/db/kvs.log")).unwrap().len() as u64,
+        used
+    );
+    check(&store, &model)?;
+    // 临时文件写不下，compaction 失败之后旧的 log 不变，临时文件被删除
+    store.set("key0".to_owned(), "v".repeat(10))?;
+    model.insert("key0".to_owned(), "v".repeat(10));
+    assert!(store.compact().is_err());
+    assert!(storage.read_file(Path::new("/db/kvs.log1")).is_none());
+    check(&store, &model)?;
+
+    // 空间释放之后可以继续写入
+    storage.set_faults(Faults::default());
+    store.set("key1".to_owned(), "y".to_owned())?;
+    model.insert("key1".to_owned(), "y".to_owned());
+    store.compact()?;
+    check(&store, &model)?;
+    drop(store);
+    check(&open(&storage, false)?, &model)
+}
+
+#[test]
+fn fsync_failure() -> Result<()> {
+    let storage = MemStorage::new();
+    let store = open(&storage, true)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    storage.set_faults(Faults {
+        failed_syncs: 1,
+        ..Faults::default()
+    });
+    assert!(store.set("key1".to_owned(), "value2".to_owned()).is_err());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    storage.set_faults(Faults {
+        failed_syncs: 1,
+        ..Faults::default()
+    });
+    assert!(store.compact().is_err());
+    drop(store);
+
+    // 失败的写入没有留在 log 中，断电之后所有成功的写入都还在
+    storage.power_off();
+    let store = open(&storage, true)?;
+    let model = BTreeMap::from([
+        ("key1".to_owned(), "value1".to_owned()),
+        ("key2".to_owned(), "value3".to_owned()),
+    ]);
+    check(&store, &model)?;
+    assert_eq!(store.stats().log_keys, 3);
+    Ok(())
+}
+
+// 在写入第 n 个修改操作时崩溃，n 覆盖整个工作负载中的每一个位置，包括打开时新建 log 和 compaction 中的每一步
+// 崩溃之后一定可以重新打开，返回成功的写入都在，崩溃时正在进行的写入可能生效也可能没有
+fn crash_everywhere(power_off: bool) -> Result<()> {
+    for n in 0.. {
+        let storage = MemStorage::new();
+        storage.set_faults(Faults {
+            crash_after: Some(n),
+            ..Faults::default()
+        });
+        let mut model = BTreeMap::new();
+        // 崩溃时正在进行的写入 (key, 写入之前的 value, 写入的 value)
+        let mut pending = None;
+        if let Ok(store) = open(&storage, power_off) {
+            for i in 0..60 {
+                let k = format!("key{}", i % 5);
+                let r = if i == 30 {
+                    store.compact().map(|_| ())
+                } else if i % 7 == 6 && model.contains_key(&k) {
+                    pending = Some((k.clone(), model.get(&k).cloned(), None));
+                    store.remove(k.clone())
+                } else {
+                    let v = format!("value{}", i);
+                    pending = Some((k.clone(), model.get(&k).cloned(), Some(v.clone())));
+                    store.set(k.clone(), v)
+                };
+                if r.is_err() {
+                    break;
+                }
+                // compact 不改变数据，没有 pending
+                if let Some((k, _, v)) = pending.take() {
+                    match v {
+                        Some(v) => model.insert(k, v),
+                        None => model.remove(&k),
+                    };
+                }
+            }
+        }
+        if !storage.crashed() {
+            // 整个工作负载都没有到达崩溃点
+            return Ok(());
+        }
+
+        if power_off {
+            storage.power_off();
+        } else {
+            storage.restart();
+        }
+        let store = open(&storage, power_off).unwrap_or_else(|e| panic!("crash at {}: {}", n, e));
+        if let Some((k, old, new)) = pending {
+            let got = store.get(k.clone())?;
+            assert!(
+                got == old || got == new,
+                "crash at {}: {} is {:?}",
+                n,
+                k,
+                got
+            );
+            match got {
+                Some(v) => model.insert(k, v),
+                None => model.remove(&k),
+            };
+        }
+        check(&store, &model)?;
+        // 重新打开之后可以正常写入
+        store.set("key0".to_owned(), "after".to_owned())?;
+        drop(store);
+        let store = open(&storage, power_off)?;
+        assert_eq!(store.get("key0".to_owned())?, Some("after".to_owned()));
+    }
+    unreachable!()
+}
+
+#[test]
+fn crash_everywhere_process() -> Result<()> {
+    crash_everywhere(false)
+}
+
+#[test]
+fn crash_everywhere_power_off() -> Result<()> {
+    crash_everywhere(true)
+}
+
+#[test]
+fn compaction_crash_before_rename() -> Result<()> {
+    let mut crashed_before_rename = false;
+    for n in 0.. {
+        let storage = MemStorage::new();
+        let store = open(&storage, true)?;
+        for i in 0..15 {
+            store.set(format!("key{}", i % 3), format!("value{}", i))?;
+        }
+        let log = storage.read_file(Path::new("/db/kvs.log"));
+        storage.set_faults(Faults {
+            crash_after: Some(n),
+            ..Faults::default()
+        });
+        if store.compact().is_ok() {
+            break;
+        }
+        drop(store);
+        // 临时文件已经写完并且 sync，还没有 rename
+        if let Some(tmp) = storage.read_file(Path::new("/db/kvs.log1")) {
+            if storage.read_file(Path::new("/db/kvs.log")) == log && tmp.len() > 100 {
+                crashed_before_rename = true;
+            }
+        }
+        storage.power_off();
+        let store = open(&storage, true)?;
+        for i in 12..15 {
+            assert_eq!(
+                store.get(format!("key{}", i % 3))?,
+                Some(format!("value{}", i))
+            );
+        }
+    }
+    assert!(crashed_before_rename);
+    Ok(())
+}
+
+// 长度字段在 log 中间损坏时打开失败，log 不变；只有最后一条不完整的记录被截掉
+#[test]
+fn corrupt_length_in_the_middle() -> Result<()> {
+    let storage = MemStorage::new();
+    let store = open(&storage, false)?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+    let path = Path::new("/db/kvs.log");
+    let log = storage.read_file(path).unwrap();
+    // 跳过文件头和前两条记录
+    let mut pos = 8 + u16::from_le_bytes([log[6], log[7]]) as usize;
+    for _ in 0..2 {
+        pos += 5 + u32::from_le_bytes(log[pos..pos + 4].try_into().unwrap()) as usize;
+    }
+    // 长度超出文件结束，和最低字节损坏导致记录边界错位
+    for (i, b) in [(3, 0x7f), (0, 0xff)] {
+        let mut bad = log.clone();
+        bad[pos + i] = b;
+        storage.write_file(path, bad.clone());
+        let e = open(&storage, false).err().expect("corrupt log opened");
+        assert!(e.to_string().contains("kvs-tool"), "{}", e);
+        assert_eq!(storage.read_file(path).unwrap(), bad);
+    }
+
+    // 崩溃时写了一半的记录
+    let mut torn = log.clone();
+    torn.extend_from_slice(&[0xe8, 0x03, 0, 0, 0]);
+    torn.extend_from_slice(br#"{"Set":["key"#);
+    storage.write_file(path, torn);
+    let store = open(&storage, false)?;
+    assert_eq!(store.get("key9".to_owned())?, Some("value9".to_owned()));
+    drop(store);
+    assert_eq!(storage.read_file(path).unwrap(), log);
+    Ok(())
+}
+
+// 和 DiskStorage 一样，同一个目录只能有一个可写的 store，只读的 store 可以同时打开
+#[test]
+fn directory_locks() -> Result<()> {
+    let storage = MemStorage::new();
+    let read_only = || {
+        let opts = KvStoreOptions {
+            read_only: true,
+            storage: Arc::new(storage.clone()),
+            ..KvStoreOptions::default()
+        };
+        KvStore::open_with_options(Path::new("/db"), opts)
+    };
+    let store = open(&storage, false)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let e = open(&storage, false).err().expect("opened twice");
+    assert!(e.to_string().contains("locked"), "{}", e);
+    let r1 = read_only()?;
+    let r2 = read_only()?;
+    assert_eq!(r2.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+    let store = open(&storage, false)?;
+    drop((r1, r2));
+
+    // 重启之后之前的进程持有的锁都已经释放
+    storage.restart();
+    let _reopened = open(&storage, false)?;
+    drop(store);
+    assert!(open(&storage, false).is_err());
+    Ok(())
+}
@@ -0,0 +1,571 @@
+use assert_cmd::prelude::*;
+use predicates::str::contains;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use kvs::auth::{hash_secret, hash_token, Authenticator};
+use kvs::net::{Addr, Listener, Stream, TLS_HANDSHAKE_TIMEOUT};
+use kvs::raft::{NodeId, RaftNode, RaftOptions, Role};
+use kvs::replication::{Primary, Replica, ReplicaOptions, Replication};
+use kvs::thread_pool::{NaiveThreadPool, SharedQueueThreadPool, ThreadPool};
+use kvs::{tls, HttpGateway, KvStore, KvsEngine, KvsServer, Result, ServerOptions};
+use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, KeyPair};
+use slog::{o, Discard, Logger};
+use tempfile::TempDir;
+
+// 测试时生成的自签名 CA，以及它签发的 server 和 client 证书
+struct Certs {
+    ca: PathBuf,
+    server_cert: PathBuf,
+    server_key: PathBuf,
+    client_cert: PathBuf,
+    client_key: PathBuf,
+}
+
+fn gen_certs(dir: &Path, client_cn: &str) -> Certs {
+    let ca_key = KeyPair::generate().unwrap();
+    let mut params = CertificateParams::new(vec![]).unwrap();
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    params
+        .distinguished_name
+        .push(DnType::CommonName, "kvs test ca");
+    let ca = params.self_signed(&ca_key).unwrap();
+
+    let server_key = KeyPair::generate().unwrap();
+    let params =
+        CertificateParams::new(vec!["localhost".to_owned(), "127.0.0.1".to_owned()]).unwrap();
+    let server = params.signed_by(&server_key, &ca, &ca_key).unwrap();
+
+    let client_key = KeyPair::generate().unwrap();
+    let mut params = CertificateParams::new(vec![]).unwrap();
+    params
+        .distinguished_name
+        .push(DnType::CommonName, client_cn);
+    let client = params.signed_by(&client_key, &ca, &ca_key).unwrap();
+
+    let write = |name: &str, pem: String| {
+        let p = dir.join(name);
+        fs::write(&p, pem).unwrap();
+        p
+    };
+    Certs {
+        ca: write("ca.pem", ca.pem()),
+        server_cert: write("server.pem", server.pem()),
+        server_key: write("server.key", server_key.serialize_pem()),
+        client_cert: write("client.pem", client.pem()),
+        client_key: write("client.key", client_key.serialize_pem()),
+    }
+}
+
+fn request(stream: &mut Stream, line: &str) -> Result<String> {
+    stream.write_all(format!("{}\n", line).as_bytes())?;
+    let mut s = String::new();
+    BufReader::new(stream.try_clone()?).read_line(&mut s)?;
+    Ok(s.trim_end().to_owned())
+}
+
+fn start_server(dir: &Path, addr: &'static str, opts: ServerOptions) {
+    let engine = Arc::new(KvStore::open(dir).unwrap());
+    let server = KvsServer::with_options(
+        engine,
+        NaiveThreadPool::new(4).unwrap(),
+        Logger::root(Discard, o!()),
+        opts,
+    );
+    thread::spawn(move || server.run(addr.parse().unwrap()).unwrap());
+    thread::sleep(Duration::from_millis(500));
+}
+
+#[test]
+fn tls_server() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let certs = gen_certs(temp_dir.path(), "alice");
+    let opts = ServerOptions {
+        tls: Some(tls::server_config(
+            &certs.server_cert,
+            &certs.server_key,
+            None,
+        )?),
+        ..ServerOptions::default()
+    };
+    start_server(temp_dir.path(), "127.0.0.1:4015", opts);
+    let addr: Addr = "127.0.0.1:4015".parse()?;
+
+    let config = tls::client_config(&certs.ca, None)?;
+    let mut c = Stream::connect_tls(&addr, config.clone(), None)?;
+    assert_eq!(request(&mut c, r#"{"Set":["key1","value1"]}"#)?, "Success");
+    assert_eq!(request(&mut c, r#"{"Get":"key1"}"#)?, "value1");
+    let mut c = Stream::connect_tls(&addr, config, Some("localhost"))?;
+    assert_eq!(request(&mut c, r#"{"Get":"key1"}"#)?, "value1");
+
+    // 明文客户端拿不到数据
+    let mut plain = Stream::connect(&addr)?;
+    assert!(request(&mut plain, r#"{"Get":"key1"}"#).map_or(true, |r| r != "value1"));
+
+    // 不信任 server 证书的客户端在握手时失败
+    let other = TempDir::new().expect("unable to create temporary working directory");
+    let other_certs = gen_certs(other.path(), "alice");
+    let config = tls::client_config(&other_certs.ca, None)?;
+    assert!(Stream::connect_tls(&addr, config, None).is_err());
+    Ok(())
+}
+
+#[test]
+fn mutual_tls_server() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let certs = gen_certs(temp_dir.path(), "alice");
+    let opts = ServerOptions {
+        tls: Some(tls::server_config(
+            &certs.server_cert,
+            &certs.server_key,
+            Some(&certs.ca),
+        )?),
+        ..ServerOptions::default()
+    };
+    start_server(temp_dir.path(), "127.0.0.1:4016", opts);
+    let addr: Addr = "127.0.0.1:4016".parse()?;
+
+    let config = tls::client_config(&certs.ca, Some((&certs.client_cert, &certs.client_key)))?;
+    let mut c = Stream::connect_tls(&addr, config, None)?;
+    assert_eq!(request(&mut c, r#"{"Set":["key1","value1"]}"#)?, "Success");
+
+    // 没有客户端证书时 server 拒绝握手，TLS 1.3 下错误在第一次读写时出现
+    let config = tls::client_config(&certs.ca, None)?;
+    let r = Stream::connect_tls(&addr, config, None)
+        .and_then(|mut c| request(&mut c, r#"{"Get":"key1"}"#));
+    assert!(r.map_or(true, |r| r.is_empty()));
+    Ok(())
+}
+
+// 客户端证书的 CN 是连接的身份
+#[test]
+fn mutual_tls_identity() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let certs = gen_certs(temp_dir.path(), "alice");
+    let server_config = tls::server_config(&certs.server_cert, &certs.server_key, Some(&certs.ca))?;
+    let addr: Addr = "127.0.0.1:4017".parse()?;
+    let listener = Listener::bind(&addr, 0o600)?;
+    let handle = thread::spawn(move || {
+        let stream = listener.accept().unwrap();
+        let mut stream = stream
+            .accept_tls(server_config, TLS_HANDSHAKE_TIMEOUT)
+            .unwrap();
+        let id = stream.peer_identity();
+        stream.write_all(b"ok\n").unwrap();
+        id
+    });
+
+    let config = tls::client_config(&certs.ca, Some((&certs.client_cert, &certs.client_key)))?;
+    let c = Stream::connect_tls(&addr, config, None)?;
+    let mut s = String::new();
+    BufReader::new(c).read_line(&mut s)?;
+    assert_eq!(s, "ok\n");
+    assert_eq!(handle.join().unwrap(), Some("alice".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn cli_tls() {
+    let temp_dir = TempDir::new().unwrap();
+    let certs = gen_certs(temp_dir.path(), "alice");
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4018"])
+        .arg("--tls-cert")
+        .arg(&certs.server_cert)
+        .arg("--tls-key")
+        .arg(&certs.server_key)
+        .arg("--tls-client-ca")
+        .arg(&certs.ca)
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args)
+            .args(["--addr", "127.0.0.1:4018"])
+            .arg("--tls-ca")
+            .arg(&certs.ca)
+            .arg("--tls-cert")
+            .arg(&certs.client_cert)
+            .arg("--tls-key")
+            .arg(&certs.client_key)
+            .current_dir(&temp_dir);
+        cmd
+    };
+    client(&["set", "key1", "value1"]).assert().success();
+    client(&["get", "key1"])
+        .assert()
+        .success()
+        .stdout(contains("value1"));
+    client(&["get", "key1", "--tls-server-name", "localhost"])
+        .assert()
+        .success()
+        .stdout(contains("value1"));
+    // 证书中没有这个名字
+    client(&["get", "key1", "--tls-server-name", "example.com"])
+        .assert()
+        .failure();
+    // 没有客户端证书
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "127.0.0.1:4018", "--tls-ca"])
+        .arg(&certs.ca)
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// 开启认证时，CN 和用户名相同的客户端证书不需要再发送 Auth
+#[test]
+fn mutual_tls_login() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let certs = gen_certs(temp_dir.path(), "alice");
+    let users = temp_dir.path().join("users.json");
+    fs::write(
+        &users,
+        r#"{"users": [{"name": "alice", "acl": [{"prefix": "a/", "perm": "rw"}]}]}"#,
+    )?;
+    let opts = ServerOptions {
+        tls: Some(tls::server_config(
+            &certs.server_cert,
+            &certs.server_key,
+            Some(&certs.ca),
+        )?),
+        auth: Some(Arc::new(Authenticator::load(&users, None)?)),
+        ..ServerOptions::default()
+    };
+    start_server(temp_dir.path(), "127.0.0.1:4023", opts);
+    let addr: Addr = "127.0.0.1:4023".parse()?;
+
+    let config = tls::client_config(&certs.ca, Some((&certs.client_cert, &certs.client_key)))?;
+    let mut c = Stream::connect_tls(&addr, config, None)?;
+    assert_eq!(request(&mut c, r#"{"Set":["a/1","v"]}"#)?, "Success");
+    assert!(request(&mut c, r#"{"Set":["b/1","v"]}"#)?.starts_with("NOPERM "));
+    Ok(())
+}
+
+// 连上之后不握手的客户端在超时之后被断开，不会一直占用线程池里唯一的线程
+#[test]
+fn stalled_tls_handshake() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let certs = gen_certs(temp_dir.path(), "alice");
+    let opts = ServerOptions {
+        tls: Some(tls::server_config(
+            &certs.server_cert,
+            &certs.server_key,
+            None,
+        )?),
+        idle_timeout: Some(Duration::from_millis(500)),
+        ..ServerOptions::default()
+    };
+    let server = KvsServer::with_options(
+        Arc::new(KvStore::open(temp_dir.path())?),
+        SharedQueueThreadPool::new(1)?,
+        Logger::root(Discard, o!()),
+        opts,
+    );
+    thread::spawn(move || server.run("127.0.0.1:4095".parse().unwrap()).unwrap());
+    thread::sleep(Duration::from_millis(500));
+    let addr: Addr = "127.0.0.1:4095".parse()?;
+
+    let stalled = TcpStream::connect("127.0.0.1:4095")?;
+    stalled.set_read_timeout(Some(Duration::from_secs(5)))?;
+    assert_eq!((&stalled).read(&mut [0; 16])?, 0);
+
+    let config = tls::client_config(&certs.ca, None)?;
+    let mut c = Stream::connect_tls(&addr, config, None)?;
+    assert_eq!(request(&mut c, r#"{"Set":["key1","value1"]}"#)?, "Success");
+    Ok(())
+}
+
+// primary 开启 TLS 和认证时 replica 用 CA 和用户连接，密码错误时不复制
+#[test]
+fn replica_of_tls_primary_with_auth() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let replica_dir = TempDir::new().expect("unable to create temporary working directory");
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    let certs = gen_certs(temp_dir.path(), "alice");
+    let users = temp_dir.path().join("users.json");
+    let users_json = serde_json::json!({"users": [
+        {"name": "repl", "password": hash_secret("pw"), "acl": [{"prefix": "", "perm": "r"}]},
+    ]});
+    fs::write(&users, users_json.to_string())?;
+    let primary = Primary::new(Arc::new(KvStore::open(temp_dir.path())?), 100);
+    let opts = ServerOptions {
+        tls: Some(tls::server_config(
+            &certs.server_cert,
+            &certs.server_key,
+            None,
+        )?),
+        auth: Some(Arc::new(Authenticator::load(&users, None)?)),
+        replication: Some(Replication::Primary(primary.clone())),
+        ..ServerOptions::default()
+    };
+    let server = KvsServer::with_options(
+        Arc::new(primary.clone()),
+        NaiveThreadPool::new(4)?,
+        Logger::root(Discard, o!()),
+        opts,
+    );
+    thread::spawn(move || server.run("127.0.0.1:4097".parse().unwrap()).unwrap());
+    thread::sleep(Duration::from_millis(500));
+    primary.set("key1".to_owned(), "value1".to_owned())?;
+
+    let start = |dir: &Path, password: &str| -> Result<_> {
+        let engine: Arc<dyn KvsEngine + Sync> = Arc::new(KvStore::open(dir)?);
+        let opts = ReplicaOptions {
+            tls: Some(tls::client_config(&certs.ca, None)?),
+            tls_server_name: Some("localhost".to_owned()),
+            user: Some("repl".to_owned()),
+            password: Some(password.to_owned()),
+        };
+        let r = Replica::with_options(
+            "127.0.0.1:4097",
+            engine.clone(),
+            dir,
+            Logger::root(Discard, o!()),
+            opts,
+        )?;
+        Ok((r, engine))
+    };
+    let (r, engine) = start(replica_dir.path(), "pw")?;
+    let (bad, bad_engine) = start(other_dir.path(), "wrong")?;
+    for _ in 0..100 {
+        if engine.get("key1".to_owned())?.is_some() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(r.status().connected);
+    assert!(!bad.status().connected);
+    assert_eq!(bad_engine.get("key1".to_owned())?, None);
+    r.stop();
+    bad.stop();
+    Ok(())
+}
+
+// 节点之间的 RPC 经过 TLS，用所有节点共用的 token 认证
+#[test]
+fn raft_cluster_over_tls_with_token() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let certs = gen_certs(temp_dir.path(), "alice");
+    let users = temp_dir.path().join("users.json");
+    let users_json = serde_json::json!({"users": [
+        {"name": "raft", "tokens": [hash_token("cluster-secret")], "acl": [{"prefix": "", "perm": "rw"}]},
+    ]});
+    fs::write(&users, users_json.to_string())?;
+    let auth = Arc::new(Authenticator::load(&users, None)?);
+    let server_tls = tls::server_config(&certs.server_cert, &certs.server_key, None)?;
+    let client_tls = tls::client_config(&certs.ca, None)?;
+    let ports = [4100, 4101, 4102];
+    let peers: BTreeMap<NodeId, String> = (1..)
+        .zip(ports)
+        .map(|(id, port)| (id, format!("127.0.0.1:{}", port)))
+        .collect();
+    let mut nodes = vec![];
+    for (id, port) in (1..).zip(ports) {
+        let dir = TempDir::new().expect("unable to create temporary working directory");
+        let store: Arc<dyn KvsEngine + Sync> = Arc::new(KvStore::open(dir.path())?);
+        let opts = RaftOptions {
+            tls: Some(client_tls.clone()),
+            tls_server_name: None,
+            user: None,
+            password: Some("cluster-secret".to_owned()),
+        };
+        let raft = RaftNode::with_options(
+            id,
+            peers.clone(),
+            store.clone(),
+            &dir.path().join("raft"),
+            100,
+            Logger::root(Discard, o!()),
+            opts,
+        )?;
+        let opts = ServerOptions {
+            tls: Some(server_tls.clone()),
+            auth: Some(auth.clone()),
+            replication: Some(Replication::Raft(raft.clone())),
+            ..ServerOptions::default()
+        };
+        let server = KvsServer::with_options(
+            raft.engine(),
+            NaiveThreadPool::new(4)?,
+            Logger::root(Discard, o!()),
+            opts,
+        );
+        thread::spawn(move || {
+            server
+                .run(format!("127.0.0.1:{}", port).parse().unwrap())
+                .unwrap()
+        });
+        nodes.push((raft, store, dir));
+    }
+
+    let start = Instant::now();
+    let leader = loop {
+        if let Some(n) = nodes.iter().find(|n| n.0.role() == Role::Leader) {
+            break n;
+        }
+        assert!(start.elapsed() < Duration::from_secs(10), "no leader");
+        thread::sleep(Duration::from_millis(50));
+    };
+    leader
+        .0
+        .engine()
+        .set("key1".to_owned(), "value1".to_owned())?;
+    for (_, store, _) in &nodes {
+        while store.get("key1".to_owned())?.is_none() {
+            assert!(start.elapsed() < Duration::from_secs(10), "not replicated");
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    // 没有认证的连接不能发送 RPC 和修改成员
+    let addr: Addr = "127.0.0.1:4100".parse()?;
+    let mut c = Stream::connect_tls(&addr, client_tls, Some("localhost"))?;
+    assert!(request(&mut c, r#"{"AddNode":[4,"127.0.0.1:4103"]}"#)?.starts_with("NOAUTH"));
+    assert!(request(&mut c, r#"{"RemoveNode":2}"#)?.starts_with("NOAUTH"));
+    assert!(request(&mut c, r#"{"Auth":[null,"wrong"]}"#)?.starts_with("WRONGPASS"));
+    assert_eq!(
+        request(&mut c, r#"{"Auth":[null,"cluster-secret"]}"#)?,
+        "Success"
+    );
+    for (raft, _, _) in &nodes {
+        raft.stop();
+    }
+    Ok(())
+}
+
+// 配置了 TLS 时 HTTP 网关也只接受 TLS 连接
+#[test]
+fn http_gateway_over_tls() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let certs = gen_certs(temp_dir.path(), "alice");
+    let gateway = HttpGateway::new(
+        Arc::new(KvStore::open(temp_dir.path())?),
+        NaiveThreadPool::new(4)?,
+        Logger::root(Discard, o!()),
+    )
+    .with_tls(Some(tls::server_config(
+        &certs.server_cert,
+        &certs.server_key,
+        None,
+    )?));
+    thread::spawn(move || gateway.run("127.0.0.1:4105".parse().unwrap()).unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    let addr: Addr = "127.0.0.1:4105".parse()?;
+    let config = tls::client_config(&certs.ca, None)?;
+    let mut c = Stream::connect_tls(&addr, config, Some("localhost"))?;
+    let status = request(&mut c, "GET /health HTTP/1.1\r\nConnection: close\r\n\r")?;
+    assert!(status.starts_with("HTTP/1.1 200 "), "{}", status);
+
+    // 明文的请求不是 TLS 握手，连接被关闭
+    let mut plain = TcpStream::connect("127.0.0.1:4105")?;
+    plain.write_all(b"GET /health HTTP/1.1\r\n\r\n")?;
+    let mut buf = vec![];
+    let _ = plain.read_to_end(&mut buf);
+    assert!(!String::from_utf8_lossy(&buf).contains("200"));
+    Ok(())
+}
+
+fn https(addr: &Addr, ca: &Path, method: &str, path: &str, auth: Option<&str>) -> u16 {
+    let config = tls::client_config(ca, None).unwrap();
+    let mut c = Stream::connect_tls(addr, config, Some("localhost")).unwrap();
+    let mut req = format!("{} {} HTTP/1.1\r\nConnection: close\r\n", method, path);
+    if let Some(a) = auth {
+        req += &format!("Authorization: {}\r\n", a);
+    }
+    let body = if method == "PUT" {
+        r#"{"value":"v"}"#
+    } else {
+        ""
+    };
+    req += &format!("Content-Length: {}\r\n\r\n{}", body.len(), body);
+    c.write_all(req.as_bytes()).unwrap();
+    let mut status = String::new();
+    BufReader::new(c).read_line(&mut status).unwrap();
+    status.split_whitespace().nth(1).unwrap().parse().unwrap()
+}
+
+// HTTP 网关的认证只在 TLS 连接上进行
+#[test]
+fn https_auth_and_acl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let certs = gen_certs(temp_dir.path(), "carol");
+    // alice 用密码认证，可以读所有 key，只能写 app/ 下的 key
+    // bob 用 token 认证，只能读 pub/ 下的 key
+    let users = temp_dir.path().join("users.json");
+    fs::write(
+        &users,
+        serde_json::json!({"users": [
+            {
+                "name": "alice",
+                "password": hash_secret("secret"),
+                "acl": [{"prefix": "app/", "perm": "rw"}, {"prefix": "", "perm": "r"}],
+            },
+            {
+                "name": "bob",
+                "tokens": [hash_token("bob-token")],
+                "acl": [{"prefix": "pub/", "perm": "r"}],
+            },
+        ]})
+        .to_string(),
+    )?;
+    let gateway = HttpGateway::new(
+        Arc::new(KvStore::open(temp_dir.path())?),
+        NaiveThreadPool::new(2)?,
+        Logger::root(Discard, o!()),
+    )
+    .with_auth(Some(Arc::new(Authenticator::load(&users, None)?)))
+    .with_tls(Some(tls::server_config(
+        &certs.server_cert,
+        &certs.server_key,
+        None,
+    )?));
+    thread::spawn(move || gateway.run("127.0.0.1:4021".parse().unwrap()).unwrap());
+    thread::sleep(Duration::from_millis(500));
+    let addr: Addr = "127.0.0.1:4021".parse()?;
+    let http = |method, path, auth| https(&addr, &certs.ca, method, path, auth);
+    // alice:secret
+    let alice = "Basic YWxpY2U6c2VjcmV0";
+
+    assert_eq!(http("GET", "/health", None), 200);
+    assert_eq!(http("GET", "/keys/app/x", None), 401);
+    assert_eq!(http("GET", "/keys", None), 401);
+    assert_eq!(http("GET", "/keys/app/x", Some("Basic YWxpY2U6eA==")), 401);
+    assert_eq!(http("PUT", "/keys/app/x", Some(alice)), 200);
+    assert_eq!(http("GET", "/keys/app/x", Some(alice)), 200);
+    assert_eq!(http("PUT", "/keys/pub/x", Some(alice)), 403);
+    assert_eq!(http("GET", "/keys/app/x", Some("Bearer bob-token")), 403);
+    assert_eq!(http("GET", "/keys/pub/x", Some("Bearer bob-token")), 404);
+    assert_eq!(http("GET", "/keys", Some("Bearer bob-token")), 200);
+
+    // 明文连接上的 Basic 认证不会被处理
+    let mut plain = TcpStream::connect("127.0.0.1:4021")?;
+    plain.write_all(
+        format!(
+            "GET /keys/app/x HTTP/1.1\r\nAuthorization: {}\r\n\r\n",
+            alice
+        )
+        .as_bytes(),
+    )?;
+    let mut buf = vec![];
+    let _ = plain.read_to_end(&mut buf);
+    assert!(!String::from_utf8_lossy(&buf).contains("200"));
+    Ok(())
+}
@@ -0,0 +1,140 @@
+use assert_cmd::prelude::*;
+use predicates::str::contains;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::process::Command;
+
+use kvs::inspect::{repair, summarize, Entry, Scanner};
+use kvs::{KvStore, KvsEngine, Result, LOGFILENAM};
+use tempfile::TempDir;
+
+// 写入 key0..key9，返回每条记录的 offset
+fn fill(dir: &Path) -> Result<Vec<u64>> {
+    let store = KvStore::open(dir)?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+    let offsets = Scanner::open(dir, None)?
+        .map(|e| match e {
+            Entry::Record { offset, .. } => offset,
+            Entry::Corrupt { .. } => panic!("corrupt record in a fresh log"),
+        })
+        .collect();
+    Ok(offsets)
+}
+
+fn flip(dir: &Path, offset: u64) {
+    let p = dir.join(LOGFILENAM);
+    let mut data = fs::read(&p).unwrap();
+    data[offset as usize] ^= 0xff;
+    fs::write(&p, data).unwrap();
+}
+
+#[test]
+fn scan_skips_corrupt_regions() -> Result<()> {
+    let dir = TempDir::new().unwrap();
+    let offsets = fill(dir.path())?;
+    // 第 3 条记录的长度字段损坏，第 6 条的 payload 损坏，最后一条只写了一半
+    flip(dir.path(), offsets[3] + 2);
+    flip(dir.path(), offsets[6] + 8);
+    let end = fs::metadata(dir.path().join(LOGFILENAM))?.len();
+    let mut f = OpenOptions::new()
+        .append(true)
+        .open(dir.path().join(LOGFILENAM))?;
+    f.write_all(&[30, 0, 0, 0, 0, b'{'])?;
+
+    let s = summarize(Scanner::open(dir.path(), None)?);
+    assert_eq!(s.records, 8);
+    let corrupt: Vec<u64> = s.corrupt.iter().map(|c| c.0).collect();
+    assert_eq!(corrupt, [offsets[3], offsets[6], end]);
+    assert_eq!(s.corrupt[0].1, offsets[4] - offsets[3]);
+    assert_eq!(s.corrupt[2].2, "truncated record payload");
+    assert!(!s.live.contains_key("key3") && !s.live.contains_key("key6"));
+
+    let r = repair(dir.path(), None)?;
+    assert_eq!((r.records, r.corrupt_regions), (8, 3));
+    assert!(r.backup.unwrap().exists());
+    let store = KvStore::open(dir.path())?;
+    assert_eq!(store.keys("")?.len(), 8);
+    assert_eq!(store.get("key9".to_owned())?, Some("value9".to_owned()));
+    drop(store);
+
+    // 已经修复过的 log 不再改动
+    let r = repair(dir.path(), None)?;
+    assert_eq!((r.records, r.corrupt_regions), (8, 0));
+    assert!(r.backup.is_none());
+    Ok(())
+}
+
+fn tool(dir: &Path, args: &[&str]) -> Command {
+    let mut cmd = Command::cargo_bin("kvs-tool").unwrap();
+    cmd.args(args).current_dir(dir);
+    cmd
+}
+
+#[test]
+fn cli_tool() -> Result<()> {
+    let dir = TempDir::new().unwrap();
+    let d = dir.path();
+    let offsets = fill(d)?;
+    let store = KvStore::open(d)?;
+    store.set("key0".to_owned(), "new".to_owned())?;
+    store.remove("key1".to_owned())?;
+    drop(store);
+
+    tool(d, &["dump"])
+        .assert()
+        .success()
+        .stdout(contains(format!(
+            "{:>12}  set            30  key0",
+            offsets[0]
+        )))
+        .stdout(contains("rm             18  key1"));
+    tool(d, &["verify"])
+        .assert()
+        .success()
+        .stdout(contains("records: 12 (11 set, 1 rm)"))
+        .stdout(contains("live keys: 9"))
+        .stdout(contains("keys written more than once: 1"))
+        .stdout(contains("log is intact"));
+    tool(d, &["stat"])
+        .assert()
+        .success()
+        .stdout(contains("keys: 9"))
+        .stdout(contains(
+            "key size histogram:\n  <=          4 B           9  100.0%",
+        ));
+
+    // 检查可以和打开 store 的进程同时进行，修复不可以
+    let store = KvStore::open(d)?;
+    flip(d, offsets[5] + 10);
+    tool(d, &["verify"])
+        .assert()
+        .failure()
+        .stdout(contains(format!(
+            "corrupt regions: 1 ({} bytes)",
+            offsets[6] - offsets[5]
+        )));
+    tool(d, &["dump"])
+        .assert()
+        .success()
+        .stdout(contains("CORRUPT"));
+
+    tool(d, &["repair"])
+        .assert()
+        .failure()
+        .stderr(contains("is locked by another process"));
+    drop(store);
+    tool(d, &["repair"])
+        .assert()
+        .success()
+        .stdout(contains("salvaged 11 records, dropped 1 corrupt regions"));
+    tool(d, &["verify"]).assert().success();
+    tool(d, &["repair"])
+        .assert()
+        .success()
+        .stdout(contains("nothing to repair"));
+    Ok(())
+}
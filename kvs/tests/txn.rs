@@ -0,0 +1,242 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use kvs::thread_pool::{NaiveThreadPool, SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, KvsEngine, KvsServer, Result, ServerOptions, SledKvsEngine, TXN_CONFLICT};
+use slog::{o, Discard, Logger};
+use tempfile::TempDir;
+
+fn txn_commit<E: KvsEngine>(engine: E) -> Result<()> {
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut txn = engine.begin()?;
+    assert_eq!(txn.get("key1".to_owned())?, Some("value1".to_owned()));
+    txn.set("key1".to_owned(), "value2".to_owned())?;
+    txn.set("key2".to_owned(), "value3".to_owned())?;
+    assert_eq!(txn.get("key2".to_owned())?, Some("value3".to_owned()));
+    assert!(txn.remove("key3".to_owned()).is_err());
+    // 提交之前其他人看不到事务里的写入
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, None);
+    txn.commit()?;
+
+    assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, Some("value3".to_owned()));
+
+    let mut txn = engine.begin()?;
+    txn.remove("key1".to_owned())?;
+    assert_eq!(txn.get("key1".to_owned())?, None);
+    txn.commit()?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+
+    // 没有提交的事务被丢弃
+    let mut txn = engine.begin()?;
+    txn.set("key4".to_owned(), "value4".to_owned())?;
+    drop(txn);
+    assert_eq!(engine.get("key4".to_owned())?, None);
+    Ok(())
+}
+
+fn txn_conflict<E: KvsEngine>(engine: E) -> Result<()> {
+    engine.set("counter".to_owned(), "0".to_owned())?;
+
+    // read-write 冲突
+    let mut t1 = engine.begin()?;
+    let mut t2 = engine.begin()?;
+    let v1: u32 = t1.get("counter".to_owned())?.unwrap().parse()?;
+    let v2: u32 = t2.get("counter".to_owned())?.unwrap().parse()?;
+    t1.set("counter".to_owned(), (v1 + 1).to_string())?;
+    t2.set("counter".to_owned(), (v2 + 1).to_string())?;
+    t1.commit()?;
+    assert_eq!(t2.commit().unwrap_err().to_string(), TXN_CONFLICT);
+    assert_eq!(engine.get("counter".to_owned())?, Some("1".to_owned()));
+
+    // write-write 冲突
+    let mut t1 = engine.begin()?;
+    t1.set("other".to_owned(), "a".to_owned())?;
+    engine.set("other".to_owned(), "b".to_owned())?;
+    assert_eq!(t1.commit().unwrap_err().to_string(), TXN_CONFLICT);
+    assert_eq!(engine.get("other".to_owned())?, Some("b".to_owned()));
+
+    // 不相交的 key 不冲突
+    let mut t1 = engine.begin()?;
+    let mut t2 = engine.begin()?;
+    t1.get("counter".to_owned())?;
+    t1.set("x".to_owned(), "1".to_owned())?;
+    t2.set("y".to_owned(), "2".to_owned())?;
+    t2.commit()?;
+    t1.commit()?;
+    Ok(())
+}
+
+#[test]
+fn kvs_txn_commit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    txn_commit(KvStore::open(temp_dir.path())?)?;
+
+    // 事务的写入在重新打开后仍然存在
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn kvs_txn_conflict() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    txn_conflict(KvStore::open(temp_dir.path())?)
+}
+
+// compaction 不会让之前开始的事务冲突，除非事务读过的 key 被修改过
+#[test]
+fn kvs_txn_across_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("a".to_owned(), "0".to_owned())?;
+    store.set("b".to_owned(), "0".to_owned())?;
+    store.set("c".to_owned(), "0".to_owned())?;
+
+    let mut t1 = store.begin()?;
+    t1.get("a".to_owned())?;
+    t1.set("a".to_owned(), "1".to_owned())?;
+    let mut t2 = store.begin()?;
+    t2.get("b".to_owned())?;
+    t2.set("b".to_owned(), "1".to_owned())?;
+    let mut t3 = store.begin()?;
+    t3.get("c".to_owned())?;
+    t3.set("d".to_owned(), "1".to_owned())?;
+
+    store.set("b".to_owned(), "2".to_owned())?;
+    store.remove("c".to_owned())?;
+    store.compact()?;
+
+    t1.commit()?;
+    assert_eq!(t2.commit().unwrap_err().to_string(), TXN_CONFLICT);
+    assert_eq!(t3.commit().unwrap_err().to_string(), TXN_CONFLICT);
+    assert_eq!(store.get("a".to_owned())?, Some("1".to_owned()));
+    assert_eq!(store.get("b".to_owned())?, Some("2".to_owned()));
+    assert_eq!(store.get("d".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn sled_txn_commit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    txn_commit(SledKvsEngine::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_txn_conflict() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    txn_conflict(SledKvsEngine::open(temp_dir.path())?)
+}
+
+fn request(stream: &mut TcpStream, reader: &mut impl BufRead, line: &str) -> String {
+    stream.write_all(format!("{}\n", line).as_bytes()).unwrap();
+    let mut s = String::new();
+    reader.read_line(&mut s).unwrap();
+    s.trim_end().to_owned()
+}
+
+#[test]
+fn server_txn_protocol() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = Arc::new(KvStore::open(temp_dir.path())?);
+    let server = KvsServer::new(
+        engine.clone(),
+        NaiveThreadPool::new(4)?,
+        Logger::root(Discard, o!()),
+    );
+    thread::spawn(move || server.run("127.0.0.1:4006".parse().unwrap()).unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    let mut c1 = TcpStream::connect("127.0.0.1:4006")?;
+    let mut r1 = BufReader::new(c1.try_clone()?);
+    let mut c2 = TcpStream::connect("127.0.0.1:4006")?;
+    let mut r2 = BufReader::new(c2.try_clone()?);
+
+    assert_eq!(request(&mut c1, &mut r1, r#"{"Set":["k","0"]}"#), "Success");
+    assert_eq!(request(&mut c1, &mut r1, r#""Begin""#), "Success");
+    assert_eq!(
+        request(&mut c1, &mut r1, r#""Begin""#),
+        "Transaction already started"
+    );
+    assert_eq!(request(&mut c1, &mut r1, r#"{"Get":"k"}"#), "0");
+    assert_eq!(request(&mut c1, &mut r1, r#"{"Set":["k","1"]}"#), "Success");
+    assert_eq!(request(&mut c2, &mut r2, r#"{"Get":"k"}"#), "0");
+    assert_eq!(request(&mut c1, &mut r1, r#""Commit""#), "Success");
+    assert_eq!(request(&mut c2, &mut r2, r#"{"Get":"k"}"#), "1");
+
+    // 另一个连接在事务进行中修改了 key，提交失败
+    assert_eq!(request(&mut c1, &mut r1, r#""Begin""#), "Success");
+    assert_eq!(request(&mut c1, &mut r1, r#"{"Get":"k"}"#), "1");
+    assert_eq!(request(&mut c2, &mut r2, r#"{"Set":["k","2"]}"#), "Success");
+    assert_eq!(request(&mut c1, &mut r1, r#"{"Set":["k","3"]}"#), "Success");
+    assert_eq!(request(&mut c1, &mut r1, r#""Commit""#), TXN_CONFLICT);
+
+    assert_eq!(request(&mut c1, &mut r1, r#""Begin""#), "Success");
+    assert_eq!(request(&mut c1, &mut r1, r#"{"Set":["k","4"]}"#), "Success");
+    assert_eq!(request(&mut c1, &mut r1, r#""Abort""#), "Success");
+    assert_eq!(request(&mut c1, &mut r1, r#""Abort""#), "No transaction");
+    assert_eq!(engine.get("k".to_owned())?, Some("2".to_owned()));
+    Ok(())
+}
+
+// 连接数超过线程池的线程数：WATCH 连接不占用线程池，空闲的连接超时之后让出线程
+#[test]
+fn more_connections_than_pool_threads() -> Result<()> {
+    const POOL: usize = 2;
+    const ADDR: &str = "127.0.0.1:4092";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = Arc::new(KvStore::open(temp_dir.path())?);
+    let opts = ServerOptions {
+        idle_timeout: Some(Duration::from_millis(500)),
+        ..ServerOptions::default()
+    };
+    let server = KvsServer::with_options(
+        engine,
+        SharedQueueThreadPool::new(POOL as u32)?,
+        Logger::root(Discard, o!()),
+        opts,
+    );
+    thread::spawn(move || server.run(ADDR.parse().unwrap()).unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    let connect = || -> Result<(TcpStream, BufReader<TcpStream>)> {
+        let c = TcpStream::connect(ADDR)?;
+        // 没有得到处理时测试失败而不是一直等待
+        c.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let r = BufReader::new(c.try_clone()?);
+        Ok((c, r))
+    };
+    let mut watchers = vec![];
+    for _ in 0..POOL {
+        let (mut c, mut r) = connect()?;
+        let reply = request(&mut c, &mut r, r#"{"Watch":["k",null]}"#);
+        assert!(reply.contains("Watching"), "{}", reply);
+        watchers.push((c, r));
+    }
+
+    // POOL 个连接各自占用一个线程，最后一个连接等到其中一个空闲超时
+    let mut conns = vec![];
+    for _ in 0..=POOL {
+        conns.push(connect()?);
+    }
+    thread::scope(|s| {
+        for (i, (c, r)) in conns.iter_mut().enumerate() {
+            s.spawn(move || {
+                let set = format!(r#"{{"Set":["k{}","v"]}}"#, i);
+                assert_eq!(request(c, r, &set), "Success");
+            });
+        }
+    });
+
+    // 空闲的连接已经被关闭
+    let (_, r) = &mut conns[0];
+    let mut s = String::new();
+    assert_eq!(r.read_line(&mut s)?, 0);
+    Ok(())
+}
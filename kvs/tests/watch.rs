@@ -0,0 +1,274 @@
+use assert_cmd::prelude::*;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::process::{Child, ChildStdout, Command, Stdio};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use kvs::watch::{WatchMessage, WATCH_FELL_BEHIND};
+use kvs::{Event, KvStore, KvsEngine, SledKvsEngine, Watch};
+use tempfile::TempDir;
+
+const WAIT: Duration = Duration::from_secs(5);
+
+fn event(seq: u64, key: &str, value: Option<&str>) -> Event {
+    Event {
+        seq,
+        key: key.to_owned(),
+        value: value.map(Into::into),
+    }
+}
+
+fn watch_engine(engine: Arc<dyn KvsEngine + Sync>) {
+    let w = engine.watch().unwrap();
+    let mut prefix = w.subscribe("user:*", None);
+    let mut exact = w.subscribe("user:1", None);
+    assert!(!prefix.resumed());
+
+    engine.set("user:1".to_owned(), "a".to_owned()).unwrap();
+    engine.set("other".to_owned(), "x".to_owned()).unwrap();
+    engine.set("user:2".to_owned(), "b".to_owned()).unwrap();
+    engine.remove("user:1".to_owned()).unwrap();
+    // 删除不存在的 key 没有写入，也没有事件
+    assert!(engine.remove("user:9".to_owned()).is_err());
+    let mut txn = engine.begin().unwrap();
+    txn.set("user:3".to_owned(), "c".to_owned()).unwrap();
+    txn.remove("user:2".to_owned()).unwrap();
+    txn.commit().unwrap();
+
+    let mut got = vec![];
+    while let Some(e) = prefix.next(Duration::from_millis(100)).unwrap() {
+        got.push(e);
+    }
+    assert_eq!(got.len(), 5);
+    assert_eq!(got[0], event(1, "user:1", Some("a")));
+    assert_eq!(got[1], event(3, "user:2", Some("b")));
+    assert_eq!(got[2], event(4, "user:1", None));
+    // 事务中的写入按 key 的顺序发布，序号连续
+    assert_eq!((got[3].seq, got[4].seq), (5, 6));
+    assert_eq!(prefix.seq(), 6);
+    assert_eq!(
+        exact.next(WAIT).unwrap(),
+        Some(event(1, "user:1", Some("a")))
+    );
+    assert_eq!(exact.next(WAIT).unwrap(), Some(event(4, "user:1", None)));
+    assert_eq!(exact.next(Duration::from_millis(100)).unwrap(), None);
+
+    // 从中间的序号继续，不会遗漏之后的事件
+    let mut resumed = w.subscribe("user:*", Some((w.run_id(), 3)));
+    assert!(resumed.resumed());
+    assert_eq!(resumed.next(WAIT).unwrap(), Some(event(4, "user:1", None)));
+    let stale = w.subscribe("user:*", Some(("another-run", 3)));
+    assert!(!stale.resumed());
+    assert_eq!(stale.seq(), w.seq());
+
+    // 等待中的订阅者在写入之后被唤醒
+    let engine2 = engine.clone();
+    let writer = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(200));
+        engine2.set("user:4".to_owned(), "d".to_owned()).unwrap();
+    });
+    assert_eq!(
+        prefix.next(WAIT).unwrap(),
+        Some(event(7, "user:4", Some("d")))
+    );
+    writer.join().unwrap();
+}
+
+#[test]
+fn watch_kvs() {
+    let dir = TempDir::new().unwrap();
+    watch_engine(Arc::new(KvStore::open(dir.path()).unwrap()));
+}
+
+#[test]
+fn watch_sled() {
+    let dir = TempDir::new().unwrap();
+    watch_engine(Arc::new(SledKvsEngine::open(dir.path()).unwrap()));
+}
+
+// 写入 sled 和 flush 时不持有 publisher，同一个 key 的事件顺序和写入顺序一致
+#[test]
+fn watch_sled_concurrent_writes() {
+    let dir = TempDir::new().unwrap();
+    let engine = Arc::new(SledKvsEngine::open(dir.path()).unwrap());
+    let w = engine.watch().unwrap();
+    let p = w.publisher();
+    let writer = {
+        let engine = engine.clone();
+        thread::spawn(move || engine.set("key".to_owned(), "v".to_owned()).unwrap())
+    };
+    let start = std::time::Instant::now();
+    while engine.get("key".to_owned()).unwrap().is_none() {
+        assert!(start.elapsed() < WAIT, "set waited for the publisher");
+        thread::sleep(Duration::from_millis(10));
+    }
+    drop(p);
+    writer.join().unwrap();
+
+    let mut watcher = w.subscribe("key", None);
+    let writers: Vec<_> = (0..8)
+        .map(|t| {
+            let engine = engine.clone();
+            thread::spawn(move || {
+                for i in 0..50 {
+                    engine
+                        .set("key".to_owned(), format!("{}-{}", t, i))
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    for h in writers {
+        h.join().unwrap();
+    }
+    let mut last = None;
+    while let Some(e) = watcher.next(Duration::from_millis(100)).unwrap() {
+        last = e.value;
+    }
+    assert_eq!(last, engine.get("key".to_owned()).unwrap());
+}
+
+#[test]
+fn watcher_fell_behind() {
+    let w = Arc::new(Watch::new(10));
+    let mut watcher = w.subscribe("*", None);
+    let mut p = w.publisher();
+    for i in 0..20 {
+        p.publish(format!("key{}", i), Some("v".to_owned()));
+    }
+    drop(p);
+    let e = watcher.next(WAIT).unwrap_err();
+    assert_eq!(e.to_string(), WATCH_FELL_BEHIND);
+    // 被淘汰的位置不能继续
+    assert!(!w.subscribe("*", Some((w.run_id(), 5))).resumed());
+    assert!(w.subscribe("*", Some((w.run_id(), 10))).resumed());
+}
+
+// 大 value 按总字节数淘汰，不会因为条数没有超过限制就一直占用内存
+#[test]
+fn watch_history_bytes() {
+    let w = Arc::new(Watch::with_max_bytes(1000, 1000));
+    let mut watcher = w.subscribe("*", None);
+    let mut p = w.publisher();
+    for i in 0..10 {
+        p.publish(format!("key{}", i), Some("v".repeat(200)));
+    }
+    drop(p);
+    let e = watcher.next(WAIT).unwrap_err();
+    assert_eq!(e.to_string(), WATCH_FELL_BEHIND);
+    // 每个事件 204 字节，只保留最后 4 个
+    assert!(!w.subscribe("*", Some((w.run_id(), 5))).resumed());
+    let mut watcher = w.subscribe("*", Some((w.run_id(), 6)));
+    assert!(watcher.resumed());
+    assert_eq!(watcher.next(WAIT).unwrap().unwrap().key, "key6");
+
+    // 超过限制的单个事件也会保留
+    w.publisher()
+        .publish("big".to_owned(), Some("v".repeat(2000)));
+    assert!(w.subscribe("*", Some((w.run_id(), 10))).resumed());
+    assert!(!w.subscribe("*", Some((w.run_id(), 9))).resumed());
+}
+
+fn server(dir: &Path) -> Child {
+    let child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4060"])
+        .current_dir(dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    child
+}
+
+fn client(dir: &Path, args: &[&str]) -> Command {
+    let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+    cmd.args(args)
+        .args(["--addr", "127.0.0.1:4060"])
+        .current_dir(dir);
+    cmd
+}
+
+fn watcher(dir: &Path, args: &[&str]) -> (Child, BufReader<ChildStdout>) {
+    let mut child = client(dir, args).stdout(Stdio::piped()).spawn().unwrap();
+    let out = BufReader::new(child.stdout.take().unwrap());
+    (child, out)
+}
+
+fn next(out: &mut BufReader<ChildStdout>) -> WatchMessage {
+    let mut line = String::new();
+    out.read_line(&mut line).unwrap();
+    serde_json::from_str(&line).unwrap()
+}
+
+#[test]
+fn cli_watch_resume() {
+    let dir = TempDir::new().unwrap();
+    let d = dir.path();
+    let mut srv = server(d);
+    let (mut w, mut out) = watcher(d, &["watch", "user:*"]);
+    let run_id = match next(&mut out) {
+        WatchMessage::Watching {
+            run_id, resumed, ..
+        } => {
+            assert!(!resumed);
+            run_id
+        }
+        m => panic!("unexpected {:?}", m),
+    };
+    client(d, &["set", "user:1", "a"]).assert().success();
+    client(d, &["set", "other", "x"]).assert().success();
+    client(d, &["rm", "user:1"]).assert().success();
+    assert_eq!(
+        next(&mut out),
+        WatchMessage::Event(event(1, "user:1", Some("a")))
+    );
+    assert_eq!(
+        next(&mut out),
+        WatchMessage::Event(event(3, "user:1", None))
+    );
+    w.kill().unwrap();
+    w.wait().unwrap();
+
+    // 断开期间的写入在重新订阅之后补上
+    client(d, &["set", "user:2", "b"]).assert().success();
+    let resume = format!("{}:3", run_id);
+    let (mut w, mut out) = watcher(d, &["watch", "user:*", "--resume", &resume]);
+    assert!(matches!(
+        next(&mut out),
+        WatchMessage::Watching {
+            resumed: true,
+            seq: 3,
+            ..
+        }
+    ));
+    assert_eq!(
+        next(&mut out),
+        WatchMessage::Event(event(4, "user:2", Some("b")))
+    );
+
+    // server 重启之后 kvs-client 自动重连，历史已经丢失
+    srv.kill().unwrap();
+    srv.wait().unwrap();
+    let mut srv = server(d);
+    match next(&mut out) {
+        WatchMessage::Watching {
+            run_id: r, resumed, ..
+        } => {
+            assert_ne!(r, run_id);
+            assert!(!resumed);
+        }
+        m => panic!("unexpected {:?}", m),
+    }
+    client(d, &["set", "user:3", "c"]).assert().success();
+    assert!(matches!(
+        next(&mut out),
+        WatchMessage::Event(Event { ref key, .. }) if key == "user:3"
+    ));
+
+    w.kill().unwrap();
+    w.wait().unwrap();
+    srv.kill().unwrap();
+    srv.wait().unwrap();
+}